@@ -12,6 +12,7 @@
 use anyhow::anyhow;
 //use async_std::task;
 use keysas_lib::certificate_field::CertificateFields;
+use keysas_lib::hash_list::{ALLOW_LIST_PATH, DENY_LIST_PATH, HashList};
 use keysas_lib::keysas_hybrid_keypair::HybridKeyPair;
 use keysas_lib::pki::generate_cert_from_csr;
 use std::fs;
@@ -83,6 +84,7 @@ pub fn run() {
             revoke_usb,
             del_pki,
             restore_pki,
+            update_hash_lists,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -480,6 +482,81 @@ async fn update(ip: String) -> bool {
     true
 }
 
+/// This function loads the deny and allow lists of SHA-256 digests on a Keysas station
+/// The lists are checked before being sent, an empty path leaves the list unchanged
+/// Keysas is restarted so that keysas-transit loads the new lists
+#[command]
+async fn update_hash_lists(ip: String, deny_list: String, allow_list: String) -> bool {
+    let private_key = match get_ssh() {
+        Ok((_, private)) => private,
+        Err(e) => {
+            log::error!("Failed to get private key: {e}");
+            return false;
+        }
+    };
+
+    // Read and validate the lists
+    let mut lists = Vec::new();
+    for (path, dest) in [(deny_list, DENY_LIST_PATH), (allow_list, ALLOW_LIST_PATH)] {
+        if path.trim().is_empty() {
+            continue;
+        }
+        let content = match fs::read_to_string(path.trim()) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("Failed to read hash list {path}: {e}");
+                return false;
+            }
+        };
+        match HashList::parse(&content) {
+            Ok(l) => log::info!("Hash list {path} contains {} digests.", l.len()),
+            Err(e) => {
+                log::error!("Invalid hash list {path}: {e}");
+                return false;
+            }
+        }
+        lists.push((content, dest));
+    }
+    if lists.is_empty() {
+        log::warn!("No hash list to update");
+        return false;
+    }
+
+    // Connect to the host
+    let mut session = match connect_key(&ip, &private_key) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Failed to open ssh connection with station: {e}");
+            return false;
+        }
+    };
+
+    for (content, dest) in lists {
+        if let Err(e) = send_hash_list_to_station(&mut session, &content, dest) {
+            log::error!("Failed to load hash list on the station: {e}");
+            session.close();
+            return false;
+        }
+    }
+
+    // The lists are loaded by keysas-transit only, the other daemons wait for it to be back
+    match session_exec(
+        &mut session,
+        &String::from("sudo /bin/systemctl restart keysas-transit"),
+    ) {
+        Ok(_) => {
+            log::info!("Hash lists updated, keysas-transit is restarting.");
+        }
+        Err(why) => {
+            log::error!("Failed to restart keysas-transit: {why:?}");
+            session.close();
+            return false;
+        }
+    }
+    session.close();
+    true
+}
+
 /// This function reboot a Keysas station using systemctl
 #[command]
 async fn reboot(ip: String) -> bool {
@@ -1,4 +1,4 @@
-use crate::ssh_wrapper::session_exec;
+use crate::ssh_wrapper::{session_exec, session_upload};
 use crate::store::{drop_pki, init_store, set_pki_config};
 use anyhow::anyhow;
 use keysas_lib::certificate_field::{CertificateFields, validate_signing_certificate};
@@ -7,7 +7,7 @@ use pkcs8::LineEnding;
 use pkcs8::der::EncodePem;
 use shlex::try_quote;
 use ssh::LocalSession;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::net::TcpStream;
 use std::path::Path;
@@ -120,6 +120,49 @@ pub fn send_cert_to_station(
     Ok(())
 }
 
+/// Utility function to write a list of SHA-256 digests on the station
+/// The list is copied with scp, it is not limited by the size of a command line
+/// The content must have been validated before
+pub fn send_hash_list_to_station(
+    session: &mut LocalSession<TcpStream>,
+    content: &str,
+    path: &str,
+) -> Result<(), anyhow::Error> {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid hash list path"))?;
+    let local = std::env::temp_dir().join(format!("keysas-{name}"));
+    fs::write(&local, content)?;
+    let remote = format!("/home/keysas/{name}");
+    let upload = session_upload(
+        session,
+        local
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid temporary path"))?,
+        &remote,
+    );
+    let _ = fs::remove_file(&local);
+    if let Err(e) = upload {
+        log::error!("Failed to copy hash list on the station: {e:?}");
+        return Err(anyhow!("Connection error"));
+    }
+
+    let command = format!(
+        "sudo /usr/bin/tee {} < {} > /dev/null && rm -f {}",
+        path,
+        try_quote(&remote)?,
+        try_quote(&remote)?
+    );
+
+    if let Err(e) = session_exec(session, &command) {
+        log::error!("Failed to write hash list on the station: {e}");
+        return Err(anyhow!("Connection error"));
+    }
+
+    Ok(())
+}
+
 pub fn save_certificate(cert: &Certificate, path: &Path) -> Result<(), anyhow::Error> {
     let output = String::from_utf8(cert.to_pem(LineEnding::LF)?.into())?;
     let mut file = File::create(path)?;
@@ -59,6 +59,22 @@ export async function update(ip) {
     }
 }
 
+export async function update_hash_lists(ip, denyList, allowList) {
+    try {
+        console.log("Updating hash lists of Keysas:", ip);
+        let res = await invoke('update_hash_lists', {
+            ip: ip,
+            denyList: denyList,
+            allowList: allowList
+        })
+        console.log(res)
+        return res;
+    } catch(e) {
+        console.log(e)
+        return Promise.reject(e);
+    }
+}
+
 /**
  * 
  * @param {String} ip         IP address of the station
@@ -248,3 +264,18 @@ export async function getPKIDir() {
       return Promise.reject(e);
     }
 }
+
+export async function getHashList() {
+    try {
+      const SelectedPath = await open({
+        multiple: false,
+        directory: false,
+        title: "Select a list of SHA-256 digests..."
+      });
+      console.log(SelectedPath);
+      return SelectedPath;
+    } catch(e){
+      console.log(e);
+      return Promise.reject(e);
+    }
+}
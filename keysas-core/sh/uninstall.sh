@@ -24,6 +24,7 @@ main() {
 		/etc/systemd/system/keysas-out.service
 		/etc/keysas/keysas-in.conf
 		/etc/keysas/keysas-transit.conf
+		/etc/keysas/keysas-transit-policy.json
 		/etc/keysas/keysas-out.conf
 		/usr/bin/keysas-in
 		/usr/bin/keysas-transit
@@ -7,7 +7,6 @@
  * This file contains various funtions
  * for building the keysas-in binary.
  */
-#![feature(unix_socket_ancillary_data)]
 #![forbid(unsafe_code)]
 #![forbid(non_shorthand_field_patterns)]
 #![warn(dead_code)]
@@ -23,42 +22,118 @@
 #![warn(overflowing_literals)]
 #![warn(deprecated)]
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::{Arg, ArgAction, Command, crate_version};
-use itertools::MultiUnzip;
 use keysas_lib::append_ext;
+use keysas_lib::protocol::{self, InputMetadata, ProtocolError};
+use keysas_lib::session::SessionManifest;
 use log::{debug, error, info, warn};
+use nix::poll::PollTimeout;
 use nix::unistd::UnlinkatFlags;
 use nix::unistd::unlinkat;
-use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
 use std::fs::File;
 use std::fs::remove_file;
+use std::os::fd::AsFd;
 use std::os::linux::net::SocketAddrExt;
-use std::os::unix::net::{SocketAddr, SocketAncillary, UnixListener, UnixStream};
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
-use std::thread as main_thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
+use watcher::Watcher;
 mod sandbox;
 mod tests;
+mod watcher;
 
-use keysas_lib::{convert_ioslice, init_logger, list_files, sha256_digest};
+use keysas_lib::{init_logger, sha256_digest};
 
 const CONFIG_DIRECTORY: &str = "/etc/keysas";
+/// Maximum number of files sent to keysas-transit before waiting for its replies
+const MAX_IN_FLIGHT: usize = 32;
+/// Delay before a file kept in sas_in is sent again, doubled at each attempt
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Maximum delay between two attempts to send a file
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
 
-#[derive(bincode::Encode, Debug, Clone)]
-struct FileMetadata {
-    filename: String,
-    digest: String,
-    timestamp: String,
-    is_corrupted: bool,
+/// Files kept in sas_in after a failure, refused by keysas-transit or that could not be read
+/// inotify does not report them again, they are sent again in their session with an
+/// increasing delay until they are sent or removed
+#[derive(Debug, Default)]
+struct Retries {
+    /// Files waiting for their next attempt, with their session and the time of the attempt
+    scheduled: BTreeMap<String, (SessionManifest, Instant)>,
+    /// Number of failed attempts of each file
+    attempts: HashMap<String, u32>,
+}
+
+impl Retries {
+    /// Record the result of the files of a session sent: the files that failed are scheduled,
+    /// the others are forgotten
+    fn update(&mut self, files: &[String], failed: &[String], session: &SessionManifest) {
+        let now = Instant::now();
+        for f in files {
+            if !failed.contains(f) {
+                self.attempts.remove(f);
+                continue;
+            }
+            let attempts = self.attempts.entry(f.clone()).or_default();
+            let delay = RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(*attempts))
+                .min(MAX_RETRY_DELAY);
+            *attempts += 1;
+            warn!("File {f} sent again in {} seconds", delay.as_secs());
+            self.scheduled
+                .insert(f.clone(), (session.clone(), now + delay));
+        }
+    }
+
+    /// Files ready again, e.g. written again, are sent at once
+    fn cancel(&mut self, files: &[String]) {
+        for f in files {
+            self.scheduled.remove(f);
+        }
+    }
+
+    /// Files of a session whose attempt is due
+    fn due(&mut self) -> Option<(Vec<String>, SessionManifest)> {
+        let now = Instant::now();
+        let session = self
+            .scheduled
+            .values()
+            .find(|(_, next)| *next <= now)
+            .map(|(s, _)| s.clone())?;
+        let files: Vec<String> = self
+            .scheduled
+            .iter()
+            .filter(|(_, (s, next))| s.id == session.id && *next <= now)
+            .map(|(f, _)| f.clone())
+            .collect();
+        for f in &files {
+            self.scheduled.remove(f);
+        }
+        Some((files, session))
+    }
+
+    /// Time until the next attempt, none if no file is scheduled
+    fn timeout(&self) -> PollTimeout {
+        match self.scheduled.values().map(|(_, next)| *next).min() {
+            Some(next) => {
+                let delay = next.saturating_duration_since(Instant::now());
+                // Rounded up so that the attempt is due once woken up
+                PollTimeout::try_from(delay + Duration::from_millis(1)).unwrap_or(PollTimeout::MAX)
+            }
+            None => PollTimeout::NONE,
+        }
+    }
 }
 
 struct Config {
     sas_in: String,
     socket_in: String,
+    transit_user: String,
 }
 
 impl Default for Config {
@@ -66,6 +141,7 @@ impl Default for Config {
         Self {
             sas_in: "/var/local/in/".to_string(),
             socket_in: "socket_in".to_string(),
+            transit_user: "keysas-transit".to_string(),
         }
     }
 }
@@ -93,6 +169,15 @@ fn command_args(config: &mut Config) {
                 .action(ArgAction::Set)
                 .help("Namespace for in-transit abstract socket"),
         )
+        .arg(
+            Arg::new("transit_user")
+                .short('u')
+                .long("transit_user")
+                .value_name("<USER>")
+                .default_value("keysas-transit")
+                .action(ArgAction::Set)
+                .help("User of keysas-transit, the only one allowed to connect to the socket"),
+        )
         .arg(
             Arg::new("version")
                 .short('v')
@@ -109,6 +194,9 @@ fn command_args(config: &mut Config) {
     if let Some(p) = matches.get_one::<String>("socket_in") {
         config.socket_in = p.to_string();
     }
+    if let Some(p) = matches.get_one::<String>("transit_user") {
+        config.transit_user = p.to_string();
+    }
 
 }
 
@@ -144,94 +232,187 @@ fn is_corrupted(file: PathBuf) -> bool {
     }
 }
 
-fn send_files(files: &[String], stream: &UnixStream, sas_in: &String) -> Result<()> {
-    //Remove any file starting by .(dot)
-    let re = Regex::new(r"^\.([a-z])*")?;
-    let mut files = files.to_owned();
-    files.retain(|x| !re.is_match(x));
-    //Don't catch .ioerror reports generated by keysas-io
-    //let re_ioerror = Regex::new(r"\.ioerror")?;
-    //files.retain(|x| !re_ioerror.is_match(x));
-    //Max X files per send in .chunks(X)
-    for batch in files.chunks(1) {
-        let (bufs, fhs, fs): (Vec<Vec<u8>>, Vec<File>, Vec<PathBuf>) = batch
-            .iter()
-            .map(|f| {
-                let mut base_path = PathBuf::from(&sas_in);
-                base_path.push(f);
-                base_path
-            })
-            .filter_map(|f| {
-                // FD is opened in read-only mode
-                let fh = match File::open(&f) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        error!("Failed to open file {}: {e}", f.display());
-                        process::exit(1);
-                    }
-                };
-                let digest = match sha256_digest(&fh) {
-                    Ok(d) => d,
-                    Err(e) => {
-                        error!("Failed to compute hash {e}");
-                        return None;
-                    }
-                };
-                let timestamp = format!(
-                    "{}-{}-{}_{}-{}-{}-{}",
-                    OffsetDateTime::now_utc().day(),
-                    OffsetDateTime::now_utc().month(),
-                    OffsetDateTime::now_utc().year(),
-                    OffsetDateTime::now_utc().hour(),
-                    OffsetDateTime::now_utc().minute(),
-                    OffsetDateTime::now_utc().second(),
-                    OffsetDateTime::now_utc().nanosecond()
-                );
+/// Remove the directories of a file sent, from the deepest one, as long as they are empty
+/// sas_in itself is kept. Nothing is removed while keysas-io is copying files in sas_in,
+/// it may have created a directory it has not moved its files into yet.
+fn remove_empty_dirs(sas_in: &str, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if Path::new(sas_in).join(watcher::LOCK).exists() {
+            info!("Directory {d:?} kept while keysas-io is copying files.");
+            break;
+        }
+        // Fails if the directory still holds other files
+        if unlinkat(None, &Path::new(sas_in).join(d), UnlinkatFlags::RemoveDir).is_err() {
+            break;
+        }
+        info!("Directory {d:?} has been removed.");
+        dir = d.parent();
+    }
+}
+
+/// Session of the files sent: the one of the manifest written by keysas-io in sas_in if any,
+/// a new one otherwise (files received from the network), with the number and size of the files
+fn new_session(sas_in: &str, files: &[String]) -> SessionManifest {
+    let mut session = match SessionManifest::take(Path::new(sas_in)) {
+        Ok(Some(s)) => s,
+        Ok(None) => SessionManifest::new("network"),
+        Err(e) => {
+            warn!("Cannot read the session manifest: {e}");
+            SessionManifest::new("unknown")
+        }
+    };
+    session.files = files.len() as u64;
+    session.bytes = files
+        .iter()
+        .filter_map(|f| fs::metadata(Path::new(sas_in).join(f)).ok())
+        .map(|m| m.len())
+        .sum();
+    info!(
+        "Session {} from {}: {} files, {} bytes",
+        session.id, session.device, session.files, session.bytes
+    );
+    session
+}
 
-                let m = FileMetadata {
-                    filename: f.file_name()?.to_str()?.to_string(),
-                    digest,
-                    timestamp,
-                    is_corrupted: is_corrupted(f.clone()),
-                };
+/// Handle the reply of keysas-transit to the oldest file in flight
+/// A file is removed from sas_in once keysas-transit has acknowledged it, with the ioerror report
+/// of keysas-io unless the report is in flight too, a file refused by keysas-transit is kept
+/// and added to the failed files
+fn receive_reply(
+    stream: &UnixStream,
+    sas_in: &str,
+    in_flight: &mut VecDeque<String>,
+    failed: &mut Vec<String>,
+) -> Result<(), ProtocolError> {
+    let Some(relative) = in_flight.front() else {
+        return Ok(());
+    };
+    let f = Path::new(sas_in).join(relative);
+    match protocol::receive_reply(stream) {
+        Ok(_) => {
+            // Files are unlinked once keysas-out is in charge of them
+            match unlinkat(None, &f, UnlinkatFlags::NoRemoveDir) {
+                Ok(_) => info!("File {f:?} has been removed."),
+                Err(e) => error!("Cannot unlink file {f:?}: {e:?}"),
+            };
+            // The report is kept until the file is acknowledged, a file sent again is still corrupted
+            let report = append_ext("ioerror", PathBuf::from(relative));
+            let report_in_flight = in_flight.iter().any(|r| Path::new(r) == report);
+            let ioerror_report = Path::new(sas_in).join(&report);
+            if !report_in_flight && ioerror_report.is_file() {
+                match remove_file(&ioerror_report) {
+                    Ok(_) => warn!("Removing ioerror report: {ioerror_report:?}"),
+                    Err(e) => error!("Cannot remove ioerror report: {e}"),
+                }
+            }
+            remove_empty_dirs(sas_in, Path::new(relative));
+        }
+        Err(e) if !e.is_fatal() => {
+            error!("File {} kept in sas_in: {e}", f.display());
+            failed.push(relative.clone());
+        }
+        Err(e) => {
+            // keysas-transit is told why the connection is closed
+            protocol::refuse(stream, &e);
+            if e.is_version() {
+                error!("Keysas-transit runs another version of the protocol: {e}");
+                process::exit(1);
+            }
+            return Err(e);
+        }
+    }
+    in_flight.pop_front();
+    Ok(())
+}
 
-                // Remove the report in sas_in
-                if m.is_corrupted {
-                    let ioerror_report = append_ext("ioerror", f.clone());
-                    match remove_file(&ioerror_report) {
-                        Ok(_) => warn!("Removing ioerror report: {ioerror_report:?}"),
-                        Err(e) => error!("Cannot remove ioerror report: {e}"),
-                    }
+/// Send the files of a session to keysas-transit, `files` are relative to sas_in
+/// Up to MAX_IN_FLIGHT files are sent before waiting for the replies of keysas-transit
+/// If the connection is lost, returns the files which have not been acknowledged
+/// so that they are sent again once keysas-transit is back
+/// The files kept in sas_in, refused or that could not be read, are added to `failed`
+fn send_files(
+    files: &[String],
+    session: &SessionManifest,
+    stream: &UnixStream,
+    sas_in: &str,
+    failed: &mut Vec<String>,
+) -> Result<(), Vec<String>> {
+    // Files sent, waiting for the reply of keysas-transit
+    let mut in_flight = VecDeque::new();
+    for (i, relative) in files.iter().enumerate() {
+        //Remove any file starting by .(dot)
+        if relative.starts_with('.') {
+            continue;
+        }
+        //Don't catch .ioerror reports generated by keysas-io
+        //let re_ioerror = Regex::new(r"\.ioerror")?;
+        //files.retain(|x| !re_ioerror.is_match(x));
+        if in_flight.len() >= MAX_IN_FLIGHT {
+            match receive_reply(stream, sas_in, &mut in_flight, failed) {
+                Ok(_) => (),
+                Err(e) => {
+                    error!("Connection to keysas-transit lost: {e}");
+                    return Err(in_flight.into_iter().chain(files[i..].to_vec()).collect());
                 }
-                let config = bincode::config::standard();
-                let data: Vec<u8> = match bincode::encode_to_vec(&m, config) {
-                    Ok(d) => d,
-                    Err(_e) => {
-                        error!("Failed to serialize FileMetadataa");
-                        return None;
-                    }
-                };
-                Some((data, fh, f))
-            })
-            .multiunzip();
+            }
+        }
+        let f = Path::new(sas_in).join(relative);
+        // FD is opened in read-only mode
+        let fh = match File::open(&f) {
+            Ok(f) => f,
+            // Removed meanwhile
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                error!("Failed to open file {}: {e}", f.display());
+                failed.push(relative.clone());
+                continue;
+            }
+        };
+        let digest = match sha256_digest(&fh) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Failed to compute hash {e}");
+                failed.push(relative.clone());
+                continue;
+            }
+        };
+        let timestamp = format!(
+            "{}-{}-{}_{}-{}-{}-{}",
+            OffsetDateTime::now_utc().day(),
+            OffsetDateTime::now_utc().month(),
+            OffsetDateTime::now_utc().year(),
+            OffsetDateTime::now_utc().hour(),
+            OffsetDateTime::now_utc().minute(),
+            OffsetDateTime::now_utc().second(),
+            OffsetDateTime::now_utc().nanosecond()
+        );
+        let Some(filename) = f.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
 
-        let (ios, fds) = convert_ioslice(&fhs, &bufs);
+        let m = InputMetadata {
+            filename: filename.to_string(),
+            path: relative.clone(),
+            session: session.clone(),
+            digest,
+            timestamp,
+            is_corrupted: is_corrupted(f.clone()),
+        };
 
-        let mut ancillary_buffer = [0; 4128];
-        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
-        ancillary.add_fds(&fds);
-        match stream.send_vectored_with_ancillary(&ios, &mut ancillary) {
-            Ok(_) => {
-                info!("Chunk of file descriptors has been sent");
+        match protocol::send_file(stream, &m, fh.as_fd()) {
+            Ok(_) => info!("File {} sent to keysas-transit.", m.filename),
+            Err(e) => {
+                error!("Cannot send file {}: {e}", f.display());
+                return Err(in_flight.into_iter().chain(files[i..].to_vec()).collect());
             }
-            Err(e) => error!("Failed to send fds: {e}"),
         }
-        // Files are unlinked once fds are sent
-        for (file_path, &fd) in fs.iter().zip(fds.iter()) {
-            match unlinkat(Some(fd), file_path, UnlinkatFlags::NoRemoveDir) {
-                Ok(_) => info!("File {file_path:?} has been removed."),
-                Err(e) => error!("Cannot unlink file {file_path:?}: {e:?}"),
-            };
+        in_flight.push_back(relative.clone());
+    }
+    while !in_flight.is_empty() {
+        if let Err(e) = receive_reply(stream, sas_in, &mut in_flight, failed) {
+            error!("Connection to keysas-transit lost: {e}");
+            return Err(in_flight.into());
         }
     }
     Ok(())
@@ -242,6 +423,15 @@ fn main() -> Result<()> {
     command_args(&mut config);
     init_logger();
 
+    // The user of keysas-transit is resolved before the sandbox is activated
+    let transit_uid = match protocol::user_id(&config.transit_user) {
+        Ok(uid) => uid,
+        Err(e) => {
+            error!("Cannot find user {}: {e}", config.transit_user);
+            process::exit(1);
+        }
+    };
+
     match sandbox::landlock_sandbox(&config.sas_in) {
         Ok(_) => log::info!("Landlock sandbox activated."),
         Err(e) => log::warn!("Landlock sandbox cannot be activated: {e}"),
@@ -255,6 +445,7 @@ fn main() -> Result<()> {
     info!("Running configuration is:");
     info!("- Abstract socket: {}", &config.socket_in);
     info!("- sas_in: {}", &config.sas_in);
+    info!("- keysas-transit user: {}", config.transit_user);
     if Path::new(&config.socket_in).exists() {
         match remove_file(&config.socket_in) {
             Ok(_) => debug!("Removing previously created socket_in"),
@@ -275,25 +466,84 @@ fn main() -> Result<()> {
             process::exit(1);
         }
     };
-    let (unix_stream, _sck_addr) = match sock.accept() {
-        Ok(r) => r,
+    // Only keysas-transit is allowed to connect
+    let accept = || match protocol::accept(&sock, transit_uid) {
+        Ok(s) => {
+            info!("Keysas-transit connected.");
+            s
+        }
         Err(e) => {
             error!("Failed to accept connection: {e}");
             process::exit(1);
         }
     };
+    let mut unix_stream = accept();
+
+    // Files ready to be sent, relative to sas_in
+    let (mut watcher, files) = match Watcher::new(&config.sas_in) {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Failed to watch directory {}: {e}", config.sas_in);
+            process::exit(1);
+        }
+    };
+    let mut pending: BTreeSet<String> = files.into_iter().collect();
+    // Files of the session being sent
+    let mut batch: Option<(Vec<String>, SessionManifest)> = None;
+    // Files kept in sas_in, sent again later
+    let mut retries = Retries::default();
+    // Files removed meanwhile and links are skipped
+    let is_file = |f: &String| {
+        fs::symlink_metadata(Path::new(&config.sas_in).join(f)).is_ok_and(|m| m.is_file())
+    };
 
     loop {
-        let files = match list_files(&config.sas_in) {
-            Ok(fs) => fs,
+        if batch.is_none() {
+            batch = retries.due().map(|(files, session)| {
+                let files: Vec<String> = files.into_iter().filter(is_file).collect();
+                info!(
+                    "Sending again {} files of session {}",
+                    files.len(),
+                    session.id
+                );
+                (files, session)
+            });
+        }
+        // Files are held while keysas-io is copying a device
+        if batch.is_none() && !pending.is_empty() && !watcher.is_locked() {
+            let files: Vec<String> = std::mem::take(&mut pending)
+                .into_iter()
+                .filter(is_file)
+                .collect();
+            retries.cancel(&files);
+            let session = new_session(&config.sas_in, &files);
+            batch = Some((files, session));
+        }
+        if let Some((files, session)) = batch.take() {
+            let mut failed = Vec::new();
+            match send_files(&files, &session, &unix_stream, &config.sas_in, &mut failed) {
+                Ok(_) => retries.update(&files, &failed, &session),
+                // The files in flight are sent again in the same session once keysas-transit is back
+                Err(files) => {
+                    retries.update(&failed, &failed, &session);
+                    warn!(
+                        "Waiting for keysas-transit, {} files of session {} requeued",
+                        files.len(),
+                        session.id
+                    );
+                    batch = Some((files, session));
+                    unix_stream = accept();
+                    continue;
+                }
+            }
+        }
+
+        match watcher.wait(retries.timeout()) {
+            Ok(files) => pending.extend(files),
             Err(e) => {
-                error!("Failed to list files in directory {}: {e}", &config.sas_in);
+                error!("Failed to watch directory {}: {e}", config.sas_in);
                 process::exit(1);
             }
-        };
-
-        send_files(&files, &unix_stream, &config.sas_in)
-            .with_context(|| "Cannot send file descriptors :/")?;
-        main_thread::sleep(Duration::from_millis(500));
+        }
     }
 }
@@ -22,6 +22,8 @@ use syscallz::{Context, Syscall};
 pub fn init() -> Result<()> {
     let mut ctx = Context::init()?;
     ctx.allow_syscall(Syscall::clock_nanosleep)?;
+    ctx.allow_syscall(Syscall::inotify_init1)?;
+    ctx.allow_syscall(Syscall::inotify_add_watch)?;
     ctx.allow_syscall(Syscall::openat)?;
     ctx.allow_syscall(Syscall::getdents64)?;
     ctx.allow_syscall(Syscall::newfstatat)?;
@@ -48,6 +50,9 @@ pub fn init() -> Result<()> {
     ctx.allow_syscall(Syscall::socket)?;
     ctx.allow_syscall(Syscall::ppoll)?;
     ctx.allow_syscall(Syscall::sendmsg)?;
+    ctx.allow_syscall(Syscall::recvmsg)?;
+    ctx.allow_syscall(Syscall::recvfrom)?;
+    ctx.allow_syscall(Syscall::getsockopt)?;
     ctx.allow_syscall(Syscall::prlimit64)?;
     ctx.allow_syscall(Syscall::rseq)?;
     ctx.allow_syscall(Syscall::landlock_add_rule)?;
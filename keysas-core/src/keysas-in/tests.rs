@@ -1,7 +1,15 @@
 #[cfg(test)]
 mod tests {
-    use crate::is_corrupted;
-    use std::fs::File;
+    use crate::watcher::Watcher;
+    use crate::{RETRY_DELAY, Retries, is_corrupted, new_session, remove_empty_dirs, send_files};
+    use keysas_lib::protocol::{self, ErrorCode, ErrorReply, InputMetadata};
+    use keysas_lib::session::SessionManifest;
+    use nix::poll::PollTimeout;
+    use std::fs::{self, File};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
     use tempfile::tempdir;
 
     #[test]
@@ -16,4 +24,160 @@ mod tests {
         File::create(&file_corrupted).unwrap();
         assert_eq!(true, is_corrupted(file));
     }
+
+    #[test]
+    fn test_remove_empty_dirs() {
+        let dir = tempdir().unwrap();
+        let sas_in = dir.path().to_str().unwrap();
+        fs::create_dir_all(dir.path().join("photos/2024")).unwrap();
+        File::create(dir.path().join("photos/kept.jpg")).unwrap();
+        remove_empty_dirs(sas_in, Path::new("photos/2024/sent.jpg"));
+        assert!(!dir.path().join("photos/2024").exists());
+        assert!(dir.path().join("photos/kept.jpg").exists());
+        fs::remove_file(dir.path().join("photos/kept.jpg")).unwrap();
+        remove_empty_dirs(sas_in, Path::new("photos/kept.jpg"));
+        assert!(!dir.path().join("photos").exists());
+        assert!(dir.path().exists());
+        // Directories are kept while keysas-io is copying files
+        fs::create_dir_all(dir.path().join("copying")).unwrap();
+        File::create(dir.path().join(".lock")).unwrap();
+        remove_empty_dirs(sas_in, Path::new("copying/sent.jpg"));
+        assert!(dir.path().join("copying").exists());
+    }
+
+    #[test]
+    fn test_new_session() {
+        let dir = tempdir().unwrap();
+        let sas_in = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("a.txt"), b"data").unwrap();
+        fs::write(dir.path().join("b.txt"), b"other data").unwrap();
+        let files = ["a.txt".to_string(), "b.txt".to_string()];
+
+        // The manifest of keysas-io is used once
+        let manifest = SessionManifest::new("0781/5581/0100/serial on /dev/sdb1");
+        manifest.write(dir.path()).unwrap();
+        let session = new_session(sas_in, &files);
+        assert_eq!(session.id, manifest.id);
+        assert_eq!(session.device, manifest.device);
+        assert_eq!((session.files, session.bytes), (2, 14));
+        assert!(!dir.path().join(".session").exists());
+
+        // Otherwise the files come from the network
+        let session = new_session(sas_in, &files[..1]);
+        assert_ne!(session.id, manifest.id);
+        assert_eq!(session.device, "network");
+        assert_eq!((session.files, session.bytes), (1, 4));
+
+        // A manifest that cannot be parsed is kept
+        fs::write(dir.path().join(".session"), b"{\"id\":").unwrap();
+        let session = new_session(sas_in, &files);
+        assert_eq!(session.device, "unknown");
+        assert!(dir.path().join(".session").exists());
+    }
+
+    #[test]
+    fn test_send_files() {
+        let dir = tempdir().unwrap();
+        let sas_in = dir.path().to_str().unwrap();
+        let files = ["a.txt", "b.txt", "c.txt"].map(String::from);
+        for f in &files {
+            fs::write(dir.path().join(f), b"data").unwrap();
+        }
+        // Reports of keysas-io on files it could not copy entirely
+        File::create(dir.path().join("a.txt.ioerror")).unwrap();
+        File::create(dir.path().join("c.txt.ioerror")).unwrap();
+        let (stream, transit) = UnixStream::pair().unwrap();
+        let peer = thread::spawn(move || {
+            let corrupted: Vec<bool> = (0..3)
+                .map(|_| {
+                    let (md, _) = protocol::receive_file::<InputMetadata>(&transit).unwrap();
+                    md.is_corrupted
+                })
+                .collect();
+            assert_eq!(corrupted, vec![true, false, true]);
+            // a.txt is acknowledged, b.txt is refused, then keysas-transit stops
+            protocol::reply_ack(&transit).unwrap();
+            let reply = ErrorReply {
+                code: ErrorCode::Internal,
+                message: "refused".into(),
+            };
+            protocol::reply_error(&transit, &reply).unwrap();
+        });
+
+        // The file in flight is requeued
+        let session = new_session(sas_in, &files);
+        let mut failed = Vec::new();
+        let result = send_files(&files, &session, &stream, sas_in, &mut failed);
+        peer.join().unwrap();
+        assert_eq!(result, Err(vec!["c.txt".to_string()]));
+        // The file refused is sent again later
+        assert_eq!(failed, vec!["b.txt".to_string()]);
+        assert!(!dir.path().join("a.txt").exists());
+        assert!(dir.path().join("b.txt").exists());
+        assert!(dir.path().join("c.txt").exists());
+        // The report is removed with the file acknowledged only, c.txt is sent again as corrupted
+        assert!(!dir.path().join("a.txt.ioerror").exists());
+        assert!(dir.path().join("c.txt.ioerror").exists());
+    }
+
+    #[test]
+    fn test_retries() {
+        let mut retries = Retries::default();
+        assert_eq!(retries.timeout(), PollTimeout::NONE);
+        let session = SessionManifest::new("network");
+        let files = ["a.txt", "b.txt"].map(String::from);
+
+        // The files that failed are sent again in their session after a delay
+        retries.update(&files, &files[..1], &session);
+        assert!(retries.due().is_none());
+        let timeout = retries.timeout().duration().unwrap();
+        assert!(timeout > RETRY_DELAY - Duration::from_secs(1) && timeout <= RETRY_DELAY * 2);
+
+        // The delay doubles at each attempt, a file sent is forgotten
+        retries.scheduled.get_mut("a.txt").unwrap().1 = Instant::now();
+        let (due, due_session) = retries.due().unwrap();
+        assert_eq!(due, vec!["a.txt".to_string()]);
+        assert_eq!(due_session.id, session.id);
+        retries.update(&due, &due, &session);
+        assert_eq!(retries.attempts["a.txt"], 2);
+        let timeout = retries.timeout().duration().unwrap();
+        assert!(timeout > RETRY_DELAY * 2 - Duration::from_secs(1));
+        retries.update(&due, &[], &session);
+        assert!(retries.attempts.is_empty());
+
+        // A file ready again is sent at once
+        retries.cancel(&due);
+        assert_eq!(retries.timeout(), PollTimeout::NONE);
+    }
+
+    #[test]
+    fn test_watcher() {
+        let dir = tempdir().unwrap();
+        let sas_in = dir.path().to_str().unwrap();
+        File::create(dir.path().join("present.txt")).unwrap();
+        let (mut watcher, files) = Watcher::new(sas_in).unwrap();
+        assert_eq!(files, ["present.txt"]);
+
+        // A file written, a directory tree moved in and hidden files
+        fs::write(dir.path().join("written.txt"), b"data").unwrap();
+        let staging = tempdir().unwrap();
+        fs::create_dir_all(staging.path().join("photos/2024")).unwrap();
+        File::create(staging.path().join("photos/2024/image.jpg")).unwrap();
+        fs::rename(staging.path().join("photos"), dir.path().join("photos")).unwrap();
+        File::create(dir.path().join(".partial.txt")).unwrap();
+        assert!(!watcher.is_locked());
+        File::create(dir.path().join(".lock")).unwrap();
+        assert!(watcher.is_locked());
+
+        let mut files = watcher.wait(PollTimeout::NONE).unwrap();
+        files.sort();
+        assert_eq!(files, ["photos/2024/image.jpg", "written.txt"]);
+
+        // Files written in the directories moved in are watched
+        fs::write(dir.path().join("photos/2024/other.jpg"), b"data").unwrap();
+        assert_eq!(
+            watcher.wait(PollTimeout::NONE).unwrap(),
+            ["photos/2024/other.jpg"]
+        );
+    }
 }
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-in".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the inotify watcher
+ * of the incoming directory.
+ */
+
+//! Pickup of the incoming files with inotify
+//!
+//! Each directory of sas_in is watched, a file is ready to be sent once it has been
+//! moved in the tree (IN_MOVED_TO, e.g. renamed by keysas-io or rsync) or closed after
+//! being written (IN_CLOSE_WRITE). The files of a directory created or moved in the tree
+//! are ready as soon as it is watched. The deletion of the lock file of keysas-io wakes
+//! the daemon up so that the files held during the copy are sent.
+
+use anyhow::Result;
+use keysas_lib::MAX_DEPTH;
+use log::{debug, warn};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use std::collections::HashMap;
+use std::fs;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+
+/// Lock file created by keysas-io in sas_in while it copies the files of a device
+pub const LOCK: &str = ".lock";
+
+/// Events watched on each directory
+fn mask() -> AddWatchFlags {
+    AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_ONLYDIR
+}
+
+/// Returns true if a name is hidden: files starting with a dot are not sent
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Watcher of the directory tree of sas_in
+#[derive(Debug)]
+pub struct Watcher {
+    inotify: Inotify,
+    sas_in: PathBuf,
+    /// Directories watched, relative to sas_in, with their depth
+    dirs: HashMap<WatchDescriptor, (PathBuf, usize)>,
+}
+
+impl Watcher {
+    /// Watch the tree of sas_in
+    /// Returns the watcher and the files already in sas_in
+    pub fn new(sas_in: &str) -> Result<(Self, Vec<String>)> {
+        let mut watcher = Self {
+            inotify: Inotify::init(InitFlags::IN_CLOEXEC)?,
+            sas_in: PathBuf::from(sas_in),
+            dirs: HashMap::new(),
+        };
+        let mut ready = Vec::new();
+        watcher.watch_tree(Path::new(""), 0, &mut ready)?;
+        Ok((watcher, ready))
+    }
+
+    /// Returns true while keysas-io is copying files in sas_in
+    pub fn is_locked(&self) -> bool {
+        self.sas_in.join(LOCK).exists()
+    }
+
+    /// Watch a directory and its sub-directories, its files are ready
+    /// The directory is watched before it is read so that no file is missed
+    fn watch_tree(&mut self, relative: &Path, depth: usize, ready: &mut Vec<String>) -> Result<()> {
+        let path = self.sas_in.join(relative);
+        let wd = self.inotify.add_watch(&path, mask())?;
+        self.dirs.insert(wd, (relative.to_path_buf(), depth));
+        debug!("Watching directory {path:?}");
+        for entry in fs::read_dir(&path)?.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str().filter(|n| !is_hidden(n)) else {
+                continue;
+            };
+            let child = relative.join(name);
+            // Links are not followed
+            match entry.file_type() {
+                Ok(t) if t.is_file() => ready.extend(child.to_str().map(String::from)),
+                Ok(t) if t.is_dir() && depth < MAX_DEPTH => {
+                    // A directory removed meanwhile is skipped
+                    if let Err(e) = self.watch_tree(&child, depth + 1, ready) {
+                        warn!("Cannot watch directory {child:?}: {e}");
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for events in sas_in until the timeout
+    /// Returns the files ready to be sent, relative to sas_in, possibly none if the
+    /// daemon is only woken up, e.g. by the deletion of the lock file, or on timeout
+    pub fn wait(&mut self, timeout: PollTimeout) -> Result<Vec<String>> {
+        let mut ready = Vec::new();
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(ready),
+            Ok(_) => (),
+            Err(e) => return Err(e.into()),
+        }
+        for event in self.inotify.read_events()? {
+            self.handle(event, &mut ready)?;
+        }
+        Ok(ready)
+    }
+
+    fn handle(&mut self, event: InotifyEvent, ready: &mut Vec<String>) -> Result<()> {
+        // Events were lost, the whole tree is read again
+        if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+            warn!("Inotify queue overflow, reading sas_in again");
+            return self.watch_tree(Path::new(""), 0, ready);
+        }
+        // The directory has been removed
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.dirs.remove(&event.wd);
+            return Ok(());
+        }
+        let (Some((dir, depth)), Some(name)) = (self.dirs.get(&event.wd), &event.name) else {
+            return Ok(());
+        };
+        let Some(name) = name.to_str().filter(|n| !is_hidden(n)) else {
+            return Ok(());
+        };
+        let (child, depth) = (dir.join(name), *depth);
+        let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+        let created = event.mask.contains(AddWatchFlags::IN_CREATE);
+        let moved = event.mask.contains(AddWatchFlags::IN_MOVED_TO);
+        let written = event.mask.contains(AddWatchFlags::IN_CLOSE_WRITE);
+        match (is_dir, created || moved, moved || written) {
+            (true, true, _) if depth < MAX_DEPTH => {
+                if let Err(e) = self.watch_tree(&child, depth + 1, ready) {
+                    warn!("Cannot watch directory {child:?}: {e}");
+                }
+            }
+            // Files created are ready once closed
+            (false, _, true) => ready.extend(child.to_str().map(String::from)),
+            _ => (),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-out".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the content disarm and reconstruction of images.
+ */
+
+//! Content disarm and reconstruction (CDR) of images
+//!
+//! Images are decoded and encoded again in the same format. Only the pixels are
+//! written in the sanitized image: metadata, trailing data and payloads of polyglot
+//! files are dropped. The frames of animated GIF images keep their position, delay and
+//! disposal, and the animation its repeat count.
+//!
+//! The dimensions given by the headers are checked before any pixel is decoded.
+//!
+//! keysas-out holds the signing keys of the station, so the images are not decoded in the
+//! daemon. The [Sanitizer] is a single threaded process forked before the keys are loaded,
+//! it forks a child for each image which closes the other descriptors, limits its CPU time
+//! and memory and loads a seccomp filter before reading the image. The child writes the
+//! rebuilt image, or the error, to a pipe read by the daemon.
+
+use crate::sandbox;
+use anyhow::{Result, anyhow};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use nix::errno::Errno;
+use nix::sys::resource::{Resource, setrlimit};
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, recv,
+    recvmsg, send, sendmsg, socketpair,
+};
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, fork};
+use std::fs::File;
+use std::io::{self, Cursor, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileExt;
+
+/// Image formats that can be rebuilt
+pub const CDR_FORMATS: [&str; 4] = ["jpg", "png", "gif", "bmp"];
+
+/// Quality of the rebuilt JPEG images
+const JPEG_QUALITY: u8 = 90;
+
+/// Maximum number of pixels of an image (256 MiB decoded)
+const MAX_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Maximum number of frames of an animated image
+const MAX_FRAMES: usize = 1024;
+
+/// Maximum number of pixels of all the frames of an animated image (256 MiB decoded)
+const MAX_FRAME_PIXELS: u64 = 64 * 1024 * 1024;
+
+/// Maximum size of a rebuilt image read by the daemon
+const MAX_OUTPUT: u64 = 512 * 1024 * 1024;
+
+/// CPU time in seconds allowed to rebuild an image
+const CPU_LIMIT: u64 = 60;
+
+/// Memory allowed to rebuild an image
+const MEMORY_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Size of the chunks read from the image
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Returns the format of the image if it is in the list of formats to rebuild
+///
+/// # Arguments
+///
+/// * `head` - Beginning of the file
+/// * `formats` - Formats to rebuild, from [CDR_FORMATS]
+pub fn image_format(head: &[u8], formats: &[String]) -> Option<ImageFormat> {
+    let ext = infer::get(head)?.extension();
+    if !formats.iter().any(|f| f == ext) {
+        return None;
+    }
+    match ext {
+        "jpg" => Some(ImageFormat::Jpeg),
+        "png" => Some(ImageFormat::Png),
+        "gif" => Some(ImageFormat::Gif),
+        "bmp" => Some(ImageFormat::Bmp),
+        _ => None,
+    }
+}
+
+/// Returns an error if the image has more pixels than the limit
+fn check_pixels(width: u64, height: u64, limit: u64) -> Result<()> {
+    match width * height > limit {
+        true => Err(anyhow!(
+            "Image too large: {width}x{height} pixels, over the limit of {limit} pixels"
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Decode the image and encode it again in the same format
+/// The size of the image, and of each frame of animated images, is checked from the headers
+/// before it is decoded. Animated images are bounded by [MAX_FRAMES] and [MAX_FRAME_PIXELS].
+pub fn rebuild_image(data: &[u8], format: ImageFormat) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    match format {
+        ImageFormat::Gif => rebuild_gif(data, &mut output)?,
+        _ => {
+            let decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+            let (width, height) = decoder.dimensions();
+            check_pixels(u64::from(width), u64::from(height), MAX_PIXELS)?;
+            let image = DynamicImage::from_decoder(decoder)?;
+            let mut output = Cursor::new(&mut output);
+            match format {
+                ImageFormat::Jpeg => image
+                    .write_with_encoder(JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY))?,
+                _ => image.write_to(&mut output, format)?,
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Rebuild each frame of a GIF image
+fn rebuild_gif(data: &[u8], output: &mut Vec<u8>) -> Result<()> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(data))?;
+    let (width, height) = (decoder.width(), decoder.height());
+    check_pixels(u64::from(width), u64::from(height), MAX_FRAME_PIXELS)?;
+    let mut encoder = gif::Encoder::new(output, width, height, &[])?;
+    encoder.set_repeat(decoder.repeat())?;
+    let mut frames = 0;
+    let mut pixels: u64 = 0;
+    while let Some(info) = decoder.next_frame_info()? {
+        frames += 1;
+        pixels += u64::from(info.width) * u64::from(info.height);
+        if frames > MAX_FRAMES || pixels > MAX_FRAME_PIXELS {
+            return Err(anyhow!(
+                "Animated image too large: more than {MAX_FRAMES} frames or {MAX_FRAME_PIXELS} pixels"
+            ));
+        }
+        let (left, top, delay, dispose) = (info.left, info.top, info.delay, info.dispose);
+        let (frame_width, frame_height) = (info.width, info.height);
+        let mut pixels = vec![0; decoder.buffer_size()];
+        decoder.read_into_buffer(&mut pixels)?;
+        let mut frame = gif::Frame::from_rgba(frame_width, frame_height, &mut pixels);
+        frame.left = left;
+        frame.top = top;
+        frame.delay = delay;
+        frame.dispose = dispose;
+        encoder.write_frame(&frame)?;
+    }
+    Ok(())
+}
+
+/// Process forking the children rebuilding the images
+#[derive(Debug)]
+pub struct Sanitizer {
+    /// Socket with the sanitizer, the images are rebuilt one at a time
+    socket: OwnedFd,
+}
+
+impl Sanitizer {
+    /// Fork the sanitizer, to be called while keysas-out is single threaded and
+    /// before it loads its signing keys
+    pub fn start() -> io::Result<Self> {
+        let (socket, sanitizer) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        // SAFETY: keysas-out is single threaded, the sanitizer only serves
+        // the daemon and exits without returning
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(socket);
+                // The descriptors of the daemon are not kept by the sanitizer
+                if close_inherited(&[sanitizer.as_raw_fd()]).is_err() {
+                    exit(1);
+                }
+                serve(sanitizer)
+            }
+            ForkResult::Parent { .. } => Ok(Self { socket }),
+        }
+    }
+
+    /// Rebuild the image of the file in a sandboxed child process
+    pub fn rebuild(&self, file: &File, format: ImageFormat) -> Result<Vec<u8>> {
+        let (mut image, output) = io::pipe()?;
+        let order = format.extensions_str()[0].as_bytes();
+        sendmsg::<()>(
+            self.socket.as_raw_fd(),
+            &[IoSlice::new(order)],
+            &[ControlMessage::ScmRights(&[
+                file.as_raw_fd(),
+                output.as_raw_fd(),
+            ])],
+            MsgFlags::empty(),
+            None,
+        )?;
+        drop(output);
+        let mut result = Vec::new();
+        let read = (&mut image).take(MAX_OUTPUT + 1).read_to_end(&mut result);
+        // The child cannot block on a full pipe once the daemon stops reading
+        drop(image);
+        let mut status = [0u8; 4];
+        let exited = recv(self.socket.as_raw_fd(), &mut status, MsgFlags::empty())?;
+        read?;
+        if exited != 4 || i32::from_ne_bytes(status) != 0 {
+            return Err(anyhow!("image rebuilding process failed"));
+        }
+        if result.len() as u64 > MAX_OUTPUT {
+            return Err(anyhow!("rebuilt image larger than {MAX_OUTPUT} bytes"));
+        }
+        // The first byte tells if the image or an error follows
+        match result.split_first() {
+            Some((0, _)) => {
+                result.remove(0);
+                Ok(result)
+            }
+            Some((_, error)) => Err(anyhow!("{}", String::from_utf8_lossy(error))),
+            None => Err(anyhow!("image rebuilding process failed")),
+        }
+    }
+}
+
+/// Serve the images sent by the daemon until it closes the socket
+fn serve(socket: OwnedFd) -> ! {
+    let mut buf = [0u8; 16];
+    loop {
+        let mut cmsg = nix::cmsg_space!([RawFd; 2]);
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let (len, fds) = match recvmsg::<()>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Ok(msg) => {
+                let mut fds = Vec::new();
+                for c in msg.cmsgs().into_iter().flatten() {
+                    if let ControlMessageOwned::ScmRights(received) = c {
+                        // SAFETY: the descriptors have just been received and are owned by the sanitizer
+                        fds.extend(
+                            received
+                                .into_iter()
+                                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                        );
+                    }
+                }
+                (msg.bytes, fds)
+            }
+            Err(Errno::EINTR) => continue,
+            Err(_) => exit(1),
+        };
+        // The daemon has stopped
+        if len == 0 {
+            exit(0);
+        }
+        let format = str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(ImageFormat::from_extension);
+        let status = match (<[OwnedFd; 2]>::try_from(fds), format) {
+            (Ok([file, output]), Some(format)) => rebuild_in_child(file, output, format),
+            _ => -nix::libc::EINVAL,
+        };
+        if send(socket.as_raw_fd(), &status.to_ne_bytes(), MsgFlags::empty()).is_err() {
+            exit(1);
+        }
+    }
+}
+
+/// Fork a child rebuilding the image and wait for it, returns 0 if it succeeded
+fn rebuild_in_child(file: OwnedFd, output: OwnedFd, format: ImageFormat) -> i32 {
+    // SAFETY: the sanitizer is single threaded
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            let keep = [file.as_raw_fd(), output.as_raw_fd()];
+            if close_inherited(&keep).is_err() || limit_resources().is_err() {
+                exit(1);
+            }
+            if sandbox::init_cdr().is_err() {
+                exit(1);
+            }
+            let mut output = File::from(output);
+            let result =
+                read_image(&File::from(file)).and_then(|data| rebuild_image(&data, format));
+            let written = match result {
+                Ok(image) => output
+                    .write_all(&[0])
+                    .and_then(|_| output.write_all(&image)),
+                Err(e) => output.write_all(format!("\x01{e}").as_bytes()),
+            };
+            exit(i32::from(written.is_err()))
+        }
+        Ok(ForkResult::Parent { child }) => {
+            drop(file);
+            drop(output);
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => 0,
+                _ => 1,
+            }
+        }
+        Err(e) => -(e as i32),
+    }
+}
+
+/// Read the whole image from its beginning, the offset of the file is shared with the daemon
+fn read_image(file: &File) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        match file.read_at(&mut chunk, data.len() as u64) {
+            Ok(0) => return Ok(data),
+            Ok(n) => data.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Limit the CPU time and the memory of the child, it is killed once over
+fn limit_resources() -> nix::Result<()> {
+    setrlimit(Resource::RLIMIT_CPU, CPU_LIMIT, CPU_LIMIT)?;
+    setrlimit(Resource::RLIMIT_AS, MEMORY_LIMIT, MEMORY_LIMIT)
+}
+
+/// Close the descriptors inherited from the sanitizer but the standard ones and those kept
+fn close_inherited(keep: &[RawFd]) -> io::Result<()> {
+    let mut keep = keep.to_vec();
+    keep.sort();
+    let mut first = 3;
+    for fd in keep {
+        if fd > first {
+            close_range(first, fd - 1)?;
+        }
+        first = first.max(fd + 1);
+    }
+    close_range(first, RawFd::MAX)
+}
+
+fn close_range(first: RawFd, last: RawFd) -> io::Result<()> {
+    // SAFETY: the descriptors closed are not used by the child
+    match unsafe { nix::libc::close_range(first as u32, last as u32, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn exit(code: i32) -> ! {
+    // SAFETY: the sanitizer and its children only exit, nothing of the daemon is shared with them
+    unsafe { nix::libc::_exit(code) }
+}
+
+#[cfg(test)]
+mod tests_cdr {
+    use super::{MAX_FRAMES, Sanitizer, image_format, rebuild_image};
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Frame, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+    use std::io::{Cursor, Write};
+    use tempfile::tempfile;
+
+    fn gif(count: usize, repeat: Repeat) -> Vec<u8> {
+        let mut data = Vec::new();
+        let frame = Frame::new(RgbaImage::from_pixel(2, 2, Rgba([0, 0, 255, 255])));
+        let mut encoder = GifEncoder::new(&mut data);
+        encoder.set_repeat(repeat).unwrap();
+        encoder.encode_frames(vec![frame; count]).unwrap();
+        drop(encoder);
+        data
+    }
+
+    #[test]
+    fn test_rebuild_polyglot_png() {
+        let mut png = Cursor::new(Vec::new());
+        RgbImage::from_pixel(4, 4, Rgb([255, 0, 0]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let mut polyglot = png.into_inner();
+        let original_len = polyglot.len();
+        polyglot.extend_from_slice(b"PK\x03\x04 appended payload");
+
+        let formats = vec!["png".to_string()];
+        assert_eq!(image_format(&polyglot, &formats), Some(ImageFormat::Png));
+        assert_eq!(image_format(&polyglot, &["jpg".to_string()]), None);
+
+        let rebuilt = rebuild_image(&polyglot, ImageFormat::Png).unwrap();
+        assert!(rebuilt.len() <= original_len);
+        assert!(!rebuilt.windows(4).any(|w| w == b"PK\x03\x04"));
+        let image = image::load_from_memory(&rebuilt).unwrap().to_rgb8();
+        assert_eq!(image.get_pixel(2, 2), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn test_rebuild_invalid_image() {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&[0u8; 64]);
+        assert!(rebuild_image(&data, ImageFormat::Png).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_gif_frames() {
+        assert!(rebuild_image(&gif(2, Repeat::Infinite), ImageFormat::Gif).is_ok());
+        // An animated image with too many frames is rejected
+        assert!(rebuild_image(&gif(MAX_FRAMES + 1, Repeat::Infinite), ImageFormat::Gif).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_gif_repeat() {
+        let repeat = |data: &[u8]| {
+            let mut options = gif::DecodeOptions::new();
+            options.set_color_output(gif::ColorOutput::RGBA);
+            let mut decoder = options.read_info(Cursor::new(data)).unwrap();
+            // The loop extension may follow the first frame
+            while decoder.read_next_frame().unwrap().is_some() {}
+            decoder.repeat()
+        };
+        let rebuilt = rebuild_image(&gif(2, Repeat::Finite(3)), ImageFormat::Gif).unwrap();
+        assert_eq!(repeat(&rebuilt), gif::Repeat::Finite(3));
+        let rebuilt = rebuild_image(&gif(2, Repeat::Infinite), ImageFormat::Gif).unwrap();
+        assert_eq!(repeat(&rebuilt), gif::Repeat::Infinite);
+    }
+
+    #[test]
+    fn test_rebuild_gif_header_dimensions() {
+        // The logical screen of the header is checked before any frame is decoded
+        let mut data = gif(1, Repeat::Infinite);
+        data[6..10].copy_from_slice(&[0xff; 4]);
+        let e = rebuild_image(&data, ImageFormat::Gif).unwrap_err();
+        assert!(e.to_string().contains("65535x65535"));
+    }
+
+    #[test]
+    fn test_sanitizer() {
+        let sanitizer = Sanitizer::start().unwrap();
+        let mut png = Cursor::new(Vec::new());
+        RgbImage::from_pixel(4, 4, Rgb([0, 255, 0]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let mut file = tempfile().unwrap();
+        file.write_all(png.get_ref()).unwrap();
+        let rebuilt = sanitizer.rebuild(&file, ImageFormat::Png).unwrap();
+        let image = image::load_from_memory(&rebuilt).unwrap().to_rgb8();
+        assert_eq!(image.get_pixel(1, 1), &Rgb([0, 255, 0]));
+
+        // The error of the child is returned and the sanitizer keeps serving
+        let mut file = tempfile().unwrap();
+        file.write_all(b"\x89PNG\r\n\x1a\n").unwrap();
+        file.write_all(&[0u8; 64]).unwrap();
+        assert!(sanitizer.rebuild(&file, ImageFormat::Png).is_err());
+        let file = tempfile().unwrap();
+        assert!(sanitizer.rebuild(&file, ImageFormat::Gif).is_err());
+    }
+}
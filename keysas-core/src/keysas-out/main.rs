@@ -18,16 +18,25 @@
 //! {
 //!     "metadata": {
 //!         "name",             // String: File name
+//!         "path",             // String: path of the file relative to the input device
+//!         "session",          // String: identifier of the transfer session of the file
 //!         "date",             // String DD-MM-YYYY_HH-mm-SS-NN: Date of creation of the report
 //!         "file_type",        // String: file type
 //!         "is_valid",         // Boolean: true if all checks passed
 //!         "report": {
-//!             "yara",         // String: yara detailed report
+//!             "yara",         // String: names of the yara rules matched
+//!             "yara_matches", // List: rule, namespace, tags, meta and matched strings of each yara match
+//!             "yara_ruleset", // String: SHA-256 hash of the yara rule set used for the scan
 //!             "av",           // String: clamav detailed report
 //!             "type_allowed", // Boolean: false if forbidden type detected
 //!             "size",         // u64: file size
 //!             "corrupted",    // boolean: true if file integrity corruption detected
-//!             "toobig"        // Boolean, true file size is too big
+//!             "toobig",       // Boolean, true file size is too big
+//!             "analyzers",    // List: verdict of each analyzer run by keysas-transit
+//!             "archive",      // List: detailed report of each entry if the file is an archive
+//!             "office",       // List: active contents found in office documents
+//!             "pdf",          // List: active contents and anomalies found in PDF documents
+//!             "extension"     // List: mismatches between the file name and the detected type
 //!         }
 //!     },
 //!     "binding" : {
@@ -40,8 +49,18 @@
 //! ```
 //!
 //! The report is signed by the station.
+//!
+//! The files and their reports are written under the same relative path as on the input device,
+//! the directories are created in the output directory. A path that could escape the output
+//! directory rejects the file, its report is then written at the top level.
+//!
+//! The files sent at once by keysas-in, e.g. the files of a USB device, share a transfer
+//! session. A signed summary of each session is written at the top level of the output
+//! directory as `<session id>.ksr`. It is updated in the hidden file `.<session id>.ksr` each
+//! time a file of the session is received and published once the session is closed: once all
+//! the files of the session are received, or once no file of the session has been received
+//! for [SESSION_TIMEOUT], it then stays incomplete.
 
-#![feature(unix_socket_ancillary_data)]
 #![warn(unused_extern_crates)]
 #![forbid(non_shorthand_field_patterns)]
 #![warn(dead_code)]
@@ -65,34 +84,50 @@ use keysas_lib::append_ext;
 use keysas_lib::file_report::bind_and_sign;
 use keysas_lib::file_report::generate_report_metadata;
 use keysas_lib::file_report::FileMetadata;
+use keysas_lib::file_report::{AnalyzerVerdict, Verdict};
 use keysas_lib::init_logger;
 use keysas_lib::keysas_hybrid_keypair::HybridKeyPair;
+use keysas_lib::protocol::{self, ErrorCode, ErrorReply};
+use keysas_lib::session::{
+    SUMMARY_EXTENSION, SessionEntry, SessionManifest, SessionSummary, sign_session,
+};
 use keysas_lib::sha256_digest;
 use log::{error, info, warn};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
 use nix::unistd;
 use pkcs8::der::EncodePem;
+use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
-use std::io::{BufWriter, IoSliceMut, Write};
-use std::os::fd::FromRawFd;
-use std::os::linux::net::SocketAddrExt;
-use std::os::unix::net::{AncillaryData, Messages, SocketAddr, SocketAncillary, UnixStream};
+use std::io::{BufWriter, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::str;
+use std::time::{Duration, Instant};
+mod cdr;
 mod sandbox;
+mod tests;
 
 /// Structure representing a file and its metadata in the daemon
-#[derive(bincode::Decode, Debug)]
+#[derive(Debug)]
 pub struct FileData {
     /// File descriptor
-    fd: i32,
+    fd: OwnedFd,
     /// Associated file metadata
     md: FileMetadata,
 }
 
+/// Time after which a session without any new file is closed
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Interval at which the sessions are checked while no file comes
+const SESSION_POLL: Duration = Duration::from_secs(60);
+/// Maximum number of sessions whose files are expected at once
+const MAX_SESSIONS: usize = 256;
+
 /// Directory containing the station signing keys
 const KEY_FILE_DIR: &str = "/etc/keysas";
 /// Password for the private signing keys PKCS#8 files
@@ -109,6 +144,10 @@ struct Configuration {
     sas_out: String,
     /// True if the file are allowed to pass even if yara failed
     yara_clean: bool,
+    /// Image formats rebuilt before being written, empty if CDR is disabled
+    cdr: Vec<String>,
+    /// User of keysas-transit, the only one allowed as peer on the socket
+    transit_user: String,
 }
 
 /// This function parse the command arguments into a structure
@@ -142,6 +181,24 @@ fn parse_args() -> Configuration {
                 .action(ArgAction::SetTrue)
                 .help("Remove the file if a Yara rule matched"),
         )
+        .arg(
+            Arg::new("cdr")
+                .short('r')
+                .long("cdr")
+                .value_name("<LIST>")
+                .default_value("")
+                .action(ArgAction::Set)
+                .help("List (comma separated) of image formats rebuilt before being written (jpg,png,gif,bmp)"),
+        )
+        .arg(
+            Arg::new("transit_user")
+                .short('u')
+                .long("transit_user")
+                .value_name("<USER>")
+                .default_value("keysas-transit")
+                .action(ArgAction::Set)
+                .help("User of keysas-transit, the only one allowed as peer on the socket"),
+        )
         .arg(
             Arg::new("version")
                 .short('v')
@@ -156,108 +213,315 @@ fn parse_args() -> Configuration {
         socket_out: matches.get_one::<String>("socket_out").unwrap().to_string(),
         sas_out: matches.get_one::<String>("sas_out").unwrap().to_string(),
         yara_clean: matches.get_flag("yara_clean"),
+        cdr: matches
+            .get_one::<String>("cdr")
+            .unwrap()
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(String::from)
+            .collect(),
+        transit_user: matches.get_one::<String>("transit_user").unwrap().to_string(),
     }
 }
 
-/// This function retrieves the file descriptors and metadata from the messages
-/// messages contains the file descriptor
-/// buffer contains the associated file metadata
-fn parse_messages(messages: Messages, buffer: &[u8]) -> Vec<FileData> {
-    messages
-        .filter_map(|m| m.ok())
-        .filter_map(|ad| {
-            // Filter AncillaryData to keep only ScmRights
-            match ad {
-                AncillaryData::ScmRights(scm_rights) => Some(scm_rights),
-                AncillaryData::ScmCredentials(_) => None,
-            }
-        })
-        .flatten()
-        .filter_map(|fd| {
-            // Deserialize metadata into a [FileMetadata] struct
-            match bincode::decode_from_slice::<FileMetadata, _>(buffer, bincode::config::standard()) {
-                Ok((meta, _)) => Some(FileData { fd, md: meta }),
-                Err(e) => {
-                    warn!(
-                        "Failed to deserialize message from keysas-transit: {e}, killing myself."
-                    );
-                    process::exit(1);
-                }
+/// Returns true if the file passed all the checks and can be written to the output
+fn is_clean(md: &FileMetadata, conf: &Configuration) -> bool {
+    md.is_digest_ok
+        && !md.is_toobig
+        && md.is_type_allowed
+        && md.av_pass
+        && !md.is_corrupted
+        && !md.is_rejected()
+        && (md.yara_pass || !conf.yara_clean)
+}
+
+/// Rebuild the file if it is an image whose format is in the CDR list
+/// Returns the sanitized image or None if the file is not rebuilt
+/// If the image cannot be rebuilt, the file is rejected
+/// The image is decoded by a sandboxed child of the sanitizer, never by keysas-out
+fn sanitize(
+    file: &File,
+    fd: i32,
+    md: &mut FileMetadata,
+    conf: &Configuration,
+    sanitizer: &cdr::Sanitizer,
+) -> Result<Option<Vec<u8>>> {
+    // Position the cursor at the beginning of the file
+    unistd::lseek(fd, 0, unistd::Whence::SeekSet)?;
+    let mut head = Vec::new();
+    file.take(8192).read_to_end(&mut head)?;
+    let format = match cdr::image_format(&head, &conf.cdr) {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    let (sanitized, verdict) = match sanitizer.rebuild(file, format) {
+        Ok(image) => (Some(image), Verdict::Pass),
+        Err(e) => {
+            warn!("Failed to rebuild image {}: {e}", md.filename);
+            (None, Verdict::Reject(format!("Image cannot be rebuilt: {e}")))
+        }
+    };
+    md.verdicts.push(AnalyzerVerdict {
+        analyzer: "cdr".to_string(),
+        verdict,
+    });
+    Ok(sanitized)
+}
+
+/// Returns the path of a file under the output directory, its report is written next to it
+/// The path relative to the input device is checked: if it could escape the output directory,
+/// or if its directories cannot be created, the file is rejected and written at the top level
+fn output_path(md: &mut FileMetadata, conf: &Configuration) -> PathBuf {
+    let requested = match md.path.is_empty() {
+        true => &md.filename,
+        false => &md.path,
+    };
+    let path = keysas_lib::safe_relative_path(requested)
+        .map(|relative| Path::new(&conf.sas_out).join(relative));
+    let error = match &path {
+        Some(p) => match p.parent().map(fs::create_dir_all) {
+            Some(Err(e)) => Some(format!("Cannot create directory of {p:?}: {e}")),
+            _ => None,
+        },
+        None => Some(format!("Unsafe path {requested:?}")),
+    };
+    match (path, error) {
+        (Some(p), None) => p,
+        (_, error) => {
+            let error = error.unwrap_or_default();
+            warn!("{error} for file {}", md.filename);
+            md.verdicts.push(AnalyzerVerdict {
+                analyzer: "path".to_string(),
+                verdict: Verdict::Reject(error),
+            });
+            // The name of the file without its directories, the digest if it has none
+            let name = Path::new(&md.filename)
+                .file_name()
+                .map_or_else(|| md.digest.clone().into(), PathBuf::from);
+            Path::new(&conf.sas_out).join(name)
+        }
+    }
+}
+
+/// Summary of a session whose files are expected
+#[derive(Debug)]
+struct Session {
+    summary: SessionSummary,
+    /// Time of the last file of the session received
+    updated: Instant,
+}
+
+/// Summaries of the transfer sessions, by identifier
+type Sessions = HashMap<String, Session>;
+
+/// Write the signed summary of a session to sas_out
+/// The summary of an open session is hidden so that keysas-io does not move it out
+/// of the station, it is published as `<session id>.ksr` once the session is closed
+fn write_summary(
+    summary: &SessionSummary,
+    conf: &Configuration,
+    sign_keys: Option<&HybridKeyPair>,
+    sign_cert: &str,
+) -> Result<()> {
+    let signed = sign_session(summary, sign_keys, sign_cert)?;
+    let name = format!("{}.{SUMMARY_EXTENSION}", summary.session.id);
+    let hidden = Path::new(&conf.sas_out).join(format!(".{name}"));
+    let mut report = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&hidden)?;
+    writeln!(report, "{}", serde_json::to_string_pretty(&signed)?)?;
+    if summary.closed {
+        fs::rename(&hidden, Path::new(&conf.sas_out).join(name))?;
+    }
+    Ok(())
+}
+
+/// Close a session and write its final summary, incomplete if some files never came
+fn close_session(
+    mut summary: SessionSummary,
+    conf: &Configuration,
+    sign_keys: Option<&HybridKeyPair>,
+    sign_cert: &str,
+) {
+    summary.close();
+    match summary.complete {
+        true => info!("Session {} complete", summary.session.id),
+        false => warn!(
+            "Session {} closed incomplete: {} of {} files received",
+            summary.session.id, summary.received, summary.session.files
+        ),
+    }
+    if let Err(e) = write_summary(&summary, conf, sign_keys, sign_cert) {
+        error!(
+            "Failed to write the summary of session {}: {e}",
+            summary.session.id
+        );
+    }
+}
+
+/// Close the sessions which have not received any file for SESSION_TIMEOUT
+fn expire_sessions(
+    sessions: &mut Sessions,
+    conf: &Configuration,
+    sign_keys: Option<&HybridKeyPair>,
+    sign_cert: &str,
+    now: Instant,
+) {
+    let expired: Vec<String> = sessions
+        .iter()
+        .filter(|(_, s)| now.saturating_duration_since(s.updated) >= SESSION_TIMEOUT)
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in expired {
+        if let Some(s) = sessions.remove(&id) {
+            close_session(s.summary, conf, sign_keys, sign_cert);
+        }
+    }
+}
+
+/// Add a file to the summary of its session and write the signed summary to sas_out
+/// The session is closed once all the files announced by keysas-in are received.
+/// At most MAX_SESSIONS are kept in memory, the least recently updated one is closed
+/// to make room for a new session.
+fn update_session(
+    sessions: &mut Sessions,
+    entry: SessionEntry,
+    manifest: &SessionManifest,
+    conf: &Configuration,
+    sign_keys: Option<&HybridKeyPair>,
+    sign_cert: &str,
+) -> Result<()> {
+    // The identifier names the summary file
+    if !manifest.is_valid_id() {
+        warn!("Invalid session for file {}", entry.path);
+        return Ok(());
+    }
+    if !sessions.contains_key(&manifest.id) && sessions.len() >= MAX_SESSIONS {
+        let oldest = sessions
+            .iter()
+            .min_by_key(|(_, s)| s.updated)
+            .map(|(id, _)| id.clone());
+        if let Some(s) = oldest.and_then(|id| sessions.remove(&id)) {
+            close_session(s.summary, conf, sign_keys, sign_cert);
+        }
+    }
+    let session = sessions
+        .entry(manifest.id.clone())
+        .or_insert_with(|| Session {
+            summary: SessionSummary::new(manifest.clone()),
+            updated: Instant::now(),
+        });
+    session.summary.add(entry);
+    session.updated = Instant::now();
+
+    match session.summary.complete {
+        true => {
+            if let Some(s) = sessions.remove(&manifest.id) {
+                close_session(s.summary, conf, sign_keys, sign_cert);
             }
-        })
-        .collect()
+            Ok(())
+        }
+        false => write_summary(&session.summary, conf, sign_keys, sign_cert),
+    }
 }
 
-/// This function output files and report received from transit
+/// This function output a file and its report received from transit
 /// The function first check the digest of the file received
-fn output_files(
-    files: Vec<FileData>,
+/// In CDR mode, the images are rebuilt and the sanitized image is written instead of the file
+/// The files are written under their path relative to the input device
+fn output_file(
+    mut f: FileData,
+    sessions: &mut Sessions,
     conf: &Configuration,
+    sanitizer: Option<&cdr::Sanitizer>,
     sign_keys: Option<&HybridKeyPair>,
     sign_cert: &str,
 ) -> Result<()> {
-    for mut f in files {
-        let file = unsafe { File::from_raw_fd(f.fd) };
-        // Position the cursor at the beginning of the file
-        unistd::lseek(f.fd, 0, unistd::Whence::SeekSet)?;
-        // Check digest
-        let digest = sha256_digest(&file)?;
-
-        // Test if digest is correct
-        if digest.ne(&f.md.digest) {
-            warn!("Digest invalid for file {}", f.md.filename);
-            f.md.is_digest_ok = false;
+    let fd = f.fd.as_raw_fd();
+    let file = File::from(f.fd);
+    // Position the cursor at the beginning of the file
+    unistd::lseek(fd, 0, unistd::Whence::SeekSet)?;
+    // Check digest
+    let digest = sha256_digest(&file)?;
+
+    // Test if digest is correct
+    if digest.ne(&f.md.digest) {
+        warn!("Digest invalid for file {}", f.md.filename);
+        f.md.is_digest_ok = false;
+    }
+
+    // Directories of the file in the output
+    let output = output_path(&mut f.md, conf);
+
+    // Rebuild the images that passed the checks
+    let sanitized = match sanitizer {
+        Some(sanitizer) if is_clean(&f.md, conf) => {
+            sanitize(&file, fd, &mut f.md, conf, sanitizer)?
         }
+        _ => None,
+    };
+    let sanitized_digest = match sanitized {
+        Some(ref image) => Some(sha256_digest(image.as_slice())?),
+        None => None,
+    };
+
+    // Generate a report
+    let report_meta = generate_report_metadata(&f.md);
 
-        // Generate a report
-        let report_meta = generate_report_metadata(&f.md);
+    // Bind the report to the file and sign it
+    let new_report = bind_and_sign(
+        &f.md,
+        &report_meta,
+        sanitized_digest.as_deref(),
+        sign_keys,
+        sign_cert,
+    )?;
 
-        // Bind the report to the file and sign it
-        let new_report = bind_and_sign(&f.md, &report_meta, sign_keys, sign_cert)?;
+    // Write the report to disk
+    let path = append_ext("krp", output.clone());
+    let mut report = File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)?;
+    let json_report = serde_json::to_string_pretty(&new_report)?;
 
-        // Write the report to disk
-        let mut path = PathBuf::new();
-        path.push(conf.sas_out.clone());
-        path.push(&f.md.filename);
-        let path = append_ext("krp", path);
-        let mut report = File::options()
+    info!("{json_report}");
+    writeln!(report, "{json_report}")?;
+
+    // Test if the check passed, if yes write the file to sas_out
+    let written = is_clean(&f.md, conf);
+    if written {
+        // Output file
+        let mut reader = BufReader::new(&file);
+
+        let output = File::options()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&path)?;
-        let json_report = serde_json::to_string_pretty(&new_report)?;
-
-        info!("{json_report}");
-        writeln!(report, "{json_report}")?;
-
-        // Test if the check passed, if yes write the file to sas_out
-        if f.md.is_digest_ok
-            && !f.md.is_toobig
-            && f.md.is_type_allowed
-            && f.md.av_pass
-            && !f.md.is_corrupted
-            && (f.md.yara_pass || !conf.yara_clean)
-        {
-            // Output file
-            let mut reader = BufReader::new(&file);
-
-            let mut path = PathBuf::new();
-            path.push(&conf.sas_out);
-            path.push(&f.md.filename);
-
-            let output = File::options()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(path)?;
-            // Position the cursor at the beginning of the file
-            unistd::lseek(f.fd, 0, unistd::Whence::SeekSet)?;
-            let mut writer = BufWriter::new(output);
-            io::copy(&mut reader, &mut writer)?;
+            .open(&output)?;
+        // Position the cursor at the beginning of the file
+        unistd::lseek(fd, 0, unistd::Whence::SeekSet)?;
+        let mut writer = BufWriter::new(output);
+        match sanitized {
+            Some(ref image) => writer.write_all(image)?,
+            None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
         }
-        // Release the file in this daemon
-        drop(file);
     }
+    // Release the file in this daemon
+    drop(file);
+
+    let entry = SessionEntry {
+        path: report_meta.path.clone(),
+        digest: f.md.digest.clone(),
+        is_valid: report_meta.is_valid,
+        written,
+    };
+    update_session(sessions, entry, &f.md.session, conf, sign_keys, sign_cert)?;
 
     Ok(())
 }
@@ -266,9 +530,9 @@ fn output_files(
 /// It starts by configuring the security (landlock and seccomp), it recovers the
 /// station signing keys
 /// Then it enters an infinite loops that:
-/// 1. Get file descriptors and metadata from transit
-/// 2. Parse them
-/// 3. Create reports for each file and outputs them with the file to the output directory
+/// 1. Get a file descriptor and its metadata from transit
+/// 2. Create a report for the file and outputs it with the file to the output directory
+/// 3. Acknowledge the file to transit
 fn main() -> Result<()> {
     // Parse command arguments
     let config = parse_args();
@@ -276,11 +540,42 @@ fn main() -> Result<()> {
     // Configure logger
     init_logger();
 
+    // Check the image formats to rebuild
+    if let Some(f) = config
+        .cdr
+        .iter()
+        .find(|f| !cdr::CDR_FORMATS.contains(&f.as_str()))
+    {
+        error!("Unsupported CDR format: {f}");
+        process::exit(1);
+    }
+
+    // The user of keysas-transit is resolved before the sandbox is activated
+    let transit_uid = match protocol::user_id(&config.transit_user) {
+        Ok(uid) => uid,
+        Err(e) => {
+            error!("Cannot find user {}: {e}", config.transit_user);
+            process::exit(1);
+        }
+    };
+
     //Init Landlock
     match sandbox::landlock_sandbox(&config.sas_out) {
         Ok(_) => log::info!("Landlock sandbox activated."),
         Err(e) => log::warn!("Landlock sandbox cannot be activated: {e}"),
     }
+    // The images are rebuilt by a process forked before the keys are loaded
+    let sanitizer = match config.cdr.is_empty() {
+        true => None,
+        false => match cdr::Sanitizer::start() {
+            Ok(s) => Some(s),
+            Err(e) => {
+                error!("Cannot start the image sanitizer: {e}");
+                process::exit(1);
+            }
+        },
+    };
+
     // Init Seccomp filters
     match sandbox::init() {
         Ok(_) => log::info!("Seccomp sandbox activated."),
@@ -326,8 +621,8 @@ fn main() -> Result<()> {
     }
 
     // Open socket with keysas-transit
-    let addr_out = SocketAddr::from_abstract_name(&config.socket_out)?;
-    let sock_out = match UnixStream::connect_addr(&addr_out) {
+    // The socket must be the one of keysas-transit, it is connected again if keysas-transit restarts
+    let connect = || match protocol::connect(&config.socket_out, transit_uid) {
         Ok(s) => {
             info!("Connected to keysas-transit socket.");
             s
@@ -337,32 +632,77 @@ fn main() -> Result<()> {
             process::exit(1);
         }
     };
+    let mut sock_out = connect();
 
-    // Allocate buffers for input messages
-    let mut ancillary_buffer_in = [0; 128];
-    let mut ancillary_in = SocketAncillary::new(&mut ancillary_buffer_in[..]);
+    // Summaries of the sessions whose files are expected
+    let mut sessions = Sessions::new();
+    // keysas-transit is polled so that the sessions expire even if no file comes
+    let poll_timeout = PollTimeout::try_from(SESSION_POLL).unwrap_or(PollTimeout::MAX);
 
     // Main loop
     // 1. receive file descriptor and metadata from transit
     // 2. Write file and report to output
+    // 3. Acknowledge the file, transit is then released from it
     loop {
-        // 4128 => filename max 4096 bytes and digest 32 bytes
-        let mut buf_in = [0; 4128];
-        let bufs_in = &mut [IoSliceMut::new(&mut buf_in[..])][..];
-
-        // Listen for message on socket
-        match sock_out.recv_vectored_with_ancillary(bufs_in, &mut ancillary_in) {
-            Ok(_) => (),
+        expire_sessions(
+            &mut sessions,
+            &config,
+            sign_keys.as_ref(),
+            &sign_cert,
+            Instant::now(),
+        );
+        let mut fds = [PollFd::new(sock_out.as_fd(), PollFlags::POLLIN)];
+        if matches!(poll(&mut fds, poll_timeout), Ok(0)) {
+            continue;
+        }
+        let (md, fd) = match protocol::receive_file::<FileMetadata>(&sock_out) {
+            Ok(r) => r,
             Err(e) => {
-                warn!("Failed to receive fds from in: {e}");
-                process::exit(1);
+                // The file is refused, keysas-transit is told why
+                protocol::refuse(&sock_out, &e);
+                if e.is_version() {
+                    error!("Keysas-transit runs another version of the protocol: {e}");
+                    process::exit(1);
+                }
+                match e.is_fatal() {
+                    true => {
+                        // The file being sent is sent again by keysas-transit
+                        warn!("Connection to keysas-transit lost: {e}, connecting again");
+                        sock_out = connect();
+                        continue;
+                    }
+                    false => {
+                        warn!("File from keysas-transit refused: {e}");
+                        continue;
+                    }
+                }
             }
-        }
-
-        // Parse messages received
-        let files = parse_messages(ancillary_in.messages(), &buf_in);
+        };
 
         // Output file
-        output_files(files, &config, sign_keys.as_ref(), &sign_cert)?;
+        let f = FileData { fd, md };
+        // The file is kept in sas_in if it cannot be written
+        let result = match output_file(
+            f,
+            &mut sessions,
+            &config,
+            sanitizer.as_ref(),
+            sign_keys.as_ref(),
+            &sign_cert,
+        ) {
+            Ok(_) => protocol::reply_ack(&sock_out),
+            Err(e) => {
+                error!("Failed to output file: {e:?}");
+                let reply = ErrorReply {
+                    code: ErrorCode::Internal,
+                    message: e.to_string(),
+                };
+                protocol::reply_error(&sock_out, &reply)
+            }
+        };
+        // The connection is checked on the next file
+        if let Err(e) = result {
+            warn!("Failed to reply to keysas-transit: {e}");
+        }
     }
 }
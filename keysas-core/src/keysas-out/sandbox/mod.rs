@@ -16,7 +16,7 @@ use landlock::{
 };
 
 #[cfg(target_os = "linux")]
-use syscallz::{Context, Syscall};
+use syscallz::{Action, Context, Syscall};
 
 #[cfg(target_os = "linux")]
 pub fn init() -> Result<()> {
@@ -25,6 +25,9 @@ pub fn init() -> Result<()> {
     ctx.allow_syscall(Syscall::write)?;
     ctx.allow_syscall(Syscall::openat)?;
     ctx.allow_syscall(Syscall::recvmsg)?;
+    ctx.allow_syscall(Syscall::sendmsg)?;
+    ctx.allow_syscall(Syscall::getsockopt)?;
+    ctx.allow_syscall(Syscall::clock_nanosleep)?;
     ctx.allow_syscall(Syscall::read)?;
     ctx.allow_syscall(Syscall::close)?;
     ctx.allow_syscall(Syscall::lseek)?;
@@ -62,8 +65,46 @@ pub fn init() -> Result<()> {
     ctx.allow_syscall(Syscall::connect)?;
     ctx.allow_syscall(Syscall::execve)?;
     ctx.allow_syscall(Syscall::copy_file_range)?;
+    #[cfg(target_arch = "x86_64")]
+    ctx.allow_syscall(Syscall::mkdir)?;
+    ctx.allow_syscall(Syscall::mkdirat)?;
     ctx.allow_syscall(Syscall::clock_gettime)?;
     ctx.allow_syscall(Syscall::futex)?;
+    // Publication of the session summaries
+    #[cfg(target_arch = "x86_64")]
+    ctx.allow_syscall(Syscall::rename)?;
+    ctx.allow_syscall(Syscall::renameat2)?;
+    // Pipes and status of the image rebuilding processes
+    ctx.allow_syscall(Syscall::pipe2)?;
+    ctx.allow_syscall(Syscall::recvfrom)?;
+    ctx.allow_syscall(Syscall::exit_group)?;
+    ctx.load()?;
+    Ok(())
+}
+
+/// Seccomp filter of the processes rebuilding the images
+/// They only read the image and write the result to descriptors they already own
+#[cfg(target_os = "linux")]
+pub fn init_cdr() -> Result<()> {
+    let mut ctx = Context::init_with_action(Action::Errno(nix::libc::EPERM as u16))?;
+    ctx.allow_syscall(Syscall::read)?;
+    ctx.allow_syscall(Syscall::pread64)?;
+    ctx.allow_syscall(Syscall::write)?;
+    ctx.allow_syscall(Syscall::close)?;
+    ctx.allow_syscall(Syscall::lseek)?;
+    ctx.allow_syscall(Syscall::mmap)?;
+    ctx.allow_syscall(Syscall::munmap)?;
+    ctx.allow_syscall(Syscall::mremap)?;
+    ctx.allow_syscall(Syscall::madvise)?;
+    ctx.allow_syscall(Syscall::mprotect)?;
+    ctx.allow_syscall(Syscall::brk)?;
+    ctx.allow_syscall(Syscall::futex)?;
+    ctx.allow_syscall(Syscall::getrandom)?;
+    ctx.allow_syscall(Syscall::clock_gettime)?;
+    ctx.allow_syscall(Syscall::rt_sigprocmask)?;
+    ctx.allow_syscall(Syscall::rt_sigreturn)?;
+    ctx.allow_syscall(Syscall::sigaltstack)?;
+    ctx.allow_syscall(Syscall::exit)?;
     ctx.allow_syscall(Syscall::exit_group)?;
     ctx.load()?;
     Ok(())
@@ -0,0 +1,95 @@
+#[cfg(test)]
+mod tests_sessions {
+    use crate::{
+        Configuration, MAX_SESSIONS, SESSION_TIMEOUT, Sessions, expire_sessions, update_session,
+    };
+    use keysas_lib::session::{SessionEntry, SessionManifest, SessionReport, SessionSummary};
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    /// Last summary written for the session, hidden until the session is closed
+    fn summary(sas_out: &Path, manifest: &SessionManifest, closed: bool) -> SessionSummary {
+        let name = format!("{}.ksr", manifest.id);
+        let path = match closed {
+            true => sas_out.join(name),
+            false => sas_out.join(format!(".{name}")),
+        };
+        let json = fs::read_to_string(path).unwrap();
+        serde_json::from_str::<SessionReport>(&json)
+            .unwrap()
+            .summary
+    }
+
+    /// Session of `files` files
+    fn manifest(files: u64) -> SessionManifest {
+        SessionManifest {
+            files,
+            ..SessionManifest::new("network")
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let dir = tempdir().unwrap();
+        let conf = Configuration {
+            socket_out: String::new(),
+            sas_out: dir.path().to_str().unwrap().to_string(),
+            yara_clean: false,
+            cdr: Vec::new(),
+            transit_user: String::new(),
+        };
+        let entry = SessionEntry {
+            path: "a.txt".into(),
+            digest: String::new(),
+            is_valid: true,
+            written: true,
+        };
+        let mut sessions = Sessions::new();
+
+        // A session is closed once all its files are received
+        let complete = manifest(1);
+        update_session(&mut sessions, entry.clone(), &complete, &conf, None, "").unwrap();
+        assert!(sessions.is_empty());
+        let s = summary(dir.path(), &complete, true);
+        assert!(s.complete && s.closed);
+        assert!(!dir.path().join(format!(".{}.ksr", complete.id)).exists());
+
+        // A session without any new file is closed incomplete
+        let idle = manifest(2);
+        update_session(&mut sessions, entry.clone(), &idle, &conf, None, "").unwrap();
+        assert!(!summary(dir.path(), &idle, false).closed);
+        assert!(!dir.path().join(format!("{}.ksr", idle.id)).exists());
+        // A file sent again is not counted twice
+        update_session(&mut sessions, entry.clone(), &idle, &conf, None, "").unwrap();
+        let s = summary(dir.path(), &idle, false);
+        assert!(!s.complete);
+        assert_eq!(s.files.len(), 1);
+        expire_sessions(&mut sessions, &conf, None, "", Instant::now());
+        assert_eq!(sessions.len(), 1);
+        expire_sessions(
+            &mut sessions,
+            &conf,
+            None,
+            "",
+            Instant::now() + SESSION_TIMEOUT,
+        );
+        assert!(sessions.is_empty());
+        let s = summary(dir.path(), &idle, true);
+        assert!(!s.complete && s.closed);
+        assert_eq!(s.received, 1);
+
+        // The least recently updated session is closed to make room for a new one
+        let oldest = manifest(2);
+        update_session(&mut sessions, entry.clone(), &oldest, &conf, None, "").unwrap();
+        thread::sleep(Duration::from_millis(1));
+        for _ in 0..MAX_SESSIONS {
+            update_session(&mut sessions, entry.clone(), &manifest(2), &conf, None, "").unwrap();
+        }
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert!(!sessions.contains_key(&oldest.id));
+        assert!(summary(dir.path(), &oldest, true).closed);
+    }
+}
@@ -0,0 +1,348 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the archive analyzer.
+ */
+
+//! Recursive inspection of archives
+//!
+//! Supported formats are zip, tar, gzip and 7z. Archives are unpacked in memory
+//! and each entry goes through the type profiles, the anti-virus engines and Yara.
+//! Nested archives are unpacked up to a maximum depth.
+//!
+//! To protect the daemon against archive bombs the inspection is stopped and the file
+//! rejected if:
+//!     - the nesting level is higher than the maximum depth
+//!     - the total number of entries is higher than the maximum
+//!     - the total uncompressed size is higher than the maximum size of the archive type or than
+//!       the archive size multiplied by the maximum expansion ratio
+//!
+//! The memory needed by the archive and its uncompressed entries is reserved before
+//! unpacking it, and the inspection stops with a timeout verdict once the time budget
+//! of the file is over.
+
+use super::av::Antivirus;
+use super::budget::Budget;
+use super::content::detect_type;
+use super::magic::get_extension;
+use super::yara::{YaraScanner, rule_names};
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::profile::Profiles;
+use anyhow::{Result, anyhow};
+use flate2::read::MultiGzDecoder;
+use infer::get;
+use keysas_lib::file_report::{Action, ArchiveEntry, Verdict};
+use log::{error, warn};
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+
+/// Limits applied when unpacking archives
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveLimits {
+    /// Maximum nesting level of archives
+    pub max_depth: u32,
+    /// Maximum number of entries, all nesting levels included
+    pub max_entries: usize,
+    /// Maximum ratio between the uncompressed size and the archive size
+    pub max_ratio: u64,
+}
+
+/// Archive formats that can be unpacked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    Gzip,
+    SevenZ,
+}
+
+/// Returns the archive format of the buffer if it is supported
+pub fn archive_kind(buf: &[u8]) -> Option<ArchiveKind> {
+    match get(buf)?.extension() {
+        "zip" => Some(ArchiveKind::Zip),
+        "tar" => Some(ArchiveKind::Tar),
+        "gz" => Some(ArchiveKind::Gzip),
+        "7z" => Some(ArchiveKind::SevenZ),
+        _ => None,
+    }
+}
+
+/// Unpack archives and check their entries
+pub struct ArchiveAnalyzer {
+    /// Limits applied to the unpacking
+    limits: ArchiveLimits,
+    /// Profiles deciding if the type of the entries is allowed
+    profiles: Arc<Profiles>,
+    /// Anti-virus engines
+    av: Arc<Antivirus>,
+    /// Yara rules
+    yara: Arc<YaraScanner>,
+}
+
+impl ArchiveAnalyzer {
+    pub fn new(
+        limits: ArchiveLimits,
+        profiles: Arc<Profiles>,
+        av: Arc<Antivirus>,
+        yara: Arc<YaraScanner>,
+    ) -> Self {
+        Self {
+            limits,
+            profiles,
+            av,
+            yara,
+        }
+    }
+
+    /// Run the type, anti-virus and yara checks on an entry
+    fn check_entry(&self, path: String, depth: u32, data: &[u8], budget: &Budget) -> ArchiveEntry {
+        // Same type selection as the top-level file
+        let profile = self.profiles.get(detect_type(data, &path));
+        let mut entry = ArchiveEntry {
+            path,
+            depth,
+            size: data.len() as u64,
+            file_type: get_extension(data),
+            is_type_allowed: profile.action != Action::Reject,
+            av_pass: false,
+            av_report: Vec::new(),
+            av_engines: Vec::new(),
+            yara_pass: false,
+            yara_report: String::new(),
+            yara_matches: Vec::new(),
+        };
+        let av = self.av.scan(data, budget);
+        entry.av_pass = av.pass;
+        entry.av_report = av.infections;
+        entry.av_engines = av.reports;
+        match self.yara.scan(data, budget) {
+            Ok(matches) => {
+                entry.yara_pass = matches.is_empty();
+                entry.yara_report = rule_names(&matches);
+                entry.yara_matches = matches;
+            }
+            Err(e) => {
+                error!("Yara cannot scan archive entry {} error {e}", entry.path);
+            }
+        }
+        entry
+    }
+}
+
+/// State of the inspection of an archive and its nested archives
+struct Inspection<'a> {
+    analyzer: &'a ArchiveAnalyzer,
+    /// Time budget of the file
+    deadline: &'a Budget,
+    /// Maximum uncompressed size allowed for this archive
+    budget: u64,
+    /// Uncompressed size already read
+    expanded: u64,
+    /// Number of entries found, directories included
+    count: usize,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl Inspection<'_> {
+    /// Read an entry without going over the uncompressed size budget
+    fn read_entry(&mut self, reader: &mut dyn Read) -> Result<Vec<u8>> {
+        let remaining = self.budget - self.expanded;
+        let mut data = Vec::new();
+        reader.take(remaining + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > remaining {
+            return Err(anyhow!(
+                "Uncompressed size is over the limit of {} bytes",
+                self.budget
+            ));
+        }
+        self.expanded += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Account for a new entry in the archive
+    fn count_entry(&mut self) -> Result<()> {
+        self.count += 1;
+        if self.count > self.analyzer.limits.max_entries {
+            return Err(anyhow!(
+                "Archive contains more than {} entries",
+                self.analyzer.limits.max_entries
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check an entry and unpack it if it is itself an archive
+    fn visit(&mut self, name: &str, path: String, depth: u32, data: Vec<u8>) -> Result<()> {
+        if self.deadline.is_expired() {
+            return Err(anyhow!("Analysis time is over"));
+        }
+        let entry = self.analyzer.check_entry(path, depth, &data, self.deadline);
+        let path = entry.path.clone();
+        self.entries.push(entry);
+        if let Some(kind) = archive_kind(&data) {
+            if depth >= self.analyzer.limits.max_depth {
+                return Err(anyhow!(
+                    "Archive nesting is deeper than {} levels",
+                    self.analyzer.limits.max_depth
+                ));
+            }
+            self.unpack(kind, name, &path, depth + 1, data)?;
+        }
+        Ok(())
+    }
+
+    /// Unpack the archive and visit each of its entries
+    /// `name` is the file name of the archive, `prefix` its path in the parent archive
+    fn unpack(
+        &mut self,
+        kind: ArchiveKind,
+        name: &str,
+        prefix: &str,
+        depth: u32,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let join = |entry: &str| match prefix.is_empty() {
+            true => entry.to_string(),
+            false => format!("{prefix}/{entry}"),
+        };
+        match kind {
+            ArchiveKind::Zip => {
+                let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+                for i in 0..archive.len() {
+                    self.count_entry()?;
+                    let mut file = archive.by_index(i)?;
+                    if file.is_dir() {
+                        continue;
+                    }
+                    let entry_name = file.name().to_string();
+                    let content = self.read_entry(&mut file)?;
+                    drop(file);
+                    self.visit(&entry_name, join(&entry_name), depth, content)?;
+                }
+            }
+            ArchiveKind::Tar => {
+                let mut archive = tar::Archive::new(Cursor::new(data));
+                for file in archive.entries()? {
+                    self.count_entry()?;
+                    let mut file = file?;
+                    if !file.header().entry_type().is_file() {
+                        continue;
+                    }
+                    let entry_name = file.path()?.to_string_lossy().to_string();
+                    let content = self.read_entry(&mut file)?;
+                    self.visit(&entry_name, join(&entry_name), depth, content)?;
+                }
+            }
+            ArchiveKind::Gzip => {
+                self.count_entry()?;
+                // A gzip stream contains a single file named after the archive
+                let entry_name = name
+                    .strip_suffix(".tgz")
+                    .map(|n| format!("{n}.tar"))
+                    .or_else(|| name.strip_suffix(".gz").map(String::from))
+                    .unwrap_or_else(|| name.to_string());
+                let content = self.read_entry(&mut MultiGzDecoder::new(Cursor::new(data)))?;
+                self.visit(&entry_name, join(&entry_name), depth, content)?;
+            }
+            ArchiveKind::SevenZ => {
+                let len = data.len() as u64;
+                let mut archive = sevenz_rust::SevenZReader::new(
+                    Cursor::new(data),
+                    len,
+                    sevenz_rust::Password::empty(),
+                )?;
+                // Errors are raised outside of the callback
+                let mut result = Ok(());
+                archive.for_each_entries(|file, reader| {
+                    result = self.count_entry().and_then(|_| {
+                        if file.is_directory() {
+                            return Ok(());
+                        }
+                        let entry_name = file.name().to_string();
+                        let content = self.read_entry(reader)?;
+                        self.visit(&entry_name, join(&entry_name), depth, content)
+                    });
+                    Ok(result.is_ok())
+                })?;
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Analyzer for ArchiveAnalyzer {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Archives whose type is not allowed are not unpacked
+        if !md.is_type_allowed {
+            return Verdict::Pass;
+        }
+        // Detect archives from the beginning of the file
+        let kind = match archive_kind(content.prefix(8192)) {
+            Some(k) => k,
+            None => return Verdict::Pass,
+        };
+        // Archives are unpacked in memory, up to the maximum size of their type
+        let max_size = content.profile.max_size;
+        let prefix = content.prefix(max_size.saturating_add(1));
+        let budget = (prefix.len() as u64)
+            .saturating_mul(self.limits.max_ratio)
+            .min(max_size);
+        let _memory = match content
+            .budget
+            .reserve((prefix.len() as u64).saturating_add(budget))
+        {
+            Ok(r) => r,
+            Err(verdict) => return verdict,
+        };
+        let mut inspection = Inspection {
+            analyzer: self,
+            deadline: &content.budget,
+            budget,
+            expanded: 0,
+            count: 0,
+            entries: Vec::new(),
+        };
+        let result = inspection.unpack(kind, &md.filename, "", 1, prefix.to_vec());
+        md.archive_entries = inspection.entries;
+        if let Err(e) = result {
+            warn!("Archive {} rejected: {e}", md.filename);
+            return match content.budget.is_expired() {
+                true => content.budget.timeout(),
+                false => Verdict::Reject(e.to_string()),
+            };
+        }
+        let rejected = md
+            .archive_entries
+            .iter()
+            .filter(|e| !e.is_type_allowed || !e.av_pass)
+            .count();
+        let unavailable = md
+            .archive_entries
+            .iter()
+            .filter(|e| e.av_engines.iter().any(|r| r.error.is_some()))
+            .count();
+        let flagged = md.archive_entries.iter().filter(|e| !e.yara_pass).count();
+        if rejected > 0 {
+            Verdict::Reject(format!("{rejected} archive entries failed the checks"))
+        } else if unavailable > 0 && content.budget.is_expired() {
+            content.budget.timeout()
+        } else if unavailable > 0 {
+            Verdict::Unavailable(format!(
+                "Anti-virus engine unavailable for {unavailable} archive entries"
+            ))
+        } else if flagged > 0 {
+            Verdict::Flag(format!("{flagged} archive entries matched Yara rules"))
+        } else {
+            Verdict::Pass
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the ClamAV engine.
+ */
+
+//! Anti-virus scan with the ClamAV daemon
+//!
+//! The file is sent to clamd with the INSTREAM command while it is read,
+//! over TCP or over the local unix socket of clamd.
+
+use super::{Engine, Session, connect_tcp};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Maximum size of the chunks sent to clamd
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Connection with clamd
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+/// Address of clamd
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClamdAddress {
+    /// IP:PORT
+    Tcp(String),
+    /// Path of the unix socket
+    Unix(PathBuf),
+}
+
+impl ClamdAddress {
+    /// Connect to clamd, the timeout applies to each exchange
+    fn connect(&self, timeout: Duration) -> io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            ClamdAddress::Tcp(addr) => Box::new(connect_tcp(addr, timeout)?),
+            ClamdAddress::Unix(path) => {
+                let s = UnixStream::connect(path)?;
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))?;
+                Box::new(s)
+            }
+        })
+    }
+}
+
+/// Session scanning data sent in chunks with the INSTREAM command of clamd
+pub struct Instream {
+    /// Connection with clamd or the first error met during the session
+    conn: io::Result<Box<dyn Connection>>,
+}
+
+impl Instream {
+    /// Connect to clamd and start the session
+    pub fn start(addr: &ClamdAddress, timeout: Duration) -> Self {
+        let conn = addr.connect(timeout).and_then(|mut c| {
+            c.write_all(b"zINSTREAM\0")?;
+            Ok(c)
+        });
+        Self { conn }
+    }
+}
+
+impl Session for Instream {
+    fn send(&mut self, data: &[u8]) {
+        let sent = match &mut self.conn {
+            Ok(c) => data.chunks(MAX_CHUNK_SIZE).try_for_each(|chunk| {
+                c.write_all(&(chunk.len() as u32).to_be_bytes())?;
+                c.write_all(chunk)
+            }),
+            Err(_) => return,
+        };
+        if let Err(e) = sent {
+            self.conn = Err(e);
+        }
+    }
+
+    fn result(self: Box<Self>) -> io::Result<Vec<String>> {
+        let mut c = self.conn?;
+        c.write_all(&[0; 4])?;
+        let mut response = String::new();
+        c.read_to_string(&mut response)?;
+        parse_response(&response)
+    }
+}
+
+/// Parse the response of clamd to the INSTREAM command
+pub fn parse_response(response: &str) -> io::Result<Vec<String>> {
+    let response = response.trim_end_matches(['\0', '\n']);
+    let result = response.strip_prefix("stream: ").unwrap_or(response);
+    match result.strip_suffix(" FOUND") {
+        Some(name) => Ok(vec![name.to_string()]),
+        None if result == "OK" => Ok(Vec::new()),
+        None => Err(io::Error::other(format!("clamd error: {response}"))),
+    }
+}
+
+/// ClamAV daemon
+#[derive(Debug, Clone)]
+pub struct ClamdEngine {
+    name: String,
+    addr: ClamdAddress,
+    /// Timeout of each exchange with clamd
+    timeout: Duration,
+}
+
+impl ClamdEngine {
+    pub fn new(name: String, addr: ClamdAddress, timeout: Duration) -> Self {
+        Self {
+            name,
+            addr,
+            timeout,
+        }
+    }
+}
+
+impl Engine for ClamdEngine {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> io::Result<String> {
+        let mut c = self.addr.connect(self.timeout)?;
+        c.write_all(b"zVERSION\0")?;
+        let mut response = String::new();
+        c.read_to_string(&mut response)?;
+        Ok(response.trim_end_matches(['\0', '\n']).to_string())
+    }
+
+    fn start(&self) -> Box<dyn Session> {
+        Box::new(Instream::start(&self.addr, self.timeout))
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the ICAP engine.
+ */
+
+//! Anti-virus scan with an ICAP server (RFC 3507)
+//!
+//! The file is sent as the body of an HTTP response in a RESPMOD request,
+//! in chunks while it is read. The server answers 204 if the file is clean.
+//! Otherwise the infection is read from the X-Infection-Found or X-Virus-ID
+//! headers set by most anti-virus gateways.
+
+use super::{Engine, Session, connect_tcp};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Encapsulated HTTP response header sent before the file
+const HTTP_HEADER: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n";
+
+/// Name reported when the server blocks the file without naming the infection
+const UNKNOWN_INFECTION: &str = "Blocked by ICAP server";
+
+/// Status and headers of an ICAP response
+#[derive(Debug)]
+pub struct IcapResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl IcapResponse {
+    /// Read the status line and the headers of the response
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        let status_line = lines
+            .next()
+            .ok_or_else(|| io::Error::other("ICAP server closed the connection"))??;
+        let status = status_line
+            .strip_prefix("ICAP/1.0 ")
+            .and_then(|s| s.get(..3))
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("Invalid ICAP response: {status_line}")))?;
+        let mut headers = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+            }
+        }
+        Ok(Self { status, headers })
+    }
+
+    /// Value of the first header with the name in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the names of the infections detected
+    pub fn infections(&self) -> io::Result<Vec<String>> {
+        match self.status {
+            204 => Ok(Vec::new()),
+            200 => {
+                // X-Infection-Found: Type=0; Resolution=2; Threat=Eicar-Signature;
+                let threat = self.header("x-infection-found").and_then(|h| {
+                    h.split(';')
+                        .find_map(|p| p.trim().strip_prefix("Threat="))
+                        .map(str::to_string)
+                });
+                let name = threat
+                    .or_else(|| self.header("x-virus-id").map(str::to_string))
+                    .unwrap_or_else(|| UNKNOWN_INFECTION.to_string());
+                Ok(vec![name])
+            }
+            s => Err(io::Error::other(format!("ICAP server error: {s}"))),
+        }
+    }
+}
+
+/// Session sending the file in a RESPMOD request
+pub struct Respmod {
+    /// Connection with the server or the first error met during the session
+    conn: io::Result<TcpStream>,
+}
+
+impl Respmod {
+    /// Connect to the server and send the request headers
+    pub fn start(addr: &str, service: &str, timeout: Duration) -> Self {
+        let conn = connect_tcp(addr, timeout).and_then(|mut c| {
+            write!(
+                c,
+                "RESPMOD icap://{addr}/{service} ICAP/1.0\r\n\
+                 Host: {addr}\r\n\
+                 Allow: 204\r\n\
+                 Encapsulated: res-hdr=0, res-body={}\r\n\r\n{HTTP_HEADER}",
+                HTTP_HEADER.len()
+            )?;
+            Ok(c)
+        });
+        Self { conn }
+    }
+}
+
+impl Session for Respmod {
+    fn send(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            // An empty chunk ends the body
+            return;
+        }
+        let sent = match &mut self.conn {
+            Ok(c) => write!(c, "{:x}\r\n", data.len())
+                .and_then(|_| c.write_all(data))
+                .and_then(|_| c.write_all(b"\r\n")),
+            Err(_) => return,
+        };
+        if let Err(e) = sent {
+            self.conn = Err(e);
+        }
+    }
+
+    fn result(self: Box<Self>) -> io::Result<Vec<String>> {
+        let mut c = self.conn?;
+        c.write_all(b"0\r\n\r\n")?;
+        IcapResponse::read(BufReader::new(c))?.infections()
+    }
+}
+
+/// ICAP server
+#[derive(Debug, Clone)]
+pub struct IcapEngine {
+    name: String,
+    /// Address of the server in the form IP:PORT
+    addr: String,
+    /// Service of the server, e.g. "avscan"
+    service: String,
+    /// Timeout of each exchange with the server
+    timeout: Duration,
+}
+
+impl IcapEngine {
+    pub fn new(name: String, addr: String, service: String, timeout: Duration) -> Self {
+        Self {
+            name,
+            addr,
+            service,
+            timeout,
+        }
+    }
+}
+
+impl Engine for IcapEngine {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn version(&self) -> io::Result<String> {
+        let mut c = connect_tcp(&self.addr, self.timeout)?;
+        write!(
+            c,
+            "OPTIONS icap://{}/{} ICAP/1.0\r\nHost: {}\r\nEncapsulated: null-body=0\r\n\r\n",
+            self.addr, self.service, self.addr
+        )?;
+        let response = IcapResponse::read(BufReader::new(c))?;
+        match response.status {
+            200 => Ok(response.header("service").unwrap_or("ICAP").to_string()),
+            s => Err(io::Error::other(format!("ICAP server error: {s}"))),
+        }
+    }
+
+    fn start(&self) -> Box<dyn Session> {
+        Box::new(Respmod::start(&self.addr, &self.service, self.timeout))
+    }
+}
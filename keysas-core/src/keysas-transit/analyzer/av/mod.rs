@@ -0,0 +1,578 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the anti-virus analyzer.
+ */
+
+//! Anti-virus scan with one or several engines
+//!
+//! Each [Engine] scans the file in its own session fed while the file is read,
+//! see [Content]. Supported engines are:
+//!     - clamav_tcp: ClamAV daemon listening on a TCP socket
+//!     - clamav_unix: ClamAV daemon listening on a local unix socket
+//!     - icap: ICAP server, e.g. an anti-virus gateway
+//!
+//! The engines are set in the "av" section of the station policy, ClamAV on the
+//! address or the unix socket given on the command line is used if no engine is set.
+//! The decision says if a detection by any engine rejects the file or if a quorum of
+//! engines is needed, detections below the quorum are flagged.
+//!
+//! A scan that fails is retried with an increasing delay. If it still fails the engine
+//! is marked unavailable and the file gets an unavailable verdict: it is not transfered
+//! but it is not reported as infected. The scans of an unavailable engine are not
+//! retried until one of them succeeds, so that an outage does not slow down every file.
+//! No retry is started after the time budget of the file: an engine failing then
+//! gives a timeout verdict.
+//!
+//! In a worker process the scans are run by the daemon, see [crate::worker]. The daemon
+//! streams the file from its descriptor to the engines, without copying it in memory.
+
+use super::budget::Budget;
+use super::content::read_chunks;
+use super::{Analyzer, Content, Stream};
+use crate::{FileMetadata, worker};
+use keysas_lib::file_report::{AvEngineReport, Verdict};
+use log::{error, info, warn};
+use serde_derive::Deserialize;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+pub mod clamav;
+pub mod icap;
+
+use clamav::{ClamdAddress, ClamdEngine};
+use icap::IcapEngine;
+
+/// Scan session of an engine fed with the content of the file
+pub trait Session {
+    /// Send a chunk of the file, errors are returned by [Session::result]
+    fn send(&mut self, data: &[u8]);
+
+    /// End the session and returns the names of the infections detected
+    fn result(self: Box<Self>) -> io::Result<Vec<String>>;
+}
+
+/// Data scanned by the engines, sent again to the engines whose session failed
+pub trait Source {
+    /// Send the whole data to the session
+    fn send_to(&self, session: &mut dyn Session, budget: &Budget) -> io::Result<()>;
+}
+
+impl Source for [u8] {
+    fn send_to(&self, session: &mut dyn Session, _budget: &Budget) -> io::Result<()> {
+        session.send(self);
+        Ok(())
+    }
+}
+
+impl Source for File {
+    fn send_to(&self, session: &mut dyn Session, budget: &Budget) -> io::Result<()> {
+        read_chunks(self, budget, |chunk| session.send(chunk))
+    }
+}
+
+/// Anti-virus engine
+pub trait Engine: Debug + Send + Sync {
+    /// Name of the engine in the station policy and the report
+    fn name(&self) -> &str;
+
+    /// Returns the version of the engine, used to check it is available
+    fn version(&self) -> io::Result<String>;
+
+    /// Start a scan session
+    fn start(&self) -> Box<dyn Session>;
+}
+
+/// Connect to a TCP address, the timeout applies to the connection and to each exchange
+pub fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid address {addr}"),
+    );
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))?;
+                return Ok(s);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// Number of engines that must detect an infection to reject the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvDecision {
+    /// A detection by any engine rejects the file
+    #[default]
+    Any,
+    /// The file is rejected if at least this number of engines detect an infection
+    Quorum(usize),
+}
+
+/// Anti-virus engine set in the station policy
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum EngineConfig {
+    /// ClamAV daemon listening on IP:PORT
+    ClamavTcp { name: String, address: String },
+    /// ClamAV daemon listening on a unix socket
+    ClamavUnix { name: String, path: String },
+    /// ICAP server listening on IP:PORT, the service is the path of the ICAP URI
+    Icap {
+        name: String,
+        address: String,
+        service: String,
+    },
+}
+
+impl EngineConfig {
+    /// Create the engine, the timeout applies to each exchange with the engine
+    pub fn build(&self, timeout: Duration) -> Box<dyn Engine> {
+        match self {
+            EngineConfig::ClamavTcp { name, address } => Box::new(ClamdEngine::new(
+                name.clone(),
+                ClamdAddress::Tcp(address.clone()),
+                timeout,
+            )),
+            EngineConfig::ClamavUnix { name, path } => Box::new(ClamdEngine::new(
+                name.clone(),
+                ClamdAddress::Unix(path.into()),
+                timeout,
+            )),
+            EngineConfig::Icap {
+                name,
+                address,
+                service,
+            } => Box::new(IcapEngine::new(
+                name.clone(),
+                address.clone(),
+                service.clone(),
+                timeout,
+            )),
+        }
+    }
+}
+
+/// Policy of the anti-virus scan
+///
+/// ```json
+/// "av": {
+///     "decision": { "quorum": 2 },
+///     "engines": [
+///         { "type": "clamav_unix", "name": "clamav", "path": "/run/clamav/clamd.ctl" },
+///         { "type": "icap", "name": "gateway", "address": "192.168.10.2:1344", "service": "avscan" }
+///     ],
+///     "retries": 2,
+///     "retry_delay": 500,
+///     "timeout": 120
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AvPolicy {
+    /// Number of engines that must detect an infection
+    pub decision: AvDecision,
+    /// Engines scanning the files, ClamAV on the command line address if empty
+    pub engines: Vec<EngineConfig>,
+    /// Number of times a failed scan is retried
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds, doubled at each retry
+    pub retry_delay: u64,
+    /// Timeout of the exchanges with the engines in seconds
+    pub timeout: u64,
+}
+
+impl Default for AvPolicy {
+    fn default() -> Self {
+        Self {
+            decision: AvDecision::Any,
+            engines: Vec::new(),
+            retries: 2,
+            retry_delay: 500,
+            timeout: 120,
+        }
+    }
+}
+
+impl AvPolicy {
+    /// Timeout of the exchanges with the engines, at least one second
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.max(1))
+    }
+
+    /// Retries of the failed scans
+    pub fn retry(&self) -> Retry {
+        Retry {
+            attempts: self.retries,
+            delay: Duration::from_millis(self.retry_delay),
+        }
+    }
+}
+
+/// Retries of the failed scans
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Number of retries
+    pub attempts: u32,
+    /// Delay before the first retry, doubled at each retry
+    pub delay: Duration,
+}
+
+/// Engine with its health state
+#[derive(Debug)]
+struct Monitored {
+    engine: Box<dyn Engine>,
+    /// False if the last scan or check of the engine failed
+    available: AtomicBool,
+}
+
+impl Monitored {
+    fn is_available(&self) -> bool {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    /// Update the health state, changes are logged
+    fn set_available(&self, available: bool, reason: &str) {
+        let previous = self.available.swap(available, Ordering::Relaxed);
+        match (previous, available) {
+            (true, false) => error!(
+                "Anti-virus engine {} is unavailable: {reason}",
+                self.engine.name()
+            ),
+            (false, true) => info!(
+                "Anti-virus engine {} is available again",
+                self.engine.name()
+            ),
+            _ => (),
+        }
+    }
+
+    /// Scan the data again after a failure, returns the first result that is not an error
+    /// Retries stop when the time budget of the file would be over before they start
+    fn retry<S>(&self, retry: Retry, data: &S, budget: &Budget) -> io::Result<Vec<String>>
+    where
+        S: Source + ?Sized,
+    {
+        let mut delay = retry.delay;
+        let mut result = Err(io::Error::other("No retry"));
+        for attempt in 1..=retry.attempts {
+            if budget.remaining() <= delay {
+                warn!(
+                    "Anti-virus engine {} not retried, analysis time is over",
+                    self.engine.name()
+                );
+                break;
+            }
+            thread::sleep(delay);
+            delay = delay.saturating_mul(2);
+            let mut session = self.engine.start();
+            result = data
+                .send_to(session.as_mut(), budget)
+                .and_then(|_| session.result());
+            match &result {
+                Ok(_) => break,
+                Err(e) => warn!(
+                    "Anti-virus engine {} failed on retry {attempt}: {e}",
+                    self.engine.name()
+                ),
+            }
+        }
+        result
+    }
+}
+
+/// Set of anti-virus engines with the decision applied to their results
+#[derive(Debug)]
+pub struct Antivirus {
+    engines: Vec<Monitored>,
+    decision: AvDecision,
+    retry: Retry,
+}
+
+impl Antivirus {
+    /// Returns an error if the quorum cannot be reached with the engines
+    pub fn new(
+        engines: Vec<Box<dyn Engine>>,
+        decision: AvDecision,
+        retry: Retry,
+    ) -> anyhow::Result<Self> {
+        if engines.is_empty() {
+            return Err(anyhow::anyhow!("No anti-virus engine"));
+        }
+        match decision {
+            AvDecision::Quorum(n) if n == 0 || n > engines.len() => {
+                return Err(anyhow::anyhow!(
+                    "Invalid quorum {n} for {} anti-virus engines",
+                    engines.len()
+                ));
+            }
+            _ => (),
+        }
+        Ok(Self {
+            engines: engines
+                .into_iter()
+                .map(|engine| Monitored {
+                    engine,
+                    available: AtomicBool::new(true),
+                })
+                .collect(),
+            decision,
+            retry,
+        })
+    }
+
+    /// Check that each engine responds and update its health state
+    pub fn check(&self) {
+        for m in &self.engines {
+            match m.engine.version() {
+                Ok(v) => {
+                    info!("Anti-virus {}: {v}", m.engine.name());
+                    m.set_available(true, "");
+                }
+                Err(e) => m.set_available(false, &e.to_string()),
+            }
+        }
+    }
+
+    /// Names of the engines marked unavailable
+    pub fn unavailable(&self) -> Vec<&str> {
+        self.engines
+            .iter()
+            .filter(|m| !m.is_available())
+            .map(|m| m.engine.name())
+            .collect()
+    }
+
+    /// Start a session on each engine
+    pub fn start(self: &Arc<Self>) -> Scan {
+        Scan {
+            sessions: self.engines.iter().map(|m| m.engine.start()).collect(),
+            av: self.clone(),
+        }
+    }
+
+    /// Scan the data in a single session on each engine
+    pub fn scan(self: &Arc<Self>, data: &[u8], budget: &Budget) -> ScanResult {
+        if let Some(result) = worker::scan(data) {
+            return result;
+        }
+        let mut scan = self.start();
+        scan.send(data);
+        scan.result(data, budget)
+    }
+
+    /// Scan the file read from its descriptor in a single session on each engine
+    /// A file that cannot be read in time is not scanned and gets a timeout verdict
+    pub fn scan_file(self: &Arc<Self>, file: &File, budget: &Budget) -> ScanResult {
+        let mut scan = self.start();
+        let sent = read_chunks(file, budget, |chunk| scan.send(chunk));
+        let mut result = scan.result(file, budget);
+        match sent {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                result.pass = false;
+                result.verdict = budget.timeout();
+            }
+            Err(e) => {
+                error!("Unable to read file to scan: {e}");
+                result.pass = false;
+                result.verdict = Verdict::Reject("Failed to read file".into());
+            }
+        }
+        result
+    }
+}
+
+/// Scan of a file by all the engines
+pub struct Scan {
+    /// Session of each engine, in the order of the engines
+    sessions: Vec<Box<dyn Session>>,
+    av: Arc<Antivirus>,
+}
+
+impl Scan {
+    /// Send a chunk of the file to each engine
+    pub fn send(&mut self, data: &[u8]) {
+        for session in self.sessions.iter_mut() {
+            session.send(data);
+        }
+    }
+
+    /// End the sessions and apply the decision to their results
+    /// The data is scanned again by the available engines whose session failed,
+    /// engines still failing once the time budget is over give a timeout verdict
+    pub fn result<S>(self, data: &S, budget: &Budget) -> ScanResult
+    where
+        S: Source + ?Sized,
+    {
+        let retry = self.av.retry;
+        let reports = self
+            .av
+            .engines
+            .iter()
+            .zip(self.sessions)
+            .map(|(m, session)| {
+                let mut result = session.result();
+                if let Err(e) = &result {
+                    warn!("Anti-virus engine {} failed to scan: {e}", m.engine.name());
+                    if m.is_available() {
+                        result = m.retry(retry, data, budget);
+                    }
+                }
+                let engine = m.engine.name().to_string();
+                match result {
+                    Ok(infections) => {
+                        m.set_available(true, "");
+                        AvEngineReport {
+                            engine,
+                            infections,
+                            error: None,
+                        }
+                    }
+                    Err(e) => {
+                        m.set_available(false, &e.to_string());
+                        AvEngineReport {
+                            engine,
+                            infections: Vec::new(),
+                            error: Some(e.to_string()),
+                        }
+                    }
+                }
+            })
+            .collect();
+        let mut result = ScanResult::new(reports, self.av.decision);
+        if matches!(result.verdict, Verdict::Unavailable(_)) && budget.is_expired() {
+            result.verdict = budget.timeout();
+        }
+        result
+    }
+}
+
+impl Stream for Scan {
+    fn update(&mut self, chunk: &[u8]) {
+        self.send(chunk);
+    }
+
+    fn finish(self: Box<Self>, content: &Content, md: &mut FileMetadata) -> Verdict {
+        self.result(content.data(), &content.budget).record(md)
+    }
+}
+
+/// Results of the engines with the decision applied to them
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ScanResult {
+    /// Result of each engine
+    pub reports: Vec<AvEngineReport>,
+    /// Names of the infections detected by all the engines, without duplicates
+    pub infections: Vec<String>,
+    /// True if the infections detected do not reach the quorum
+    pub pass: bool,
+    /// Verdict of the anti-virus
+    pub verdict: Verdict,
+}
+
+impl ScanResult {
+    /// Apply the decision to the results of the engines
+    /// Infections reaching the quorum reject the file even if some engines are unavailable,
+    /// otherwise an unavailable engine prevents the decision.
+    pub fn new(reports: Vec<AvEngineReport>, decision: AvDecision) -> Self {
+        let mut infections: Vec<String> = Vec::new();
+        for name in reports.iter().flat_map(|r| r.infections.iter()) {
+            if !infections.contains(name) {
+                infections.push(name.clone());
+            }
+        }
+        let failed: Vec<&str> = reports
+            .iter()
+            .filter(|r| r.error.is_some())
+            .map(|r| r.engine.as_str())
+            .collect();
+        let detections = reports.iter().filter(|r| !r.infections.is_empty()).count();
+        let quorum = match decision {
+            AvDecision::Any => 1,
+            AvDecision::Quorum(n) => n,
+        };
+
+        let verdict = if detections >= quorum {
+            Verdict::Reject(infections.join(", "))
+        } else if !failed.is_empty() {
+            Verdict::Unavailable(format!(
+                "Anti-virus engine unavailable: {}",
+                failed.join(", ")
+            ))
+        } else if detections > 0 {
+            warn!("Infection detected by {detections} engines, below the quorum of {quorum}");
+            Verdict::Flag(format!(
+                "Detected by {detections} of {} engines: {}",
+                reports.len(),
+                infections.join(", ")
+            ))
+        } else {
+            Verdict::Pass
+        };
+        Self {
+            pass: detections < quorum,
+            reports,
+            infections,
+            verdict,
+        }
+    }
+
+    /// Record the result in the file metadata
+    pub fn record(self, md: &mut FileMetadata) -> Verdict {
+        md.av_pass = self.pass;
+        md.av_report = self.infections;
+        md.av_engines = self.reports;
+        self.verdict
+    }
+}
+
+/// Scan the file with the anti-virus engines
+#[derive(Debug, Clone)]
+pub struct AvAnalyzer {
+    av: Arc<Antivirus>,
+}
+
+impl AvAnalyzer {
+    pub fn new(av: Arc<Antivirus>) -> Self {
+        Self { av }
+    }
+}
+
+impl Analyzer for AvAnalyzer {
+    fn name(&self) -> &'static str {
+        "av"
+    }
+
+    /// The file is not streamed in a worker, its scan is run by the daemon
+    fn stream(&self) -> Option<Box<dyn Stream>> {
+        match worker::is_worker() {
+            true => None,
+            false => Some(Box::new(self.av.start())),
+        }
+    }
+
+    /// Scan the whole content at once when the file is not streamed
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        worker::scan_file()
+            .unwrap_or_else(|| self.av.scan(content.data(), &content.budget))
+            .record(md)
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.av_pass = true;
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the time and memory budgets of the analysis.
+ */
+
+//! Time and memory budgets of the analysis
+//!
+//! Each file gets a [Budget]: a wall-clock timeout covering all its analyzers and
+//! the access to a [MemoryPool] shared by the files analyzed at once.
+//! The registry stops running analyzers once the timeout is reached, and the
+//! analyzers doing long operations (anti-virus retries, Yara scans, archive
+//! unpacking) check it themselves. The analyzers working in memory reserve the
+//! memory they may use before allocating it, waiting for other files to release
+//! theirs until the timeout. In a worker process the memory is reserved from the pool
+//! of the daemon, see [crate::worker].
+
+use crate::worker;
+use keysas_lib::file_report::Verdict;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Time allowed to analyze a file if not set on the command line, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 300;
+
+/// Memory that can be reserved by the files analyzed at once
+#[derive(Debug)]
+pub struct MemoryPool {
+    /// Maximum memory reserved at once in bytes, 0 for no limit
+    limit: u64,
+    /// Memory currently reserved
+    used: Mutex<u64>,
+    /// Notified when memory is released
+    released: Condvar,
+}
+
+impl Default for MemoryPool {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl MemoryPool {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+}
+
+/// Memory reserved by a file, released when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    /// Pool of the memory, None if reserved by the daemon for a worker
+    pool: Option<Arc<MemoryPool>>,
+    size: u64,
+}
+
+impl Reservation {
+    /// Memory reserved in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        match &self.pool {
+            Some(pool) => {
+                let mut used = pool.used.lock().unwrap_or_else(PoisonError::into_inner);
+                *used -= self.size;
+                pool.released.notify_all();
+            }
+            None => worker::release(self.size),
+        }
+    }
+}
+
+/// Time and memory budget of the analysis of a file
+#[derive(Debug, Clone)]
+pub struct Budget {
+    start: Instant,
+    timeout: Duration,
+    memory: Arc<MemoryPool>,
+}
+
+impl Default for Budget {
+    /// Default timeout without memory limit
+    fn default() -> Self {
+        Self::new(
+            Duration::from_secs(DEFAULT_TIMEOUT),
+            Arc::new(MemoryPool::default()),
+        )
+    }
+}
+
+impl Budget {
+    /// Start the budget of a file
+    pub fn new(timeout: Duration, memory: Arc<MemoryPool>) -> Self {
+        Self {
+            start: Instant::now(),
+            timeout,
+            memory,
+        }
+    }
+
+    /// Time left to analyze the file
+    pub fn remaining(&self) -> Duration {
+        self.timeout.saturating_sub(self.start.elapsed())
+    }
+
+    /// Returns true if the time to analyze the file is over
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// Verdict of a file whose analysis did not complete in time
+    pub fn timeout(&self) -> Verdict {
+        Verdict::Timeout(format!(
+            "Analysis timeout after {} seconds",
+            self.timeout.as_secs()
+        ))
+    }
+
+    /// Reserve memory, waiting until the timeout if the pool is full
+    /// Returns the verdict of the file if the memory cannot be reserved
+    pub fn reserve(&self, size: u64) -> Result<Reservation, Verdict> {
+        if let Some(reserved) = worker::reserve(size) {
+            return reserved.map(|_| Reservation { pool: None, size });
+        }
+        let pool = &self.memory;
+        if pool.limit > 0 && size > pool.limit {
+            return Err(Verdict::Reject(format!(
+                "Analysis needs {size} bytes of memory, over the limit of {} bytes",
+                pool.limit
+            )));
+        }
+        let mut used = pool.used.lock().unwrap_or_else(PoisonError::into_inner);
+        while pool.limit > 0 && *used + size > pool.limit {
+            let remaining = self.remaining();
+            if remaining.is_zero() {
+                return Err(self.timeout());
+            }
+            used = pool
+                .released
+                .wait_timeout(used, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        *used += size;
+        Ok(Reservation {
+            pool: Some(pool.clone()),
+            size,
+        })
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the content of the files
+ * shared by the analyzers.
+ */
+
+//! Content of a file read once for all the analyzers
+//!
+//! The type of the file is detected from its beginning, or from its name for the text formats,
+//! to select its [Profile]. A file bigger than the maximum size of its profile is not copied,
+//! only the analyzers that do not need its content check it. Otherwise the file is then copied in memory reserved from the [Budget] of the file,
+//! with bounded reads: keysas-in keeps the file in sas_in until keysas-out has it, so the file
+//! may be modified or truncated during the analysis, the analyzers and the digest only see this
+//! private copy, which starts with the beginning used to detect its type. The copy is then read in a single pass that computes its SHA-256 digest and
+//! feeds the [Stream] of the analyzers scanning it as a stream (e.g. the ClamAV INSTREAM session).
+//! The analyzers working in memory (type detection, Yara, archives, documents) share the same copy.
+//! The content carries the [Budget] of the file, started before it is read.
+
+use super::budget::{Budget, Reservation};
+use super::text;
+use crate::FileMetadata;
+use crate::profile::{Profile, Profiles};
+use infer::get;
+use keysas_lib::file_report::Verdict;
+use log::error;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+/// Size of the chunks given to the hasher and the streams
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Size of the beginning of the file used to detect its type
+const HEAD_SIZE: u64 = 1024 * 1024;
+
+/// Type of a file detected from the beginning of its content or from its name, empty if unknown
+/// Text formats have no magic number, their type is given by the name of the file
+pub fn detect_type(head: &[u8], name: &str) -> &'static str {
+    get(head)
+        .map(|t| t.extension())
+        .or_else(|| text::detect(name))
+        .unwrap_or_default()
+}
+
+/// Read the file with bounded reads after the data already read, until `size` bytes
+/// or less if it is truncated meanwhile
+fn read_file(file: &File, data: &mut Vec<u8>, size: u64) -> io::Result<()> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    while (data.len() as u64) < size {
+        let len = CHUNK_SIZE.min(usize::try_from(size - data.len() as u64).unwrap_or(CHUNK_SIZE));
+        match file.read_at(&mut chunk[..len], data.len() as u64) {
+            Ok(0) => break,
+            Ok(n) => data.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Read the file by chunks with bounded reads until its end or the end of the time budget
+pub fn read_chunks<F>(file: &File, budget: &Budget, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&[u8]),
+{
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        if budget.is_expired() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        match file.read_at(&mut chunk, offset) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                f(&chunk[..n]);
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Size of the file and type detected from its beginning, with this beginning
+pub fn read_head(file: &File, name: &str) -> io::Result<(u64, Vec<u8>, &'static str)> {
+    let size = file.metadata()?.len();
+    let mut head = Vec::new();
+    read_file(file, &mut head, size.min(HEAD_SIZE))?;
+    let file_type = detect_type(&head, name);
+    Ok((size, head, file_type))
+}
+
+/// Consumer fed with the content of the file during the single read
+pub trait Stream {
+    /// Called with each chunk of the file, in order
+    fn update(&mut self, chunk: &[u8]);
+
+    /// Called once the whole file has been read, with its content
+    /// to retry the analysis if the stream failed.
+    /// The stream records its report in the file metadata and returns
+    /// the verdict of the analyzer.
+    fn finish(self: Box<Self>, content: &Content, md: &mut FileMetadata) -> Verdict;
+}
+
+/// Content of a file shared by the analyzers
+#[derive(Debug)]
+pub struct Content {
+    /// Size of the file
+    pub size: u64,
+    /// SHA-256 digest of the file, computed by [Content::read]
+    pub digest: String,
+    /// Type of the file detected from its magic number or its text format, empty if unknown
+    pub file_type: &'static str,
+    /// Profile of the type of the file
+    pub profile: Profile,
+    /// Time and memory budget of the analysis
+    pub budget: Budget,
+    /// Private copy of the file
+    data: Vec<u8>,
+    /// False if the file is too big for its profile, its copy is then empty
+    copied: bool,
+    /// Memory reserved for the copy
+    _memory: Option<Reservation>,
+}
+
+impl Content {
+    /// Select the profile of the type of the file and copy the file in memory
+    /// if it is not bigger than the maximum size of the profile
+    /// Returns the verdict of the file if it cannot be copied
+    pub fn load(
+        file: &File,
+        name: &str,
+        profiles: &Profiles,
+        budget: Budget,
+    ) -> Result<Self, Verdict> {
+        let failed = |e: io::Error| {
+            error!("Unable to read file {name}: {e}");
+            Verdict::Reject("Failed to read file".into())
+        };
+        // Synchronize the file before reading it
+        if let Err(e) = file.sync_all() {
+            error!("Failed to synchronize file: {e}");
+        }
+        let (size, mut data, file_type) = read_head(file, name).map_err(failed)?;
+        let profile = profiles.get(file_type).clone();
+        if size > profile.max_size {
+            return Ok(Self {
+                size,
+                digest: String::new(),
+                file_type,
+                profile,
+                budget,
+                data: Vec::new(),
+                copied: false,
+                _memory: None,
+            });
+        }
+        let memory = budget.reserve(size)?;
+        read_file(file, &mut data, size).map_err(failed)?;
+        Ok(Self {
+            size: data.len() as u64,
+            digest: String::new(),
+            file_type,
+            profile,
+            budget,
+            data,
+            copied: true,
+            _memory: Some(memory),
+        })
+    }
+
+    /// Returns false if the file is too big for its profile and has not been copied
+    pub fn is_copied(&self) -> bool {
+        self.copied
+    }
+
+    /// Read the file once, feeding the hasher and the streams
+    pub fn read<'a, I>(&mut self, streams: I)
+    where
+        I: IntoIterator<Item = &'a mut Box<dyn Stream>>,
+    {
+        let mut streams: Vec<_> = streams.into_iter().collect();
+        let mut hasher = Sha256::new();
+        for chunk in self.data().chunks(CHUNK_SIZE) {
+            hasher.update(chunk);
+            for s in streams.iter_mut() {
+                s.update(chunk);
+            }
+        }
+        self.digest = format!("{:x}", hasher.finalize());
+    }
+
+    /// Whole content of the file
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// At most the first `len` bytes of the file
+    pub fn prefix(&self, len: u64) -> &[u8] {
+        let data = self.data();
+        &data[..data.len().min(usize::try_from(len).unwrap_or(usize::MAX))]
+    }
+
+    /// Beginning of the file used to detect its type
+    pub fn head(&self) -> &[u8] {
+        self.prefix(HEAD_SIZE)
+    }
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the digest analyzer.
+ */
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use keysas_lib::file_report::Verdict;
+
+/// Check that the digest of the file matches the one computed by keysas-in
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DigestAnalyzer;
+
+impl Analyzer for DigestAnalyzer {
+    fn name(&self) -> &'static str {
+        "digest"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // The SHA256 hash is calculated while the file is read
+        md.is_digest_ok = md.digest.eq(&content.digest);
+        match md.is_digest_ok {
+            true => Verdict::Pass,
+            false => Verdict::Reject("Digest mismatch".into()),
+        }
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.is_digest_ok = true;
+    }
+}
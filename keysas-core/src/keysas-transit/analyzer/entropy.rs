@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the entropy and packed executables analyzer.
+ */
+
+//! Entropy and packed executables heuristics
+//!
+//! The Shannon entropy of the file is computed in bits per byte, for the whole file
+//! and for each block. Compressed or encrypted data are close to 8 bits per byte
+//! while text, documents and machine code stay far below. The following findings are reported:
+//!     - HighEntropy: consecutive blocks over the entropy limit in a type that is not
+//!       expected to hold compressed data, e.g. an encrypted payload appended to an
+//!       executable or hidden in a text file
+//!     - Packer: signature of a known packer in the headers of an executable
+//!       (UPX, MPRESS, ASPack, PECompact, Themida, VMProtect, FSG, Petite, NsPack)
+//!     - Score: the score of the file reaches the threshold of the policy
+//!
+//! The score, from 0 to 100, adds the share of the file in high entropy blocks (70 points)
+//! and the entropy of the whole file (30 points). The types compressed by design
+//! (archives, images, media, PDF and OOXML documents) are exempted: they get no high entropy
+//! finding and a score of 0, only their entropy is reported.
+//!
+//! The handling of each finding, the limits and the exempted types are set by the [EntropyPolicy].
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use keysas_lib::file_report::{Action, EntropyFinding, EntropyFindingKind, EntropyReport, Verdict};
+use log::warn;
+use serde_derive::Deserialize;
+
+/// Blocks shorter than this size are not significant, e.g. the end of the file
+const MIN_BLOCK: u64 = 1024;
+
+/// Size of the beginning of an executable searched for packer signatures,
+/// it holds the headers and the section table
+const PACKER_WINDOW: u64 = 4096;
+
+/// Types of executables searched for packer signatures
+const EXECUTABLES: &[&str] = &["exe", "dll", "elf", "mach"];
+
+/// Signatures of the packers, mostly the names of the sections they add
+const PACKERS: &[(&str, &[&[u8]])] = &[
+    ("UPX", &[b"UPX0\0", b"UPX1\0", b"UPX!"]),
+    ("MPRESS", &[b".MPRESS1", b".MPRESS2"]),
+    ("ASPack", &[b".aspack\0", b".adata\0"]),
+    ("PECompact", &[b"PEC2TO", b"PECompact2"]),
+    ("Themida", &[b".themida", b".winlice"]),
+    ("VMProtect", &[b".vmp0\0", b".vmp1\0"]),
+    ("FSG", &[b"FSG!"]),
+    ("Petite", &[b".petite"]),
+    ("NsPack", &[b".nsp0\0", b".nsp1\0"]),
+];
+
+/// Types compressed by design, exempted if not set by the policy
+const EXEMPT: &[&str] = &[
+    "zip", "gz", "bz2", "xz", "7z", "rar", "zst", "lz", "lz4", "cab", "jpg", "png", "gif", "webp",
+    "avif", "heif", "jxl", "mp4", "m4v", "mkv", "webm", "mov", "avi", "wmv", "mpg", "flv", "mp3",
+    "m4a", "ogg", "flac", "aac", "pdf", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "jar",
+    "apk", "woff", "woff2",
+];
+
+/// Handling of the findings of the entropy analysis and its limits
+/// Missing fields take the built-in default value
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EntropyPolicy {
+    pub high_entropy: Action,
+    pub packer: Action,
+    pub score: Action,
+    /// Score from 0 to 100 from which the score action is applied
+    pub threshold: u32,
+    /// Entropy limit of a block in bits per byte
+    pub block_entropy: f64,
+    /// Size of the blocks in bytes
+    pub block_size: u64,
+    /// Types expected to have a high entropy, replaces the built-in list
+    pub exempt: Vec<String>,
+}
+
+impl Default for EntropyPolicy {
+    fn default() -> Self {
+        Self {
+            high_entropy: Action::Flag,
+            packer: Action::Reject,
+            score: Action::Reject,
+            threshold: 80,
+            block_entropy: 7.2,
+            block_size: 64 * 1024,
+            exempt: EXEMPT.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+impl EntropyPolicy {
+    fn action(&self, kind: EntropyFindingKind) -> Action {
+        match kind {
+            EntropyFindingKind::HighEntropy => self.high_entropy,
+            EntropyFindingKind::Packer => self.packer,
+            EntropyFindingKind::Score => self.score,
+        }
+    }
+}
+
+/// Number of occurrences of each byte value
+fn histogram(data: &[u8]) -> [u64; 256] {
+    let mut counts = [0u64; 256];
+    for b in data {
+        counts[*b as usize] += 1;
+    }
+    counts
+}
+
+/// Shannon entropy in bits per byte of the data whose histogram is given
+fn shannon(counts: &[u64; 256]) -> f64 {
+    let len: u64 = counts.iter().sum();
+    if len == 0 {
+        return 0.0;
+    }
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Search the packer signatures in the headers of an executable
+/// Returns the packers found with the offset and the signature matched
+fn packers(head: &[u8]) -> Vec<(&'static str, usize, &'static [u8])> {
+    PACKERS
+        .iter()
+        .filter_map(|(packer, signatures)| {
+            signatures.iter().find_map(|s| {
+                head.windows(s.len())
+                    .position(|w| w == *s)
+                    .map(|offset| (*packer, offset, *s))
+            })
+        })
+        .collect()
+}
+
+/// Compute the entropy of the files and search packed or encrypted contents
+#[derive(Debug, Clone)]
+pub struct EntropyAnalyzer {
+    /// Handling of the findings and limits
+    policy: EntropyPolicy,
+}
+
+impl EntropyAnalyzer {
+    pub fn new(mut policy: EntropyPolicy) -> Self {
+        policy.block_size = policy.block_size.max(MIN_BLOCK);
+        Self { policy }
+    }
+
+    fn finding(
+        &self,
+        kind: EntropyFindingKind,
+        offset: u64,
+        length: u64,
+        detail: String,
+    ) -> EntropyFinding {
+        EntropyFinding {
+            kind,
+            offset,
+            length,
+            detail,
+            action: self.policy.action(kind),
+        }
+    }
+}
+
+impl Analyzer for EntropyAnalyzer {
+    fn name(&self) -> &'static str {
+        "entropy"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        let exempt = self.policy.exempt.iter().any(|t| t == content.file_type);
+        let mut report = EntropyReport {
+            block_size: self.policy.block_size,
+            threshold: self.policy.threshold,
+            ..EntropyReport::default()
+        };
+        let mut total = [0u64; 256];
+        // Region of consecutive high entropy blocks: offset, length and highest entropy
+        let mut region: Option<(u64, u64, f64)> = None;
+        let mut regions = Vec::new();
+        let mut high_bytes = 0;
+        let mut offset = 0;
+        for block in content.data().chunks(self.policy.block_size as usize) {
+            if content.budget.is_expired() {
+                return content.budget.timeout();
+            }
+            let counts = histogram(block);
+            for (t, c) in total.iter_mut().zip(counts) {
+                *t += c;
+            }
+            let len = block.len() as u64;
+            let entropy = shannon(&counts);
+            if len >= MIN_BLOCK {
+                report.max_block_entropy = report.max_block_entropy.max(entropy);
+            }
+            let high = len >= MIN_BLOCK && entropy >= self.policy.block_entropy;
+            if high {
+                report.high_blocks += 1;
+                high_bytes += len;
+            }
+            region = match (region, high) {
+                (Some((start, length, max)), true) => Some((start, length + len, max.max(entropy))),
+                (None, true) => Some((offset, len, entropy)),
+                (Some(r), false) => {
+                    regions.push(r);
+                    None
+                }
+                (None, false) => None,
+            };
+            offset += len;
+        }
+        regions.extend(region);
+        report.entropy = shannon(&total);
+
+        if !exempt {
+            for (offset, length, max) in regions {
+                report.findings.push(self.finding(
+                    EntropyFindingKind::HighEntropy,
+                    offset,
+                    length,
+                    format!("Entropy up to {max:.2} bits per byte over {length} bytes"),
+                ));
+            }
+            let share = match content.size {
+                0 => 0.0,
+                size => high_bytes as f64 / size as f64,
+            };
+            report.score = (share * 70.0 + report.entropy / 8.0 * 30.0).round() as u32;
+            if report.score >= report.threshold {
+                report.findings.push(self.finding(
+                    EntropyFindingKind::Score,
+                    0,
+                    content.size,
+                    format!(
+                        "Entropy score {} over the threshold of {}",
+                        report.score, report.threshold
+                    ),
+                ));
+            }
+        }
+        if EXECUTABLES.contains(&content.file_type) {
+            for (packer, offset, signature) in packers(content.prefix(PACKER_WINDOW)) {
+                report.findings.push(self.finding(
+                    EntropyFindingKind::Packer,
+                    offset as u64,
+                    signature.len() as u64,
+                    format!(
+                        "{packer} packer signature \"{}\"",
+                        signature.strip_suffix(b"\0").unwrap_or(signature).escape_ascii()
+                    ),
+                ));
+            }
+        }
+
+        for f in &report.findings {
+            warn!("File {}: {}", md.filename, f.detail);
+        }
+        let verdict = findings_verdict(
+            report.findings.iter().map(|f| (f.kind, f.action)),
+            "Packed or encrypted content",
+        );
+        md.entropy = Some(report);
+        verdict
+    }
+}
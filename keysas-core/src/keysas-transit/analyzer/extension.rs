@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the file extension analyzer.
+ */
+
+//! Comparison of the file name with the type detected from the content
+//!
+//! The following mismatches are detected:
+//!     - Mismatch: the last extension does not match the detected type,
+//!       e.g. a report.docx that is a plain zip archive
+//!     - DoubleExtension: a decoy extension is placed before an executable one,
+//!       e.g. invoice.pdf.exe or invoice.pdf      .exe
+//!     - BidiControl: the name contains unicode bidirectional control characters,
+//!       e.g. invoice\u{202E}fdp.exe displayed as invoiceexe.pdf
+//!     - Missing: the name has no extension but the type is detected
+//!
+//! The extensions of a detected type are the one given by infer and its aliases,
+//! e.g. jpeg for jpg or docm for docx. Aliases can be added in the [ExtensionPolicy].
+//! Files whose type cannot be detected (e.g. text files) are only checked for
+//! double extensions and bidirectional control characters.
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use infer::get;
+use keysas_lib::file_report::{Action, ExtensionFinding, ExtensionFindingKind, Verdict};
+use log::warn;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Extensions accepted for a detected type in addition to the one given by infer
+const ALIASES: &[(&str, &[&str])] = &[
+    ("jpg", &["jpeg", "jpe", "jfif"]),
+    ("tif", &["tiff"]),
+    ("gz", &["tgz", "gzip"]),
+    ("mpg", &["mpeg"]),
+    ("html", &["htm"]),
+    ("doc", &["dot"]),
+    ("xls", &["xlt"]),
+    ("ppt", &["pps", "pot"]),
+    ("docx", &["docm", "dotx", "dotm"]),
+    ("xlsx", &["xlsm", "xltx", "xltm"]),
+    ("pptx", &["pptm", "potx", "potm", "ppsx", "ppsm"]),
+];
+
+/// Extensions whose combination is legitimate, e.g. archive.tar.gz
+const COMPOUND: &[(&str, &str)] = &[
+    ("tar", "gz"),
+    ("tar", "bz2"),
+    ("tar", "xz"),
+    ("tar", "zst"),
+    ("tar", "lz"),
+    ("tar", "lz4"),
+];
+
+/// Extensions of documents and media used as decoys
+const DECOYS: &[&str] = &[
+    "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "odt", "ods", "odp", "rtf", "txt", "csv",
+    "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "mp3", "mp4", "avi", "wav", "zip",
+];
+
+/// Extensions of executables and scripts
+const EXECUTABLES: &[&str] = &[
+    "exe", "scr", "com", "pif", "bat", "cmd", "msi", "dll", "cpl", "lnk", "hta", "jar", "js",
+    "jse", "vbs", "vbe", "wsf", "wsh", "ps1", "sh", "elf", "bin", "run", "app", "apk", "deb",
+    "rpm",
+];
+
+/// Unicode bidirectional control characters
+const BIDI_CONTROLS: &[char] = &[
+    '\u{200E}', '\u{200F}', '\u{202A}', '\u{202B}', '\u{202C}', '\u{202D}', '\u{202E}', '\u{2066}',
+    '\u{2067}', '\u{2068}', '\u{2069}',
+];
+
+/// Handling of the mismatches between the file name and the detected type
+/// Missing fields take the built-in default value
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtensionPolicy {
+    pub mismatch: Action,
+    pub double_extension: Action,
+    pub bidi_control: Action,
+    pub missing: Action,
+    /// Extensions accepted for a detected type in addition to the built-in ones,
+    /// e.g. {"zip": ["kdbx"]}
+    pub aliases: HashMap<String, Vec<String>>,
+}
+
+impl Default for ExtensionPolicy {
+    fn default() -> Self {
+        Self {
+            mismatch: Action::Reject,
+            double_extension: Action::Reject,
+            bidi_control: Action::Reject,
+            missing: Action::Allow,
+            aliases: HashMap::new(),
+        }
+    }
+}
+
+impl ExtensionPolicy {
+    fn action(&self, kind: ExtensionFindingKind) -> Action {
+        match kind {
+            ExtensionFindingKind::Mismatch => self.mismatch,
+            ExtensionFindingKind::DoubleExtension => self.double_extension,
+            ExtensionFindingKind::BidiControl => self.bidi_control,
+            ExtensionFindingKind::Missing => self.missing,
+        }
+    }
+
+    /// Returns true if the extension is accepted for the detected type
+    fn is_accepted(&self, declared: &str, detected: &str) -> bool {
+        declared == detected
+            || ALIASES
+                .iter()
+                .any(|(t, a)| *t == detected && a.contains(&declared))
+            || self
+                .aliases
+                .get(detected)
+                .is_some_and(|a| a.iter().any(|e| e.eq_ignore_ascii_case(declared)))
+    }
+}
+
+/// Compare the file name with the type detected from the content
+#[derive(Debug, Clone)]
+pub struct ExtensionAnalyzer {
+    /// Handling of the mismatches
+    policy: ExtensionPolicy,
+}
+
+impl ExtensionAnalyzer {
+    pub fn new(policy: ExtensionPolicy) -> Self {
+        Self { policy }
+    }
+
+    /// Compare the file name with the detected type, `detected` is empty if the type is unknown
+    fn check(&self, filename: &str, detected: &str) -> Vec<ExtensionFinding> {
+        let name = Path::new(filename)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // Leading dots of hidden files are not extensions
+        let mut parts = name.trim_start_matches('.').split('.');
+        parts.next();
+        let extensions: Vec<String> = parts.map(|e| e.trim().to_lowercase()).collect();
+        let declared = extensions.last().cloned().unwrap_or_default();
+
+        let mut found = Vec::new();
+        let mut push = |kind, detail: String| {
+            found.push(ExtensionFinding {
+                kind,
+                declared: declared.clone(),
+                detected: detected.to_string(),
+                detail,
+                action: self.policy.action(kind),
+            })
+        };
+        if name.contains(BIDI_CONTROLS) {
+            push(
+                ExtensionFindingKind::BidiControl,
+                "Name contains unicode bidirectional control characters".into(),
+            );
+        }
+        // The decoy is either followed by an executable extension
+        // or it is the real type hidden behind another extension
+        match extensions.as_slice() {
+            [.., decoy, last]
+                if decoy != last
+                    && !COMPOUND.contains(&(decoy.as_str(), last.as_str()))
+                    && DECOYS.contains(&decoy.as_str())
+                    && (EXECUTABLES.contains(&last.as_str())
+                        || (!detected.is_empty() && self.policy.is_accepted(decoy, detected))) =>
+            {
+                push(
+                    ExtensionFindingKind::DoubleExtension,
+                    format!("Decoy extension .{decoy} before .{last}"),
+                )
+            }
+            _ => (),
+        }
+        if !detected.is_empty() {
+            if declared.is_empty() {
+                push(
+                    ExtensionFindingKind::Missing,
+                    format!("No extension for a {detected} file"),
+                );
+            } else if !self.policy.is_accepted(&declared, detected) {
+                push(
+                    ExtensionFindingKind::Mismatch,
+                    format!("Extension .{declared} for a {detected} file"),
+                );
+            }
+        }
+        found
+    }
+}
+
+impl Analyzer for ExtensionAnalyzer {
+    fn name(&self) -> &'static str {
+        "extension"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Only 1Mo of the file is used like in the magic analyzer
+        let detected = get(content.head())
+            .map(|t| t.extension())
+            .unwrap_or_default();
+        md.extension_findings = self.check(&md.filename, detected);
+        for f in &md.extension_findings {
+            warn!("File {}: {}", md.filename, f.detail);
+        }
+        findings_verdict(
+            md.extension_findings.iter().map(|f| (f.kind, f.action)),
+            "File name does not match its type",
+        )
+    }
+}
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the hash lists analyzer.
+ */
+
+//! Check of the file digest against the allow and deny lists of the administrator
+//!
+//! The lists are loaded at startup, see [HashList] for their format.
+//! A file in the deny list is rejected whatever the verdicts of the other analyzers.
+//! A file in the allow list, and not in the deny list, is fast-tracked:
+//! the analyzers registered after this one are bypassed.
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use keysas_lib::file_report::{HashListKind, HashListMatch, Verdict};
+use keysas_lib::hash_list::HashList;
+use log::{info, warn};
+
+/// Check the file digest against the allow and deny lists
+#[derive(Debug, Clone)]
+pub struct HashListAnalyzer {
+    /// Digests of the files always rejected
+    deny: HashList,
+    /// Digests of the files fast-tracked
+    allow: HashList,
+}
+
+impl HashListAnalyzer {
+    pub fn new(deny: HashList, allow: HashList) -> Self {
+        Self { deny, allow }
+    }
+}
+
+impl Analyzer for HashListAnalyzer {
+    fn name(&self) -> &'static str {
+        "hashlist"
+    }
+
+    /// A profile cannot disable the deny list
+    fn always_runs(&self) -> bool {
+        true
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // The deny list is checked first so that it overrides the allow list
+        for (kind, list) in [
+            (HashListKind::Deny, &self.deny),
+            (HashListKind::Allow, &self.allow),
+        ] {
+            if let Some(label) = list.get(&content.digest) {
+                md.hash_list = Some(HashListMatch {
+                    list: kind,
+                    digest: content.digest.clone(),
+                    label: label.to_string(),
+                });
+                return match kind {
+                    HashListKind::Deny => {
+                        warn!("File {} is in the deny list: {label}", md.filename);
+                        Verdict::Reject(format!("File is in the deny list: {label}"))
+                    }
+                    HashListKind::Allow => {
+                        info!("File {} is in the allow list: {label}", md.filename);
+                        Verdict::Pass
+                    }
+                };
+            }
+        }
+        Verdict::Pass
+    }
+
+    fn is_conclusive(&self, md: &FileMetadata) -> bool {
+        md.hash_list
+            .as_ref()
+            .is_some_and(|m| m.list == HashListKind::Allow)
+    }
+}
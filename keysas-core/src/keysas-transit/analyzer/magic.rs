@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the magic number analyzer.
+ */
+
+use super::{Analyzer, Content, text};
+use crate::FileMetadata;
+use crate::policy::verdict;
+use infer::get;
+use keysas_lib::file_report::{Action, Verdict};
+
+/// Apply the action of the profile of the file type
+#[derive(Debug, Clone, Copy)]
+pub struct MagicAnalyzer;
+
+/// This function returns the file type detected from the buffer
+pub fn get_extension(buf: &[u8]) -> String {
+    match get(buf) {
+        Some(info) => info.to_string(),
+        None => "".into(),
+    }
+}
+
+impl Analyzer for MagicAnalyzer {
+    fn name(&self) -> &'static str {
+        "magic"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Only 1Mo of the file is used to be faster on large files
+        md.file_type = match get_extension(content.head()) {
+            // Text formats are detected from the file name
+            t if t.is_empty() => text::mime_type(content.file_type)
+                .unwrap_or_default()
+                .to_string(),
+            t => t,
+        };
+        md.is_type_allowed = content.profile.action != Action::Reject;
+        let reason = match content.profile.action {
+            Action::Flag => format!("File type {} is flagged", md.file_type),
+            _ => format!("File type {} is not allowed", md.file_type),
+        };
+        verdict(content.profile.action, reason)
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.is_type_allowed = true;
+    }
+}
@@ -0,0 +1,863 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the embedded metadata analyzer.
+ */
+
+//! Extraction of the metadata embedded in images, office and PDF documents
+//!
+//! The metadata that can disclose information on the people, the places and
+//! the devices involved with a file are recorded in the report:
+//!     - images (jpg, png, tif, webp): EXIF tags (GPS position, artist, camera make,
+//!       model and serial number, dates), XMP packets and PNG text chunks
+//!     - OOXML documents (docx, xlsx, pptx): core and application properties
+//!       (author, last modified by, revision, company, template), printer settings,
+//!       authors of tracked changes and comments
+//!     - OLE2 documents (doc, xls, ppt): summary information property sets
+//!     - PDF documents: Info dictionary, XMP packet and incremental updates
+//!
+//! Each metadata is classified as a [MetadataField] whose handling is decided
+//! by the [MetadataPolicy], all of them are allowed by default.
+
+use super::office::{MAX_RATIO, attribute, read_limited};
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use anyhow::Result;
+use keysas_lib::file_report::{Action, MetadataField, MetadataFinding, Verdict};
+use log::warn;
+use lopdf::{Document, decode_text_string};
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use time::OffsetDateTime;
+
+/// Maximum length of a value recorded in the report
+const MAX_VALUE: usize = 256;
+
+/// Maximum number of entries read in a TIFF directory
+const MAX_IFD_ENTRIES: usize = 512;
+
+/// Tags of the TIFF directories: IFD0 and EXIF sub-directory
+const TIFF_TAGS: &[(u16, &str, MetadataField)] = &[
+    (0x010E, "ImageDescription", MetadataField::Description),
+    (0x010F, "Make", MetadataField::Device),
+    (0x0110, "Model", MetadataField::Device),
+    (0x0131, "Software", MetadataField::Software),
+    (0x0132, "DateTime", MetadataField::Date),
+    (0x013B, "Artist", MetadataField::Author),
+    (0x8298, "Copyright", MetadataField::Author),
+    (0x9003, "DateTimeOriginal", MetadataField::Date),
+    (0x9286, "UserComment", MetadataField::Description),
+    (0x9C9B, "XPTitle", MetadataField::Description),
+    (0x9C9C, "XPComment", MetadataField::Description),
+    (0x9C9D, "XPAuthor", MetadataField::Author),
+    (0xA430, "CameraOwnerName", MetadataField::Author),
+    (0xA431, "BodySerialNumber", MetadataField::Device),
+    (0xA435, "LensSerialNumber", MetadataField::Device),
+];
+
+/// Pointers to the EXIF and GPS sub-directories
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+
+/// Keywords of the PNG text chunks
+const PNG_KEYWORDS: &[(&str, MetadataField)] = &[
+    ("Author", MetadataField::Author),
+    ("Copyright", MetadataField::Author),
+    ("Software", MetadataField::Software),
+    ("Source", MetadataField::Device),
+    ("Creation Time", MetadataField::Date),
+    ("Title", MetadataField::Description),
+    ("Description", MetadataField::Description),
+    ("Comment", MetadataField::Description),
+];
+
+/// Keyword of the PNG text chunk holding an XMP packet
+const PNG_XMP: &str = "XML:com.adobe.xmp";
+
+/// Properties of the XMP packets and of the OOXML property parts
+const XML_PROPERTIES: &[(&str, MetadataField)] = &[
+    // XMP
+    ("dc:creator", MetadataField::Author),
+    ("dc:rights", MetadataField::Author),
+    ("dc:title", MetadataField::Description),
+    ("dc:description", MetadataField::Description),
+    ("xmp:CreatorTool", MetadataField::Software),
+    ("pdf:Producer", MetadataField::Software),
+    ("xmp:CreateDate", MetadataField::Date),
+    ("xmp:ModifyDate", MetadataField::Date),
+    ("exif:GPSLatitude", MetadataField::Gps),
+    ("exif:GPSLongitude", MetadataField::Gps),
+    ("tiff:Make", MetadataField::Device),
+    ("tiff:Model", MetadataField::Device),
+    ("aux:SerialNumber", MetadataField::Device),
+    // OOXML core properties
+    ("cp:lastModifiedBy", MetadataField::LastModifiedBy),
+    ("cp:revision", MetadataField::Revision),
+    ("cp:lastPrinted", MetadataField::Date),
+    ("cp:keywords", MetadataField::Description),
+    ("dc:subject", MetadataField::Description),
+    ("dcterms:created", MetadataField::Date),
+    ("dcterms:modified", MetadataField::Date),
+    // OOXML application properties
+    ("Company", MetadataField::Company),
+    ("Manager", MetadataField::Author),
+    ("Application", MetadataField::Software),
+    ("Template", MetadataField::Template),
+];
+
+/// Elements of the XMP packets listing the revision history
+const XMP_HISTORY: &str = "xmpMM:History";
+
+/// Elements of Word documents recording a tracked change
+const TRACKED_CHANGES: &[&str] = &["w:ins", "w:del", "w:moveFrom", "w:moveTo"];
+
+/// Properties of an OLE2 property set: identifier, name and field
+type PropertySet = &'static [(u32, &'static str, MetadataField)];
+
+/// Properties of the OLE2 summary information streams
+const OLE_PROPERTIES: &[(&str, PropertySet)] = &[
+    (
+        "\u{5}SummaryInformation",
+        &[
+            (2, "Title", MetadataField::Description),
+            (3, "Subject", MetadataField::Description),
+            (4, "Author", MetadataField::Author),
+            (5, "Keywords", MetadataField::Description),
+            (6, "Comments", MetadataField::Description),
+            (7, "Template", MetadataField::Template),
+            (8, "LastAuthor", MetadataField::LastModifiedBy),
+            (9, "RevNumber", MetadataField::Revision),
+            (11, "LastPrinted", MetadataField::Date),
+            (12, "CreateTime", MetadataField::Date),
+            (13, "LastSaveTime", MetadataField::Date),
+            (18, "AppName", MetadataField::Software),
+        ],
+    ),
+    (
+        "\u{5}DocumentSummaryInformation",
+        &[
+            (14, "Manager", MetadataField::Author),
+            (15, "Company", MetadataField::Company),
+        ],
+    ),
+];
+
+/// Entries of the PDF Info dictionary
+const PDF_INFO: &[(&str, MetadataField)] = &[
+    ("Author", MetadataField::Author),
+    ("Creator", MetadataField::Software),
+    ("Producer", MetadataField::Software),
+    ("Title", MetadataField::Description),
+    ("Subject", MetadataField::Description),
+    ("Keywords", MetadataField::Description),
+    ("CreationDate", MetadataField::Date),
+    ("ModDate", MetadataField::Date),
+];
+
+/// Handling of the metadata embedded in the files
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataPolicy {
+    /// Action applied to the fields not listed in `fields`
+    pub default: Action,
+    /// Action applied to a field, e.g. {"gps": "reject"}
+    pub fields: HashMap<MetadataField, Action>,
+}
+
+impl Default for MetadataPolicy {
+    fn default() -> Self {
+        Self {
+            default: Action::Allow,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl MetadataPolicy {
+    fn action(&self, field: MetadataField) -> Action {
+        *self.fields.get(&field).unwrap_or(&self.default)
+    }
+}
+
+/// Metadata found in a file before the policy is applied
+struct Found {
+    field: MetadataField,
+    source: String,
+    name: String,
+    value: String,
+}
+
+/// Metadata found in a file, empty values and duplicates are skipped
+#[derive(Default)]
+struct Collector {
+    found: Vec<Found>,
+}
+
+impl Collector {
+    fn push(&mut self, field: MetadataField, source: &str, name: &str, value: &str) {
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if value.is_empty() {
+            return;
+        }
+        let value: String = value.chars().take(MAX_VALUE).collect();
+        if self
+            .found
+            .iter()
+            .any(|f| f.field == field && f.name == name && f.value == value)
+        {
+            return;
+        }
+        self.found.push(Found {
+            field,
+            source: source.to_string(),
+            name: name.to_string(),
+            value,
+        });
+    }
+}
+
+/// Decode Latin-1 text
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|b| *b as char).collect()
+}
+
+/// Decode UTF-16LE text up to the first NUL character
+fn utf16le(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|u| *u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Entry of a TIFF directory
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: usize,
+    /// Offset of the value in the TIFF data
+    value: usize,
+}
+
+/// TIFF structure holding the EXIF tags
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Self {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    /// Entries of the directory at the offset
+    fn ifd(&self, offset: usize) -> Vec<IfdEntry> {
+        let count = self.u16(offset).unwrap_or(0) as usize;
+        (0..count.min(MAX_IFD_ENTRIES))
+            .map_while(|i| {
+                let entry = offset + 2 + i * 12;
+                let kind = self.u16(entry + 2)?;
+                let count = self.u32(entry + 4)? as usize;
+                let size = match kind {
+                    3 | 8 => 2,
+                    4 | 9 | 11 => 4,
+                    5 | 10 | 12 => 8,
+                    _ => 1,
+                };
+                // Values of 4 bytes or less are stored in the entry
+                let value = match count.saturating_mul(size) {
+                    0..=4 => entry + 8,
+                    _ => self.u32(entry + 8)? as usize,
+                };
+                Some(IfdEntry {
+                    tag: self.u16(entry)?,
+                    kind,
+                    count,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Raw bytes of the value of an entry
+    fn bytes(&self, entry: &IfdEntry) -> &'a [u8] {
+        let end = entry.value.saturating_add(entry.count).min(self.data.len());
+        self.data.get(entry.value..end).unwrap_or_default()
+    }
+
+    /// Text value of an entry
+    fn text(&self, entry: &IfdEntry) -> String {
+        let bytes = self.bytes(entry);
+        match (entry.tag, entry.kind) {
+            // Windows tags are UTF-16LE encoded bytes
+            (0x9C9B..=0x9C9F, _) => utf16le(bytes),
+            // The user comment starts with the code of its encoding
+            (0x9286, _) => match bytes.split_at_checked(8) {
+                Some((b"UNICODE\0", text)) => utf16le(text),
+                Some((_, text)) => latin1(text),
+                None => String::new(),
+            },
+            // ASCII values end with a NUL character
+            (_, 2) => {
+                let text = bytes.split(|b| *b == 0).next().unwrap_or_default();
+                String::from_utf8_lossy(text).to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Rational values of an entry
+    fn rationals(&self, entry: &IfdEntry) -> Vec<f64> {
+        (0..entry.count.min(3))
+            .filter_map(|i| {
+                let numerator = self.u32(entry.value + i * 8)?;
+                let denominator = self.u32(entry.value + i * 8 + 4)?;
+                (denominator != 0).then(|| numerator as f64 / denominator as f64)
+            })
+            .collect()
+    }
+
+    /// Record the tags of IFD0 and of the EXIF and GPS sub-directories
+    fn inspect(&self, source: &str, found: &mut Collector) {
+        let Some(ifd0) = self.u32(4) else {
+            return;
+        };
+        let ifd0 = self.ifd(ifd0 as usize);
+        let pointer = |tag| {
+            ifd0.iter()
+                .find(|e| e.tag == tag)
+                .and_then(|e| self.u32(e.value))
+                .map(|o| self.ifd(o as usize))
+                .unwrap_or_default()
+        };
+        let exif = pointer(EXIF_IFD);
+        let gps = pointer(GPS_IFD);
+        for entry in ifd0.iter().chain(&exif) {
+            if let Some((_, name, field)) = TIFF_TAGS.iter().find(|(t, _, _)| *t == entry.tag) {
+                found.push(*field, source, name, &self.text(entry));
+            }
+        }
+        if gps.is_empty() {
+            return;
+        }
+        // Latitude and longitude are given as degrees, minutes and seconds with their reference
+        let coordinate = |reference: u16, value: u16| {
+            let reference = gps.iter().find(|e| e.tag == reference)?;
+            let value = gps.iter().find(|e| e.tag == value)?;
+            let degrees = match self.rationals(value).as_slice() {
+                [d, m, s] => d + m / 60.0 + s / 3600.0,
+                _ => return None,
+            };
+            Some(format!("{degrees:.6} {}", self.text(reference)))
+        };
+        let value = match (coordinate(1, 2), coordinate(3, 4)) {
+            (Some(latitude), Some(longitude)) => format!("{latitude}, {longitude}"),
+            _ => format!("{} GPS tags", gps.len()),
+        };
+        found.push(MetadataField::Gps, source, "GPSInfo", &value);
+    }
+}
+
+/// Record the properties given as attributes, e.g. of rdf:Description
+fn xml_attributes(source: &str, e: &BytesStart, found: &mut Collector) {
+    for a in e.attributes().flatten() {
+        let key = String::from_utf8_lossy(a.key.as_ref()).to_string();
+        if let Some((_, field)) = XML_PROPERTIES.iter().find(|(p, _)| *p == key) {
+            found.push(
+                *field,
+                source,
+                &key,
+                &a.unescape_value().unwrap_or_default(),
+            );
+        }
+    }
+}
+
+/// Record the properties of an XMP packet or of an OOXML property part
+fn inspect_xml(source: &str, xml: &[u8], found: &mut Collector) -> Result<()> {
+    let mut reader = Reader::from_reader(xml);
+    // Names of the elements enclosing the current event
+    let mut path: Vec<String> = Vec::new();
+    let mut history = 0;
+    loop {
+        match reader.read_event()? {
+            event @ (Event::Start(_) | Event::Empty(_)) => {
+                let (Event::Start(e) | Event::Empty(e)) = &event else {
+                    continue;
+                };
+                xml_attributes(source, e, found);
+                let name = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                if name == "rdf:li" && path.iter().any(|p| p == XMP_HISTORY) {
+                    history += 1;
+                }
+                if matches!(event, Event::Start(_)) {
+                    path.push(name);
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(t) => {
+                // The property is the closest element that is not an RDF container
+                let property = path.iter().rev().find(|p| !p.starts_with("rdf:"));
+                if let Some((name, field)) =
+                    property.and_then(|p| XML_PROPERTIES.iter().find(|(n, _)| n == p))
+                {
+                    found.push(*field, source, name, &t.unescape().unwrap_or_default());
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    if history > 0 {
+        let events = format!("{history} history events");
+        found.push(MetadataField::Revision, source, XMP_HISTORY, &events);
+    }
+    Ok(())
+}
+
+/// Record the EXIF tags, the XMP packet and the comments of a JPEG image
+fn inspect_jpeg(data: &[u8], found: &mut Collector) {
+    let mut i = 2;
+    while let Some(&[0xFF, marker, high, low]) = data.get(i..i + 4) {
+        // Markers without length, the next one is not read
+        if marker == 0xFF || matches!(marker, 0x01 | 0xD0..=0xD7) {
+            i += if marker == 0xFF { 1 } else { 2 };
+            continue;
+        }
+        // The metadata are before the image data
+        if matches!(marker, 0xD9 | 0xDA) {
+            break;
+        }
+        let end = i + 2 + u16::from_be_bytes([high, low]) as usize;
+        let Some(segment) = data.get(i + 4..end) else {
+            break;
+        };
+        let exif = segment.strip_prefix(b"Exif\0\0").and_then(Tiff::new);
+        let xmp = segment.strip_prefix(b"http://ns.adobe.com/xap/1.0/\0");
+        match (marker, exif, xmp) {
+            (0xE1, Some(tiff), _) => tiff.inspect("EXIF", found),
+            (0xE1, None, Some(xmp)) => {
+                if let Err(e) = inspect_xml("XMP", xmp, found) {
+                    warn!("Invalid XMP packet: {e}");
+                }
+            }
+            (0xFE, _, _) => found.push(
+                MetadataField::Description,
+                "JPEG",
+                "Comment",
+                &latin1(segment),
+            ),
+            _ => (),
+        }
+        i = end;
+    }
+}
+
+/// Record the EXIF tags, the XMP packet and the text chunks of a PNG image
+fn inspect_png(data: &[u8], found: &mut Collector) {
+    let mut i = 8;
+    while let Some(header) = data.get(i..i + 8) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let Some(chunk) = data.get(i + 8..(i + 8).saturating_add(len)) else {
+            break;
+        };
+        match &header[4..] {
+            b"eXIf" => {
+                if let Some(tiff) = Tiff::new(chunk) {
+                    tiff.inspect("EXIF", found);
+                }
+            }
+            // Latin-1 text: keyword, NUL, text
+            b"tEXt" => {
+                let mut parts = chunk.splitn(2, |b| *b == 0);
+                if let (Some(keyword), Some(text)) = (parts.next(), parts.next()) {
+                    png_text(&latin1(keyword), &latin1(text), found);
+                }
+            }
+            // UTF-8 text: keyword, NUL, compression flag and method, language, NUL,
+            // translated keyword, NUL, text
+            b"iTXt" => {
+                let mut parts = chunk.splitn(2, |b| *b == 0);
+                let keyword = latin1(parts.next().unwrap_or_default());
+                let rest = parts.next().unwrap_or_default();
+                if let Some((&[0, _], rest)) = rest.split_at_checked(2) {
+                    let text = rest.splitn(3, |b| *b == 0).nth(2).unwrap_or_default();
+                    match keyword.as_str() {
+                        PNG_XMP => {
+                            if let Err(e) = inspect_xml("XMP", text, found) {
+                                warn!("Invalid XMP packet: {e}");
+                            }
+                        }
+                        _ => png_text(&keyword, &String::from_utf8_lossy(text), found),
+                    }
+                }
+            }
+            b"IEND" => break,
+            _ => (),
+        }
+        // Length, type, data and CRC
+        i += 12 + len;
+    }
+}
+
+/// Record a PNG text chunk whose keyword is known
+fn png_text(keyword: &str, text: &str, found: &mut Collector) {
+    if let Some((_, field)) = PNG_KEYWORDS.iter().find(|(k, _)| *k == keyword) {
+        found.push(*field, "PNG", keyword, text);
+    }
+}
+
+/// Record the EXIF tags and the XMP packet of a WebP image
+fn inspect_webp(data: &[u8], found: &mut Collector) {
+    let mut i = 12;
+    while let Some(header) = data.get(i..i + 8) {
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let Some(chunk) = data.get(i + 8..(i + 8).saturating_add(len)) else {
+            break;
+        };
+        match &header[..4] {
+            b"EXIF" => {
+                let tiff = chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk);
+                if let Some(tiff) = Tiff::new(tiff) {
+                    tiff.inspect("EXIF", found);
+                }
+            }
+            b"XMP " => {
+                if let Err(e) = inspect_xml("XMP", chunk, found) {
+                    warn!("Invalid XMP packet: {e}");
+                }
+            }
+            _ => (),
+        }
+        // Chunks are padded to an even size
+        i += 8 + len + len % 2;
+    }
+}
+
+/// Record the printer, authors of tracked changes and of comments of an OOXML part
+fn inspect_ooxml_part(name: &str, xml: &[u8], found: &mut Collector) -> Result<()> {
+    let mut reader = Reader::from_reader(xml);
+    let mut comment_author = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => {
+                let element = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                let (field, key) = match element.as_str() {
+                    n if TRACKED_CHANGES.contains(&n) => (MetadataField::Revision, "author"),
+                    "w:comment" => (MetadataField::Author, "author"),
+                    "p:cmAuthor" => (MetadataField::Author, "name"),
+                    // Authors of the comments of a workbook are listed in elements
+                    "author" => {
+                        comment_author = true;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if let Some(value) = attribute(&e, key.as_bytes()) {
+                    found.push(field, name, &element, &value);
+                }
+            }
+            Event::Text(t) if comment_author => {
+                found.push(
+                    MetadataField::Author,
+                    name,
+                    "author",
+                    &t.unescape().unwrap_or_default(),
+                );
+            }
+            Event::End(_) => comment_author = false,
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Record the metadata of an OOXML document
+fn inspect_ooxml(data: &[u8], max_size: u64, found: &mut Collector) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut budget = max_size;
+    for i in 0..archive.len() {
+        let mut part = archive.by_index(i)?;
+        if part.is_dir() {
+            continue;
+        }
+        let name = part.name().to_string();
+        let lower = name.to_ascii_lowercase();
+        if lower == "docprops/core.xml" || lower == "docprops/app.xml" {
+            let content = read_limited(&mut part, &mut budget)?;
+            inspect_xml(&name, &content, found)?;
+        } else if lower.contains("/printersettings/") {
+            // DEVMODE structure starting with the name of the printer
+            let mut device = [0u8; 64];
+            let len = part.read(&mut device)?;
+            found.push(
+                MetadataField::Printer,
+                &name,
+                "dmDeviceName",
+                &utf16le(&device[..len]),
+            );
+        } else if lower == "word/document.xml"
+            || lower == "word/comments.xml"
+            || lower == "ppt/commentauthors.xml"
+            || (lower.starts_with("xl/comments") && lower.ends_with(".xml"))
+        {
+            let content = read_limited(&mut part, &mut budget)?;
+            inspect_ooxml_part(&name, &content, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Value of a property of an OLE2 property set
+fn ole_property(data: &[u8], offset: usize) -> Option<String> {
+    let u32_at = |o: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            data.get(o..o.checked_add(4)?)?.try_into().ok()?,
+        ))
+    };
+    match u32_at(offset)? & 0xFFFF {
+        // VT_I2 and VT_I4
+        2 => Some((u32_at(offset + 4)? & 0xFFFF).to_string()),
+        3 => Some(u32_at(offset + 4)?.to_string()),
+        // VT_LPSTR: length in bytes then text in the code page of the set
+        0x1E => {
+            let len = u32_at(offset + 4)? as usize;
+            let text = data.get(offset + 8..(offset + 8).checked_add(len)?)?;
+            Some(latin1(text.split(|b| *b == 0).next().unwrap_or_default()))
+        }
+        // VT_LPWSTR: length in characters then UTF-16LE text
+        0x1F => {
+            let len = u32_at(offset + 4)? as usize;
+            let text = data.get(offset + 8..(offset + 8).checked_add(len.checked_mul(2)?)?)?;
+            Some(utf16le(text))
+        }
+        // VT_FILETIME: 100 ns intervals since 1601
+        0x40 => {
+            let low = u32_at(offset + 4)? as i64;
+            let high = u32_at(offset + 8)? as i64;
+            let seconds = ((high << 32) | low) / 10_000_000 - 11_644_473_600;
+            let date = OffsetDateTime::from_unix_timestamp(seconds).ok()?;
+            // Durations, e.g. the editing time, are stored as dates before 1602
+            (date.year() > 1601).then(|| format!("{} {}", date.date(), date.time()))
+        }
+        _ => None,
+    }
+}
+
+/// Record the properties of an OLE2 property set stream
+fn inspect_property_set(stream: &str, data: &[u8], properties: PropertySet, found: &mut Collector) {
+    let u32_at = |o: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(data.get(o..o.checked_add(4)?)?.try_into().ok()?) as usize)
+    };
+    // Header then the identifier and the offset of the first section
+    let Some(section) = u32_at(44) else {
+        return;
+    };
+    let count = u32_at(section + 4).unwrap_or(0).min(MAX_IFD_ENTRIES);
+    for i in 0..count {
+        let (Some(id), Some(offset)) = (u32_at(section + 8 + i * 8), u32_at(section + 12 + i * 8))
+        else {
+            break;
+        };
+        let property = properties.iter().find(|(p, _, _)| *p as usize == id);
+        let value = ole_property(data, section.saturating_add(offset));
+        if let (Some((_, name, field)), Some(value)) = (property, value) {
+            found.push(*field, stream, name, &value);
+        }
+    }
+}
+
+/// Record the summary information of an OLE2 document
+fn inspect_ole(data: &[u8], max_size: u64, found: &mut Collector) -> Result<()> {
+    let mut comp = cfb::CompoundFile::open(Cursor::new(data))?;
+    let mut budget = max_size;
+    for (stream, properties) in OLE_PROPERTIES {
+        let path = format!("/{stream}");
+        if !comp.is_stream(&path) {
+            continue;
+        }
+        let content = read_limited(&mut comp.open_stream(&path)?, &mut budget)?;
+        inspect_property_set(&stream[1..], &content, properties, found);
+    }
+    Ok(())
+}
+
+/// Record the Info dictionary, the XMP packet and the incremental updates of a PDF document
+fn inspect_pdf(data: &[u8], found: &mut Collector) -> Result<()> {
+    // Each incremental update ends with its own end of file marker
+    let updates = data.windows(5).filter(|w| w == b"%%EOF").count();
+    if updates > 1 {
+        let value = format!("{} incremental updates", updates - 1);
+        found.push(MetadataField::Revision, "trailer", "%%EOF", &value);
+    }
+    let doc = Document::load_mem(data)?;
+    if let Ok(info) = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|i| doc.dereference(i))
+        .and_then(|(_, i)| i.as_dict())
+    {
+        for (key, value) in info.iter() {
+            let key = String::from_utf8_lossy(key).to_string();
+            let field = PDF_INFO
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map_or(MetadataField::Description, |(_, f)| *f);
+            let value = doc
+                .dereference(value)
+                .ok()
+                .and_then(|(_, v)| decode_text_string(v).ok())
+                .unwrap_or_default();
+            found.push(field, "Info", &key, &value);
+        }
+    }
+    let xmp = doc
+        .catalog()
+        .and_then(|c| c.get(b"Metadata"))
+        .and_then(|m| doc.dereference(m))
+        .and_then(|(_, m)| m.as_stream());
+    if let Ok(stream) = xmp {
+        let content = match stream.decompressed_content() {
+            Ok(c) => c,
+            Err(_) => stream.content.clone(),
+        };
+        inspect_xml("XMP", &content, found)?;
+    }
+    Ok(())
+}
+
+/// Extract the metadata embedded in images, office and PDF documents
+#[derive(Debug, Clone)]
+pub struct MetadataAnalyzer {
+    /// Handling of the metadata
+    policy: MetadataPolicy,
+}
+
+impl MetadataAnalyzer {
+    pub fn new(policy: MetadataPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl Analyzer for MetadataAnalyzer {
+    fn name(&self) -> &'static str {
+        "metadata"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Files whose type is not allowed are not opened
+        if !md.is_type_allowed {
+            return Verdict::Pass;
+        }
+        let data = content.prefix(content.profile.max_size);
+        let mut found = Collector::default();
+        let result = match content.file_type {
+            "jpg" => {
+                inspect_jpeg(data, &mut found);
+                Ok(())
+            }
+            "png" => {
+                inspect_png(data, &mut found);
+                Ok(())
+            }
+            "webp" => {
+                inspect_webp(data, &mut found);
+                Ok(())
+            }
+            "tif" => {
+                if let Some(tiff) = Tiff::new(data) {
+                    tiff.inspect("EXIF", &mut found);
+                }
+                Ok(())
+            }
+            format @ ("docx" | "xlsx" | "pptx" | "doc" | "xls" | "ppt" | "pdf") => {
+                // The parts read are limited like in the office analyzer,
+                // the parsed PDF objects take about as much memory as the document
+                let limit = match format {
+                    "pdf" => data.len() as u64,
+                    _ => (data.len() as u64)
+                        .saturating_mul(MAX_RATIO)
+                        .min(content.profile.max_size),
+                };
+                let _memory = match content.budget.reserve(limit) {
+                    Ok(r) => r,
+                    Err(verdict) => return verdict,
+                };
+                match format {
+                    "pdf" => inspect_pdf(data, &mut found),
+                    "doc" | "xls" | "ppt" => inspect_ole(data, limit, &mut found),
+                    _ => inspect_ooxml(data, limit, &mut found),
+                }
+            }
+            _ => return Verdict::Pass,
+        };
+        // The metadata found before a parsing error are reported, the structure
+        // of the document is checked by the office and pdf analyzers
+        if let Err(e) = result {
+            warn!("Cannot read all the metadata of {}: {e}", md.filename);
+        }
+
+        // Apply the policy
+        md.metadata_findings = found
+            .found
+            .into_iter()
+            .map(|f| MetadataFinding {
+                action: self.policy.action(f.field),
+                field: f.field,
+                source: f.source,
+                name: f.name,
+                value: f.value,
+            })
+            .collect();
+        findings_verdict(
+            md.metadata_findings
+                .iter()
+                .filter(|f| f.action != Action::Allow)
+                .map(|f| (f.field, f.action)),
+            "Metadata denied by the policy",
+        )
+    }
+}
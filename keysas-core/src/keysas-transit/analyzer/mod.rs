@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the analyzer interface and
+ * the registry running the analyzers on each file.
+ */
+
+//! Analyzers run by keysas-transit on each file
+//!
+//! Each check performed on a file is implemented by an [Analyzer].
+//! Analyzers are stored in a [Registry] which runs the enabled ones
+//! in their registration order and records their [Verdict] in the file metadata.
+//! The profile of the file type can restrict the analyzers run on the file.
+//! The file is read only once, see [Content].
+//! The analysis of a file has a time budget covering all the analyzers: once it
+//! is over the remaining analyzers are not run and the file gets a timeout verdict,
+//! see [Budget].
+//! The registry of the daemon runs each file in a sandboxed worker process, see [crate::worker].
+//!
+//! Built-in analyzers are:
+//!     - digest: file digest is correct
+//!     - hashlist: file digest is not in the deny list, files in the allow list bypass the next analyzers
+//!     - size: file size is less than the maximum size of its type
+//!     - av: anti-virus check by one or several engines
+//!     - yara: yara rules check
+//!     - magic: file type is allowed, flagged or rejected by its profile
+//!     - extension: file name matches the file type
+//!     - archive: entries of archives pass the type, anti-virus and yara checks
+//!     - office: office documents do not contain active contents denied by the policy
+//!     - pdf: PDF documents do not contain active contents or anomalies denied by the policy
+//!     - metadata: metadata embedded in images, office and PDF documents are reported, those denied by the policy reject the file
+//!     - text: text, CSV, XML and JSON files are valid text, well-formed and without formulas denied by the policy
+//!     - entropy: files are not packed executables and do not hide compressed or encrypted data
+
+use crate::profile::{Profile, Profiles};
+use crate::{FileMetadata, worker};
+use anyhow::{Result, anyhow};
+use av::Antivirus;
+use budget::{Budget, MemoryPool};
+use content::read_head;
+use keysas_lib::file_report::{AnalyzerVerdict, Verdict};
+use log::error;
+use std::fs::File;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod archive;
+pub mod av;
+pub mod budget;
+mod content;
+pub mod digest;
+pub mod entropy;
+pub mod extension;
+pub mod hashlist;
+pub mod magic;
+pub mod metadata;
+pub mod office;
+pub mod pdf;
+pub mod size;
+pub mod text;
+pub mod yara;
+
+pub use content::{Content, Stream, read_chunks};
+
+/// Interface implemented by each check run on the files
+/// Analyzers are shared by the workers analyzing several files at once
+pub trait Analyzer: Send + Sync {
+    /// Name of the analyzer, used in the configuration and in the report
+    fn name(&self) -> &'static str;
+
+    /// Returns the stream fed with the content of the file during its single read.
+    /// Analyzers scanning the whole file as a stream return one, its verdict
+    /// replaces the one of [Analyzer::analyze].
+    fn stream(&self) -> Option<Box<dyn Stream>> {
+        None
+    }
+
+    /// Returns true if the analyzer is run on the files of all the types when it is enabled,
+    /// even if their profile does not list it
+    fn always_runs(&self) -> bool {
+        false
+    }
+
+    /// Returns false if the analyzer does not read the content of the file, it then
+    /// checks the files too big for their profile, which are not copied.
+    fn needs_content(&self) -> bool {
+        true
+    }
+
+    /// Run the check on the content of the file.
+    /// The analyzer records its detailed report in the file metadata
+    /// and returns its verdict.
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict;
+
+    /// Called after [Analyzer::analyze], returns true if its verdict is conclusive:
+    /// the analyzers registered after this one are then bypassed.
+    fn is_conclusive(&self, _md: &FileMetadata) -> bool {
+        false
+    }
+
+    /// Called instead of [Analyzer::analyze] when the analyzer is disabled,
+    /// not run for the file type or bypassed after a conclusive verdict.
+    /// It must mark the check as passed in the file metadata.
+    fn bypass(&self, _md: &mut FileMetadata) {}
+}
+
+/// Analyzer registered with its activation status
+struct Entry {
+    analyzer: Box<dyn Analyzer>,
+    enabled: bool,
+}
+
+impl Entry {
+    /// Returns true if the analyzer is run on a file of the profile, whatever the verdicts
+    /// of the other analyzers. `copied` is false if the file is too big to be copied.
+    fn runs(&self, profile: &Profile, copied: bool) -> bool {
+        self.enabled
+            && (self.analyzer.always_runs() || profile.runs(self.analyzer.name()))
+            && (copied || !self.analyzer.needs_content())
+    }
+}
+
+/// List of the analyzers known by the daemon
+pub struct Registry {
+    entries: Vec<Entry>,
+    /// Profiles of the file types
+    profiles: Arc<Profiles>,
+    /// Time allowed to analyze a file
+    timeout: Duration,
+    /// Memory shared by the files analyzed at once
+    memory: Arc<MemoryPool>,
+    /// Zygote forking the workers and anti-virus run by the daemon for them,
+    /// None to analyze the files in the daemon
+    isolation: Option<(Arc<worker::Zygote>, Arc<Antivirus>)>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(Arc::new(Profiles::default()))
+    }
+}
+
+impl Registry {
+    /// Registry with the default time budget and no memory limit
+    pub fn new(profiles: Arc<Profiles>) -> Self {
+        Self {
+            entries: Vec::new(),
+            profiles,
+            timeout: Duration::from_secs(budget::DEFAULT_TIMEOUT),
+            memory: Arc::new(MemoryPool::default()),
+            isolation: None,
+        }
+    }
+
+    /// Set the time allowed to analyze a file and the memory shared by the files
+    pub fn set_budget(&mut self, timeout: Duration, memory: Arc<MemoryPool>) {
+        self.timeout = timeout;
+        self.memory = memory;
+    }
+
+    /// Analyze each file in a sandboxed worker process forked by the zygote,
+    /// the anti-virus scans of the workers are run by the daemon
+    pub fn isolate(&mut self, zygote: Arc<worker::Zygote>, av: Arc<Antivirus>) {
+        self.isolation = Some((zygote, av));
+    }
+
+    /// Start the budget of a file
+    pub fn budget(&self) -> Budget {
+        Budget::new(self.timeout, self.memory.clone())
+    }
+
+    /// Add an analyzer to the registry, it is enabled by default
+    pub fn register(&mut self, analyzer: Box<dyn Analyzer>) {
+        self.entries.push(Entry {
+            analyzer,
+            enabled: true,
+        });
+    }
+
+    /// Enable only the analyzers whose name is in the list provided
+    /// Returns an error if a name does not match any registered analyzer, if the list is empty
+    /// or if a profile names an analyzer that is unknown or not in the list
+    pub fn configure(&mut self, enabled: &[String]) -> Result<()> {
+        if enabled.is_empty() {
+            return Err(anyhow!("No analyzer enabled"));
+        }
+        for name in enabled {
+            if !self.entries.iter().any(|e| e.analyzer.name() == name) {
+                return Err(anyhow!("Unknown analyzer: {name}"));
+            }
+        }
+        let known: Vec<&str> = self.entries.iter().map(|e| e.analyzer.name()).collect();
+        self.profiles.check_analyzers(&known, enabled)?;
+        for entry in self.entries.iter_mut() {
+            entry.enabled = enabled.iter().any(|n| n == entry.analyzer.name());
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the enabled analyzers
+    pub fn enabled(&self) -> Vec<&'static str> {
+        self.entries
+            .iter()
+            .filter(|e| e.enabled)
+            .map(|e| e.analyzer.name())
+            .collect()
+    }
+
+    /// Returns true if the analyzer is run on the file according to the profile of its type,
+    /// whatever the verdicts of the other analyzers. Used by the daemon to check the
+    /// analysis of a worker, a file that cannot be read requires the enabled analyzers.
+    pub fn requires(&self, analyzer: &str, file: &File, name: &str) -> bool {
+        let Some(entry) = self.entries.iter().find(|e| e.analyzer.name() == analyzer) else {
+            return false;
+        };
+        match read_head(file, name) {
+            Ok((size, _, file_type)) => {
+                let profile = self.profiles.get(file_type);
+                entry.runs(profile, size <= profile.max_size)
+            }
+            Err(_) => entry.enabled,
+        }
+    }
+
+    /// Analyze the file in a worker process if the registry is isolated, in the daemon otherwise
+    pub fn run(&self, file: &File, md: &mut FileMetadata) {
+        match &self.isolation {
+            Some((zygote, av)) => worker::run(self, zygote, av, file, md),
+            None => self.analyze(file, md),
+        }
+    }
+
+    /// Run the enabled analyzers selected by the profile of the file type
+    /// and record their verdicts
+    /// Once the time budget is over the next analyzer gets a timeout verdict
+    /// and the others are bypassed, as after a conclusive verdict
+    pub fn analyze(&self, file: &File, md: &mut FileMetadata) {
+        let budget = self.budget();
+        let mut content = Content::load(file, &md.filename, &self.profiles, budget.clone());
+        // Analyzers not run for the type, or needing the content of a file too big to be copied,
+        // are bypassed, the enabled ones reject a file that cannot be read
+        let runs: Vec<bool> = self
+            .entries
+            .iter()
+            .map(|e| match &content {
+                Ok(c) => e.runs(&c.profile, c.is_copied()),
+                Err(_) => e.enabled,
+            })
+            .collect();
+        // Streams are started before the file is read
+        let mut streams: Vec<Option<Box<dyn Stream>>> = self
+            .entries
+            .iter()
+            .zip(&runs)
+            .map(|(e, run)| run.then(|| e.analyzer.stream()).flatten())
+            .collect();
+        if let Ok(c) = &mut content {
+            c.read(streams.iter_mut().flatten());
+        }
+        let mut conclusive = false;
+        for ((entry, stream), run) in self.entries.iter().zip(streams).zip(runs) {
+            if !run || conclusive {
+                entry.analyzer.bypass(md);
+                continue;
+            }
+            let verdict = match (&content, stream) {
+                (Ok(_), _) if budget.is_expired() => budget.timeout(),
+                (Ok(c), Some(s)) => s.finish(c, md),
+                (Ok(c), None) => entry.analyzer.analyze(c, md),
+                (Err(verdict), _) => verdict.clone(),
+            };
+            // A timeout stops the analysis
+            let timeout = matches!(verdict, Verdict::Timeout(_));
+            if timeout {
+                error!(
+                    "Analysis of file {} timed out in analyzer {}",
+                    md.filename,
+                    entry.analyzer.name()
+                );
+            }
+            md.verdicts.push(AnalyzerVerdict {
+                analyzer: entry.analyzer.name().to_string(),
+                verdict,
+            });
+            conclusive = content.is_ok() && (timeout || entry.analyzer.is_conclusive(md));
+        }
+    }
+}
@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the office documents analyzer.
+ */
+
+//! Detection of active contents in office documents
+//!
+//! OOXML documents (docx, xlsx, pptx) are opened as zip archives:
+//!     - VBA macros are stored in a vbaProject.bin part
+//!     - OLE objects are stored in the embeddings and activeX parts
+//!     - External links are relationships with an External target mode
+//!       and INCLUDETEXT or INCLUDEPICTURE fields
+//!     - DDE are DDE or DDEAUTO fields and ddeLink elements
+//!
+//! Legacy OLE2 documents (doc, xls, ppt) are opened as compound files:
+//!     - VBA macros are stored in VBA storages
+//!     - OLE objects are stored in the ObjectPool storage (Word) or in MBD storages (Excel)
+//!     - External links and DDE are searched in the field codes of the WordDocument stream
+//!
+//! The parts read are limited to the maximum size of the document type and to 100 times
+//! the document size, the memory they need is reserved from the budget of the file.
+//!
+//! The handling of each finding is decided by the [OfficePolicy] for the document format.
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use anyhow::{Result, anyhow};
+use infer::get;
+use keysas_lib::file_report::{Action, OfficeFinding, OfficeFindingKind, Verdict};
+use log::warn;
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+/// Office formats handled by the analyzer
+const OOXML_FORMATS: [&str; 3] = ["docx", "xlsx", "pptx"];
+const OLE_FORMATS: [&str; 3] = ["doc", "xls", "ppt"];
+
+/// Maximum length of a field instruction recorded in the report
+const MAX_FIELD_LEN: usize = 128;
+
+/// Maximum ratio between the size of the parts read and the document size
+pub(super) const MAX_RATIO: u64 = 100;
+
+/// Handling of each kind of active content
+/// Missing fields take the built-in default value
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfficeRules {
+    /// VBA macros
+    pub macros: Action,
+    /// Embedded OLE objects
+    pub ole_objects: Action,
+    /// External relationships and links
+    pub external_links: Action,
+    /// DDE fields
+    pub dde: Action,
+}
+
+impl Default for OfficeRules {
+    fn default() -> Self {
+        Self {
+            macros: Action::Reject,
+            ole_objects: Action::Reject,
+            external_links: Action::Flag,
+            dde: Action::Reject,
+        }
+    }
+}
+
+impl OfficeRules {
+    fn action(&self, kind: OfficeFindingKind) -> Action {
+        match kind {
+            OfficeFindingKind::Macro => self.macros,
+            OfficeFindingKind::OleObject => self.ole_objects,
+            OfficeFindingKind::ExternalLink => self.external_links,
+            OfficeFindingKind::Dde => self.dde,
+        }
+    }
+}
+
+/// Handling of active contents in office documents
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfficePolicy {
+    /// Rules applied to the formats not listed in `formats`
+    pub default: OfficeRules,
+    /// Rules for a given format, e.g. "docx"
+    pub formats: HashMap<String, OfficeRules>,
+}
+
+impl OfficePolicy {
+    fn rules(&self, format: &str) -> &OfficeRules {
+        self.formats.get(format).unwrap_or(&self.default)
+    }
+}
+
+/// Active content found in a document before the policy is applied
+struct Found {
+    kind: OfficeFindingKind,
+    location: String,
+    detail: String,
+}
+
+/// Search active contents in office documents
+#[derive(Debug, Clone)]
+pub struct OfficeAnalyzer {
+    /// Handling of the findings
+    policy: OfficePolicy,
+}
+
+impl OfficeAnalyzer {
+    pub fn new(policy: OfficePolicy) -> Self {
+        Self { policy }
+    }
+}
+
+/// Read at most `budget` bytes, returns an error if there is more to read
+pub(super) fn read_limited(reader: &mut dyn Read, budget: &mut u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(*budget + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > *budget {
+        return Err(anyhow!("Document is too big once unpacked"));
+    }
+    *budget -= data.len() as u64;
+    Ok(data)
+}
+
+/// Returns the value of an attribute from its local name
+pub(super) fn attribute(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name)
+        .and_then(|a| a.unescape_value().ok().map(|v| v.to_string()))
+}
+
+/// Classify a field instruction
+fn check_field(instr: &str) -> Option<OfficeFindingKind> {
+    let instr = instr.trim_start().to_ascii_uppercase();
+    if instr.starts_with("DDE") {
+        Some(OfficeFindingKind::Dde)
+    } else if instr.starts_with("INCLUDETEXT") || instr.starts_with("INCLUDEPICTURE") {
+        Some(OfficeFindingKind::ExternalLink)
+    } else {
+        None
+    }
+}
+
+/// Truncate a field instruction for the report
+fn field_detail(instr: &str) -> String {
+    instr.trim().chars().take(MAX_FIELD_LEN).collect()
+}
+
+/// Search the external relationships of an OOXML relationships part
+fn find_external_relationships(part: &str, content: &[u8], found: &mut Vec<Found>) -> Result<()> {
+    let mut reader = Reader::from_reader(content);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Relationship" => {
+                if attribute(&e, b"TargetMode").is_some_and(|m| m.eq_ignore_ascii_case("External"))
+                {
+                    let target = attribute(&e, b"Target").unwrap_or_default();
+                    let rel_type = attribute(&e, b"Type").unwrap_or_default();
+                    let rel_type = rel_type.rsplit('/').next().unwrap_or_default();
+                    found.push(Found {
+                        kind: OfficeFindingKind::ExternalLink,
+                        location: part.to_string(),
+                        detail: format!("{rel_type}: {target}"),
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Search the DDE and external link fields of an OOXML part
+fn find_fields(part: &str, content: &[u8], found: &mut Vec<Found>) -> Result<()> {
+    let mut reader = Reader::from_reader(content);
+    let mut buf = Vec::new();
+    // Instruction of the complex field being parsed
+    let mut field = String::new();
+    let mut in_instr = false;
+    let push = |instr: &str, found: &mut Vec<Found>| {
+        if let Some(kind) = check_field(instr) {
+            found.push(Found {
+                kind,
+                location: part.to_string(),
+                detail: field_detail(instr),
+            });
+        }
+    };
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) if e.local_name().as_ref() == b"instrText" => in_instr = true,
+            Event::End(e) if e.local_name().as_ref() == b"instrText" => in_instr = false,
+            Event::Text(t) if in_instr => field.push_str(&t.unescape()?),
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"fldChar" => {
+                    if attribute(&e, b"fldCharType").as_deref() != Some("begin") {
+                        push(&field, found);
+                    }
+                    field.clear();
+                }
+                b"fldSimple" => {
+                    if let Some(instr) = attribute(&e, b"instr") {
+                        push(&instr, found);
+                    }
+                }
+                b"ddeLink" => found.push(Found {
+                    kind: OfficeFindingKind::Dde,
+                    location: part.to_string(),
+                    detail: format!(
+                        "{} {}",
+                        attribute(&e, b"ddeService").unwrap_or_default(),
+                        attribute(&e, b"ddeTopic").unwrap_or_default()
+                    ),
+                }),
+                _ => (),
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+/// Search active contents in an OOXML document
+fn inspect_ooxml(data: &[u8], max_size: u64) -> Result<Vec<Found>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut budget = max_size;
+    let mut found = Vec::new();
+    for i in 0..archive.len() {
+        let mut part = archive.by_index(i)?;
+        if part.is_dir() {
+            continue;
+        }
+        let name = part.name().to_string();
+        let lower = name.to_ascii_lowercase();
+        if lower.ends_with("vbaproject.bin") {
+            found.push(Found {
+                kind: OfficeFindingKind::Macro,
+                location: name,
+                detail: "VBA project".into(),
+            });
+        } else if lower.contains("/embeddings/")
+            || (lower.contains("/activex/") && lower.ends_with(".bin"))
+        {
+            found.push(Found {
+                kind: OfficeFindingKind::OleObject,
+                location: name,
+                detail: format!("{} bytes", part.size()),
+            });
+        } else if lower.ends_with(".rels") {
+            let content = read_limited(&mut part, &mut budget)?;
+            find_external_relationships(&name, &content, &mut found)?;
+        } else if lower.ends_with(".xml") {
+            let content = read_limited(&mut part, &mut budget)?;
+            find_fields(&name, &content, &mut found)?;
+        }
+    }
+    Ok(found)
+}
+
+/// Search the field instructions in the text of a Word binary document
+/// Fields start with the 0x13 character, the text is either 8 bits or UTF-16LE encoded
+fn find_binary_fields(location: &str, content: &[u8], found: &mut Vec<Found>) {
+    for (i, _) in content.iter().enumerate().filter(|(_, b)| **b == 0x13) {
+        // 8 bits encoding
+        let narrow: String = content[i + 1..]
+            .iter()
+            .take(MAX_FIELD_LEN)
+            .take_while(|b| b.is_ascii() && **b >= 0x20)
+            .map(|b| *b as char)
+            .collect();
+        // UTF-16LE encoding
+        let wide: String = content[i + 1..]
+            .chunks_exact(2)
+            .skip(1)
+            .take(MAX_FIELD_LEN)
+            .take_while(|c| c[1] == 0 && c[0].is_ascii() && c[0] >= 0x20)
+            .map(|c| c[0] as char)
+            .collect();
+        let instr = match content.get(i + 1) {
+            Some(0) => wide,
+            _ => narrow,
+        };
+        if let Some(kind) = check_field(&instr) {
+            found.push(Found {
+                kind,
+                location: location.to_string(),
+                detail: field_detail(&instr),
+            });
+        }
+    }
+}
+
+/// Search active contents in an OLE2 document
+fn inspect_ole(data: &[u8], max_size: u64) -> Result<Vec<Found>> {
+    let mut comp = cfb::CompoundFile::open(Cursor::new(data))?;
+    let mut found = Vec::new();
+    for entry in comp.walk() {
+        let path = entry.path().to_string_lossy().to_string();
+        let parent = entry
+            .path()
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if entry.is_storage() && entry.name().eq_ignore_ascii_case("VBA") {
+            found.push(Found {
+                kind: OfficeFindingKind::Macro,
+                location: path,
+                detail: "VBA project".into(),
+            });
+        } else if entry.is_storage() && (parent == "ObjectPool" || entry.name().starts_with("MBD"))
+        {
+            found.push(Found {
+                kind: OfficeFindingKind::OleObject,
+                location: path,
+                detail: "Embedded object".into(),
+            });
+        }
+    }
+    if comp.is_stream("/WordDocument") {
+        let mut budget = max_size;
+        let content = read_limited(&mut comp.open_stream("/WordDocument")?, &mut budget)?;
+        find_binary_fields("/WordDocument", &content, &mut found);
+    }
+    Ok(found)
+}
+
+impl Analyzer for OfficeAnalyzer {
+    fn name(&self) -> &'static str {
+        "office"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Documents whose type is not allowed are not opened
+        if !md.is_type_allowed {
+            return Verdict::Pass;
+        }
+        // Detect the format from the beginning of the file
+        let format = match get(content.prefix(8192)) {
+            Some(info)
+                if OOXML_FORMATS.contains(&info.extension())
+                    || OLE_FORMATS.contains(&info.extension()) =>
+            {
+                info.extension()
+            }
+            _ => return Verdict::Pass,
+        };
+        // The document once unpacked is limited to the maximum size of its type
+        // and to the maximum ratio, this memory is reserved before reading its parts
+        let max_size = content.profile.max_size;
+        let data = content.prefix(max_size.saturating_add(1));
+        let limit = (data.len() as u64).saturating_mul(MAX_RATIO).min(max_size);
+        let _memory = match content.budget.reserve(limit) {
+            Ok(r) => r,
+            Err(verdict) => return verdict,
+        };
+        let found = match OOXML_FORMATS.contains(&format) {
+            true => inspect_ooxml(data, limit),
+            false => inspect_ole(data, limit),
+        };
+        let found = match found {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Failed to parse document {}: {e}", md.filename);
+                return Verdict::Reject(format!("Failed to parse {format} document"));
+            }
+        };
+
+        // Apply the policy
+        let rules = self.policy.rules(format);
+        md.office_findings = found
+            .into_iter()
+            .map(|f| OfficeFinding {
+                action: rules.action(f.kind),
+                kind: f.kind,
+                location: f.location,
+                detail: f.detail,
+            })
+            .collect();
+        findings_verdict(
+            md.office_findings.iter().map(|f| (f.kind, f.action)),
+            "Active content found in document",
+        )
+    }
+}
@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the PDF documents analyzer.
+ */
+
+//! Structural analysis of PDF documents
+//!
+//! The document is parsed and each object of the tree, nested dictionaries included,
+//! is searched for:
+//!     - JavaScript actions and name trees
+//!     - OpenAction and additional actions (AA) run on document or page events
+//!     - Launch actions
+//!     - Embedded files
+//!     - XFA forms
+//!     - Encryption of the document or of streams (Crypt filter)
+//!
+//! The cross reference table is malformed if it cannot be parsed or if it points
+//! to objects that cannot be read. In this case the names of the raw document
+//! are also searched so that the active contents hidden in unreadable objects are found.
+//!
+//! The handling of each finding is decided by the [PdfPolicy].
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use infer::get;
+use keysas_lib::file_report::{Action, PdfFinding, PdfFindingKind, Verdict};
+use log::warn;
+use lopdf::xref::XrefEntry;
+use lopdf::{Dictionary, Document, Object, ObjectId, decode_text_string};
+use serde_derive::Deserialize;
+
+/// Maximum nesting level of the direct objects searched
+const MAX_NESTING: usize = 32;
+
+/// Handling of each kind of finding in PDF documents
+/// Missing fields take the built-in default value
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PdfPolicy {
+    pub javascript: Action,
+    pub open_action: Action,
+    pub launch: Action,
+    pub embedded_files: Action,
+    pub xfa: Action,
+    pub encryption: Action,
+    pub malformed_xref: Action,
+}
+
+impl Default for PdfPolicy {
+    fn default() -> Self {
+        Self {
+            javascript: Action::Reject,
+            open_action: Action::Flag,
+            launch: Action::Reject,
+            embedded_files: Action::Reject,
+            xfa: Action::Flag,
+            encryption: Action::Reject,
+            malformed_xref: Action::Reject,
+        }
+    }
+}
+
+impl PdfPolicy {
+    fn action(&self, kind: PdfFindingKind) -> Action {
+        match kind {
+            PdfFindingKind::JavaScript => self.javascript,
+            PdfFindingKind::OpenAction => self.open_action,
+            PdfFindingKind::Launch => self.launch,
+            PdfFindingKind::EmbeddedFile => self.embedded_files,
+            PdfFindingKind::Xfa => self.xfa,
+            PdfFindingKind::Encrypted => self.encryption,
+            PdfFindingKind::MalformedXref => self.malformed_xref,
+        }
+    }
+}
+
+/// Content found in a document before the policy is applied
+struct Found {
+    kind: PdfFindingKind,
+    object: String,
+    detail: String,
+}
+
+impl Found {
+    fn new(kind: PdfFindingKind, object: &str, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            object: object.to_string(),
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Parse PDF documents and search their active contents
+#[derive(Debug, Clone)]
+pub struct PdfAnalyzer {
+    /// Handling of the findings
+    policy: PdfPolicy,
+}
+
+impl PdfAnalyzer {
+    pub fn new(policy: PdfPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+/// Returns the name of the reference, e.g. "12 0 R"
+fn reference(id: ObjectId) -> String {
+    format!("{} {} R", id.0, id.1)
+}
+
+/// Returns the value of a name entry of the dictionary
+fn name<'a>(dict: &'a Dictionary, key: &[u8]) -> Option<&'a [u8]> {
+    dict.get(key).and_then(Object::as_name).ok()
+}
+
+/// Returns the file name of a file specification (string or dictionary)
+fn file_name(doc: &Document, spec: &Object) -> String {
+    let spec = match spec {
+        Object::Reference(id) => match doc.get_object(*id) {
+            Ok(o) => o,
+            Err(_) => return reference(*id),
+        },
+        o => o,
+    };
+    match spec {
+        Object::Dictionary(d) => spec_name(d),
+        o => decode_text_string(o).unwrap_or_default(),
+    }
+}
+
+/// Returns the file name of a file specification dictionary
+fn spec_name(spec: &Dictionary) -> String {
+    [b"UF".as_slice(), b"F", b"Unix", b"DOS"]
+        .iter()
+        .find_map(|k| spec.get(k).ok())
+        .and_then(|f| decode_text_string(f).ok())
+        .unwrap_or_default()
+}
+
+/// Search the active contents of a dictionary and of its nested direct objects
+fn visit(doc: &Document, object: &str, dict: &Dictionary, depth: usize, found: &mut Vec<Found>) {
+    match name(dict, b"S") {
+        Some(b"JavaScript") => found.push(Found::new(
+            PdfFindingKind::JavaScript,
+            object,
+            "JavaScript action",
+        )),
+        Some(b"Launch") => {
+            let target = dict
+                .get(b"F")
+                .or_else(|_| dict.get(b"Win"))
+                .map(|f| file_name(doc, f))
+                .unwrap_or_default();
+            found.push(Found::new(
+                PdfFindingKind::Launch,
+                object,
+                format!("Launch action: {target}"),
+            ))
+        }
+        _ if dict.has(b"JS") => found.push(Found::new(
+            PdfFindingKind::JavaScript,
+            object,
+            "JavaScript action",
+        )),
+        _ => (),
+    }
+    if dict.has(b"JavaScript") && !dict.has(b"S") {
+        found.push(Found::new(
+            PdfFindingKind::JavaScript,
+            object,
+            "Document level JavaScript",
+        ));
+    }
+    if let Ok(action) = dict.get(b"OpenAction") {
+        // An OpenAction can also be a destination which is harmless
+        let action = match action {
+            Object::Reference(id) => doc.get_object(*id).ok(),
+            o => Some(o),
+        };
+        if let Some(Object::Dictionary(a)) = action {
+            let kind = name(a, b"S").unwrap_or_default();
+            found.push(Found::new(
+                PdfFindingKind::OpenAction,
+                object,
+                format!("OpenAction: {}", String::from_utf8_lossy(kind)),
+            ));
+        }
+    }
+    if let Ok(Object::Dictionary(aa)) = dict.get(b"AA") {
+        let events: Vec<String> = aa
+            .iter()
+            .map(|(k, _)| String::from_utf8_lossy(k).to_string())
+            .collect();
+        found.push(Found::new(
+            PdfFindingKind::OpenAction,
+            object,
+            format!("Additional actions: {}", events.join(", ")),
+        ));
+    } else if let Ok(Object::Reference(_)) = dict.get(b"AA") {
+        found.push(Found::new(
+            PdfFindingKind::OpenAction,
+            object,
+            "Additional actions",
+        ));
+    }
+    if dict.has(b"EF") {
+        found.push(Found::new(
+            PdfFindingKind::EmbeddedFile,
+            object,
+            spec_name(dict),
+        ));
+    }
+    if dict.has(b"XFA") {
+        found.push(Found::new(PdfFindingKind::Xfa, object, "XFA form"));
+    }
+
+    if depth >= MAX_NESTING {
+        return;
+    }
+    for (_, value) in dict.iter() {
+        visit_object(doc, object, value, depth + 1, found);
+    }
+}
+
+/// Search the active contents of the direct objects nested in an object
+fn visit_object(
+    doc: &Document,
+    object: &str,
+    value: &Object,
+    depth: usize,
+    found: &mut Vec<Found>,
+) {
+    match value {
+        Object::Dictionary(d) => visit(doc, object, d, depth, found),
+        Object::Array(a) if depth < MAX_NESTING => {
+            for v in a {
+                visit_object(doc, object, v, depth + 1, found);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Returns the decoded name starting at the beginning of the buffer (after the slash)
+/// Characters written as #XX are decoded
+fn raw_name(buf: &[u8]) -> Vec<u8> {
+    let mut name = Vec::new();
+    let mut i = 0;
+    while let Some(&c) = buf.get(i) {
+        if c.is_ascii_whitespace() || b"()<>[]{}/%".contains(&c) {
+            break;
+        }
+        let escaped = buf
+            .get(i + 1..i + 3)
+            .filter(|_| c == b'#')
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match escaped {
+            Some(v) => {
+                name.push(v);
+                i += 3;
+            }
+            None => {
+                name.push(c);
+                i += 1;
+            }
+        }
+    }
+    name
+}
+
+/// Search the names of the raw document, used when the object tree cannot be fully read
+fn scan_names(data: &[u8]) -> Vec<Found> {
+    let mut found: Vec<Found> = Vec::new();
+    for (i, _) in data.iter().enumerate().filter(|(_, c)| **c == b'/') {
+        let kind = match raw_name(&data[i + 1..]).as_slice() {
+            b"JavaScript" | b"JS" => PdfFindingKind::JavaScript,
+            b"OpenAction" | b"AA" => PdfFindingKind::OpenAction,
+            b"Launch" => PdfFindingKind::Launch,
+            b"EmbeddedFile" | b"EF" => PdfFindingKind::EmbeddedFile,
+            b"XFA" => PdfFindingKind::Xfa,
+            b"Encrypt" => PdfFindingKind::Encrypted,
+            _ => continue,
+        };
+        if !found.iter().any(|f| f.kind == kind) {
+            found.push(Found::new(kind, "", "Name found in the raw document"));
+        }
+    }
+    found
+}
+
+/// Parse the document and search its active contents
+fn inspect(data: &[u8]) -> Vec<Found> {
+    let doc = match Document::load_mem(data) {
+        Ok(d) => d,
+        Err(e) => {
+            let mut found = vec![Found::new(
+                PdfFindingKind::MalformedXref,
+                "",
+                format!("Document cannot be parsed: {e}"),
+            )];
+            found.append(&mut scan_names(data));
+            return found;
+        }
+    };
+
+    let mut found = Vec::new();
+    if let Ok(encrypt) = doc.trailer.get(b"Encrypt") {
+        let encrypt = match encrypt {
+            Object::Reference(id) => doc.get_object(*id).ok(),
+            o => Some(o),
+        };
+        let filter = encrypt
+            .and_then(|e| e.as_dict().ok())
+            .and_then(|e| name(e, b"Filter"))
+            .unwrap_or_default();
+        found.push(Found::new(
+            PdfFindingKind::Encrypted,
+            "",
+            format!("Encrypted document: {}", String::from_utf8_lossy(filter)),
+        ));
+    }
+    for (id, object) in &doc.objects {
+        let object_ref = reference(*id);
+        match object {
+            Object::Stream(s) => {
+                let crypt = match s.dict.get(b"Filter") {
+                    Ok(Object::Name(n)) => n == b"Crypt",
+                    Ok(Object::Array(a)) => {
+                        a.iter().any(|f| f.as_name().is_ok_and(|n| n == b"Crypt"))
+                    }
+                    _ => false,
+                };
+                if crypt {
+                    found.push(Found::new(
+                        PdfFindingKind::Encrypted,
+                        &object_ref,
+                        "Stream encrypted with a Crypt filter",
+                    ));
+                }
+                visit(&doc, &object_ref, &s.dict, 0, &mut found);
+            }
+            o => visit_object(&doc, &object_ref, o, 0, &mut found),
+        }
+    }
+
+    // Objects referenced by the cross reference table must have been read
+    let missing = doc
+        .reference_table
+        .entries
+        .iter()
+        .filter(|(id, entry)| match entry {
+            XrefEntry::Normal { generation, .. } => !doc.objects.contains_key(&(**id, *generation)),
+            XrefEntry::Compressed { .. } => !doc.objects.contains_key(&(**id, 0)),
+            _ => false,
+        })
+        .count();
+    if missing > 0 {
+        found.push(Found::new(
+            PdfFindingKind::MalformedXref,
+            "",
+            format!("{missing} objects of the cross reference table cannot be read"),
+        ));
+        for f in scan_names(data) {
+            if !found.iter().any(|g| g.kind == f.kind) {
+                found.push(f);
+            }
+        }
+    }
+    found
+}
+
+impl Analyzer for PdfAnalyzer {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Documents whose type is not allowed are not opened
+        if !md.is_type_allowed {
+            return Verdict::Pass;
+        }
+        // Detect the format from the beginning of the file
+        if get(content.prefix(8192)).map(|t| t.extension()) != Some("pdf") {
+            return Verdict::Pass;
+        }
+
+        // The parsed objects take about as much memory as the document
+        let data = content.prefix(content.profile.max_size);
+        let _memory = match content.budget.reserve(data.len() as u64) {
+            Ok(r) => r,
+            Err(verdict) => return verdict,
+        };
+        md.pdf_findings = inspect(data)
+            .into_iter()
+            .map(|f| PdfFinding {
+                action: self.policy.action(f.kind),
+                kind: f.kind,
+                object: f.object,
+                detail: f.detail,
+            })
+            .collect();
+        if !md.pdf_findings.is_empty() {
+            warn!(
+                "{} findings in PDF document {}",
+                md.pdf_findings.len(),
+                md.filename
+            );
+        }
+        findings_verdict(
+            md.pdf_findings.iter().map(|f| (f.kind, f.action)),
+            "Active content found in PDF document",
+        )
+    }
+}
@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the size analyzer.
+ */
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use keysas_lib::file_report::Verdict;
+
+/// Check that the file size is less than the maximum size of its type
+#[derive(Debug, Clone, Copy)]
+pub struct SizeAnalyzer;
+
+impl Analyzer for SizeAnalyzer {
+    fn name(&self) -> &'static str {
+        "size"
+    }
+
+    /// The size of the file is checked before it is copied
+    fn needs_content(&self) -> bool {
+        false
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        let max_size = content.profile.max_size;
+        md.is_toobig = content.size.gt(&max_size);
+        md.size = content.size;
+        match md.is_toobig {
+            true => Verdict::Reject(format!("File is bigger than {max_size} bytes")),
+            false => Verdict::Pass,
+        }
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.is_toobig = false;
+    }
+}
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the text files analyzer.
+ */
+
+//! Validation of text, CSV, XML and JSON files
+//!
+//! These formats have no magic number: their type is given by the extension of the
+//! file name (see [detect]) so that they can be allowed by the type profiles, and this
+//! analyzer makes sure that their content is text. The following anomalies are detected:
+//!     - Latin1: the file is not valid UTF-8, it is read as Latin-1
+//!     - ControlCharacter: control characters other than tabulations and line breaks,
+//!       C1 control characters included
+//!     - Nul: NUL characters, e.g. a binary file renamed
+//!     - Malformed: XML or JSON document that is not well-formed, or JSON that is not UTF-8
+//!     - Formula: CSV cell starting with =, + or @ interpreted as a formula by spreadsheets
+//!       (formula injection), cells holding a number are not formulas
+//!
+//! CSV cells are split on commas, semicolons and tabulations, so that the formulas are found
+//! whatever separator the spreadsheet opening the file uses.
+//! The handling of each anomaly is decided by the [TextPolicy].
+
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::policy::findings_verdict;
+use keysas_lib::file_report::{Action, TextFinding, TextFindingKind, Verdict};
+use log::warn;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use serde::de::IgnoredAny;
+use serde_derive::Deserialize;
+use std::path::Path;
+
+/// Text formats: type, MIME type and extensions of the file names
+const FORMATS: &[(&str, &str, &[&str])] = &[
+    ("txt", "text/plain", &["txt", "text"]),
+    ("csv", "text/csv", &["csv", "tsv"]),
+    ("xml", "text/xml", &["xml"]),
+    ("json", "application/json", &["json"]),
+];
+
+/// Maximum number of formulas recorded in the report
+const MAX_FORMULAS: usize = 16;
+
+/// Length of the beginning of a formula recorded in the report
+const FORMULA_EXCERPT: usize = 32;
+
+/// UTF-8 byte order mark
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Returns the text format of a file from its name, None if it is not a text format
+pub fn detect(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename)
+        .extension()?
+        .to_string_lossy()
+        .to_lowercase();
+    FORMATS
+        .iter()
+        .find(|(_, _, e)| e.contains(&extension.as_str()))
+        .map(|(t, _, _)| *t)
+}
+
+/// Returns the MIME type of a text format
+pub fn mime_type(file_type: &str) -> Option<&'static str> {
+    FORMATS
+        .iter()
+        .find(|(t, _, _)| *t == file_type)
+        .map(|(_, m, _)| *m)
+}
+
+/// Handling of each kind of anomaly in text files
+/// Missing fields take the built-in default value
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TextPolicy {
+    pub latin1: Action,
+    pub control_characters: Action,
+    pub nul: Action,
+    pub malformed: Action,
+    pub formulas: Action,
+}
+
+impl Default for TextPolicy {
+    fn default() -> Self {
+        Self {
+            latin1: Action::Allow,
+            control_characters: Action::Reject,
+            nul: Action::Reject,
+            malformed: Action::Reject,
+            formulas: Action::Flag,
+        }
+    }
+}
+
+impl TextPolicy {
+    fn action(&self, kind: TextFindingKind) -> Action {
+        match kind {
+            TextFindingKind::Latin1 => self.latin1,
+            TextFindingKind::ControlCharacter => self.control_characters,
+            TextFindingKind::Nul => self.nul,
+            TextFindingKind::Malformed => self.malformed,
+            TextFindingKind::Formula => self.formulas,
+        }
+    }
+}
+
+/// Anomaly found in a file before the policy is applied
+struct Found {
+    kind: TextFindingKind,
+    line: u64,
+    column: u64,
+    detail: String,
+}
+
+/// Returns the line and the column of an offset, starting at 1
+fn position(data: &[u8], offset: usize) -> (u64, u64) {
+    let before = &data[..offset.min(data.len())];
+    let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+    let start = before
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |p| p + 1);
+    (line as u64, (before.len() - start + 1) as u64)
+}
+
+/// Search the NUL and control characters
+fn find_controls(data: &[u8], utf8: bool, found: &mut Vec<Found>) {
+    // First occurrence and number of NUL and control characters
+    let mut nul: (Option<usize>, u64) = (None, 0);
+    let mut control: (Option<usize>, u64) = (None, 0);
+    for (i, b) in data.iter().enumerate() {
+        let occurrences = match *b {
+            0 => &mut nul,
+            b'\t' | b'\n' | b'\r' => continue,
+            0x01..=0x1F | 0x7F => &mut control,
+            // C1 control characters, encoded on two bytes in UTF-8
+            0x80..=0x9F if !utf8 => &mut control,
+            0xC2 if utf8 && matches!(data.get(i + 1), Some(0x80..=0x9F)) => &mut control,
+            _ => continue,
+        };
+        occurrences.0.get_or_insert(i);
+        occurrences.1 += 1;
+    }
+    for (kind, (first, count), name) in [
+        (TextFindingKind::Nul, nul, "NUL"),
+        (TextFindingKind::ControlCharacter, control, "control"),
+    ] {
+        if let Some(offset) = first {
+            let (line, column) = position(data, offset);
+            found.push(Found {
+                kind,
+                line,
+                column,
+                detail: format!("{name} characters: {count}"),
+            });
+        }
+    }
+}
+
+/// Check that the XML document is well-formed: balanced elements and a single root
+fn check_xml(data: &[u8]) -> Result<(), (usize, String)> {
+    let mut reader = Reader::from_reader(data);
+    reader.config_mut().enable_all_checks(true);
+    let mut depth = 0usize;
+    let mut roots = 0;
+    loop {
+        let offset = reader.buffer_position() as usize;
+        match reader.read_event() {
+            Ok(Event::Start(_)) => {
+                if depth == 0 {
+                    roots += 1;
+                }
+                depth += 1;
+            }
+            Ok(Event::End(_)) => depth = depth.saturating_sub(1),
+            Ok(Event::Empty(_)) if depth == 0 => roots += 1,
+            Ok(Event::Text(t)) if depth == 0 && !t.iter().all(u8::is_ascii_whitespace) => {
+                return Err((offset, "Text outside of the root element".into()));
+            }
+            Ok(Event::CData(_)) if depth == 0 => {
+                return Err((offset, "CDATA outside of the root element".into()));
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => return Err((reader.error_position() as usize, e.to_string())),
+        }
+        if roots > 1 {
+            return Err((offset, "Several root elements".into()));
+        }
+    }
+    match (depth, roots) {
+        (0, 1) => Ok(()),
+        (0, _) => Err((data.len(), "No root element".into())),
+        _ => Err((data.len(), format!("{depth} elements not closed"))),
+    }
+}
+
+/// Search the CSV cells starting like a formula
+fn find_formulas(data: &[u8], found: &mut Vec<Found>) {
+    let mut count = 0;
+    let mut cell = 1;
+    let mut quoted = false;
+    let mut start = true;
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        if start {
+            start = false;
+            let first = match b {
+                b'"' => i + 1,
+                _ => i,
+            };
+            let value = data[first..]
+                .split(|c| matches!(c, b',' | b';' | b'\t' | b'\r' | b'\n' | b'"'))
+                .next()
+                .unwrap_or_default();
+            let is_number = std::str::from_utf8(value).is_ok_and(|v| v.parse::<f64>().is_ok());
+            if matches!(value.first(), Some(b'=' | b'+' | b'@')) && !is_number {
+                count += 1;
+                if count <= MAX_FORMULAS {
+                    let excerpt = &value[..value.len().min(FORMULA_EXCERPT)];
+                    found.push(Found {
+                        kind: TextFindingKind::Formula,
+                        line: position(data, first).0,
+                        column: cell,
+                        detail: format!(
+                            "Cell starting with a formula: {}",
+                            String::from_utf8_lossy(excerpt)
+                        ),
+                    });
+                }
+            }
+            if b == b'"' {
+                quoted = true;
+                i += 1;
+                continue;
+            }
+        }
+        match b {
+            // A quote is escaped by doubling it
+            b'"' if quoted => match data.get(i + 1) {
+                Some(b'"') => i += 1,
+                _ => quoted = false,
+            },
+            b'\n' if !quoted => {
+                cell = 1;
+                start = true;
+            }
+            b',' | b';' | b'\t' if !quoted => {
+                cell += 1;
+                start = true;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    if count > MAX_FORMULAS {
+        warn!("{count} formulas found, the first {MAX_FORMULAS} are reported");
+    }
+}
+
+/// Validate the encoding, the characters and the syntax of text files
+#[derive(Debug, Clone, Copy)]
+pub struct TextAnalyzer {
+    /// Handling of the anomalies
+    policy: TextPolicy,
+}
+
+impl TextAnalyzer {
+    pub fn new(policy: TextPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl Analyzer for TextAnalyzer {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // Files whose type is not allowed are not read
+        if !md.is_type_allowed || mime_type(content.file_type).is_none() {
+            return Verdict::Pass;
+        }
+        let data = content.data();
+        let text = data.strip_prefix(BOM).unwrap_or(data);
+        let utf8 = std::str::from_utf8(text).is_ok();
+        let mut found = Vec::new();
+        if !utf8 {
+            found.push(Found {
+                kind: TextFindingKind::Latin1,
+                line: 0,
+                column: 0,
+                detail: "Not valid UTF-8, read as Latin-1".into(),
+            });
+        }
+        find_controls(text, utf8, &mut found);
+        if content.budget.is_expired() {
+            return content.budget.timeout();
+        }
+        let syntax = match content.file_type {
+            "xml" => check_xml(text).map_err(|(offset, e)| (position(text, offset), e)),
+            "json" => serde_json::from_slice::<IgnoredAny>(text)
+                .map(|_| ())
+                .map_err(|e| ((e.line() as u64, e.column() as u64), e.to_string())),
+            _ => Ok(()),
+        };
+        if let Err(((line, column), detail)) = syntax {
+            found.push(Found {
+                kind: TextFindingKind::Malformed,
+                line,
+                column,
+                detail: format!("{} is not well-formed: {detail}", content.file_type),
+            });
+        }
+        if content.file_type == "csv" {
+            find_formulas(text, &mut found);
+        }
+
+        // Apply the policy
+        md.text_findings = found
+            .into_iter()
+            .map(|f| TextFinding {
+                action: self.policy.action(f.kind),
+                kind: f.kind,
+                line: f.line,
+                column: f.column,
+                detail: f.detail,
+            })
+            .collect();
+        for f in &md.text_findings {
+            warn!("File {} line {}: {}", md.filename, f.line, f.detail);
+        }
+        findings_verdict(
+            md.text_findings.iter().map(|f| (f.kind, f.action)),
+            "Invalid text file",
+        )
+    }
+}
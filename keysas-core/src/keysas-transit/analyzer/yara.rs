@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the Yara analyzer.
+ */
+
+use super::budget::Budget;
+use super::{Analyzer, Content};
+use crate::FileMetadata;
+use crate::rules::RuleSet;
+use keysas_lib::file_report::{Verdict, YaraMatch, YaraMetaValue, YaraString};
+use log::{error, warn};
+use std::sync::{Arc, PoisonError, RwLock};
+use yara::{MetadataValue, Rule};
+
+/// Maximum number of offsets reported for each string of a rule
+const MAX_OFFSETS: usize = 16;
+
+/// Scan data with the active Yara rule set
+/// Shared by the Yara and archive analyzers
+pub struct YaraScanner {
+    /// Active rule set, replaced when the rules are reloaded
+    rules: RwLock<Arc<RuleSet>>,
+    /// Timeout for yara
+    timeout: i32,
+    /// Report the offsets of the matched strings
+    offsets: bool,
+}
+
+impl YaraScanner {
+    pub fn new(rules: RuleSet, timeout: i32, offsets: bool) -> Self {
+        Self {
+            rules: RwLock::new(Arc::new(rules)),
+            timeout,
+            offsets,
+        }
+    }
+
+    /// Active rule set
+    pub fn rules(&self) -> Arc<RuleSet> {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the active rule set, the scans in progress end with the previous one
+    pub fn replace(&self, rules: RuleSet) {
+        *self.rules.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(rules);
+    }
+
+    /// Returns the rules of the rule set matched by the data
+    /// The scan stops at the Yara timeout or when the time budget of the file is over
+    pub fn scan_with(
+        &self,
+        rules: &RuleSet,
+        data: &[u8],
+        budget: &Budget,
+    ) -> Result<Vec<YaraMatch>, yara::Error> {
+        let remaining =
+            i32::try_from(budget.remaining().as_secs_f64().ceil() as u64).unwrap_or(i32::MAX);
+        let matched = rules.scan(data, self.timeout.min(remaining).max(1))?;
+        Ok(matched.iter().map(|r| to_match(r, self.offsets)).collect())
+    }
+
+    /// Returns the rules of the active rule set matched by the data
+    pub fn scan(&self, data: &[u8], budget: &Budget) -> Result<Vec<YaraMatch>, yara::Error> {
+        self.scan_with(&self.rules(), data, budget)
+    }
+}
+
+/// Convert a rule matched by libyara in the report format
+/// The strings are only kept with their offsets
+pub fn to_match(rule: &Rule, offsets: bool) -> YaraMatch {
+    YaraMatch {
+        rule: rule.identifier.to_string(),
+        namespace: rule.namespace.to_string(),
+        tags: rule.tags.iter().map(|t| t.to_string()).collect(),
+        meta: rule
+            .metadatas
+            .iter()
+            .map(|m| {
+                let value = match m.value {
+                    MetadataValue::Integer(i) => YaraMetaValue::Integer(i),
+                    MetadataValue::Boolean(b) => YaraMetaValue::Boolean(b),
+                    MetadataValue::String(s) => YaraMetaValue::String(s.to_string()),
+                };
+                (m.identifier.to_string(), value)
+            })
+            .collect(),
+        strings: match offsets {
+            true => rule
+                .strings
+                .iter()
+                .filter(|s| !s.matches.is_empty())
+                .map(|s| YaraString {
+                    identifier: s.identifier.to_string(),
+                    offsets: s
+                        .matches
+                        .iter()
+                        .take(MAX_OFFSETS)
+                        .map(|m| (m.base + m.offset) as u64)
+                        .collect(),
+                })
+                .collect(),
+            false => Vec::new(),
+        },
+    }
+}
+
+/// Names of the matched rules, as shown in the summary of the report
+pub fn rule_names(matches: &[YaraMatch]) -> String {
+    matches
+        .iter()
+        .map(|m| m.rule.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Scan the file with the compiled Yara rules
+/// A match is only flagged, keysas-out decides if the file is removed
+pub struct YaraAnalyzer {
+    scanner: Arc<YaraScanner>,
+}
+
+impl YaraAnalyzer {
+    pub fn new(scanner: Arc<YaraScanner>) -> Self {
+        Self { scanner }
+    }
+}
+
+impl Analyzer for YaraAnalyzer {
+    fn name(&self) -> &'static str {
+        "yara"
+    }
+
+    fn analyze(&self, content: &Content, md: &mut FileMetadata) -> Verdict {
+        // The rule set is stamped in the report even if it is replaced during the scan
+        let rules = self.scanner.rules();
+        md.yara_ruleset = rules.hash().to_string();
+        // The file is scanned through the copy shared with the other analyzers
+        match self
+            .scanner
+            .scan_with(&rules, content.data(), &content.budget)
+        {
+            Ok(matches) => match matches.is_empty() {
+                true => {
+                    md.yara_pass = true;
+                    Verdict::Pass
+                }
+                false => {
+                    md.yara_report = rule_names(&matches);
+                    md.yara_matches = matches;
+                    md.yara_pass = false;
+                    warn!("Yara rules matched");
+                    Verdict::Flag(md.yara_report.clone())
+                }
+            },
+            Err(e) => {
+                error!("Yara cannot scan file {} error {e}", md.filename);
+                match content.budget.is_expired() {
+                    true => content.budget.timeout(),
+                    false => Verdict::Flag("Yara scan failed".into()),
+                }
+            }
+        }
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.yara_pass = true;
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the connections with
+ * keysas-in and keysas-out.
+ */
+
+//! Connections with keysas-in and keysas-out
+//!
+//! keysas-in removes a file once it is acknowledged: the acknowledgement is only sent
+//! once keysas-out is in charge of the file, so that no file is lost if a daemon restarts.
+//! The replies to keysas-in are sent in the order the files were received, the files refused
+//! on reception included, see [Replies].
+//!
+//! When keysas-in restarts, the files received on the previous connection are not sent
+//! to keysas-out: keysas-in sends them again. When keysas-out restarts, the file being sent
+//! is sent again once keysas-out is connected, see [Output].
+
+use keysas_lib::file_report::FileMetadata;
+use keysas_lib::protocol::{self, ErrorReply, ProtocolError};
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::os::fd::BorrowedFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+
+/// Replies to the files received from keysas-in
+#[derive(Debug)]
+pub struct Replies {
+    stream: UnixStream,
+    /// Replies in the order the files were received, None while the file is being analyzed
+    queue: VecDeque<Option<Result<(), ErrorReply>>>,
+    /// Number of the connection with keysas-in
+    generation: u64,
+}
+
+impl Replies {
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            queue: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Number of the connection with keysas-in
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// A file has been received, returns the number of the connection
+    pub fn received(&mut self) -> u64 {
+        self.queue.push_back(None);
+        self.generation
+    }
+
+    /// A file has been refused on reception
+    pub fn refused(&mut self, reply: ErrorReply) {
+        self.queue.push_back(Some(Err(reply)));
+        self.flush();
+    }
+
+    /// The oldest file being analyzed has been delivered to keysas-out, or refused
+    /// Ignored if the file was received on a previous connection
+    pub fn delivered(&mut self, generation: u64, result: Result<(), ErrorReply>) {
+        if generation != self.generation {
+            return;
+        }
+        if let Some(reply) = self.queue.iter_mut().find(|r| r.is_none()) {
+            *reply = Some(result);
+        }
+        self.flush();
+    }
+
+    /// keysas-in is connected again, the files in flight are sent again by keysas-in
+    pub fn reset(&mut self, stream: UnixStream) {
+        self.stream = stream;
+        self.queue.clear();
+        self.generation += 1;
+    }
+
+    /// Send the replies ready, in order
+    fn flush(&mut self) {
+        while let Some(Some(reply)) = self.queue.front() {
+            let result = match reply {
+                Ok(_) => protocol::reply_ack(&self.stream),
+                Err(reply) => protocol::reply_error(&self.stream, reply),
+            };
+            // keysas-in sends the file again if the reply is lost
+            if let Err(e) = result {
+                warn!("Failed to reply to keysas-in: {e}");
+            }
+            self.queue.pop_front();
+        }
+    }
+}
+
+/// Connection with keysas-out, accepted again when it is lost
+#[derive(Debug)]
+pub struct Output {
+    listener: UnixListener,
+    stream: UnixStream,
+    /// User of keysas-out
+    uid: u32,
+}
+
+impl Output {
+    /// Wait for keysas-out to connect
+    pub fn new(listener: UnixListener, uid: u32) -> Self {
+        let stream = accept(&listener, uid);
+        Self {
+            listener,
+            stream,
+            uid,
+        }
+    }
+
+    /// Send a file to keysas-out and wait for its reply
+    /// If keysas-out is lost, the file is sent again once it is connected
+    pub fn send(&mut self, md: &FileMetadata, fd: BorrowedFd) -> Result<(), ErrorReply> {
+        loop {
+            let result = protocol::send_file(&self.stream, md, fd)
+                .and_then(|_| protocol::receive_reply(&self.stream));
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if !e.is_fatal() => {
+                    return Err(match e {
+                        ProtocolError::Refused(reply) => reply,
+                        e => e.reply(),
+                    });
+                }
+                Err(e) => {
+                    // keysas-out is told why the connection is closed
+                    protocol::refuse(&self.stream, &e);
+                    if e.is_version() {
+                        error!("Keysas-out runs another version of the protocol: {e}");
+                        process::exit(1);
+                    }
+                    warn!("Connection to keysas-out lost: {e}, waiting for keysas-out");
+                    self.stream = accept(&self.listener, self.uid);
+                }
+            }
+        }
+    }
+}
+
+/// Accept the connection of keysas-out
+fn accept(listener: &UnixListener, uid: u32) -> UnixStream {
+    match protocol::accept(listener, uid) {
+        Ok(s) => {
+            info!("Keysas-out connected.");
+            s
+        }
+        Err(e) => {
+            error!("Failed to accept connection: {e}");
+            process::exit(1);
+        }
+    }
+}
@@ -1,14 +1,13 @@
 // SPDX-License-Identifier: GPL-3.0-only
 /*
- * The "keysas-out".
+ * The "keysas-transit".
  *
  * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
  *
  * This file contains various funtions
- * for building the keysas-out binary.
+ * for building the keysas-transit binary.
  */
 
-#![feature(unix_socket_ancillary_data)]
 #![warn(unused_extern_crates)]
 #![forbid(non_shorthand_field_patterns)]
 #![warn(dead_code)]
@@ -24,76 +23,95 @@
 #![warn(deprecated)]
 
 use anyhow::Result;
-use clamav_tcp::scan;
-use clamav_tcp::version;
 use clap::{Arg, ArgAction, Command, crate_version};
-use infer::get;
+use keysas_lib::file_report::{AnalyzerVerdict, FileMetadata, Verdict};
+use keysas_lib::hash_list::{ALLOW_LIST_PATH, DENY_LIST_PATH, HashList};
 use keysas_lib::init_logger;
-use keysas_lib::sha256_digest;
+use keysas_lib::protocol::{self, InputMetadata};
 use log::{error, info, warn};
-use nix::unistd;
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::io::{IoSlice, IoSliceMut};
 use std::net::IpAddr;
-use std::net::ToSocketAddrs;
-use std::os::fd::FromRawFd;
+use std::os::fd::{AsFd, OwnedFd};
 use std::os::linux::net::SocketAddrExt;
-use std::os::unix::net::{
-    AncillaryData, Messages, SocketAddr, SocketAncillary, UnixListener, UnixStream,
-};
+use std::os::unix::net::{SocketAddr, UnixListener};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str;
-use std::thread as main_thread;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::Duration;
-use yara::*;
+mod analyzer;
+mod link;
+mod policy;
+mod pool;
+mod profile;
+mod rules;
 mod sandbox;
+#[cfg(test)]
+mod tests;
+mod worker;
 
-const CONFIG_DIRECTORY: &str = "/etc/keysas";
-
-#[derive(bincode::Decode, Debug)]
-struct InputMetadata {
-    filename: String,
-    digest: String,
-    timestamp: String,
-    is_corrupted: bool,
-}
+use analyzer::Registry;
+use analyzer::archive::{ArchiveAnalyzer, ArchiveLimits};
+use analyzer::av::{Antivirus, AvAnalyzer, EngineConfig};
+use analyzer::budget::MemoryPool;
+use analyzer::digest::DigestAnalyzer;
+use analyzer::entropy::EntropyAnalyzer;
+use analyzer::extension::ExtensionAnalyzer;
+use analyzer::hashlist::HashListAnalyzer;
+use analyzer::magic::MagicAnalyzer;
+use analyzer::metadata::MetadataAnalyzer;
+use analyzer::office::OfficeAnalyzer;
+use analyzer::pdf::PdfAnalyzer;
+use analyzer::size::SizeAnalyzer;
+use analyzer::text::TextAnalyzer;
+use analyzer::yara::{YaraAnalyzer, YaraScanner};
+use link::{Output, Replies};
+use policy::Policy;
+use pool::Pool;
+use profile::Profiles;
+use rules::{Reloader, RuleSet, RuleSource};
+use worker::Zygote;
 
-#[derive(bincode::Encode, Debug)]
-struct FileMetadata {
-    filename: String,
-    digest: String,
-    is_digest_ok: bool,
-    is_toobig: bool,
-    size: u64,
-    is_type_allowed: bool,
-    av_pass: bool,
-    av_report: Vec<String>,
-    yara_pass: bool,
-    yara_report: String,
-    timestamp: String,
-    is_corrupted: bool,
-    file_type: String,
-}
+const CONFIG_DIRECTORY: &str = "/etc/keysas";
 
+/// File received from keysas-in
+/// The descriptor is owned until it is sent to keysas-out,
+/// it is closed when the file is dropped on any path.
 #[derive(Debug)]
 struct FileData {
-    fd: i32,
+    fd: OwnedFd,
     md: FileMetadata,
+    /// Number of the connection with keysas-in the file was received on
+    generation: u64,
 }
 
 /// Daemon configuration arguments
 struct Configuration {
     socket_in: String,         // path for the socket with keysas-in
     socket_out: String,        // path for the socket with keysas-out
-    max_size: u64,             // Maximum size for files
-    magic_list: Vec<String>,   // List of allowed file type
+    max_size: u64,             // Maximum size for files, if no profile is set in the policy
+    magic_list: Vec<String>,   // List of allowed file type, if no profile is set in the policy
     clamav_ip: String,         // ClamAV IP address
     clamav_port: u16,          // ClamAV port number
-    rule_path: String,         // Path to yara rules
+    clamav_socket: String,     // ClamAV unix socket, used instead of the IP address if set
+    rule_sources: Vec<RuleSource>, // Yara rule files and directories
+    rules_cache: String,       // Directory of the compiled yara rules
+    rules_reload: u64,         // Interval between checks of the yara rule files
     yara_timeout: i32,         // Timeout for yara
-    yara_rules: Option<Rules>, // Yara rules
+    yara_offsets: bool,        // Report the offsets of the strings matched by yara
+    analysis_timeout: u64,     // Time allowed to analyze a file
+    memory_limit: u64,         // Memory shared by the files analyzed at once
     type_off: bool,
+    analyzers: Vec<String>,    // List of enabled analyzers
+    archive_max_depth: u32,    // Maximum nesting level of archives
+    archive_max_entries: usize, // Maximum number of entries in archives
+    archive_max_ratio: u64,    // Maximum expansion ratio of archives
+    policy_path: String,       // Path to the station policy
+    workers: usize,            // Number of files analyzed at once
+    deny_list: String,         // Path to the deny list of digests
+    allow_list: String,        // Path to the allow list of digests
+    in_user: String,           // User of keysas-in, the only one allowed as peer on socket_in
+    out_user: String,          // User of keysas-out, the only one allowed to connect to socket_out
 }
 
 /// This function parse the command arguments into a structure
@@ -128,7 +146,7 @@ fn parse_args() -> Configuration {
                  .default_value("500000000")
                  .action(ArgAction::Set)
                  .value_parser(clap::value_parser!(u64))
-                 .help("Maximum size for files"),
+                 .help("Maximum size for files, ignored if the policy sets type profiles"),
          )
          .arg(
              Arg::new("allowed_formats")
@@ -137,7 +155,7 @@ fn parse_args() -> Configuration {
                  .value_name("<LIST>")
                  .default_value("jpg,png,gif,bmp,mp4,m4v,avi,wmv,mpg,flv,mp3,wav,ogg,epub,mobi,doc,docx,xls,xlsx,ppt,pptx")
                  .action(ArgAction::Set)
-                 .help("Whitelist (comma separated) of allowed file formats"),
+                 .help("Whitelist (comma separated) of allowed file formats, ignored if the policy sets type profiles"),
          )
          .arg(
              Arg::new("clamavip")
@@ -146,7 +164,7 @@ fn parse_args() -> Configuration {
                  .value_name("<IP>")
                  .default_value("127.0.0.1")
                  .action(ArgAction::Set)
-                 .help("Clamav IP address"),
+                 .help("Clamav IP address, used if no anti-virus engine is set in the policy"),
          )
          .arg(
              Arg::new("clamavport")
@@ -156,17 +174,45 @@ fn parse_args() -> Configuration {
                  .default_value("3310")
                  .action(ArgAction::Set)
                  .value_parser(clap::value_parser!(u16))
-                 .help("Clamav port number"),
+                 .help("Clamav port number, used if no anti-virus engine is set in the policy"),
+         )
+         .arg(
+             Arg::new("clamav_socket")
+                 .short('u')
+                 .long("clamav_socket")
+                 .value_name("<PATH>")
+                 .default_value("")
+                 .action(ArgAction::Set)
+                 .help("Clamav unix socket, used instead of the IP address if set"),
          )
          .arg(
              Arg::new("rules_path")
                  .short('r')
                  .long("rules_path")
-                 .value_name("<PATH>")
+                 .value_name("<LIST>")
                  .default_value("/usr/share/keysas/rules/index.yar")
                  .action(ArgAction::Set)
-                 .help("Sets a custom path for Yara rules"),
+                 .help("List (comma separated) of Yara rule files and directories in the form [NAMESPACE=]PATH"),
          )
+         .arg(
+            Arg::new("rules_cache")
+                .short('C')
+                .long("rules_cache")
+                .value_name("<PATH>")
+                .default_value("")
+                .action(ArgAction::Set)
+                .help("Directory where the compiled Yara rules are cached, disabled if empty"),
+        )
+         .arg(
+            Arg::new("rules_reload")
+                .short('R')
+                .long("rules_reload")
+                .value_name("<SECONDS>")
+                .default_value("10")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("Interval between checks of the Yara rule files for changes, 0 to only reload on SIGHUP"),
+        )
          .arg(
              Arg::new("yara_timeout")
                  .short('t')
@@ -177,12 +223,133 @@ fn parse_args() -> Configuration {
                  .value_parser(clap::value_parser!(i32))
                  .help("Sets a custom timeout for libyara scans"),
          )
+         .arg(
+            Arg::new("yara_offsets")
+                .short('O')
+                .long("yara_offsets")
+                .action(ArgAction::SetTrue)
+                .help("Report the offsets of the strings matched by Yara rules"),
+        )
+         .arg(
+            Arg::new("analysis_timeout")
+                .short('T')
+                .long("analysis_timeout")
+                .value_name("<SECONDS>")
+                .default_value("300")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Time allowed to analyze a file with all the analyzers"),
+        )
+         .arg(
+            Arg::new("memory_limit")
+                .short('M')
+                .long("memory_limit")
+                .value_name("<BYTES>")
+                .default_value("4294967296")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("Memory used at once to copy the files, unpack archives and parse documents, 0 for no limit"),
+        )
          .arg(
             Arg::new("type_off")
                 .short('m')
                 .long("type_off")
                 .action(ArgAction::SetTrue)
-                .help("Disable the magic number check"),
+                .help("Disable the magic number check, ignored if the policy sets type profiles"),
+        )
+         .arg(
+            Arg::new("analyzers")
+                .short('l')
+                .long("analyzers")
+                .value_name("<LIST>")
+                .default_value("digest,hashlist,size,av,yara,magic,extension,archive,office,pdf,metadata,text,entropy")
+                .action(ArgAction::Set)
+                .help("List (comma separated) of enabled analyzers"),
+        )
+         .arg(
+            Arg::new("archive_max_depth")
+                .short('d')
+                .long("archive_max_depth")
+                .value_name("<LEVELS>")
+                .default_value("3")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u32))
+                .help("Maximum nesting level of archives"),
+        )
+         .arg(
+            Arg::new("archive_max_entries")
+                .short('e')
+                .long("archive_max_entries")
+                .value_name("<NUMBER>")
+                .default_value("1000")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .help("Maximum number of entries in archives, all nesting levels included"),
+        )
+         .arg(
+            Arg::new("archive_max_ratio")
+                .short('x')
+                .long("archive_max_ratio")
+                .value_name("<RATIO>")
+                .default_value("100")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("Maximum ratio between the uncompressed size and the size of archives"),
+        )
+         .arg(
+            Arg::new("policy")
+                .short('y')
+                .long("policy")
+                .value_name("<PATH>")
+                .default_value("/etc/keysas/keysas-transit-policy.json")
+                .action(ArgAction::Set)
+                .help("Sets a custom path for the station policy"),
+        )
+         .arg(
+            Arg::new("workers")
+                .short('w')
+                .long("workers")
+                .value_name("<NUMBER>")
+                .default_value("4")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64).range(1..=32))
+                .help("Number of files analyzed at once, at most 32 (limit of libyara)"),
+        )
+         .arg(
+            Arg::new("deny_list")
+                .short('D')
+                .long("deny_list")
+                .value_name("<PATH>")
+                .default_value(DENY_LIST_PATH)
+                .action(ArgAction::Set)
+                .help("Sets a custom path for the deny list of SHA-256 digests"),
+        )
+         .arg(
+            Arg::new("allow_list")
+                .short('A')
+                .long("allow_list")
+                .value_name("<PATH>")
+                .default_value(ALLOW_LIST_PATH)
+                .action(ArgAction::Set)
+                .help("Sets a custom path for the allow list of SHA-256 digests"),
+        )
+         .arg(
+            Arg::new("in_user")
+                .short('I')
+                .long("in_user")
+                .value_name("<USER>")
+                .default_value("keysas-in")
+                .action(ArgAction::Set)
+                .help("User of keysas-in, the only one allowed as peer on the input socket"),
+        )
+         .arg(
+            Arg::new("out_user")
+                .short('U')
+                .long("out_user")
+                .value_name("<USER>")
+                .default_value("keysas-out")
+                .action(ArgAction::Set)
+                .help("User of keysas-out, the only one allowed to connect to the output socket"),
         )
          .arg(
             Arg::new("version")
@@ -206,286 +373,165 @@ fn parse_args() -> Configuration {
             .collect(),
         clamav_ip: matches.get_one::<String>("clamavip").unwrap().to_string(),
         clamav_port: *matches.get_one::<u16>("clamavport").unwrap(),
-        rule_path: matches.get_one::<String>("rules_path").unwrap().to_string(),
+        clamav_socket: matches.get_one::<String>("clamav_socket").unwrap().to_string(),
+        rule_sources: matches
+            .get_one::<String>("rules_path")
+            .unwrap()
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(RuleSource::parse)
+            .collect(),
+        rules_cache: matches.get_one::<String>("rules_cache").unwrap().to_string(),
+        rules_reload: *matches.get_one::<u64>("rules_reload").unwrap(),
         yara_timeout: *matches.get_one::<i32>("yara_timeout").unwrap(),
-        yara_rules: None,
+        yara_offsets: matches.get_flag("yara_offsets"),
+        analysis_timeout: *matches.get_one::<u64>("analysis_timeout").unwrap(),
+        memory_limit: *matches.get_one::<u64>("memory_limit").unwrap(),
         type_off: matches.get_flag("type_off"),
+        analyzers: matches
+            .get_one::<String>("analyzers")
+            .unwrap()
+            .split(',')
+            .filter(|a| !a.is_empty())
+            .map(String::from)
+            .collect(),
+        archive_max_depth: *matches.get_one::<u32>("archive_max_depth").unwrap(),
+        archive_max_entries: *matches.get_one::<usize>("archive_max_entries").unwrap(),
+        archive_max_ratio: *matches.get_one::<u64>("archive_max_ratio").unwrap(),
+        policy_path: matches.get_one::<String>("policy").unwrap().to_string(),
+        workers: *matches.get_one::<u64>("workers").unwrap() as usize,
+        deny_list: matches.get_one::<String>("deny_list").unwrap().to_string(),
+        allow_list: matches.get_one::<String>("allow_list").unwrap().to_string(),
+        in_user: matches.get_one::<String>("in_user").unwrap().to_string(),
+        out_user: matches.get_one::<String>("out_user").unwrap().to_string(),
     }
 }
 
-/// This function retrieves the file descriptors and metadata from the messages
-fn parse_messages(messages: Messages, buffer: &[u8]) -> Vec<FileData> {
-    messages
-        .filter_map(|m| {
-            //Desencapsulate Result
-            match m {
-                Ok(ad) => Some(ad),
-                Err(e) => {
-                    log::warn!("failed to get ancillary data: {e:?}");
-                    None
-                }
-            }
-        })
-        .filter_map(|ad| {
-            // Filter AncillaryData to keep only ScmRights
-            match ad {
-                AncillaryData::ScmRights(scm_rights) => Some(scm_rights),
-                AncillaryData::ScmCredentials(_) => None,
-            }
-        })
-        .flatten()
-        .filter_map(|fd| {
-            // Deserialize metadata
-            let config = bincode::config::standard().with_limit::<4128>();
-            match bincode::decode_from_slice::<InputMetadata, _>(buffer, config) {
-                Ok(meta) => {
-                    // Initialize with failed value by default
-                    log::info!("Receiving fd of file: {}", &meta.0.filename);
-                    Some(FileData {
-                        fd,
-                        md: FileMetadata {
-                            filename: meta.0.filename,
-                            digest: meta.0.digest,
-                            is_digest_ok: false,
-                            is_toobig: true,
-                            size: 0,
-                            is_type_allowed: false,
-                            av_pass: false,
-                            av_report: Vec::new(),
-                            yara_pass: false,
-                            yara_report: String::new(),
-                            timestamp: meta.0.timestamp,
-                            is_corrupted: meta.0.is_corrupted,
-                            file_type: "Unknown".into(),
-                        },
-                    })
-                }
-                Err(e) => {
-                    log::warn!("Failed to deserialize message from in: {e}");
-                    None
-                }
-            }
-        })
-        .collect()
-}
-
-/// This function returns true if the file type is in the list provided
-fn check_is_extension_allowed(buf: &[u8], conf: &Configuration) -> bool {
-    match get(buf) {
-        Some(info) => conf.magic_list.contains(&info.extension().to_string()),
-        None => false,
-    }
-}
-/// This function returns true if the file type is in the list provided
-fn get_extension(buf: Vec<u8>) -> String {
-    match get(&buf) {
-        Some(info) => info.to_string(),
-        None => "".into(),
+/// This function initializes the metadata of a file received from keysas-in,
+/// the checks are marked as failed until they are run
+fn new_file(meta: InputMetadata, fd: OwnedFd, generation: u64) -> FileData {
+    log::info!("Receiving fd of file: {}", meta.filename);
+    FileData {
+        fd,
+        generation,
+        md: FileMetadata {
+            filename: meta.filename,
+            path: meta.path,
+            session: meta.session,
+            digest: meta.digest,
+            is_digest_ok: false,
+            is_toobig: true,
+            size: 0,
+            is_type_allowed: false,
+            av_pass: false,
+            av_report: Vec::new(),
+            yara_pass: false,
+            yara_report: String::new(),
+            yara_matches: Vec::new(),
+            yara_ruleset: String::new(),
+            timestamp: meta.timestamp,
+            is_corrupted: meta.is_corrupted,
+            file_type: "Unknown".into(),
+            verdicts: Vec::new(),
+            archive_entries: Vec::new(),
+            office_findings: Vec::new(),
+            pdf_findings: Vec::new(),
+            extension_findings: Vec::new(),
+            hash_list: None,
+            av_engines: Vec::new(),
+            entropy: None,
+            text_findings: Vec::new(),
+            metadata_findings: Vec::new(),
+        },
     }
 }
-/// This function check each file given in the input vector.
-/// Checks are made against the provided configuration.
-/// Checks performed are:
-///     - File digest is correct
-///     - File size is less than maximum size provided in configuration
-///     - File type is in the list provided in configuration
-///     - Anti-virus check
-///     - Yara rules check
-/// Checks results are marked in file metadata.
-/// This function does not modify the files.
-fn check_files(files: &mut Vec<FileData>, conf: &Configuration, clam_addr: String) {
-    for f in files {
-        match unistd::dup2(f.fd, 500) {
-            Ok(nfd) => {
-                // Safety: We are using a file descriptor that we know is valid
-                let mut file = unsafe { File::from_raw_fd(nfd) };
-                // Synchronize the file before calculating the SHA256 hash
-                match file.sync_all() {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to synchronize file: {e}");
-                    }
-                }
-                // Position the cursor at the beginning of the file
-                match unistd::lseek(nfd, 0, unistd::Whence::SeekSet) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Unable to lseek on file descriptor: {e:?}, killing myself.");
-                        process::exit(1);
-                    }
-                }
-                // Check digest
-                match sha256_digest(&file) {
-                    Ok(d) => {
-                        f.md.is_digest_ok = f.md.digest.eq(&d);
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Failed to calculate digest for file {}, error {e}.",
-                            f.md.filename
-                        );
-                    }
-                }
-                // Position the cursor at the beginning of the file
-                match unistd::lseek(nfd, 0, unistd::Whence::SeekSet) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Unable to lseek on file descriptor: {e:?}, killing myself.");
-                        process::exit(1);
-                    }
-                }
 
-                // Check size
-                match &file.metadata() {
-                    Ok(meta) => {
-                        f.md.is_toobig = meta.len().gt(&conf.max_size);
-                        f.md.size = meta.len();
-                    }
-                    Err(e) => {
-                        warn!("Failed to get metadata of file {} error {e}", f.md.filename);
-                    }
-                }
-
-                // Position the cursor at the beginning of the file
-                match unistd::lseek(nfd, 0, unistd::Whence::SeekSet) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Unable to lseek on file descriptor: {e:?}, killing myself.");
-                        process::exit(1);
-                    }
-                }
-                // Check anti-virus
-                match scan(clam_addr.clone(), &mut file, None) {
-                    Ok(result) => {
-                        f.md.av_pass = !result.is_infected;
-                        f.md.av_report = result.detected_infections;
-                    }
-                    Err(e) => {
-                        error!("Failed to run clam on file {e}");
-                        f.md.av_pass = false;
-                    }
-                }
+/// This function check the file given.
+/// Checks are made by the analyzers enabled in the registry,
+/// see [analyzer] for the list of the built-in ones.
+/// Checks results are marked in file metadata.
+/// This function does not modify the file.
+fn check_file(f: &mut FileData, registry: &Registry) {
+    // Each file is analyzed through its own descriptor,
+    // the one received is kept to be sent to keysas-out
+    match f.fd.try_clone() {
+        Ok(fd) => registry.run(&File::from(fd), &mut f.md),
+        Err(e) => {
+            error!("Cannot duplicate file descriptor for analysing {}: {e:?}", f.md.filename);
+            f.md.verdicts.push(AnalyzerVerdict {
+                analyzer: "transit".into(),
+                verdict: Verdict::Reject("Cannot open file for analysis".into()),
+            });
+        }
+    };
+    log::info!(
+        "Report for {}: digest_ok: {}, type_allowed: {}, yara_pass: {}, av_pass: {}, too_big: {}, rejected: {}",
+        f.md.filename,
+        f.md.is_digest_ok,
+        f.md.is_type_allowed,
+        f.md.yara_pass,
+        f.md.av_pass,
+        f.md.is_toobig,
+        f.md.verdicts.iter().any(|v| v.verdict.is_reject())
+    );
+}
 
-                // Position the cursor at the beginning of the file
-                match unistd::lseek(nfd, 0, unistd::Whence::SeekSet) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Unable to lseek on file descriptor: {e:?}, killing myself.");
-                        process::exit(1);
-                    }
-                }
-                // Check yara rules
-                match &conf.yara_rules {
-                    Some(rules) => match rules.scan_fd(&file, conf.yara_timeout) {
-                        Ok(results) => match results.is_empty() {
-                            true => {
-                                f.md.yara_pass = true;
-                            }
-                            false => {
-                                for result in results {
-                                    f.md.yara_report.push_str(result.identifier);
-                                }
-                                f.md.yara_pass = false;
-                                warn!("Yara rules matched");
-                            }
-                        },
-                        Err(e) => {
-                            error!("Yara cannot scan file {} error {e}", f.md.filename);
-                        }
-                    },
-                    None => {
-                        error!("Yara rules not present");
-                        f.md.yara_pass = false;
-                    }
-                }
-                // Position the cursor at the beginning of the file
-                match unistd::lseek(nfd, 0, unistd::Whence::SeekSet) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Unable to lseek on file descriptor: {e:?}, killing myself.");
-                        process::exit(1);
-                    }
-                }
-                // Check the magic number
-                // Read only 1Mo of the file to be faster and do not read large files
-                let reader = BufReader::new(&file);
-                let limited_reader = &mut reader.take(1024 * 1024);
-                let mut buffer = Vec::new();
-                match limited_reader.read_to_end(&mut buffer) {
-                    Ok(_) => {
-                        if !conf.type_off {
-                            f.md.is_type_allowed = check_is_extension_allowed(&buffer, conf);
-                            f.md.file_type = get_extension(buffer);
-                        } else {
-                            f.md.is_type_allowed = true;
-                            f.md.file_type = get_extension(buffer);
-                        }
-                    }
-                    Err(e) => {
-                        error!(
-                            "Cannot read limited buffer: {e:?}, file will be marked as not allowed !"
-                        );
-                        f.md.is_type_allowed = false;
-                        f.md.file_type = "Unknown".into();
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Cannot duplicate file descriptor for analysing: {e:?}, killing myself.");
-                process::exit(1);
-            }
-        };
-        log::info!(
-            "Report for {}: digest_ok: {}, type_allowed: {}, yara_pass: {}, av_pass: {}, too_big: {}",
-            f.md.filename,
-            f.md.is_digest_ok,
-            f.md.is_type_allowed,
-            f.md.yara_pass,
-            f.md.av_pass,
-            f.md.is_toobig
+/// This functions send the file filedescriptor and metadata to keysas-out
+/// and tells keysas-in once keysas-out is in charge of the file
+/// The file descriptor is closed once sent
+fn send_file(file: FileData, output: &mut Output, replies: &Mutex<Replies>) {
+    let generation = replies
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .generation();
+    // keysas-in has been restarted meanwhile and sends the file again
+    if file.generation != generation {
+        warn!(
+            "File {} received before keysas-in restarted, not sent.",
+            file.md.filename
         );
+        return;
     }
-}
-
-/// This functions send the files filedescriptor and metadata to the socket
-fn send_files(files: &Vec<FileData>, stream: &UnixStream) {
-    let config = bincode::config::standard();
-    for file in files {
-        // Get metadata
-        let data = match bincode::encode_to_vec(&file.md, config) {
-            Ok(d) => d,
-            Err(e) => {
-                error!("Failed to serialize: {e}");
-                process::exit(1);
-            }
-        };
-        let bufs = &[IoSlice::new(&data[..])];
-
-        // Send them on the socket
-        let mut ancillary_buffer = [0; 4096];
-        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
-        ancillary.add_fds(&[file.fd][..]);
-        match stream.send_vectored_with_ancillary(&bufs[..], &mut ancillary) {
-            Ok(_) => info!("File {} sent to Keysas-out.", file.md.filename),
-            Err(e) => error!("Failed to send file {e}."),
-        }
-        // Close the file descriptor
-        match unistd::close(file.fd) {
-            Ok(_) => info!("File descriptor {} closed for file {}.", file.fd, file.md.filename),
-            Err(e) => error!("Failed to close file descriptor {} for file {}: {e}", file.fd, file.md.filename),
-        }
+    let result = output.send(&file.md, file.fd.as_fd());
+    match &result {
+        Ok(_) => info!("File {} sent to Keysas-out.", file.md.filename),
+        Err(e) => error!(
+            "File {} refused by keysas-out: {}",
+            file.md.filename, e.message
+        ),
     }
+    replies
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .delivered(file.generation, result);
 }
 
 fn main() -> Result<()> {
     // Parse command arguments
-    let mut config = parse_args();
+    let config = parse_args();
 
     // Configure logger
     init_logger();
 
+    // The users of the peers are resolved before the sandbox is activated
+    let (in_uid, out_uid) = match (
+        protocol::user_id(&config.in_user),
+        protocol::user_id(&config.out_user),
+    ) {
+        (Ok(in_uid), Ok(out_uid)) => (in_uid, out_uid),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Cannot find the users of keysas-in and keysas-out: {e}");
+            process::exit(1);
+        }
+    };
+
     // Landlock initialization
-    match sandbox::landlock_sandbox(&config.rule_path) {
+    let rules_cache = match config.rules_cache.is_empty() {
+        true => None,
+        false => Some(PathBuf::from(&config.rules_cache)),
+    };
+    let rule_dirs: Vec<&Path> = config.rule_sources.iter().map(|s| s.directory()).collect();
+    match sandbox::landlock_sandbox(&rule_dirs, rules_cache.as_deref()) {
         Ok(_) => log::info!("Landlock sandbox activated."),
         Err(e) => log::warn!("Landlock sandbox cannot be activated: {e}"),
     }
@@ -494,65 +540,178 @@ fn main() -> Result<()> {
         Ok(_) => log::info!("Seccomp sandbox activated."),
         Err(e) => log::warn!("Seccomp sandbox cannot be activated: {e}"),
     }
-    // Initilize clamd client
-    // Test if ClamAV IP is valid
-    match config.clamav_ip.parse::<IpAddr>() {
-        Ok(_) => (),
+    // Initialize yara rules
+    let yara_rules = match RuleSet::load(&config.rule_sources, rules_cache.as_deref()) {
+        Ok(r) => {
+            info!("Yara rules initialized, active rule set: {}", r.hash());
+            r
+        }
         Err(e) => {
-            error!("ClamAV invalid IP address {e}");
+            error!("Failed to load yara rules {e:?}");
             process::exit(1);
         }
-    }
-    // Test if clamd is responding
-    let url = format!("{}{}{}", &config.clamav_ip, ":", config.clamav_port);
-    match url.to_socket_addrs() {
-        Ok(mut socket_addrs) => match socket_addrs.next() {
-            Some(clam_addr) => match version(clam_addr) {
-                Ok(v) => info!("Version: {v}"),
-                Err(e) => {
-                    error!("Clamav not available: {e:?}, killing my self.");
-                    process::exit(1);
-                }
-            },
-            None => {
-                error!(
-                    "Cannot parse any valid SocketAddr for connecting to clamav server, killing my self."
-                );
-                process::exit(1);
-            }
-        },
+    };
+    let yara = Arc::new(YaraScanner::new(
+        yara_rules,
+        config.yara_timeout,
+        config.yara_offsets,
+    ));
+    // Load the station policy
+    let policy = match Policy::load(&config.policy_path) {
+        Ok(p) => p,
         Err(e) => {
-            error!("Cannot parse clamav configuration: {e:?}")
+            error!("Cannot load the station policy: {e:?}");
+            process::exit(1);
         }
     };
 
-    // Initialize yara rules
-    match Compiler::new() {
-        Ok(c) => match c.add_rules_file_with_namespace(&config.rule_path, "keysas") {
-            Ok(c) => match c.compile_rules() {
-                Ok(r) => {
-                    info!("Yara compiler initialized.");
-                    config.yara_rules = Some(r);
-                }
+    // Initialize the anti-virus engines
+    // ClamAV on the command line socket or address is used if no engine is set in the policy
+    let engines = match policy.av.engines.is_empty() {
+        true if !config.clamav_socket.is_empty() => vec![EngineConfig::ClamavUnix {
+            name: "clamav".into(),
+            path: config.clamav_socket.clone(),
+        }],
+        true => {
+            // Test if ClamAV IP is valid
+            match config.clamav_ip.parse::<IpAddr>() {
+                Ok(_) => (),
                 Err(e) => {
-                    error!("Failed to compile yara rules {e}");
+                    error!("ClamAV invalid IP address {e}");
                     process::exit(1);
                 }
-            },
-            Err(e) => {
-                error!("Failed to add yara rules to compiler {e}");
-                process::exit(1);
             }
+            vec![EngineConfig::ClamavTcp {
+                name: "clamav".into(),
+                address: format!("{}:{}", config.clamav_ip, config.clamav_port),
+            }]
+        }
+        false => policy.av.engines.clone(),
+    };
+    let av = match Antivirus::new(
+        engines
+            .iter()
+            .map(|e| e.build(policy.av.timeout()))
+            .collect(),
+        policy.av.decision,
+        policy.av.retry(),
+    ) {
+        Ok(av) => Arc::new(av),
+        Err(e) => {
+            error!("Invalid anti-virus configuration: {e}");
+            process::exit(1);
+        }
+    };
+    // Test if the engines are responding
+    // Files are not transfered while an engine is unavailable, it is checked again on each scan
+    av.check();
+    let unavailable = av.unavailable();
+    if !unavailable.is_empty() {
+        warn!(
+            "Anti-virus engines not available at startup: {}",
+            unavailable.join(", ")
+        );
+    }
+
+    // Load the lists of digests maintained by the administrator
+    let (deny_list, allow_list) = match (
+        HashList::load(&config.deny_list),
+        HashList::load(&config.allow_list),
+    ) {
+        (Ok(deny), Ok(allow)) => {
+            info!(
+                "Hash lists loaded: {} denied and {} allowed digests.",
+                deny.len(),
+                allow.len()
+            );
+            (deny, allow)
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Cannot load the hash lists: {e:?}");
+            process::exit(1);
+        }
+    };
+
+    // The profiles of the policy replace the maximum size and the allowed types of the command line
+    let profiles = Arc::new(match policy.profiles {
+        Some(p) => {
+            info!(
+                "Type profiles loaded from the policy: {} types.",
+                p.types.len()
+            );
+            p
+        }
+        None => Profiles::from_flags(config.max_size, &config.magic_list, config.type_off),
+    });
+
+    // Register the built-in analyzers in their execution order
+    let mut registry = Registry::new(profiles.clone());
+    registry.set_budget(
+        Duration::from_secs(config.analysis_timeout),
+        Arc::new(MemoryPool::new(config.memory_limit)),
+    );
+    registry.register(Box::new(DigestAnalyzer));
+    registry.register(Box::new(HashListAnalyzer::new(deny_list, allow_list)));
+    registry.register(Box::new(SizeAnalyzer));
+    registry.register(Box::new(AvAnalyzer::new(av.clone())));
+    registry.register(Box::new(YaraAnalyzer::new(yara.clone())));
+    registry.register(Box::new(MagicAnalyzer));
+    registry.register(Box::new(ExtensionAnalyzer::new(policy.extension)));
+    registry.register(Box::new(ArchiveAnalyzer::new(
+        ArchiveLimits {
+            max_depth: config.archive_max_depth,
+            max_entries: config.archive_max_entries,
+            max_ratio: config.archive_max_ratio,
         },
+        profiles,
+        av.clone(),
+        yara.clone(),
+    )));
+    registry.register(Box::new(OfficeAnalyzer::new(policy.office)));
+    registry.register(Box::new(PdfAnalyzer::new(policy.pdf)));
+    registry.register(Box::new(MetadataAnalyzer::new(policy.metadata)));
+    registry.register(Box::new(TextAnalyzer::new(policy.text)));
+    registry.register(Box::new(EntropyAnalyzer::new(policy.entropy)));
+    match registry.configure(&config.analyzers) {
+        Ok(_) => info!("Enabled analyzers: {}", registry.enabled().join(", ")),
+        Err(e) => {
+            error!("Invalid analyzers configuration: {e}");
+            process::exit(1);
+        }
+    }
+
+    // The workers are forked from a zygote started before any thread of the daemon,
+    // its copy of the Yara rules is reloaded with the one of the daemon
+    let zygote = match Zygote::start(
+        &registry,
+        Some(Reloader::new(
+            yara.clone(),
+            config.rule_sources.clone(),
+            rules_cache.clone(),
+        )),
+    ) {
+        Ok(z) => Arc::new(z),
         Err(e) => {
-            error!("Failed to initialize yara compiler {e}");
+            error!("Cannot start the zygote of the analysis workers: {e}");
             process::exit(1);
         }
     };
+    // Each file is analyzed in a sandboxed worker process
+    registry.isolate(zygote.clone(), av);
+    let registry = Arc::new(registry);
+
+    // Reload the rules on SIGHUP or when they change, the files being analyzed are not interrupted
+    let reloader = Reloader::new(yara, config.rule_sources.clone(), rules_cache);
+    if let Err(e) = reloader.watch(config.rules_reload, move || zygote.reload()) {
+        error!("Cannot watch the yara rules {e}");
+        process::exit(1);
+    }
 
     // Open socket with keysas-in
-    let addr_in = SocketAddr::from_abstract_name(&config.socket_in)?;
-    let sock_in = match UnixStream::connect_addr(&addr_in) {
+    // The socket must be the one of keysas-in, it is connected again if keysas-in restarts
+    let connect = || match protocol::connect(&config.socket_in, in_uid)
+        .and_then(|s| Ok((s.try_clone()?, s)))
+    {
         Ok(s) => {
             info!("Connected to Keysas-in socket.");
             s
@@ -577,44 +736,66 @@ fn main() -> Result<()> {
     };
 
     // Wait to be connected with keysas-out before starting to accept files in
-    let (out_stream, _sck_addr) = match sock_out.accept() {
-        Ok(r) => r,
+    // Only keysas-out is allowed to connect
+    let mut output = Output::new(sock_out, out_uid);
+    let (mut sock_in, replies_stream) = connect();
+    let replies = Arc::new(Mutex::new(Replies::new(replies_stream)));
+
+    // Start the workers, analyzed files are sent to out in the order they were received
+    let sink_replies = replies.clone();
+    let mut pool = match Pool::new(config.workers, registry, move |f| {
+        send_file(f, &mut output, &sink_replies)
+    }) {
+        Ok(p) => {
+            info!("{} workers started.", config.workers);
+            p
+        }
         Err(e) => {
-            error!("Failed to accept connection: {e}");
+            error!("Failed to start the workers: {e}");
             process::exit(1);
         }
     };
 
-    // Allocate buffers for input messages
-    let mut ancillary_buffer_in = [0; 128];
-    let mut ancillary_in = SocketAncillary::new(&mut ancillary_buffer_in[..]);
-
     // Main loop
     // 1. receive file descriptors from in
-    // 2. run check on the file
-    // 3. send fd and report to out
+    // 2. queue the files for the workers running the checks
+    // 3. the workers send fd and report to out, keysas-in is told once out has the file
     loop {
-        // 4128 => filename max 4096 bytes and digest 32 bytes
-        let mut buf_in = [0; 4128];
-        let bufs_in = &mut [IoSliceMut::new(&mut buf_in[..])][..];
-
-        // Listen for message on socket
-        match sock_in.recv_vectored_with_ancillary(bufs_in, &mut ancillary_in) {
-            Ok(size) => info!("Receiving data from keysas-in, message size: {size}"),
+        let received = protocol::receive_file::<InputMetadata>(&sock_in);
+        let mut replies_lock = replies.lock().unwrap_or_else(PoisonError::into_inner);
+        let f = match received {
+            Ok((meta, fd)) => new_file(meta, fd, replies_lock.received()),
+            Err(e) if e.is_fatal() => {
+                // keysas-in is told why the connection is closed
+                protocol::refuse(&sock_in, &e);
+                if e.is_version() {
+                    error!("Keysas-in runs another version of the protocol: {e}");
+                    process::exit(1);
+                }
+                // The files in flight are sent again by keysas-in
+                warn!("Connection to keysas-in lost: {e}, connecting again");
+                drop(replies_lock);
+                let (stream, replies_stream) = connect();
+                sock_in = stream;
+                replies
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .reset(replies_stream);
+                continue;
+            }
             Err(e) => {
-                warn!("Failed to receive fds from in: {e}");
-                process::exit(1);
+                // The file is refused, keysas-in is told why
+                warn!("File from keysas-in refused: {e}");
+                replies_lock.refused(e.reply());
+                continue;
             }
-        }
-
-        // Parse messages received
-        let mut files = parse_messages(ancillary_in.messages(), &buf_in);
-
-        // Run check on message received
-        check_files(&mut files, &config, url.clone());
+        };
+        drop(replies_lock);
 
-        // Send fd and report to out
-        send_files(&files, &out_stream);
-        main_thread::sleep(Duration::from_millis(100));
+        // Queue the file, blocks while the workers are busy
+        if let Err(e) = pool.submit(f) {
+            error!("Failed to queue file for analysis: {e}");
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the station policy loaded by keysas-transit.
+ */
+
+//! Station policy
+//!
+//! The policy is a JSON file that decides how the findings of the analyzers are handled.
+//! Each section is optional, a missing section or field takes its default value.
+//! If the file does not exist the default policy is applied.
+//!
+//! ```json
+//! {
+//!     "office": {
+//!         "default": {
+//!             "macros": "reject",
+//!             "ole_objects": "reject",
+//!             "external_links": "flag",
+//!             "dde": "reject"
+//!         },
+//!         "formats": {
+//!             "xlsx": { "external_links": "allow" }
+//!         }
+//!     },
+//!     "pdf": {
+//!         "javascript": "reject",
+//!         "open_action": "flag",
+//!         "launch": "reject",
+//!         "embedded_files": "reject",
+//!         "xfa": "flag",
+//!         "encryption": "reject",
+//!         "malformed_xref": "reject"
+//!     },
+//!     "extension": {
+//!         "mismatch": "reject",
+//!         "double_extension": "reject",
+//!         "bidi_control": "reject",
+//!         "missing": "allow",
+//!         "aliases": { "zip": ["kdbx"] }
+//!     },
+//!     "metadata": {
+//!         "default": "allow",
+//!         "fields": { "gps": "reject", "printer": "flag" }
+//!     },
+//!     "text": {
+//!         "latin1": "allow",
+//!         "control_characters": "reject",
+//!         "nul": "reject",
+//!         "malformed": "reject",
+//!         "formulas": "flag"
+//!     },
+//!     "entropy": {
+//!         "high_entropy": "flag",
+//!         "packer": "reject",
+//!         "score": "reject",
+//!         "threshold": 80,
+//!         "block_entropy": 7.2,
+//!         "block_size": 65536
+//!     },
+//!     "av": {
+//!         "decision": "any",
+//!         "engines": [
+//!             { "type": "clamav_tcp", "name": "clamav", "address": "127.0.0.1:3310" }
+//!         ]
+//!     },
+//!     "profiles": {
+//!         "default": { "max_size": 52428800, "action": "reject" },
+//!         "types": {
+//!             "mp4": { "max_size": 2147483648 }
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::analyzer::av::AvPolicy;
+use crate::analyzer::entropy::EntropyPolicy;
+use crate::analyzer::extension::ExtensionPolicy;
+use crate::analyzer::metadata::MetadataPolicy;
+use crate::analyzer::office::OfficePolicy;
+use crate::analyzer::pdf::PdfPolicy;
+use crate::analyzer::text::TextPolicy;
+use crate::profile::Profiles;
+use anyhow::{Context, Result};
+use keysas_lib::file_report::{Action, Verdict};
+use log::info;
+use serde_derive::Deserialize;
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Policy applied by the analyzers
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Handling of active contents in office documents
+    pub office: OfficePolicy,
+    /// Handling of active contents and anomalies in PDF documents
+    pub pdf: PdfPolicy,
+    /// Handling of the mismatches between the file names and their type
+    pub extension: ExtensionPolicy,
+    /// Handling of the metadata embedded in images, office and PDF documents
+    pub metadata: MetadataPolicy,
+    /// Handling of the anomalies in text, CSV, XML and JSON files
+    pub text: TextPolicy,
+    /// Handling of packed executables and high entropy contents
+    pub entropy: EntropyPolicy,
+    /// Anti-virus engines and decision applied to their results
+    pub av: AvPolicy,
+    /// Size limit, handling and analyzers of each file type,
+    /// replaces the maximum size and the allowed types of the command line
+    pub profiles: Option<Profiles>,
+}
+
+impl Policy {
+    /// Load the policy from a JSON file
+    /// The default policy is returned if the file does not exist
+    pub fn load(path: &str) -> Result<Policy> {
+        match fs::read_to_string(Path::new(path)) {
+            Ok(content) => {
+                serde_json::from_str(&content).with_context(|| format!("Invalid policy {path}"))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("No policy found at {path}, using the default policy.");
+                Ok(Policy::default())
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to read policy {path}")),
+        }
+    }
+}
+
+/// Convert the action decided by the policy into an analyzer verdict
+pub fn verdict(action: Action, reason: String) -> Verdict {
+    match action {
+        Action::Allow => Verdict::Pass,
+        Action::Flag => Verdict::Flag(reason),
+        Action::Reject => Verdict::Reject(reason),
+    }
+}
+
+/// Returns the verdict for a list of findings with the action decided for each of them
+/// The most restrictive action is applied and the reason lists the kinds of
+/// findings it applies to, e.g. "{message}: Macro, Dde"
+pub fn findings_verdict<K: Debug + PartialEq>(
+    findings: impl IntoIterator<Item = (K, Action)>,
+    message: &str,
+) -> Verdict {
+    let mut action = Action::Allow;
+    let mut kinds: Vec<K> = Vec::new();
+    for (kind, a) in findings {
+        if a > action {
+            action = a;
+            kinds.clear();
+        }
+        if a == action && !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    let kinds: Vec<String> = kinds.iter().map(|k| format!("{k:?}")).collect();
+    verdict(action, format!("{message}: {}", kinds.join(", ")))
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the worker pool analyzing
+ * several files at once.
+ */
+
+//! Worker pool analyzing several files at once
+//!
+//! Files received from keysas-in are numbered and dispatched to a fixed number
+//! of workers through a bounded queue. When the queue is full, [Pool::submit]
+//! blocks and keysas-in is not read anymore until a worker is available.
+//!
+//! Analyzed files are reordered by their number before being given to the sink,
+//! keysas-out receives them in the order they were received from keysas-in.
+//! The number of files held by the pool is bounded: a file taking a long time
+//! to be analyzed stops the intake once the queues behind it are full.
+
+use crate::analyzer::Registry;
+use crate::{FileData, check_file};
+use anyhow::{Result, anyhow};
+use log::error;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Pool of workers running the analyzers of the registry
+#[derive(Debug)]
+pub struct Pool {
+    /// Number given to the next file submitted
+    next: u64,
+    /// Queue of the files waiting for a worker
+    jobs: Option<SyncSender<(u64, FileData)>>,
+    /// Workers and the thread giving the files to the sink
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    /// Start `size` workers, analyzed files are given in order to `sink`
+    pub fn new<S>(size: usize, registry: Arc<Registry>, sink: S) -> Result<Self>
+    where
+        S: FnMut(FileData) + Send + 'static,
+    {
+        if size == 0 {
+            return Err(anyhow!("The pool needs at least one worker"));
+        }
+        let (jobs, jobs_rx) = sync_channel::<(u64, FileData)>(size);
+        let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+        let (results, results_rx) = sync_channel::<(u64, FileData)>(size);
+
+        let mut threads = Vec::with_capacity(size + 1);
+        for i in 0..size {
+            let jobs_rx = jobs_rx.clone();
+            let results = results.clone();
+            let registry = registry.clone();
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("worker-{i}"))
+                    .spawn(move || work(&jobs_rx, &results, &registry))?,
+            );
+        }
+        // Only the workers hold a sender, the results channel is closed when they stop
+        drop(results);
+        threads.push(
+            thread::Builder::new()
+                .name("sender".into())
+                .spawn(move || reorder(results_rx, sink))?,
+        );
+
+        Ok(Self {
+            next: 0,
+            jobs: Some(jobs),
+            threads,
+        })
+    }
+
+    /// Queue a file for analysis, blocks while all the workers are busy
+    pub fn submit(&mut self, file: FileData) -> Result<()> {
+        let jobs = self
+            .jobs
+            .as_ref()
+            .ok_or_else(|| anyhow!("The pool is stopped"))?;
+        jobs.send((self.next, file))
+            .map_err(|_| anyhow!("No worker is running"))?;
+        self.next += 1;
+        Ok(())
+    }
+}
+
+impl Drop for Pool {
+    /// Wait for the files already submitted to be given to the sink
+    fn drop(&mut self) {
+        self.jobs.take();
+        for t in self.threads.drain(..) {
+            if t.join().is_err() {
+                error!("A thread of the pool panicked");
+            }
+        }
+    }
+}
+
+/// Analyze the files of the queue until it is closed
+fn work(
+    jobs: &Mutex<Receiver<(u64, FileData)>>,
+    results: &SyncSender<(u64, FileData)>,
+    registry: &Registry,
+) {
+    loop {
+        // The lock is released as soon as a file is received
+        let job = match jobs.lock() {
+            Ok(rx) => rx.recv(),
+            Err(_) => return,
+        };
+        let (seq, mut file) = match job {
+            Ok(j) => j,
+            Err(_) => return,
+        };
+        check_file(&mut file, registry);
+        if results.send((seq, file)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Give the analyzed files to the sink in the order of their number
+fn reorder<S: FnMut(FileData)>(results: Receiver<(u64, FileData)>, mut sink: S) {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for (seq, file) in results {
+        pending.insert(seq, file);
+        while let Some(file) = pending.remove(&next) {
+            sink(file);
+            next += 1;
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the profiles applied to each file type.
+ */
+
+//! Type profiles
+//!
+//! A profile sets the maximum size, the handling and the analyzers of the files
+//! of a type, detected from their magic number. The profiles are set in the
+//! "profiles" section of the station policy, in place of the command line flags
+//! setting the maximum size and the allowed types:
+//!
+//! ```json
+//! {
+//!     "profiles": {
+//!         "default": { "max_size": 52428800, "action": "reject" },
+//!         "types": {
+//!             "mp4": {
+//!                 "max_size": 2147483648,
+//!                 "analyzers": ["digest", "hashlist", "size", "av", "magic", "extension"]
+//!             },
+//!             "pdf": { "max_size": 52428800 },
+//!             "zip": { "max_size": 52428800, "action": "flag" }
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The default profile applies to the types not listed and to the files whose type is unknown.
+//! The hashlist analyzer is run on all the types, whether their profile lists it or not.
+
+use anyhow::{Result, anyhow};
+use keysas_lib::file_report::Action;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+
+/// Maximum size of the files if not set by the profile
+pub const DEFAULT_MAX_SIZE: u64 = 500_000_000;
+
+/// Handling of the files of a type
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Maximum size of the files
+    pub max_size: u64,
+    /// Action applied to the files by the magic analyzer
+    pub action: Action,
+    /// Analyzers run on the files, all the enabled analyzers if not set
+    pub analyzers: Option<Vec<String>>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+            action: Action::Allow,
+            analyzers: None,
+        }
+    }
+}
+
+impl Profile {
+    /// Returns true if the analyzer is run on the files of the type
+    pub fn runs(&self, analyzer: &str) -> bool {
+        self.analyzers
+            .as_ref()
+            .is_none_or(|a| a.iter().any(|n| n == analyzer))
+    }
+}
+
+/// Profiles of the file types
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles {
+    /// Profile of the types not listed
+    pub default: Profile,
+    /// Profiles by type, named after the extension of the type
+    pub types: BTreeMap<String, Profile>,
+}
+
+impl Default for Profiles {
+    /// Types not listed are rejected
+    fn default() -> Self {
+        Self {
+            default: Profile {
+                action: Action::Reject,
+                ..Profile::default()
+            },
+            types: BTreeMap::new(),
+        }
+    }
+}
+
+impl Profiles {
+    /// Profiles equivalent to the command line flags: the allowed types share the maximum size,
+    /// other types are rejected unless the type check is disabled
+    pub fn from_flags(max_size: u64, magic_list: &[String], type_off: bool) -> Self {
+        let allowed = Profile {
+            max_size,
+            ..Profile::default()
+        };
+        Self {
+            default: Profile {
+                action: match type_off {
+                    true => Action::Allow,
+                    false => Action::Reject,
+                },
+                ..allowed.clone()
+            },
+            types: magic_list
+                .iter()
+                .map(|t| (t.clone(), allowed.clone()))
+                .collect(),
+        }
+    }
+
+    /// Profile of a type, given as the extension detected from its magic number
+    pub fn get(&self, file_type: &str) -> &Profile {
+        self.types.get(file_type).unwrap_or(&self.default)
+    }
+
+    /// Returns an error if a profile names an analyzer that is not known
+    /// or not enabled, it would be skipped silently
+    pub fn check_analyzers(&self, known: &[&str], enabled: &[String]) -> Result<()> {
+        let profiles = self
+            .types
+            .iter()
+            .map(|(t, p)| (t.as_str(), p))
+            .chain([("default", &self.default)]);
+        for (file_type, profile) in profiles {
+            for name in profile.analyzers.iter().flatten() {
+                if !known.contains(&name.as_str()) {
+                    return Err(anyhow!("Unknown analyzer {name} in profile {file_type}"));
+                }
+                if !enabled.contains(name) {
+                    return Err(anyhow!(
+                        "Analyzer {name} in profile {file_type} is not enabled"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the Yara rule sets loaded by keysas-transit.
+ */
+
+//! Yara rule sets
+//!
+//! The rules are loaded from several sources given as `[NAMESPACE=]PATH`:
+//!  - a file is compiled in the namespace "keysas" by default, its includes are resolved
+//!    from its directory,
+//!  - each `.yar` or `.yara` file of a directory is compiled in the namespace named after
+//!    the directory by default.
+//!
+//! The rule set is identified by a SHA-256 hash of the namespaces and of the rule files
+//! (the includes of a file are expected in its directory). This hash names the precompiled
+//! rules saved in the cache directory and is stamped in each report.
+//!
+//! The rule set is reloaded on SIGHUP or when a rule file changes. The files being analyzed
+//! are scanned with the rule set active when their scan started.
+
+use crate::analyzer::yara::YaraScanner;
+use anyhow::{Context, Result, anyhow};
+use log::{error, info, warn};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use yara::{Compiler, Rule, Rules};
+
+/// Namespace of a rule file given without namespace
+pub const DEFAULT_NAMESPACE: &str = "keysas";
+
+/// Extensions of the rule files
+const RULE_EXTENSIONS: [&str; 2] = ["yar", "yara"];
+
+/// Extension of the precompiled rules in the cache
+const CACHE_EXTENSION: &str = "yarc";
+
+/// Set by the SIGHUP handler, cleared by the watcher
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Source of Yara rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSource {
+    pub namespace: String,
+    pub path: PathBuf,
+}
+
+impl RuleSource {
+    /// Parse a source in the form [NAMESPACE=]PATH
+    pub fn parse(source: &str) -> Self {
+        match source.split_once('=') {
+            Some((namespace, path)) => Self {
+                namespace: namespace.to_string(),
+                path: PathBuf::from(path),
+            },
+            None => {
+                let path = PathBuf::from(source);
+                let namespace = match path.is_dir() {
+                    true => path
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string()),
+                    false => DEFAULT_NAMESPACE.to_string(),
+                };
+                Self { namespace, path }
+            }
+        }
+    }
+
+    /// Directory of the rules, read by the includes
+    pub fn directory(&self) -> &Path {
+        match self.path.is_dir() {
+            true => &self.path,
+            false => match self.path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            },
+        }
+    }
+
+    /// Files compiled in the namespace, sorted by name
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        match self.path.is_dir() {
+            true => rule_files(&self.path, false),
+            false => Ok(vec![self.path.clone()]),
+        }
+    }
+
+    /// Files that can change the rules: the compiled files and the rule files of
+    /// the directory and its sub-directories, sorted by name
+    fn watched(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = rule_files(self.directory(), true)?;
+        files.extend(self.files()?);
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+}
+
+/// Rule files of a directory
+fn rule_files(dir: &Path, recursive: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                files.extend(rule_files(&path, true)?);
+            }
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| RULE_EXTENSIONS.contains(&e))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Hash of the namespaces and of the content of the rule files
+pub fn ruleset_hash(sources: &[RuleSource]) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    for source in sources {
+        hasher.update((source.namespace.len() as u64).to_le_bytes());
+        hasher.update(source.namespace.as_bytes());
+        for file in source.files()? {
+            let name = file.as_os_str().as_bytes();
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name);
+        }
+        for file in source.watched()? {
+            let content = fs::read(&file)?;
+            hasher.update((content.len() as u64).to_le_bytes());
+            hasher.update(content);
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Size and modification time of the rule files, cheaper to check than the hash
+fn fingerprint(sources: &[RuleSource]) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut fingerprint = Vec::new();
+    for source in sources {
+        for file in source.watched()? {
+            let meta = fs::metadata(&file)?;
+            fingerprint.push((file, meta.len(), meta.modified()?));
+        }
+    }
+    Ok(fingerprint)
+}
+
+/// Compiled Yara rules and the hash identifying them
+pub struct RuleSet {
+    rules: Rules,
+    hash: String,
+}
+
+impl RuleSet {
+    /// Load the rules from the cache or compile them
+    /// The compiled rules are saved in the cache, the previous rule sets are removed from it
+    pub fn load(sources: &[RuleSource], cache: Option<&Path>) -> Result<Self> {
+        let hash = ruleset_hash(sources).context("Cannot read the Yara rules")?;
+        let cached = cache.map(|dir| dir.join(format!("{hash}.{CACHE_EXTENSION}")));
+        match &cached {
+            Some(file) if file.exists() => match Rules::load_from_file(&file.to_string_lossy()) {
+                Ok(rules) => {
+                    info!("Yara rules {hash} loaded from the cache.");
+                    return Ok(Self { rules, hash });
+                }
+                Err(e) => warn!("Cannot load the cached Yara rules {}: {e}", file.display()),
+            },
+            _ => (),
+        }
+
+        let mut compiler = Compiler::new()?;
+        for source in sources {
+            for file in source.files()? {
+                compiler = compiler
+                    .add_rules_file_with_namespace(&file, &source.namespace)
+                    .map_err(|e| anyhow!("{}: {e}", file.display()))?;
+            }
+        }
+        let mut rules = compiler.compile_rules()?;
+        info!("Yara rules {hash} compiled.");
+
+        if let Some(file) = &cached {
+            match rules.save(&file.to_string_lossy()) {
+                Ok(_) => prune_cache(file),
+                Err(e) => warn!("Cannot save the Yara rules in the cache: {e}"),
+            }
+        }
+        Ok(Self { rules, hash })
+    }
+
+    /// Hash of the rule set
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Scan the data with the rules
+    pub fn scan(&self, data: &[u8], timeout: i32) -> Result<Vec<Rule<'_>>, yara::Error> {
+        self.rules.scan_mem(data, timeout)
+    }
+}
+
+/// Remove the rule sets other than the one just saved from the cache
+fn prune_cache(keep: &Path) {
+    let entries = match keep.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return,
+    };
+    let stale = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p != keep && p.extension().is_some_and(|e| e == CACHE_EXTENSION));
+    for path in stale {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Cannot remove {} from the cache: {e}", path.display());
+        }
+    }
+}
+
+/// Reload the rules of a scanner when they change
+pub struct Reloader {
+    scanner: Arc<YaraScanner>,
+    sources: Vec<RuleSource>,
+    cache: Option<PathBuf>,
+    fingerprint: Option<Vec<(PathBuf, u64, SystemTime)>>,
+}
+
+impl Reloader {
+    pub fn new(
+        scanner: Arc<YaraScanner>,
+        sources: Vec<RuleSource>,
+        cache: Option<PathBuf>,
+    ) -> Self {
+        let fingerprint = fingerprint(&sources).ok();
+        Self {
+            scanner,
+            sources,
+            cache,
+            fingerprint,
+        }
+    }
+
+    /// Returns true if a rule file has been added, removed or modified since the last check
+    pub fn changed(&mut self) -> bool {
+        let fingerprint = fingerprint(&self.sources).ok();
+        match fingerprint == self.fingerprint {
+            true => false,
+            false => {
+                self.fingerprint = fingerprint;
+                true
+            }
+        }
+    }
+
+    /// Replace the rule set of the scanner if the rules have changed
+    /// Returns true if the rule set has been replaced,
+    /// the previous rule set is kept if the new one cannot be loaded
+    pub fn reload(&self) -> Result<bool> {
+        let hash = ruleset_hash(&self.sources).context("Cannot read the Yara rules")?;
+        if hash == self.scanner.rules().hash() {
+            return Ok(false);
+        }
+        let rules = RuleSet::load(&self.sources, self.cache.as_deref())?;
+        self.scanner.replace(rules);
+        Ok(true)
+    }
+
+    /// Reload the rules on SIGHUP and check the rule files every interval (in seconds, 0 to disable)
+    /// `reloaded` is called each time the rule set is replaced
+    pub fn watch<F>(mut self, interval: u64, reloaded: F) -> Result<()>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let handler = SigAction::new(
+            SigHandler::Handler(on_sighup),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        // SAFETY: the handler only stores an atomic boolean
+        unsafe { sigaction(Signal::SIGHUP, &handler) }?;
+        thread::Builder::new()
+            .name("yara-rules".into())
+            .spawn(move || {
+                let mut elapsed = 0;
+                loop {
+                    thread::sleep(Duration::from_secs(1));
+                    elapsed += 1;
+                    let signaled = RELOAD.swap(false, Ordering::Relaxed);
+                    let changed = match interval > 0 && elapsed >= interval {
+                        true => {
+                            elapsed = 0;
+                            self.changed()
+                        }
+                        false => false,
+                    };
+                    if !signaled && !changed {
+                        continue;
+                    }
+                    match self.reload() {
+                        Ok(true) => {
+                            info!(
+                                "Yara rules reloaded, active rule set: {}",
+                                self.scanner.rules().hash()
+                            );
+                            reloaded();
+                        }
+                        Ok(false) => info!("Yara rules unchanged."),
+                        Err(e) => error!(
+                            "Cannot reload the Yara rules, keeping the active rule set: {e:?}"
+                        ),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+extern "C" fn on_sighup(_: nix::libc::c_int) {
+    RELOAD.store(true, Ordering::Relaxed);
+}
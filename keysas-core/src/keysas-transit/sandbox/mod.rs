@@ -14,10 +14,9 @@ use landlock::{
     RulesetError, RulesetStatus, path_beneath_rules,
 };
 use std::path::Path;
-use std::process;
 
 #[cfg(target_os = "linux")]
-use syscallz::{Context, Syscall};
+use syscallz::{Action, Context, Syscall};
 
 #[cfg(target_os = "linux")]
 pub fn init() -> Result<()> {
@@ -35,6 +34,7 @@ pub fn init() -> Result<()> {
     ctx.allow_syscall(Syscall::recvmsg)?;
     ctx.allow_syscall(Syscall::lseek)?;
     ctx.allow_syscall(Syscall::socket)?;
+    ctx.allow_syscall(Syscall::socketpair)?;
     ctx.allow_syscall(Syscall::mmap)?;
     ctx.allow_syscall(Syscall::sendmsg)?;
     ctx.allow_syscall(Syscall::statx)?;
@@ -81,29 +81,79 @@ pub fn init() -> Result<()> {
     ctx.allow_syscall(Syscall::clock_gettime)?;
     ctx.allow_syscall(Syscall::exit_group)?;
     ctx.allow_syscall(Syscall::fstatfs)?;
+    // Workers analyzing several files at once
+    ctx.allow_syscall(Syscall::clone)?;
+    ctx.allow_syscall(Syscall::clone3)?;
+    ctx.allow_syscall(Syscall::exit)?;
+    ctx.allow_syscall(Syscall::sched_yield)?;
+    // Duplication of the file descriptors for analysis
+    ctx.allow_syscall(Syscall::fcntl)?;
+    // Timeouts of the connections with the anti-virus engines
+    ctx.allow_syscall(Syscall::setsockopt)?;
+    ctx.allow_syscall(Syscall::getsockopt)?;
+    // Reload of the Yara rules on SIGHUP and update of the rules cache
+    ctx.allow_syscall(Syscall::rt_sigreturn)?;
+    ctx.allow_syscall(Syscall::getdents64)?;
+    #[cfg(target_arch = "x86_64")]
+    ctx.allow_syscall(Syscall::unlink)?;
+    ctx.allow_syscall(Syscall::unlinkat)?;
+    // Worker processes analyzing the files
+    ctx.allow_syscall(Syscall::pipe2)?;
+    ctx.allow_syscall(Syscall::close_range)?;
+    ctx.allow_syscall(Syscall::seccomp)?;
+    ctx.allow_syscall(Syscall::kill)?;
+    ctx.allow_syscall(Syscall::wait4)?;
     ctx.load()?;
     Ok(())
 }
 
-pub fn landlock_sandbox(rule_path: &String) -> Result<(), RulesetError> {
-    let rules = Path::new(rule_path);
-    let rules = match rules.parent() {
-        Some(rules) => rules,
-        None => {
-            log::error!("Error getting Yara rules directory for Landlock");
-            process::exit(1);
-        }
-    };
+/// Filter of the worker processes analyzing the files
+/// They only read the file, in memory, and their pipes with the daemon:
+/// the other system calls fail, sockets and files cannot be opened
+#[cfg(target_os = "linux")]
+pub fn init_worker() -> Result<()> {
+    let mut ctx = Context::init_with_action(Action::Errno(nix::libc::EPERM as u16))?;
+    ctx.allow_syscall(Syscall::read)?;
+    ctx.allow_syscall(Syscall::write)?;
+    ctx.allow_syscall(Syscall::close)?;
+    ctx.allow_syscall(Syscall::lseek)?;
+    ctx.allow_syscall(Syscall::pread64)?;
+    ctx.allow_syscall(Syscall::fsync)?;
+    ctx.allow_syscall(Syscall::fstat)?;
+    ctx.allow_syscall(Syscall::newfstatat)?;
+    ctx.allow_syscall(Syscall::statx)?;
+    ctx.allow_syscall(Syscall::mmap)?;
+    ctx.allow_syscall(Syscall::munmap)?;
+    ctx.allow_syscall(Syscall::mremap)?;
+    ctx.allow_syscall(Syscall::mprotect)?;
+    ctx.allow_syscall(Syscall::madvise)?;
+    ctx.allow_syscall(Syscall::brk)?;
+    ctx.allow_syscall(Syscall::futex)?;
+    ctx.allow_syscall(Syscall::sched_yield)?;
+    ctx.allow_syscall(Syscall::clock_gettime)?;
+    ctx.allow_syscall(Syscall::clock_nanosleep)?;
+    ctx.allow_syscall(Syscall::getrandom)?;
+    ctx.allow_syscall(Syscall::rt_sigprocmask)?;
+    ctx.allow_syscall(Syscall::rt_sigreturn)?;
+    ctx.allow_syscall(Syscall::sigaltstack)?;
+    ctx.allow_syscall(Syscall::exit)?;
+    ctx.allow_syscall(Syscall::exit_group)?;
+    ctx.load()?;
+    Ok(())
+}
+
+pub fn landlock_sandbox(rule_dirs: &[&Path], cache: Option<&Path>) -> Result<(), RulesetError> {
     let abi = ABI::V2;
+    let mut read_only = vec![Path::new(CONFIG_DIRECTORY)];
+    read_only.extend_from_slice(rule_dirs);
     let status = Ruleset::default()
         .handle_access(AccessFs::from_all(abi))?
         .set_compatibility(CompatLevel::HardRequirement)
         .create()?
         // Read-only access.
-        .add_rules(path_beneath_rules(
-            &[CONFIG_DIRECTORY, &rules.to_string_lossy()],
-            AccessFs::from_read(abi),
-        ))?
+        .add_rules(path_beneath_rules(read_only, AccessFs::from_read(abi)))?
+        // Read-write access to the cache of the compiled Yara rules
+        .add_rules(path_beneath_rules(cache, AccessFs::from_all(abi)))?
         .restrict_self()?;
     match status.ruleset {
         // The FullyEnforced case must be tested.
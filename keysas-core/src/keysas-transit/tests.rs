@@ -0,0 +1,1685 @@
+use crate::analyzer::archive::{ArchiveAnalyzer, ArchiveLimits};
+use crate::analyzer::av::clamav::parse_response;
+use crate::analyzer::av::icap::IcapResponse;
+use crate::analyzer::av::{Antivirus, AvAnalyzer, AvDecision, EngineConfig, Retry, ScanResult};
+use crate::analyzer::budget::{Budget, MemoryPool};
+use crate::analyzer::entropy::{EntropyAnalyzer, EntropyPolicy};
+use crate::analyzer::extension::{ExtensionAnalyzer, ExtensionPolicy};
+use crate::analyzer::hashlist::HashListAnalyzer;
+use crate::analyzer::magic::MagicAnalyzer;
+use crate::analyzer::metadata::{MetadataAnalyzer, MetadataPolicy};
+use crate::analyzer::office::{OfficeAnalyzer, OfficePolicy, OfficeRules};
+use crate::analyzer::pdf::{PdfAnalyzer, PdfPolicy};
+use crate::analyzer::size::SizeAnalyzer;
+use crate::analyzer::text::{TextAnalyzer, TextPolicy};
+use crate::analyzer::yara::{YaraScanner, rule_names, to_match};
+use crate::analyzer::{Analyzer, Content, Registry, Stream};
+use crate::link::Replies;
+use crate::policy::Policy;
+use crate::pool::Pool;
+use crate::profile::Profiles;
+use crate::rules::{DEFAULT_NAMESPACE, Reloader, RuleSet, RuleSource, ruleset_hash};
+use crate::worker::Zygote;
+use crate::{FileData, FileMetadata, check_file};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use keysas_lib::file_report::{
+    Action, AvEngineReport, EntropyFindingKind, ExtensionFindingKind, HashListKind, MetadataField,
+    OfficeFindingKind, PdfFindingKind, TextFindingKind, Verdict, YaraMetaValue,
+};
+use keysas_lib::hash_list::HashList;
+use keysas_lib::protocol::{self, ErrorCode, ErrorReply, ProtocolError};
+use keysas_lib::session::SessionManifest;
+use keysas_lib::sha256_digest;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, Write};
+use std::net::TcpListener;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::tempfile;
+use yara::{Match, Metadata, MetadataValue, Rule, YrString};
+
+struct DummyAnalyzer;
+
+impl Analyzer for DummyAnalyzer {
+    fn name(&self) -> &'static str {
+        "dummy"
+    }
+
+    fn analyze(&self, _content: &Content, md: &mut FileMetadata) -> Verdict {
+        md.yara_pass = false;
+        Verdict::Reject("dummy".into())
+    }
+
+    fn bypass(&self, md: &mut FileMetadata) {
+        md.yara_pass = true;
+    }
+}
+
+fn dummy_metadata() -> FileMetadata {
+    FileMetadata {
+        filename: "file.txt".into(),
+        path: "file.txt".into(),
+        session: SessionManifest::default(),
+        digest: String::new(),
+        is_digest_ok: false,
+        is_toobig: true,
+        size: 0,
+        is_type_allowed: false,
+        av_pass: false,
+        av_report: Vec::new(),
+        yara_pass: false,
+        yara_report: String::new(),
+        timestamp: String::new(),
+        is_corrupted: false,
+        file_type: "Unknown".into(),
+        verdicts: Vec::new(),
+        archive_entries: Vec::new(),
+        office_findings: Vec::new(),
+        pdf_findings: Vec::new(),
+        extension_findings: Vec::new(),
+        hash_list: None,
+        av_engines: Vec::new(),
+        entropy: None,
+        text_findings: Vec::new(),
+        metadata_findings: Vec::new(),
+        yara_matches: Vec::new(),
+        yara_ruleset: String::new(),
+    }
+}
+
+#[test]
+fn test_registry_configure() {
+    let mut registry = Registry::default();
+    registry.register(Box::new(DummyAnalyzer));
+    assert!(registry.configure(&["unknown".to_string()]).is_err());
+    assert!(registry.configure(&[]).is_err());
+    assert!(registry.configure(&["dummy".to_string()]).is_ok());
+    assert_eq!(registry.enabled(), vec!["dummy"]);
+}
+
+#[test]
+fn test_registry_run() {
+    let mut registry = Registry::default();
+    registry.register(Box::new(DummyAnalyzer));
+    let file = tempfile().unwrap();
+
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert!(!md.yara_pass);
+    assert_eq!(md.verdicts.len(), 1);
+    assert_eq!(md.verdicts[0].verdict, Verdict::Reject("dummy".into()));
+
+    // A disabled analyzer is bypassed and does not record a verdict
+    let mut registry = Registry::default();
+    registry.register(Box::new(DummyAnalyzer));
+    registry.register(Box::new(crate::analyzer::digest::DigestAnalyzer));
+    registry.configure(&["digest".to_string()]).unwrap();
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert!(md.yara_pass);
+    assert_eq!(md.verdicts.len(), 1);
+    assert_eq!(md.verdicts[0].analyzer, "digest");
+}
+
+#[test]
+fn test_check_file_keeps_descriptor() {
+    let mut registry = Registry::default();
+    registry.register(Box::new(DummyAnalyzer));
+    let mut file = tempfile().unwrap();
+    file.write_all(b"content").unwrap();
+    let mut f = FileData {
+        fd: OwnedFd::from(file),
+        md: dummy_metadata(),
+        generation: 0,
+    };
+    check_file(&mut f, &registry);
+    assert_eq!(f.md.verdicts.len(), 1);
+
+    // The received descriptor is still open to be sent to keysas-out
+    let mut file = File::from(f.fd);
+    file.rewind().unwrap();
+    let mut content = String::new();
+    file.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "content");
+}
+
+fn archive_analyzer(max_depth: u32, max_entries: usize, max_ratio: u64) -> ArchiveAnalyzer {
+    ArchiveAnalyzer::new(
+        ArchiveLimits {
+            max_depth,
+            max_entries,
+            max_ratio,
+        },
+        Arc::new(Profiles::from_flags(
+            10_000_000,
+            &["tar".to_string(), "gz".to_string()],
+            false,
+        )),
+        antivirus(
+            vec![clamav_tcp("clamav", "127.0.0.1:1".into())],
+            AvDecision::Any,
+        ),
+        Arc::new(YaraScanner::new(
+            RuleSet::load(&[], None).unwrap(),
+            10,
+            false,
+        )),
+    )
+}
+
+fn tar_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *content).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+/// Content of a temporary file containing the data
+fn file_content(data: &[u8], streams: &mut [Box<dyn Stream>]) -> Content {
+    named_content("", data, streams)
+}
+
+/// Content of a temporary file containing the data, whose type can be given by its name
+fn named_content(name: &str, data: &[u8], streams: &mut [Box<dyn Stream>]) -> Content {
+    let mut file = tempfile().unwrap();
+    file.write_all(data).unwrap();
+    let mut content = Content::load(
+        &file,
+        name,
+        &Profiles::from_flags(10_000_000, &[], true),
+        Budget::default(),
+    )
+    .unwrap();
+    content.read(streams.iter_mut());
+    content
+}
+
+fn run_analyzer(analyzer: &dyn Analyzer, content: &[u8]) -> (Verdict, FileMetadata) {
+    let mut md = dummy_metadata();
+    md.is_type_allowed = true;
+    let verdict = analyzer.analyze(&file_content(content, &mut []), &mut md);
+    (verdict, md)
+}
+
+#[test]
+fn test_archive_max_entries() {
+    let archive = tar_archive(&[("a", b"a"), ("b", b"b"), ("c", b"c"), ("d", b"d")]);
+
+    let (verdict, md) = run_analyzer(&archive_analyzer(3, 10, 100), &archive);
+    assert!(verdict.is_reject());
+    assert_eq!(md.archive_entries.len(), 4);
+    assert_eq!(md.archive_entries[0].path, "a");
+
+    let (verdict, _) = run_analyzer(&archive_analyzer(3, 3, 100), &archive);
+    assert_eq!(
+        verdict,
+        Verdict::Reject("Archive contains more than 3 entries".into())
+    );
+}
+
+#[test]
+fn test_archive_max_depth() {
+    let inner = tar_archive(&[("file", b"content")]);
+    let middle = tar_archive(&[("inner.tar", &inner)]);
+    let outer = tar_archive(&[("middle.tar", &middle)]);
+
+    let (_, md) = run_analyzer(&archive_analyzer(3, 10, 100), &outer);
+    let paths: Vec<&str> = md.archive_entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "middle.tar",
+            "middle.tar/inner.tar",
+            "middle.tar/inner.tar/file"
+        ]
+    );
+    assert_eq!(md.archive_entries[2].depth, 3);
+
+    let (verdict, _) = run_analyzer(&archive_analyzer(2, 10, 100), &outer);
+    assert_eq!(
+        verdict,
+        Verdict::Reject("Archive nesting is deeper than 2 levels".into())
+    );
+}
+
+#[test]
+fn test_archive_max_ratio() {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&[0u8; 1_000_000]).unwrap();
+    let bomb = encoder.finish().unwrap();
+
+    let (verdict, _) = run_analyzer(&archive_analyzer(3, 10, 10), &bomb);
+    assert!(verdict.is_reject());
+    assert!(
+        matches!(verdict, Verdict::Reject(r) if r.starts_with("Uncompressed size is over the limit"))
+    );
+}
+
+#[test]
+fn test_archive_entry_type() {
+    // Text entries have no magic number, their type is given by their name
+    let archive = tar_archive(&[("notes.csv", b"a,b\n1,2\n"), ("run.sh", b"ls\n")]);
+    let analyzer = ArchiveAnalyzer::new(
+        ArchiveLimits {
+            max_depth: 3,
+            max_entries: 10,
+            max_ratio: 100,
+        },
+        Arc::new(Profiles::from_flags(
+            10_000_000,
+            &["tar".to_string(), "csv".to_string()],
+            false,
+        )),
+        antivirus(
+            vec![clamav_tcp("clamav", "127.0.0.1:1".into())],
+            AvDecision::Any,
+        ),
+        Arc::new(YaraScanner::new(
+            RuleSet::load(&[], None).unwrap(),
+            10,
+            false,
+        )),
+    );
+    let (_, md) = run_analyzer(&analyzer, &archive);
+    assert!(md.archive_entries[0].is_type_allowed);
+    assert!(!md.archive_entries[1].is_type_allowed);
+}
+
+fn docx_document(parts: &[(&str, &str)]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    for (name, content) in parts {
+        writer.start_file(*name, options).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn test_office_active_content() {
+    let document = docx_document(&[
+        ("[Content_Types].xml", "<Types/>"),
+        (
+            "word/document.xml",
+            r#"<w:document xmlns:w="w"><w:body><w:p>
+            <w:r><w:fldChar w:fldCharType="begin"/></w:r>
+            <w:r><w:instrText> DDEAUTO c:\\windows\\system32\\cmd.exe "/k calc"</w:instrText></w:r>
+            <w:r><w:fldChar w:fldCharType="end"/></w:r>
+            </w:p></w:body></w:document>"#,
+        ),
+        (
+            "word/_rels/document.xml.rels",
+            r#"<Relationships><Relationship Id="rId1"
+            Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/attachedTemplate"
+            Target="http://example.com/template.dotm" TargetMode="External"/></Relationships>"#,
+        ),
+        ("word/vbaProject.bin", "vba"),
+    ]);
+
+    let analyzer = OfficeAnalyzer::new(OfficePolicy::default());
+    let (verdict, md) = run_analyzer(&analyzer, &document);
+    assert!(verdict.is_reject());
+    let kinds: Vec<OfficeFindingKind> = md.office_findings.iter().map(|f| f.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            OfficeFindingKind::Dde,
+            OfficeFindingKind::ExternalLink,
+            OfficeFindingKind::Macro
+        ]
+    );
+    assert_eq!(
+        md.office_findings[1].detail,
+        "attachedTemplate: http://example.com/template.dotm"
+    );
+    assert_eq!(md.office_findings[1].action, Action::Flag);
+
+    // Allow everything but the external links for docx documents
+    let mut policy = OfficePolicy::default();
+    policy.formats.insert(
+        "docx".into(),
+        OfficeRules {
+            macros: Action::Allow,
+            ole_objects: Action::Allow,
+            external_links: Action::Flag,
+            dde: Action::Allow,
+        },
+    );
+    let (verdict, _) = run_analyzer(&OfficeAnalyzer::new(policy), &document);
+    assert_eq!(
+        verdict,
+        Verdict::Flag("Active content found in document: ExternalLink".into())
+    );
+}
+
+/// Build a PDF document with the objects numbered from 1, the first one is the catalog
+/// `shift` is added to the offsets of the cross reference table
+fn pdf_document(objects: &[&str], shift: usize) -> Vec<u8> {
+    let mut pdf = b"%PDF-1.7\n".to_vec();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len() + shift);
+        pdf.extend(format!("{} 0 obj\n{object}\nendobj\n", i + 1).as_bytes());
+    }
+    let xref = pdf.len();
+    pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        pdf.extend(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    pdf.extend(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        )
+        .as_bytes(),
+    );
+    pdf
+}
+
+#[test]
+fn test_pdf_active_content() {
+    let analyzer = PdfAnalyzer::new(PdfPolicy::default());
+
+    let clean = pdf_document(
+        &[
+            "<< /Type /Catalog /Pages 2 0 R /OpenAction [3 0 R /Fit] >>",
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R >>",
+        ],
+        0,
+    );
+    let (verdict, md) = run_analyzer(&analyzer, &clean);
+    assert_eq!(verdict, Verdict::Pass);
+    assert!(md.pdf_findings.is_empty());
+
+    let document = pdf_document(
+        &[
+            "<< /Type /Catalog /Pages 2 0 R /OpenAction 4 0 R /AcroForm << /XFA 5 0 R >> >>",
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>",
+            "<< /Type /Page /Parent 2 0 R /AA << /O << /S /Launch /F (cmd.exe) >> >> >>",
+            "<< /S /JavaScript /JS (app.alert(1)) >>",
+            "<< /Type /Filespec /UF (payload.exe) /EF << /F 6 0 R >> >>",
+            "<< /Type /EmbeddedFile /Length 0 >>\nstream\n\nendstream",
+        ],
+        0,
+    );
+    let (verdict, md) = run_analyzer(&analyzer, &document);
+    let found: Vec<(PdfFindingKind, &str)> = md
+        .pdf_findings
+        .iter()
+        .map(|f| (f.kind, f.object.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (PdfFindingKind::OpenAction, "1 0 R"),
+            (PdfFindingKind::Xfa, "1 0 R"),
+            (PdfFindingKind::OpenAction, "3 0 R"),
+            (PdfFindingKind::Launch, "3 0 R"),
+            (PdfFindingKind::JavaScript, "4 0 R"),
+            (PdfFindingKind::EmbeddedFile, "5 0 R"),
+        ]
+    );
+    assert_eq!(md.pdf_findings[0].detail, "OpenAction: JavaScript");
+    assert_eq!(md.pdf_findings[5].detail, "payload.exe");
+    assert_eq!(
+        verdict,
+        Verdict::Reject(
+            "Active content found in PDF document: Launch, JavaScript, EmbeddedFile".into()
+        )
+    );
+}
+
+#[test]
+fn test_pdf_malformed_xref() {
+    // Offsets of the cross reference table point to the wrong objects
+    let document = pdf_document(
+        &[
+            "<< /Type /Catalog /Pages 2 0 R >>",
+            "<< /Type /Pages /Kids [] /Count 0 >>",
+            "<< /S /J#61vaScript /JS (app.alert(1)) >>",
+        ],
+        3,
+    );
+    let (verdict, md) = run_analyzer(&PdfAnalyzer::new(PdfPolicy::default()), &document);
+    assert!(verdict.is_reject());
+    let kinds: Vec<PdfFindingKind> = md.pdf_findings.iter().map(|f| f.kind).collect();
+    assert!(kinds.contains(&PdfFindingKind::MalformedXref));
+    assert!(kinds.contains(&PdfFindingKind::JavaScript));
+}
+
+fn extension_kinds(
+    analyzer: &ExtensionAnalyzer,
+    filename: &str,
+    content: &[u8],
+) -> Vec<ExtensionFindingKind> {
+    let mut md = dummy_metadata();
+    md.filename = filename.into();
+    analyzer.analyze(&file_content(content, &mut []), &mut md);
+    md.extension_findings.iter().map(|f| f.kind).collect()
+}
+
+#[test]
+fn test_extension_mismatch() {
+    let analyzer = ExtensionAnalyzer::new(ExtensionPolicy::default());
+    let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+    let jpg = b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00";
+    let gz = b"\x1F\x8B\x08\x00\x00\x00\x00\x00";
+
+    assert!(extension_kinds(&analyzer, "photo.png", png).is_empty());
+    assert!(extension_kinds(&analyzer, "photo.JPEG", jpg).is_empty());
+    assert!(extension_kinds(&analyzer, "backup.tar.gz", gz).is_empty());
+    assert!(extension_kinds(&analyzer, "notes.txt", b"plain text").is_empty());
+    assert_eq!(
+        extension_kinds(&analyzer, "photo", png),
+        vec![ExtensionFindingKind::Missing]
+    );
+    assert_eq!(
+        extension_kinds(&analyzer, "photo.jpg", png),
+        vec![ExtensionFindingKind::Mismatch]
+    );
+    assert_eq!(
+        extension_kinds(&analyzer, "invoice.pdf.exe", png),
+        vec![
+            ExtensionFindingKind::DoubleExtension,
+            ExtensionFindingKind::Mismatch
+        ]
+    );
+    assert_eq!(
+        extension_kinds(&analyzer, "invoice.pdf   .sh", b"#!/bin/sh"),
+        vec![ExtensionFindingKind::DoubleExtension]
+    );
+    assert_eq!(
+        extension_kinds(&analyzer, "photo.png.txt", png),
+        vec![
+            ExtensionFindingKind::DoubleExtension,
+            ExtensionFindingKind::Mismatch
+        ]
+    );
+    assert_eq!(
+        extension_kinds(&analyzer, "invoice\u{202E}fdp.exe", b"MZ"),
+        vec![ExtensionFindingKind::BidiControl]
+    );
+    // A docx that is a plain zip archive
+    let zip = docx_document(&[("script.js", "alert(1)")]);
+    assert_eq!(
+        extension_kinds(&analyzer, "report.docx", &zip),
+        vec![ExtensionFindingKind::Mismatch]
+    );
+
+    // Aliases added by the policy
+    let mut policy = ExtensionPolicy::default();
+    policy.aliases.insert("zip".into(), vec!["kdbx".into()]);
+    let analyzer = ExtensionAnalyzer::new(policy);
+    assert!(extension_kinds(&analyzer, "secrets.kdbx", &zip).is_empty());
+}
+
+/// Analyzer taking more time for the first files
+struct SlowAnalyzer;
+
+impl Analyzer for SlowAnalyzer {
+    fn name(&self) -> &'static str {
+        "slow"
+    }
+
+    fn analyze(&self, _content: &Content, md: &mut FileMetadata) -> Verdict {
+        let rank: u64 = md.filename.parse().unwrap();
+        thread::sleep(Duration::from_millis((8 - rank) * 20));
+        Verdict::Pass
+    }
+}
+
+/// Data with an entropy close to 8 bits per byte
+fn random_data(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_entropy() {
+    let analyzer = EntropyAnalyzer::new(EntropyPolicy::default());
+    let kinds = |md: &FileMetadata| -> Vec<EntropyFindingKind> {
+        let report = md.entropy.as_ref().unwrap();
+        report.findings.iter().map(|f| f.kind).collect()
+    };
+    let text = b"The quick brown fox jumps over the lazy dog. ".repeat(2000);
+
+    let (verdict, md) = run_analyzer(&analyzer, &text);
+    assert_eq!(verdict, Verdict::Pass);
+    let report = md.entropy.as_ref().unwrap();
+    assert!(report.entropy > 3.0 && report.entropy < 5.0);
+    assert!(report.score < report.threshold);
+    assert_eq!(report.high_blocks, 0);
+
+    // Encrypted payload appended to a text file
+    let mut hidden = text.clone();
+    hidden.extend(random_data(128 * 1024));
+    let (verdict, md) = run_analyzer(&analyzer, &hidden);
+    assert!(matches!(verdict, Verdict::Flag(_)));
+    assert_eq!(kinds(&md), vec![EntropyFindingKind::HighEntropy]);
+    let finding = &md.entropy.as_ref().unwrap().findings[0];
+    // The region is made of whole blocks covering the payload
+    assert!(finding.offset <= text.len() as u64);
+    assert_eq!(finding.offset + finding.length, hidden.len() as u64);
+
+    // Random data reach the score threshold
+    let (verdict, md) = run_analyzer(&analyzer, &random_data(256 * 1024));
+    assert!(verdict.is_reject());
+    assert_eq!(
+        kinds(&md),
+        vec![EntropyFindingKind::HighEntropy, EntropyFindingKind::Score]
+    );
+    assert!(md.entropy.as_ref().unwrap().max_block_entropy > 7.9);
+
+    // Compressed types are exempted
+    let mut gz = b"\x1F\x8B\x08\x00\x00\x00\x00\x00".to_vec();
+    gz.extend(random_data(256 * 1024));
+    let (verdict, md) = run_analyzer(&analyzer, &gz);
+    assert_eq!(verdict, Verdict::Pass);
+    assert_eq!(md.entropy.as_ref().unwrap().score, 0);
+    assert!(kinds(&md).is_empty());
+
+    // Packed executable
+    let mut exe = b"MZ".to_vec();
+    exe.resize(0x200, 0);
+    exe.extend(b"UPX0\0\0\0\0");
+    exe.resize(0x400, 0);
+    let (verdict, md) = run_analyzer(&analyzer, &exe);
+    assert!(verdict.is_reject());
+    assert_eq!(kinds(&md), vec![EntropyFindingKind::Packer]);
+    assert_eq!(md.entropy.as_ref().unwrap().findings[0].offset, 0x200);
+
+    // Actions and threshold of the policy
+    let analyzer = EntropyAnalyzer::new(EntropyPolicy {
+        high_entropy: Action::Allow,
+        threshold: 101,
+        ..EntropyPolicy::default()
+    });
+    let (verdict, md) = run_analyzer(&analyzer, &random_data(256 * 1024));
+    assert_eq!(verdict, Verdict::Pass);
+    assert_eq!(kinds(&md), vec![EntropyFindingKind::HighEntropy]);
+}
+
+fn text_kinds(
+    analyzer: &TextAnalyzer,
+    filename: &str,
+    data: &[u8],
+) -> (Verdict, Vec<TextFindingKind>) {
+    let mut md = dummy_metadata();
+    md.filename = filename.into();
+    md.is_type_allowed = true;
+    let verdict = analyzer.analyze(&named_content(filename, data, &mut []), &mut md);
+    (verdict, md.text_findings.iter().map(|f| f.kind).collect())
+}
+
+#[test]
+fn test_text_validation() {
+    let analyzer = TextAnalyzer::new(TextPolicy::default());
+
+    let (verdict, kinds) = text_kinds(&analyzer, "notes.txt", "Élément\tvalide\r\n".as_bytes());
+    assert_eq!(verdict, Verdict::Pass);
+    assert!(kinds.is_empty());
+    let (verdict, kinds) = text_kinds(&analyzer, "notes.txt", b"\xC9l\xE9ment en Latin-1\n");
+    assert_eq!(verdict, Verdict::Pass);
+    assert_eq!(kinds, vec![TextFindingKind::Latin1]);
+    let (verdict, kinds) = text_kinds(&analyzer, "notes.TXT", b"line\nescape \x1B[2J\x00");
+    assert!(verdict.is_reject());
+    assert_eq!(
+        kinds,
+        vec![TextFindingKind::Nul, TextFindingKind::ControlCharacter]
+    );
+    // C1 control characters
+    let (_, kinds) = text_kinds(&analyzer, "notes.txt", "a\u{85}b".as_bytes());
+    assert_eq!(kinds, vec![TextFindingKind::ControlCharacter]);
+    // Other types are not checked
+    let (verdict, _) = text_kinds(&analyzer, "data.bin", b"\x00\x01");
+    assert_eq!(verdict, Verdict::Pass);
+
+    // Well-formed documents
+    let xml = b"<?xml version=\"1.0\"?>\n<root><item x=\"1\"/>text</root>\n";
+    assert!(text_kinds(&analyzer, "doc.xml", xml).1.is_empty());
+    let json = b"\xEF\xBB\xBF{\"a\": [1, 2, {\"b\": null}]}";
+    assert!(text_kinds(&analyzer, "doc.json", json).1.is_empty());
+    for (name, data) in [
+        ("doc.xml", &b"<root><item></root>"[..]),
+        ("doc.xml", b"<root></root><item/>"),
+        ("doc.xml", b"<root>"),
+        ("doc.json", b"{\"a\": [1, 2}"),
+    ] {
+        let (verdict, kinds) = text_kinds(&analyzer, name, data);
+        assert!(verdict.is_reject(), "{data:?}");
+        assert_eq!(kinds, vec![TextFindingKind::Malformed]);
+    }
+
+    // Formula injection in CSV cells
+    let csv = b"name;amount;phone\nalice;+12.5;\"+33 1 23\"\nbob,=HYPERLINK(\"http://x\")\n\"@SUM(A1)\"\n";
+    let mut md = dummy_metadata();
+    md.filename = "data.csv".into();
+    md.is_type_allowed = true;
+    let verdict = analyzer.analyze(&named_content("data.csv", csv, &mut []), &mut md);
+    assert!(matches!(verdict, Verdict::Flag(_)));
+    let cells: Vec<(u64, u64)> = md
+        .text_findings
+        .iter()
+        .map(|f| (f.line, f.column))
+        .collect();
+    assert_eq!(cells, vec![(2, 3), (3, 2), (4, 1)]);
+    assert!(md.text_findings[1].detail.contains("=HYPERLINK("));
+}
+
+/// Build a JPEG image whose EXIF tags hold an artist and a GPS position
+fn jpeg_image() -> Vec<u8> {
+    let entry = |tag: u16, kind: u16, count: u32, value: u32| {
+        [
+            &tag.to_le_bytes()[..],
+            &kind.to_le_bytes(),
+            &count.to_le_bytes(),
+            &value.to_le_bytes(),
+        ]
+        .concat()
+    };
+    let rationals = |values: [u32; 3]| -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| [v.to_le_bytes(), 1u32.to_le_bytes()].concat())
+            .collect()
+    };
+    // Header, IFD0 at 8, GPS directory at 38, then the values at 92
+    let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+    tiff.extend(2u16.to_le_bytes());
+    tiff.extend(entry(0x013B, 2, 6, 92));
+    tiff.extend(entry(0x8825, 4, 1, 38));
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend(4u16.to_le_bytes());
+    tiff.extend(entry(1, 2, 2, u32::from(b'N')));
+    tiff.extend(entry(2, 5, 3, 98));
+    tiff.extend(entry(3, 2, 2, u32::from(b'E')));
+    tiff.extend(entry(4, 5, 3, 122));
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend(b"Alice\0");
+    tiff.extend(rationals([48, 51, 30]));
+    tiff.extend(rationals([2, 17, 40]));
+
+    let mut jpeg = b"\xFF\xD8\xFF\xE1".to_vec();
+    jpeg.extend((tiff.len() as u16 + 8).to_be_bytes());
+    jpeg.extend(b"Exif\0\0");
+    jpeg.extend(tiff);
+    jpeg.extend(b"\xFF\xFE\0\x0Bcomment\0\0");
+    jpeg.extend(b"\xFF\xDA\0\x02\x12\x34\xFF\xD9");
+    jpeg
+}
+
+#[test]
+fn test_metadata() {
+    let fields = |md: &FileMetadata| -> Vec<(MetadataField, String)> {
+        md.metadata_findings
+            .iter()
+            .map(|f| (f.field, f.value.clone()))
+            .collect()
+    };
+    let analyzer = MetadataAnalyzer::new(MetadataPolicy::default());
+    let image = jpeg_image();
+    let (verdict, md) = run_analyzer(&analyzer, &image);
+    assert_eq!(verdict, Verdict::Pass);
+    assert_eq!(
+        fields(&md),
+        vec![
+            (MetadataField::Author, "Alice".into()),
+            (MetadataField::Gps, "48.858333 N, 2.294444 E".into()),
+            (MetadataField::Description, "comment".into()),
+        ]
+    );
+    assert_eq!(md.metadata_findings[0].source, "EXIF");
+    assert_eq!(md.metadata_findings[0].name, "Artist");
+
+    // Core properties, tracked changes and comments of a Word document
+    let document = docx_document(&[
+        ("[Content_Types].xml", "<Types/>"),
+        (
+            "docProps/core.xml",
+            r#"<cp:coreProperties xmlns:cp="cp" xmlns:dc="dc">
+            <dc:creator>Alice</dc:creator><cp:lastModifiedBy>Bob</cp:lastModifiedBy>
+            <cp:revision>7</cp:revision></cp:coreProperties>"#,
+        ),
+        (
+            "word/document.xml",
+            r#"<w:document xmlns:w="w"><w:body><w:p>
+            <w:ins w:id="1" w:author="Carol"><w:r><w:t>added</w:t></w:r></w:ins>
+            </w:p></w:body></w:document>"#,
+        ),
+        (
+            "word/comments.xml",
+            r#"<w:comments xmlns:w="w"><w:comment w:id="0" w:author="Dave"/></w:comments>"#,
+        ),
+    ]);
+    let (verdict, md) = run_analyzer(&analyzer, &document);
+    assert_eq!(verdict, Verdict::Pass);
+    assert_eq!(
+        fields(&md),
+        vec![
+            (MetadataField::Author, "Alice".into()),
+            (MetadataField::LastModifiedBy, "Bob".into()),
+            (MetadataField::Revision, "7".into()),
+            (MetadataField::Revision, "Carol".into()),
+            (MetadataField::Author, "Dave".into()),
+        ]
+    );
+
+    // GPS positions are rejected, the other authors flagged
+    let mut policy = MetadataPolicy::default();
+    policy.fields.insert(MetadataField::Gps, Action::Reject);
+    policy.fields.insert(MetadataField::Author, Action::Flag);
+    let analyzer = MetadataAnalyzer::new(policy);
+    let (verdict, md) = run_analyzer(&analyzer, &image);
+    assert!(verdict.is_reject());
+    assert_eq!(md.metadata_findings[1].action, Action::Reject);
+    let (verdict, _) = run_analyzer(&analyzer, &document);
+    assert!(matches!(verdict, Verdict::Flag(_)));
+}
+
+#[test]
+fn test_pool_order() {
+    let mut registry = Registry::default();
+    registry.register(Box::new(SlowAnalyzer));
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let sink = sent.clone();
+    let mut pool = Pool::new(4, Arc::new(registry), move |f: FileData| {
+        sink.lock()
+            .unwrap()
+            .push((f.md.filename, f.md.verdicts.len()));
+    })
+    .unwrap();
+    for rank in 0..8 {
+        let mut md = dummy_metadata();
+        md.filename = rank.to_string();
+        let fd = OwnedFd::from(tempfile().unwrap());
+        pool.submit(FileData {
+            fd,
+            md,
+            generation: 0,
+        })
+        .unwrap();
+    }
+    // Dropping the pool waits for all the files to be sent
+    drop(pool);
+    let sent = sent.lock().unwrap();
+    let expected: Vec<(String, usize)> = (0..8).map(|r| (r.to_string(), 1)).collect();
+    assert_eq!(*sent, expected);
+    assert!(Pool::new(0, Arc::new(Registry::default()), |_| ()).is_err());
+}
+
+#[test]
+fn test_registry_timeout() {
+    let mut registry = Registry::default();
+    registry.set_budget(Duration::from_millis(50), Arc::new(MemoryPool::default()));
+    registry.register(Box::new(SlowAnalyzer));
+    registry.register(Box::new(DummyAnalyzer));
+    registry.register(Box::new(crate::analyzer::digest::DigestAnalyzer));
+    let file = tempfile().unwrap();
+    let mut md = dummy_metadata();
+    md.filename = "0".into();
+    registry.run(&file, &mut md);
+
+    // The analyzer started after the timeout is not run, the next ones are bypassed
+    assert_eq!(md.verdicts.len(), 2);
+    assert_eq!(md.verdicts[0].verdict, Verdict::Pass);
+    assert_eq!(md.verdicts[1].analyzer, "dummy");
+    assert!(matches!(md.verdicts[1].verdict, Verdict::Timeout(_)));
+    assert!(md.verdicts[1].verdict.is_reject());
+    assert!(!md.yara_pass);
+}
+
+#[test]
+fn test_budget_memory() {
+    let pool = Arc::new(MemoryPool::new(100));
+    let budget = Budget::new(Duration::from_millis(50), pool.clone());
+    assert!(matches!(budget.reserve(150), Err(Verdict::Reject(_))));
+    let reserved = budget.reserve(80).unwrap();
+    // Waits for the memory until the timeout
+    assert!(matches!(budget.reserve(30), Err(Verdict::Timeout(_))));
+    assert!(budget.is_expired());
+    drop(reserved);
+    let budget = Budget::new(Duration::from_millis(50), pool.clone());
+    drop(budget.reserve(100).unwrap());
+
+    // Memory released by another file wakes up the waiting one
+    let budget = Budget::new(Duration::from_secs(10), pool.clone());
+    let reserved = budget.reserve(80).unwrap();
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        drop(reserved);
+    });
+    let reserved = budget.reserve(30).unwrap();
+    release.join().unwrap();
+    drop(reserved);
+    drop(budget.reserve(100).unwrap());
+
+    // No limit by default
+    assert!(Budget::default().reserve(u64::MAX).is_ok());
+}
+
+/// Stream recording the data received
+struct RecordStream(Arc<Mutex<Vec<u8>>>);
+
+impl Stream for RecordStream {
+    fn update(&mut self, chunk: &[u8]) {
+        self.0.lock().unwrap().extend_from_slice(chunk);
+    }
+
+    fn finish(self: Box<Self>, _content: &Content, _md: &mut FileMetadata) -> Verdict {
+        Verdict::Pass
+    }
+}
+
+#[test]
+fn test_content_single_read() {
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut streams: Vec<Box<dyn Stream>> = vec![Box::new(RecordStream(received.clone()))];
+    let content = file_content(&data, &mut streams);
+    assert_eq!(content.size, data.len() as u64);
+    assert_eq!(content.digest, sha256_digest(&data[..]).unwrap());
+    assert_eq!(*received.lock().unwrap(), data);
+    assert_eq!(content.data(), &data[..]);
+    assert_eq!(content.head().len(), 1024 * 1024);
+    assert_eq!(content.prefix(10), &data[..10]);
+
+    // Empty files are read too
+    let content = file_content(b"", &mut []);
+    assert_eq!(content.size, 0);
+    assert!(content.data().is_empty());
+    assert_eq!(content.digest, sha256_digest(&b""[..]).unwrap());
+
+    // The content is a private copy of the file, in memory reserved from the budget
+    let mut file = tempfile().unwrap();
+    file.write_all(b"content").unwrap();
+    let profiles = Profiles::from_flags(10_000_000, &[], true);
+    let budget = Budget::new(Duration::from_secs(1), Arc::new(MemoryPool::new(4)));
+    let verdict = Content::load(&file, "", &profiles, budget).unwrap_err();
+    assert!(verdict.is_reject());
+    let content = Content::load(&file, "", &profiles, Budget::default()).unwrap();
+    file.set_len(2).unwrap();
+    assert_eq!(content.data(), b"content");
+}
+
+/// Minimal clamd answering one INSTREAM session
+fn clamd_session(mut conn: impl Read + Write) {
+    let mut command = [0; 10];
+    conn.read_exact(&mut command).unwrap();
+    assert_eq!(&command, b"zINSTREAM\0");
+    let mut data = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        conn.read_exact(&mut len).unwrap();
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            break;
+        }
+        let mut chunk = vec![0; len];
+        conn.read_exact(&mut chunk).unwrap();
+        data.extend_from_slice(&chunk);
+    }
+    let response: &[u8] = match data.windows(5).any(|w| w == b"EICAR") {
+        true => b"stream: Eicar-Signature FOUND\0",
+        false => b"stream: OK\0",
+    };
+    conn.write_all(response).unwrap();
+}
+
+/// Minimal clamd listening on TCP
+fn fake_clamd() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || clamd_session(listener.accept().unwrap().0));
+    addr
+}
+
+const NO_RETRY: Retry = Retry {
+    attempts: 0,
+    delay: Duration::ZERO,
+};
+
+fn antivirus(engines: Vec<EngineConfig>, decision: AvDecision) -> Arc<Antivirus> {
+    let engines = engines
+        .iter()
+        .map(|e| e.build(Duration::from_secs(5)))
+        .collect();
+    Arc::new(Antivirus::new(engines, decision, NO_RETRY).unwrap())
+}
+
+fn clamav_tcp(name: &str, address: String) -> EngineConfig {
+    EngineConfig::ClamavTcp {
+        name: name.into(),
+        address,
+    }
+}
+
+#[test]
+fn test_clamav_instream() {
+    assert_eq!(
+        parse_response("stream: OK\0").unwrap(),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        parse_response("stream: Eicar-Signature FOUND\0").unwrap(),
+        vec!["Eicar-Signature".to_string()]
+    );
+    assert!(parse_response("INSTREAM size limit exceeded. ERROR\0").is_err());
+
+    // The file is streamed to clamd during the single read
+    for (data, av_pass) in [(&b"clean"[..], true), (&b"xxEICARxx"[..], false)] {
+        let mut registry = Registry::default();
+        let av = antivirus(vec![clamav_tcp("clamav", fake_clamd())], AvDecision::Any);
+        registry.register(Box::new(AvAnalyzer::new(av)));
+        let mut file = tempfile().unwrap();
+        file.write_all(data).unwrap();
+        let mut md = dummy_metadata();
+        registry.run(&file, &mut md);
+        assert_eq!(md.av_pass, av_pass);
+        assert_eq!(md.verdicts[0].verdict.is_reject(), !av_pass);
+        assert_eq!(md.av_engines[0].engine, "clamav");
+    }
+
+    // clamd on its local unix socket
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("clamd.ctl");
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || clamd_session(listener.accept().unwrap().0));
+    let av = antivirus(
+        vec![EngineConfig::ClamavUnix {
+            name: "local".into(),
+            path: path.to_string_lossy().into(),
+        }],
+        AvDecision::Any,
+    );
+    let result = av.scan(b"xxEICARxx", &Budget::default());
+    assert_eq!(result.infections, vec!["Eicar-Signature".to_string()]);
+    assert!(!result.pass);
+}
+
+#[test]
+fn test_av_unavailable() {
+    // A scan that cannot reach clamd is not reported as a detection
+    let mut md = dummy_metadata();
+    let av = antivirus(
+        vec![clamav_tcp("clamav", "127.0.0.1:1".into())],
+        AvDecision::Any,
+    );
+    let verdict = AvAnalyzer::new(av.clone()).analyze(&file_content(b"data", &mut []), &mut md);
+    assert_eq!(
+        verdict,
+        Verdict::Unavailable("Anti-virus engine unavailable: clamav".into())
+    );
+    assert!(verdict.is_reject());
+    assert!(md.av_pass);
+    assert!(md.av_report.is_empty());
+    assert!(md.av_engines[0].error.is_some());
+    assert_eq!(av.unavailable(), vec!["clamav"]);
+
+    // A failed session is retried and the engine is available again
+    let flaky_antivirus = || {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            drop(listener.accept().unwrap());
+            clamd_session(listener.accept().unwrap().0);
+        });
+        let engine = clamav_tcp("clamav", addr).build(Duration::from_secs(5));
+        let retry = Retry {
+            attempts: 2,
+            delay: Duration::from_millis(10),
+        };
+        Arc::new(Antivirus::new(vec![engine], AvDecision::Any, retry).unwrap())
+    };
+    let av = flaky_antivirus();
+    let result = av.scan(b"xxEICARxx", &Budget::default());
+    assert_eq!(result.verdict, Verdict::Reject("Eicar-Signature".into()));
+    assert!(av.unavailable().is_empty());
+
+    // A file streamed from its descriptor is read again for the retry
+    let mut file = tempfile().unwrap();
+    file.write_all(b"xxEICARxx").unwrap();
+    let result = flaky_antivirus().scan_file(&file, &Budget::default());
+    assert_eq!(result.verdict, Verdict::Reject("Eicar-Signature".into()));
+
+    // Infections reaching the quorum reject the file even if an engine is unavailable
+    let report = |engine: &str, infections: &[&str], error: Option<&str>| AvEngineReport {
+        engine: engine.into(),
+        infections: infections.iter().map(|i| i.to_string()).collect(),
+        error: error.map(String::from),
+    };
+    let reports = vec![
+        report("clamav", &["Eicar-Signature"], None),
+        report("gateway", &[], Some("Connection refused")),
+    ];
+    let result = ScanResult::new(reports.clone(), AvDecision::Any);
+    assert_eq!(result.verdict, Verdict::Reject("Eicar-Signature".into()));
+    let result = ScanResult::new(reports, AvDecision::Quorum(2));
+    assert_eq!(
+        result.verdict,
+        Verdict::Unavailable("Anti-virus engine unavailable: gateway".into())
+    );
+}
+
+#[test]
+fn test_av_quorum() {
+    let report = |engine: &str, infections: &[&str]| AvEngineReport {
+        engine: engine.into(),
+        infections: infections.iter().map(|i| i.to_string()).collect(),
+        error: None,
+    };
+    let reports = vec![
+        report("clamav", &["Eicar-Signature"]),
+        report("gateway", &[]),
+        report("other", &["Eicar-Signature"]),
+    ];
+
+    let result = ScanResult::new(reports.clone(), AvDecision::Any);
+    assert!(!result.pass);
+    assert_eq!(result.infections, vec!["Eicar-Signature".to_string()]);
+    assert_eq!(result.verdict, Verdict::Reject("Eicar-Signature".into()));
+
+    assert!(!ScanResult::new(reports.clone(), AvDecision::Quorum(2)).pass);
+
+    // Detections below the quorum are flagged
+    let result = ScanResult::new(reports, AvDecision::Quorum(3));
+    assert!(result.pass);
+    assert_eq!(
+        result.verdict,
+        Verdict::Flag("Detected by 2 of 3 engines: Eicar-Signature".into())
+    );
+
+    let engines = || vec![clamav_tcp("clamav", "127.0.0.1:1".into()).build(Duration::from_secs(1))];
+    assert!(Antivirus::new(engines(), AvDecision::Quorum(2), NO_RETRY).is_err());
+    assert!(Antivirus::new(engines(), AvDecision::Quorum(0), NO_RETRY).is_err());
+    assert!(Antivirus::new(Vec::new(), AvDecision::Any, NO_RETRY).is_err());
+
+    let policy: Policy = serde_json::from_str(
+        r#"{ "av": { "decision": { "quorum": 1 }, "engines": [
+            { "type": "icap", "name": "gateway", "address": "127.0.0.1:1344", "service": "avscan" }
+        ] } }"#,
+    )
+    .unwrap();
+    assert_eq!(policy.av.decision, AvDecision::Quorum(1));
+    assert_eq!(
+        policy.av.engines,
+        vec![EngineConfig::Icap {
+            name: "gateway".into(),
+            address: "127.0.0.1:1344".into(),
+            service: "avscan".into(),
+        }]
+    );
+}
+
+/// Minimal ICAP server answering one RESPMOD request
+fn fake_icap() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (conn, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(conn.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("RESPMOD icap://"));
+        // ICAP headers, encapsulated HTTP headers, then the chunked body
+        let mut blank_lines = 0;
+        while blank_lines < 2 {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                blank_lines += 1;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let len = usize::from_str_radix(line.trim_end(), 16).unwrap();
+            let mut chunk = vec![0; len + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            if len == 0 {
+                break;
+            }
+            data.extend_from_slice(&chunk[..len]);
+        }
+        let response: &[u8] = match data.windows(5).any(|w| w == b"EICAR") {
+            true => b"ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=Eicar-Test;\r\n\r\n",
+            false => b"ICAP/1.0 204 No Content\r\n\r\n",
+        };
+        (&conn).write_all(response).unwrap();
+    });
+    addr
+}
+
+#[test]
+fn test_icap_respmod() {
+    let icap = |address| EngineConfig::Icap {
+        name: "gateway".into(),
+        address,
+        service: "avscan".into(),
+    };
+    for (data, infections) in [
+        (&b"clean"[..], Vec::new()),
+        (&b"xxEICARxx"[..], vec!["Eicar-Test".to_string()]),
+    ] {
+        let mut registry = Registry::default();
+        let av = antivirus(
+            vec![icap(fake_icap()), clamav_tcp("clamav", fake_clamd())],
+            AvDecision::Any,
+        );
+        registry.register(Box::new(AvAnalyzer::new(av)));
+        let mut file = tempfile().unwrap();
+        file.write_all(data).unwrap();
+        let mut md = dummy_metadata();
+        registry.run(&file, &mut md);
+        assert_eq!(md.av_engines[0].engine, "gateway");
+        assert_eq!(md.av_engines[0].infections, infections);
+        assert_eq!(md.av_pass, infections.is_empty());
+    }
+
+    let response =
+        IcapResponse::read(&b"ICAP/1.0 200 OK\r\nX-Virus-ID: Trojan\r\n\r\n"[..]).unwrap();
+    assert_eq!(response.infections().unwrap(), vec!["Trojan".to_string()]);
+    let response = IcapResponse::read(&b"ICAP/1.0 500 Server Error\r\n\r\n"[..]).unwrap();
+    assert!(response.infections().is_err());
+}
+
+#[test]
+fn test_hash_lists() {
+    let installer = b"vendor installer";
+    let digest = sha256_digest(&installer[..]).unwrap();
+    let registry = |deny: &str, allow: &str| {
+        let mut registry = Registry::default();
+        registry.register(Box::new(HashListAnalyzer::new(
+            HashList::parse(deny).unwrap(),
+            HashList::parse(allow).unwrap(),
+        )));
+        registry.register(Box::new(DummyAnalyzer));
+        registry
+    };
+    let mut file = tempfile().unwrap();
+    file.write_all(installer).unwrap();
+
+    // A file in the allow list bypasses the next analyzers
+    let mut md = dummy_metadata();
+    registry("", &format!("{digest} Installer v1.2")).run(&file, &mut md);
+    let found = md.hash_list.clone().unwrap();
+    assert_eq!(found.list, HashListKind::Allow);
+    assert_eq!(found.label, "Installer v1.2");
+    assert_eq!(found.digest, digest);
+    assert!(md.yara_pass);
+    assert_eq!(md.verdicts.len(), 1);
+    assert!(!md.verdicts.iter().any(|v| v.verdict.is_reject()));
+
+    // The deny list overrides the allow list
+    let mut md = dummy_metadata();
+    registry(
+        &format!("{digest} Known bad"),
+        &format!("{digest} Installer"),
+    )
+    .run(&file, &mut md);
+    assert_eq!(md.hash_list.clone().unwrap().list, HashListKind::Deny);
+    assert_eq!(
+        md.verdicts[0].verdict,
+        Verdict::Reject("File is in the deny list: Known bad".into())
+    );
+    assert!(md.verdicts[1].verdict.is_reject());
+    assert_eq!(md.verdicts.len(), 2);
+
+    // Files in no list are checked by all the analyzers
+    let mut md = dummy_metadata();
+    registry("", "").run(&file, &mut md);
+    assert!(md.hash_list.is_none());
+    assert_eq!(md.verdicts.len(), 2);
+
+    // A profile cannot disable the deny list
+    let mut profiles = Profiles::from_flags(10_000_000, &[], true);
+    profiles.default.analyzers = Some(vec!["dummy".into()]);
+    let mut registry = Registry::new(Arc::new(profiles));
+    registry.register(Box::new(HashListAnalyzer::new(
+        HashList::parse(&format!("{digest} Known bad")).unwrap(),
+        HashList::default(),
+    )));
+    registry.register(Box::new(DummyAnalyzer));
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert_eq!(md.verdicts[0].analyzer, "hashlist");
+    assert!(md.verdicts[0].verdict.is_reject());
+}
+
+#[test]
+fn test_yara_match() {
+    let rule = Rule {
+        identifier: "Eicar",
+        namespace: "keysas",
+        metadatas: vec![
+            Metadata {
+                identifier: "author",
+                value: MetadataValue::String("keysas"),
+            },
+            Metadata {
+                identifier: "score",
+                value: MetadataValue::Integer(80),
+            },
+        ],
+        tags: vec!["test", "av"],
+        strings: vec![
+            YrString {
+                identifier: "$a",
+                matches: (0..20)
+                    .map(|i| Match {
+                        base: 100,
+                        offset: i,
+                        length: 4,
+                        data: b"X5O!".to_vec(),
+                        xor_key: 0,
+                    })
+                    .collect(),
+            },
+            YrString {
+                identifier: "$b",
+                matches: Vec::new(),
+            },
+        ],
+    };
+
+    let m = to_match(&rule, false);
+    assert_eq!(m.rule, "Eicar");
+    assert_eq!(m.namespace, "keysas");
+    assert_eq!(m.tags, vec!["test", "av"]);
+    assert_eq!(m.meta["author"], YaraMetaValue::String("keysas".into()));
+    assert_eq!(m.meta["score"], YaraMetaValue::Integer(80));
+    assert!(m.strings.is_empty());
+
+    // Only the strings found are kept, with a limited number of offsets
+    let m = to_match(&rule, true);
+    assert_eq!(m.strings.len(), 1);
+    assert_eq!(m.strings[0].identifier, "$a");
+    assert_eq!(m.strings[0].offsets.len(), 16);
+    assert_eq!(m.strings[0].offsets[1], 101);
+
+    assert_eq!(rule_names(&[m.clone(), m]), "Eicar, Eicar");
+}
+
+#[test]
+fn test_rule_sources() {
+    let dir = tempfile::tempdir().unwrap();
+    let sigs = dir.path().join("sigs");
+    std::fs::create_dir(&sigs).unwrap();
+    std::fs::write(sigs.join("b.yar"), "rule b { condition: true }").unwrap();
+    std::fs::write(sigs.join("a.yara"), "rule a { condition: true }").unwrap();
+    std::fs::write(sigs.join("README"), "not a rule").unwrap();
+    let index = dir.path().join("index.yar");
+    std::fs::write(&index, "include \"sigs/b.yar\"").unwrap();
+
+    // A directory is compiled in the namespace named after it
+    let source = RuleSource::parse(&sigs.to_string_lossy());
+    assert_eq!(source.namespace, "sigs");
+    assert_eq!(
+        source.files().unwrap(),
+        vec![sigs.join("a.yara"), sigs.join("b.yar")]
+    );
+    let source = RuleSource::parse(&format!("vendor={}", sigs.display()));
+    assert_eq!(source.namespace, "vendor");
+
+    // A file is compiled in the default namespace, its includes are in its directory
+    let source = RuleSource::parse(&index.to_string_lossy());
+    assert_eq!(source.namespace, DEFAULT_NAMESPACE);
+    assert_eq!(source.files().unwrap(), vec![index.clone()]);
+    assert_eq!(source.directory(), dir.path());
+
+    // A change in an included file changes the hash of the rule set
+    let sources = vec![source];
+    let hash = ruleset_hash(&sources).unwrap();
+    assert_eq!(hash, ruleset_hash(&sources).unwrap());
+    std::fs::write(sigs.join("b.yar"), "rule c { condition: true }").unwrap();
+    assert_ne!(hash, ruleset_hash(&sources).unwrap());
+    assert!(ruleset_hash(&[RuleSource::parse("/nonexistent/index.yar")]).is_err());
+}
+
+#[test]
+fn test_rules_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let rule = dir.path().join("rule.yar");
+    std::fs::write(&rule, "rule a { condition: true }").unwrap();
+    let sources = vec![RuleSource::parse(&format!("test={}", dir.path().display()))];
+    let cache = tempfile::tempdir().unwrap();
+
+    let rules = RuleSet::load(&sources, Some(cache.path())).unwrap();
+    let hash = rules.hash().to_string();
+    let scanner = Arc::new(YaraScanner::new(rules, 10, false));
+    let mut reloader = Reloader::new(
+        scanner.clone(),
+        sources.clone(),
+        Some(cache.path().to_path_buf()),
+    );
+    assert!(!reloader.changed());
+    assert!(!reloader.reload().unwrap());
+
+    // A scan started before the reload keeps its rule set
+    let active = scanner.rules();
+    std::fs::write(
+        &rule,
+        "rule b { condition: true }\nrule c { condition: true }",
+    )
+    .unwrap();
+    assert!(reloader.changed());
+    assert!(reloader.reload().unwrap());
+    assert_eq!(active.hash(), hash);
+    assert_ne!(scanner.rules().hash(), hash);
+    assert_eq!(
+        scanner.rules().hash(),
+        RuleSet::load(&sources, Some(cache.path())).unwrap().hash()
+    );
+
+    // The active rule set is kept if the rules cannot be read
+    let reloaded = scanner.rules().hash().to_string();
+    std::fs::remove_dir_all(dir.path()).unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(scanner.rules().hash(), reloaded);
+}
+
+#[test]
+fn test_profiles() {
+    let profiles: Profiles = serde_json::from_str(
+        r#"{
+            "default": { "max_size": 100 },
+            "types": {
+                "png": { "max_size": 10, "action": "flag", "analyzers": ["size", "magic"] },
+                "exe": { "action": "reject" }
+            }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(profiles.get("png").max_size, 10);
+    assert_eq!(profiles.get("exe").action, Action::Reject);
+    assert_eq!(profiles.get("").max_size, 100);
+    assert_eq!(profiles.get("").action, Action::Allow);
+    // Without a default profile the types not listed are rejected
+    let profiles_without_default: Profiles = serde_json::from_str(r#"{ "types": {} }"#).unwrap();
+    assert_eq!(profiles_without_default.get("png").action, Action::Reject);
+
+    let mut registry = Registry::new(Arc::new(profiles.clone()));
+    registry.register(Box::new(crate::analyzer::digest::DigestAnalyzer));
+    registry.register(Box::new(SizeAnalyzer));
+    registry.register(Box::new(MagicAnalyzer));
+    registry
+        .configure(&["digest".into(), "size".into(), "magic".into()])
+        .unwrap();
+
+    // The profile of the type sets the size limit, the action and the analyzers run
+    let mut file = tempfile().unwrap();
+    file.write_all(b"\x89PNG\r\n\x1a\n").unwrap();
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    let verdicts: Vec<(&str, &Verdict)> = md
+        .verdicts
+        .iter()
+        .map(|v| (v.analyzer.as_str(), &v.verdict))
+        .collect();
+    assert_eq!(
+        verdicts,
+        vec![
+            ("size", &Verdict::Pass),
+            (
+                "magic",
+                &Verdict::Flag("File type image/png is flagged".into())
+            ),
+        ]
+    );
+    assert!(md.is_type_allowed);
+
+    // A file too big for its profile is rejected without being copied,
+    // the analyzers reading its content are bypassed
+    file.write_all(b"0123456789").unwrap();
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert_eq!(md.verdicts.len(), 1);
+    assert_eq!(
+        md.verdicts[0].verdict,
+        Verdict::Reject("File is bigger than 10 bytes".into())
+    );
+    assert!(md.is_toobig);
+    assert_eq!(md.size, 18);
+    let budget = Budget::new(Duration::from_secs(1), Arc::new(MemoryPool::new(4)));
+    let content = Content::load(&file, "", &profiles, budget).unwrap();
+    assert!(!content.is_copied());
+    assert!(content.data().is_empty());
+
+    // Other types get the default profile
+    let mut file = tempfile().unwrap();
+    file.write_all(b"plain text content").unwrap();
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert_eq!(md.verdicts.len(), 3);
+    assert_eq!(md.verdicts[1].verdict, Verdict::Pass);
+    assert_eq!(md.verdicts[2].verdict, Verdict::Pass);
+
+    // The analyzers named by the profiles must be registered
+    let mut profiles = profiles;
+    profiles.default.analyzers = Some(vec!["unknown".into()]);
+    let mut registry = Registry::new(Arc::new(profiles));
+    registry.register(Box::new(SizeAnalyzer));
+    assert!(registry.configure(&["size".into()]).is_err());
+
+    // and enabled, instead of being skipped
+    let mut profiles = Profiles::from_flags(10_000_000, &[], true);
+    profiles.default.analyzers = Some(vec!["size".into(), "dummy".into()]);
+    let mut registry = Registry::new(Arc::new(profiles));
+    registry.register(Box::new(SizeAnalyzer));
+    registry.register(Box::new(DummyAnalyzer));
+    assert!(registry.configure(&["size".into()]).is_err());
+    assert!(registry.configure(&["size".into(), "dummy".into()]).is_ok());
+}
+
+/// Analyzer failing in the worker
+struct PanicAnalyzer;
+
+impl Analyzer for PanicAnalyzer {
+    fn name(&self) -> &'static str {
+        "panic"
+    }
+
+    fn analyze(&self, _content: &Content, _md: &mut FileMetadata) -> Verdict {
+        panic!("parser exploited");
+    }
+}
+
+/// Analyzer of a compromised worker forging the results of the file
+struct ForgeAnalyzer;
+
+impl Analyzer for ForgeAnalyzer {
+    fn name(&self) -> &'static str {
+        "forge"
+    }
+
+    fn analyze(&self, _content: &Content, md: &mut FileMetadata) -> Verdict {
+        md.verdicts.clear();
+        md.is_digest_ok = true;
+        md.av_pass = true;
+        md.av_report.clear();
+        Verdict::Pass
+    }
+
+    /// The anti-virus is bypassed in the worker
+    fn is_conclusive(&self, _md: &FileMetadata) -> bool {
+        true
+    }
+}
+
+/// Analyzer never ending in the worker
+struct HangAnalyzer;
+
+impl Analyzer for HangAnalyzer {
+    fn name(&self) -> &'static str {
+        "hang"
+    }
+
+    fn analyze(&self, _content: &Content, _md: &mut FileMetadata) -> Verdict {
+        thread::sleep(Duration::from_secs(60));
+        Verdict::Pass
+    }
+}
+
+/// Analyzer sending data to scan to the daemon, with or without reserving its memory
+struct ScanAnalyzer {
+    reserve: bool,
+}
+
+impl Analyzer for ScanAnalyzer {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    fn analyze(&self, content: &Content, _md: &mut FileMetadata) -> Verdict {
+        let size = 3 * 1024 * 1024;
+        let _memory = self.reserve.then(|| content.budget.reserve(size as u64));
+        crate::worker::scan(&vec![0u8; size]);
+        Verdict::Pass
+    }
+}
+
+#[test]
+fn test_worker_isolation() {
+    let mut file = tempfile().unwrap();
+    file.write_all(b"xxEICARxx").unwrap();
+
+    // The verdicts of the worker are recorded, its anti-virus scan is run by the daemon
+    let mut registry = Registry::default();
+    let av = antivirus(vec![clamav_tcp("clamav", fake_clamd())], AvDecision::Any);
+    registry.register(Box::new(crate::analyzer::digest::DigestAnalyzer));
+    registry.register(Box::new(AvAnalyzer::new(av.clone())));
+    let zygote = Arc::new(Zygote::start(&registry, None).unwrap());
+    registry.isolate(zygote, av);
+    let mut md = dummy_metadata();
+    md.digest = sha256_digest(&b"xxEICARxx"[..]).unwrap();
+    registry.run(&file, &mut md);
+    assert_eq!(md.verdicts.len(), 2);
+    assert!(md.is_digest_ok);
+    assert!(!md.av_pass);
+    assert_eq!(md.av_report, vec!["Eicar-Signature".to_string()]);
+    assert_eq!(md.filename, "file.txt");
+
+    // A compromised worker cannot clear the digest and anti-virus results of the daemon,
+    // nor skip the scan of the file
+    let mut registry = Registry::default();
+    let av = antivirus(vec![clamav_tcp("clamav", fake_clamd())], AvDecision::Any);
+    registry.register(Box::new(crate::analyzer::digest::DigestAnalyzer));
+    registry.register(Box::new(ForgeAnalyzer));
+    registry.register(Box::new(AvAnalyzer::new(av.clone())));
+    let zygote = Arc::new(Zygote::start(&registry, None).unwrap());
+    registry.isolate(zygote, av);
+    let mut md = dummy_metadata();
+    md.digest = sha256_digest(&b"other"[..]).unwrap();
+    registry.run(&file, &mut md);
+    let verdicts: Vec<(&str, &Verdict)> = md
+        .verdicts
+        .iter()
+        .map(|v| (v.analyzer.as_str(), &v.verdict))
+        .collect();
+    assert!(verdicts.contains(&("digest", &Verdict::Reject("Digest mismatch".into()))));
+    assert!(verdicts.contains(&("av", &Verdict::Reject("Eicar-Signature".into()))));
+    assert!(!md.is_digest_ok);
+    assert!(!md.av_pass);
+    assert_eq!(md.av_report, vec!["Eicar-Signature".to_string()]);
+
+    // A worker failing rejects the file
+    let av = antivirus(
+        vec![clamav_tcp("clamav", "127.0.0.1:1".into())],
+        AvDecision::Any,
+    );
+    let mut registry = Registry::default();
+    registry.register(Box::new(PanicAnalyzer));
+    let zygote = Arc::new(Zygote::start(&registry, None).unwrap());
+    registry.isolate(zygote, av.clone());
+    let mut md = dummy_metadata();
+    registry.run(&file, &mut md);
+    assert_eq!(md.verdicts.len(), 1);
+    assert_eq!(md.verdicts[0].analyzer, "transit");
+    assert!(matches!(md.verdicts[0].verdict, Verdict::Reject(_)));
+
+    // A worker still running after the timeout is killed
+    let mut registry = Registry::default();
+    registry.set_budget(Duration::from_millis(50), Arc::new(MemoryPool::default()));
+    registry.register(Box::new(HangAnalyzer));
+    let zygote = Arc::new(Zygote::start(&registry, None).unwrap());
+    registry.isolate(zygote, av.clone());
+    let mut md = dummy_metadata();
+    let start = std::time::Instant::now();
+    registry.run(&file, &mut md);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(md.verdicts.len(), 1);
+    assert!(matches!(md.verdicts[0].verdict, Verdict::Timeout(_)));
+
+    // The data scanned for a worker is bounded by the memory it has reserved
+    for reserve in [true, false] {
+        let mut registry = Registry::default();
+        registry.register(Box::new(ScanAnalyzer { reserve }));
+        let zygote = Arc::new(Zygote::start(&registry, None).unwrap());
+        registry.isolate(zygote, av.clone());
+        let mut md = dummy_metadata();
+        registry.run(&file, &mut md);
+        assert_eq!(md.verdicts.len(), 1);
+        match reserve {
+            true => assert_eq!(md.verdicts[0].verdict, Verdict::Pass),
+            false => assert_eq!(md.verdicts[0].analyzer, "transit"),
+        }
+    }
+}
+
+#[test]
+fn test_replies_order() {
+    let (transit, keysas_in) = UnixStream::pair().unwrap();
+    let mut replies = Replies::new(transit);
+    let reply = |message: &str| ErrorReply {
+        code: ErrorCode::Internal,
+        message: message.into(),
+    };
+    let generation = replies.received();
+    replies.received();
+    // The file refused on reception is replied after the files received before it
+    replies.refused(reply("refused"));
+    replies.delivered(generation, Ok(()));
+    replies.delivered(generation, Err(reply("output")));
+    assert!(protocol::receive_reply(&keysas_in).is_ok());
+    for message in ["output", "refused"] {
+        match protocol::receive_reply(&keysas_in) {
+            Err(ProtocolError::Refused(r)) => assert_eq!(r.message, message),
+            r => panic!("Unexpected reply {r:?}"),
+        }
+    }
+
+    // The files received before keysas-in restarted are not replied
+    let stale = replies.received();
+    let (transit, keysas_in) = UnixStream::pair().unwrap();
+    replies.reset(transit);
+    replies.delivered(stale, Ok(()));
+    let generation = replies.received();
+    assert_ne!(generation, stale);
+    replies.delivered(generation, Err(reply("output")));
+    assert!(matches!(
+        protocol::receive_reply(&keysas_in),
+        Err(ProtocolError::Refused(_))
+    ));
+    drop(replies);
+    assert!(matches!(
+        protocol::receive_reply(&keysas_in),
+        Err(ProtocolError::Closed)
+    ));
+}
@@ -0,0 +1,649 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-transit".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the sandboxed worker processes
+ * analyzing the files.
+ */
+
+//! Sandboxed worker processes
+//!
+//! Each file is analyzed in a worker process started for this file only. The workers are
+//! not forked from the daemon, whose threads may hold locks (allocator, logger, Yara rules)
+//! at any time, but from a [Zygote]: a single threaded process forked from the daemon before
+//! it starts any thread, holding its own copy of the registry. The zygote forks each worker
+//! through an intermediate process, the worker is then a child of the daemon (child subreaper)
+//! which waits for it and kills it on timeout.
+//!
+//! Before running the analyzers the worker closes the descriptors inherited from the
+//! zygote, but the file and a pair of pipes, and loads a seccomp filter tighter than
+//! the one of the daemon: it cannot open files, create sockets or start processes.
+//! A parser exploit cannot reach keysas-in, keysas-out or the other files being analyzed.
+//!
+//! The worker sends its requests to the daemon through the pipes:
+//!     - the anti-virus scans, run by the daemon which connects to the engines,
+//!       the data is sent by chunks and bounded by the memory reserved by the worker
+//!     - the memory reserved from the pool shared by the files
+//!
+//! and ends with the metadata of the file holding the verdicts of the analyzers,
+//! the only data of the worker kept by the daemon. The daemon never maps nor copies the file:
+//! it streams it to the anti-virus engines with bounded reads when the worker asks for its scan.
+//! The daemon keeps its own results of the digest check and of the anti-virus scan of the file
+//! when the profile of the file requires them: it computes the digest itself and scans the file
+//! if the worker did not ask for it. A worker cannot clear these rejects.
+//!
+//! A worker still running after the time budget of the file and a grace delay is killed
+//! and the file gets a timeout verdict. A worker exiting without its result rejects the file.
+
+use crate::analyzer::av::{Antivirus, ScanResult};
+use crate::analyzer::budget::{Budget, Reservation};
+use crate::analyzer::{Registry, read_chunks};
+use crate::rules::Reloader;
+use crate::{FileMetadata, sandbox};
+use keysas_lib::file_report::{AnalyzerVerdict, Verdict};
+use keysas_lib::protocol::MAX_PAYLOAD;
+use log::{error, info};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::sys::prctl::set_child_subreaper;
+use nix::sys::signal::{Signal, kill};
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType, recv,
+    recvmsg, send, sendmsg, socketpair,
+};
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, fork};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader, IoSlice, IoSliceMut, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Maximum time left to the worker after the time budget to send its timeout verdict
+const GRACE: Duration = Duration::from_secs(5);
+
+/// Size of the chunks of data sent to the daemon and read by the daemon from the file,
+/// the size of the chunks sent to the anti-virus engines
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Maximum size of a message: the metadata of the file, sent to keysas-out in a single frame,
+/// is the largest one, the data to scan is sent by chunks
+const MAX_MESSAGE: usize = MAX_PAYLOAD;
+
+/// Maximum size of an order of the daemon to the zygote
+const MAX_ORDER: usize = 64 * 1024;
+
+/// Request of the worker to the daemon
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+enum Request {
+    /// Scan the file analyzed with the anti-virus engines
+    ScanFile,
+    /// Chunk of data to scan with the anti-virus engines, e.g. an archive entry, not answered
+    ScanChunk(Vec<u8>),
+    /// Scan the data sent by chunks
+    ScanEnd,
+    /// Reserve memory from the pool
+    Reserve(u64),
+    /// Release memory reserved, not answered
+    Release(u64),
+    /// Result of the analysis, not answered
+    Done(Box<FileMetadata>),
+}
+
+/// Answer of the daemon to a request of the worker
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+enum Answer {
+    Scanned(ScanResult),
+    Reserved(Result<(), Verdict>),
+}
+
+fn config() -> impl bincode::config::Config {
+    bincode::config::standard().with_limit::<MAX_MESSAGE>()
+}
+
+/// Pipes of the worker with the daemon
+#[derive(Debug)]
+struct Channel {
+    requests: PipeWriter,
+    answers: PipeReader,
+}
+
+/// Channel with the daemon, only set in a worker process
+static DAEMON: OnceLock<Mutex<Channel>> = OnceLock::new();
+
+/// Exit the worker without running the cleanup of the daemon
+fn exit(code: i32) -> ! {
+    // SAFETY: the worker only exits, nothing of the daemon is shared with it
+    unsafe { nix::libc::_exit(code) }
+}
+
+/// Send a request to the daemon and wait for the answer if any
+/// Returns None if not called in a worker, the worker exits if the daemon cannot be reached
+fn request(request: &Request, answered: bool) -> Option<Option<Answer>> {
+    let mut channel = DAEMON.get()?.lock().unwrap_or_else(PoisonError::into_inner);
+    let result = bincode::encode_into_std_write(request, &mut channel.requests, config())
+        .map_err(|e| e.to_string())
+        .and_then(|_| match answered {
+            true => bincode::decode_from_std_read(&mut channel.answers, config())
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            false => Ok(None),
+        });
+    match result {
+        Ok(answer) => Some(answer),
+        Err(e) => {
+            error!("Analysis worker lost the daemon: {e}");
+            exit(1)
+        }
+    }
+}
+
+/// Returns true in a worker process
+pub fn is_worker() -> bool {
+    DAEMON.get().is_some()
+}
+
+/// Scan the file analyzed by the worker with the anti-virus engines of the daemon
+/// Returns None if not called in a worker
+pub fn scan_file() -> Option<ScanResult> {
+    match request(&Request::ScanFile, true)? {
+        Some(Answer::Scanned(result)) => Some(result),
+        _ => exit(1),
+    }
+}
+
+/// Scan data with the anti-virus engines of the daemon
+/// Returns None if not called in a worker
+pub fn scan(data: &[u8]) -> Option<ScanResult> {
+    for chunk in data.chunks(CHUNK_SIZE) {
+        request(&Request::ScanChunk(chunk.to_vec()), false)?;
+    }
+    match request(&Request::ScanEnd, true)? {
+        Some(Answer::Scanned(result)) => Some(result),
+        _ => exit(1),
+    }
+}
+
+/// Reserve memory from the pool of the daemon
+/// Returns None if not called in a worker
+pub fn reserve(size: u64) -> Option<Result<(), Verdict>> {
+    match request(&Request::Reserve(size), true)? {
+        Some(Answer::Reserved(result)) => Some(result),
+        _ => exit(1),
+    }
+}
+
+/// Release memory reserved from the pool of the daemon
+pub fn release(size: u64) {
+    request(&Request::Release(size), false);
+}
+
+/// Order of the daemon to the zygote
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+enum Order {
+    /// Fork a worker analyzing a file, the descriptors of the file and of the pipes
+    /// of the worker are attached
+    Spawn(Box<FileMetadata>),
+    /// Reload the Yara rules of the zygote
+    Reload,
+}
+
+/// Single threaded process forking the workers
+#[derive(Debug)]
+pub struct Zygote {
+    /// Socket with the zygote, the orders are sent one at a time
+    socket: Mutex<OwnedFd>,
+    pid: Pid,
+}
+
+impl Zygote {
+    /// Fork the zygote with its copy of the registry, to be called before the daemon starts
+    /// any thread. `rules` reloads the Yara rules of the registry on [Zygote::reload].
+    pub fn start(registry: &Registry, rules: Option<Reloader>) -> io::Result<Self> {
+        // The workers are reparented to the daemon once the intermediate process exits
+        set_child_subreaper(true)?;
+        let (socket, zygote) = socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?;
+        // SAFETY: the daemon has not started any thread yet, the zygote only serves
+        // the orders of the daemon and exits without returning
+        match unsafe { fork() }? {
+            ForkResult::Child => {
+                drop(socket);
+                serve_zygote(registry, rules, zygote)
+            }
+            ForkResult::Parent { child } => Ok(Self {
+                socket: Mutex::new(socket),
+                pid: child,
+            }),
+        }
+    }
+
+    /// Reload the Yara rules of the zygote, used by the workers forked afterwards
+    pub fn reload(&self) {
+        let socket = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = bincode::encode_to_vec(Order::Reload, config())
+            .map_err(io::Error::other)
+            .and_then(|order| Ok(send(socket.as_raw_fd(), &order, MsgFlags::empty())?));
+        if let Err(e) = result {
+            error!("Cannot reload the Yara rules of the workers: {e}");
+        }
+    }
+
+    /// Fork a worker analyzing the file with the ends of its pipes, returns its process id
+    fn spawn(
+        &self,
+        file: &File,
+        md: &FileMetadata,
+        requests: &PipeWriter,
+        answers: &PipeReader,
+    ) -> io::Result<Pid> {
+        let order = bincode::encode_to_vec(Order::Spawn(Box::new(md.clone())), config())
+            .map_err(io::Error::other)?;
+        if order.len() > MAX_ORDER {
+            return Err(io::Error::other("metadata of the file too large"));
+        }
+        let fds = [file.as_raw_fd(), requests.as_raw_fd(), answers.as_raw_fd()];
+        let socket = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        sendmsg::<()>(
+            socket.as_raw_fd(),
+            &[IoSlice::new(&order)],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )?;
+        let mut reply = [0u8; 4];
+        match recv(socket.as_raw_fd(), &mut reply, MsgFlags::empty())? {
+            4 => match i32::from_ne_bytes(reply) {
+                pid if pid > 0 => Ok(Pid::from_raw(pid)),
+                errno => Err(io::Error::from_raw_os_error(-errno)),
+            },
+            _ => Err(io::Error::other("zygote stopped")),
+        }
+    }
+}
+
+impl Drop for Zygote {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+    }
+}
+
+/// Serve the orders of the daemon until it closes the socket
+fn serve_zygote(registry: &Registry, rules: Option<Reloader>, socket: OwnedFd) -> ! {
+    let mut buf = vec![0u8; MAX_ORDER];
+    loop {
+        let mut cmsg = nix::cmsg_space!([RawFd; 3]);
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let (len, fds) = match recvmsg::<()>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Ok(msg) => {
+                let mut fds = Vec::new();
+                for c in msg.cmsgs().into_iter().flatten() {
+                    if let ControlMessageOwned::ScmRights(received) = c {
+                        // SAFETY: the descriptors have just been received and are owned by the zygote
+                        fds.extend(
+                            received
+                                .into_iter()
+                                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                        );
+                    }
+                }
+                (msg.bytes, fds)
+            }
+            Err(Errno::EINTR) => continue,
+            Err(_) => exit(1),
+        };
+        // The daemon has stopped
+        if len == 0 {
+            exit(0);
+        }
+        let order = bincode::decode_from_slice::<Order, _>(&buf[..len], config());
+        match order.map(|(o, _)| o) {
+            Ok(Order::Reload) => match rules.as_ref().map(Reloader::reload) {
+                Some(Ok(true)) => info!("Yara rules of the workers reloaded."),
+                Some(Err(e)) => error!("Cannot reload the Yara rules of the workers: {e:?}"),
+                _ => (),
+            },
+            Ok(Order::Spawn(md)) => {
+                let reply = match <[OwnedFd; 3]>::try_from(fds) {
+                    Ok([file, requests, answers]) => {
+                        let channel = Channel {
+                            requests: PipeWriter::from(requests),
+                            answers: PipeReader::from(answers),
+                        };
+                        fork_worker(registry, File::from(file), *md, channel).map_or_else(
+                            |e| -e.raw_os_error().unwrap_or(nix::libc::EIO),
+                            |p| p.as_raw(),
+                        )
+                    }
+                    Err(_) => -nix::libc::EINVAL,
+                };
+                if send(socket.as_raw_fd(), &reply.to_ne_bytes(), MsgFlags::empty()).is_err() {
+                    exit(1);
+                }
+            }
+            Err(_) => exit(1),
+        }
+    }
+}
+
+/// Fork a worker through an intermediate process, the worker is reparented to the daemon
+/// Returns the process id of the worker
+fn fork_worker(
+    registry: &Registry,
+    file: File,
+    mut md: FileMetadata,
+    channel: Channel,
+) -> io::Result<Pid> {
+    let (mut pid_rx, mut pid_tx) = io::pipe()?;
+    // SAFETY: the zygote is single threaded, the intermediate process only forks the worker
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            drop(pid_rx);
+            // SAFETY: the intermediate process is single threaded
+            match unsafe { fork() } {
+                Ok(ForkResult::Child) => {
+                    drop(pid_tx);
+                    work(registry, &file, &mut md, channel)
+                }
+                Ok(ForkResult::Parent { child }) => {
+                    let _ = pid_tx.write_all(&child.as_raw().to_ne_bytes());
+                    exit(0)
+                }
+                Err(_) => exit(1),
+            }
+        }
+        ForkResult::Parent { child } => {
+            drop(pid_tx);
+            let mut pid = [0u8; 4];
+            let read = pid_rx.read_exact(&mut pid);
+            let _ = waitpid(child, None);
+            read?;
+            Ok(Pid::from_raw(i32::from_ne_bytes(pid)))
+        }
+    }
+}
+
+/// Analyze the file in a worker process and record the verdicts in the metadata
+pub fn run(
+    registry: &Registry,
+    zygote: &Zygote,
+    av: &Arc<Antivirus>,
+    file: &File,
+    md: &mut FileMetadata,
+) {
+    let budget = registry.budget();
+    let failure = match spawn(zygote, file, md) {
+        Ok(worker) => worker.serve(registry, av, file, md, &budget),
+        Err(e) => {
+            error!("Cannot start the analysis worker of {}: {e}", md.filename);
+            Some(Verdict::Reject("Cannot start analysis worker".into()))
+        }
+    };
+    if let Some(verdict) = failure {
+        md.verdicts.push(AnalyzerVerdict {
+            analyzer: "transit".into(),
+            verdict,
+        });
+    }
+}
+
+/// Start the worker analyzing the file from the zygote
+fn spawn(zygote: &Zygote, file: &File, md: &FileMetadata) -> io::Result<Worker> {
+    let (requests_rx, requests) = io::pipe()?;
+    let (answers, answers_tx) = io::pipe()?;
+    // The ends of the worker are closed by the daemon once sent
+    let pid = zygote.spawn(file, md, &requests, &answers)?;
+    Ok(Worker {
+        pid,
+        requests: requests_rx,
+        answers: answers_tx,
+    })
+}
+
+/// Close the descriptors inherited from the zygote but the standard ones and those kept
+fn close_inherited(keep: &[RawFd]) -> io::Result<()> {
+    let mut keep = keep.to_vec();
+    keep.sort();
+    let mut first = 3;
+    for fd in keep {
+        if fd > first {
+            close_range(first, fd - 1)?;
+        }
+        first = first.max(fd + 1);
+    }
+    close_range(first, RawFd::MAX)
+}
+
+fn close_range(first: RawFd, last: RawFd) -> io::Result<()> {
+    // SAFETY: the descriptors closed are not used by the worker
+    match unsafe { nix::libc::close_range(first as u32, last as u32, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sandbox the worker, run the analyzers and send the result to the daemon
+fn work(registry: &Registry, file: &File, md: &mut FileMetadata, channel: Channel) -> ! {
+    let keep = [
+        file.as_raw_fd(),
+        channel.requests.as_raw_fd(),
+        channel.answers.as_raw_fd(),
+    ];
+    if let Err(e) = close_inherited(&keep) {
+        error!("Cannot close the descriptors of the analysis worker: {e}");
+        exit(1);
+    }
+    if let Err(e) = sandbox::init_worker() {
+        error!("Cannot sandbox the analysis worker: {e}");
+        exit(1);
+    }
+    if DAEMON.set(Mutex::new(channel)).is_err() {
+        exit(1);
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        registry.analyze(file, md);
+        request(&Request::Done(Box::new(md.clone())), false);
+    }));
+    exit(match result {
+        Ok(_) => 0,
+        Err(_) => 1,
+    })
+}
+
+/// Pipe read until a deadline
+struct Deadline {
+    pipe: PipeReader,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(self.pipe.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) => Err(io::ErrorKind::TimedOut.into()),
+            Ok(_) => self.pipe.read(buf),
+            Err(Errno::EINTR) => Err(io::ErrorKind::Interrupted.into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Daemon side of a worker
+#[derive(Debug)]
+struct Worker {
+    pid: Pid,
+    requests: PipeReader,
+    answers: PipeWriter,
+}
+
+impl Worker {
+    /// Answer the requests of the worker until it sends its result, then wait for its end
+    /// Returns the verdict of the file if the worker failed
+    fn serve(
+        mut self,
+        registry: &Registry,
+        av: &Arc<Antivirus>,
+        file: &File,
+        md: &mut FileMetadata,
+        budget: &Budget,
+    ) -> Option<Verdict> {
+        let grace = GRACE.min(budget.remaining());
+        let mut requests = BufReader::new(Deadline {
+            pipe: self.requests,
+            deadline: Instant::now() + budget.remaining() + grace,
+        });
+        // Memory reserved by the worker, released when it ends at the latest
+        let mut reserved: Vec<Reservation> = Vec::new();
+        // Data sent by chunks to be scanned
+        let mut scanned: Vec<u8> = Vec::new();
+        // Results of the daemon, computed while the worker runs its analyzers
+        let digest = registry
+            .requires("digest", file, &md.filename)
+            .then(|| digest_file(file, budget));
+        let scan_required = registry.requires("av", file, &md.filename);
+        let mut file_scan: Option<ScanResult> = None;
+        let outcome = loop {
+            let request: Request = match bincode::decode_from_std_read(&mut requests, config()) {
+                Ok(r) => r,
+                Err(bincode::error::DecodeError::Io { inner, .. }) => break Err(inner),
+                Err(e) => break Err(io::Error::other(e)),
+            };
+            let answer = match request {
+                Request::ScanFile => {
+                    let result = av.scan_file(file, budget);
+                    file_scan = Some(result.clone());
+                    Answer::Scanned(result)
+                }
+                Request::ScanChunk(chunk) => {
+                    // The worker holds the data it sends, it has reserved the memory for it
+                    let limit: u64 = reserved.iter().map(Reservation::size).sum();
+                    if chunk.len() > CHUNK_SIZE
+                        || (scanned.len() + chunk.len()) as u64 > limit.max(CHUNK_SIZE as u64)
+                    {
+                        break Err(io::Error::other("data to scan over the memory reserved"));
+                    }
+                    scanned.extend_from_slice(&chunk);
+                    continue;
+                }
+                Request::ScanEnd => Answer::Scanned(av.scan(&std::mem::take(&mut scanned), budget)),
+                Request::Reserve(size) => {
+                    Answer::Reserved(budget.reserve(size).map(|r| reserved.push(r)))
+                }
+                Request::Release(size) => {
+                    if let Some(i) = reserved.iter().position(|r| r.size() == size) {
+                        reserved.swap_remove(i);
+                    }
+                    continue;
+                }
+                Request::Done(result) => break Ok(result),
+            };
+            if let Err(e) = bincode::encode_into_std_write(answer, &mut self.answers, config()) {
+                break Err(io::Error::other(e));
+            }
+        };
+
+        // The worker is killed if it is still running
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let status = waitpid(self.pid, None);
+        match outcome {
+            Ok(result) => {
+                // A worker skipping the scan of the file does not bypass the anti-virus
+                if scan_required && file_scan.is_none() {
+                    file_scan = Some(av.scan_file(file, budget));
+                }
+                merge(md, *result, digest, file_scan);
+                None
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                error!(
+                    "Analysis worker of {} killed after the timeout",
+                    md.filename
+                );
+                Some(budget.timeout())
+            }
+            Err(e) => {
+                error!(
+                    "Analysis worker of {} failed: {e}, status: {status:?}",
+                    md.filename
+                );
+                Some(Verdict::Reject("Analysis worker failed".into()))
+            }
+        }
+    }
+}
+
+/// SHA-256 digest of the file computed by the daemon
+fn digest_file(file: &File, budget: &Budget) -> Result<String, Verdict> {
+    let mut hasher = Sha256::new();
+    match read_chunks(file, budget, |chunk| hasher.update(chunk)) {
+        Ok(_) => Ok(format!("{:x}", hasher.finalize())),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(budget.timeout()),
+        Err(e) => {
+            error!("Unable to read file: {e}");
+            Err(Verdict::Reject("Failed to read file".into()))
+        }
+    }
+}
+
+/// Keep the verdicts and the reports of the analyzers of the worker with the results of the daemon
+/// The name, the path, the session and the digest received from keysas-in are kept.
+/// The digest check and the anti-virus scan of the file, if required, are those of the daemon:
+/// their verdicts replace the ones of the worker and are added if the worker has none but a reject.
+fn merge(
+    md: &mut FileMetadata,
+    mut result: FileMetadata,
+    digest: Option<Result<String, Verdict>>,
+    file_scan: Option<ScanResult>,
+) {
+    result.filename = std::mem::take(&mut md.filename);
+    result.path = std::mem::take(&mut md.path);
+    result.session = std::mem::take(&mut md.session);
+    result.digest = std::mem::take(&mut md.digest);
+    let digest = digest.map(|digest| match digest {
+        Ok(d) if d == result.digest => Verdict::Pass,
+        Ok(_) => Verdict::Reject("Digest mismatch".into()),
+        Err(verdict) => verdict,
+    });
+    // Not checked, as when the digest analyzer is bypassed
+    result.is_digest_ok = digest.as_ref().is_none_or(|v| *v == Verdict::Pass);
+    let av = match file_scan {
+        Some(scan) => Some(scan.record(&mut result)),
+        None => {
+            // Not scanned, as when the anti-virus is bypassed
+            result.av_pass = true;
+            result.av_report.clear();
+            result.av_engines.clear();
+            None
+        }
+    };
+    for (analyzer, verdict) in [("digest", digest), ("av", av)] {
+        let Some(verdict) = verdict else {
+            continue;
+        };
+        match result.verdicts.iter_mut().find(|v| v.analyzer == analyzer) {
+            Some(v) => v.verdict = verdict,
+            None if verdict.is_reject() => result.verdicts.push(AnalyzerVerdict {
+                analyzer: analyzer.into(),
+                verdict,
+            }),
+            None => (),
+        }
+    }
+    *md = result;
+}
@@ -20,6 +20,7 @@ use base64::{Engine as _, engine::general_purpose};
 use clap::{Arg, Command as Clap_Command, crate_version};
 use log::{debug, error, info, warn};
 use regex::Regex;
+use std::collections::HashSet;
 use std::fs::{self, create_dir_all};
 use std::path::PathBuf;
 use std::random::random;
@@ -46,6 +47,7 @@ use ed25519_dalek::Signature as SignatureDalek;
 use keysas_lib::init_logger;
 use keysas_lib::keysas_key::PublicKeys;
 use keysas_lib::keysas_key::{KeysasHybridPubKeys, KeysasHybridSignature};
+use keysas_lib::session::SessionManifest;
 use kv::Config as kvConfig;
 use kv::*;
 use libc::{c_int, c_short, c_ulong, c_void};
@@ -345,7 +347,8 @@ fn is_signed(
     }
 }
 
-fn copy_device_in(device: &Path) -> Result<()> {
+/// Copy the files of an unsigned device to sas_in, `source` describes the device in the session manifest
+fn copy_device_in(device: &Path, source: &str) -> Result<()> {
     let dir = tempfile::tempdir()?;
     let mount_point = dir.path();
     info!("Unsigned USB device {device:?} will be mounted on path: {mount_point:?}");
@@ -358,7 +361,7 @@ fn copy_device_in(device: &Path) -> Result<()> {
         Ok(mount) => {
             // Copying file to the mounted device.
             info!("Unsigned device is mounted on: {mount_point:?}");
-            copy_files_in(&mount_point.to_path_buf())?;
+            copy_files_in(&mount_point.to_path_buf(), source)?;
             // Make the mount temporary, so that it will be unmounted on drop.
             let _mount = mount.into_unmount_drop(UnmountFlags::DETACH);
         }
@@ -400,10 +403,35 @@ fn move_device_out(device: &Path) -> Result<PathBuf> {
     Ok(mount_point.to_path_buf())
 }
 
-fn copy_files_in(mount_point: &PathBuf) -> Result<()> {
+/// Relative path of a file with each name cleaned: no diacritics and no question mark
+fn clean_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| {
+            let name = c.as_os_str().to_string_lossy();
+            diacritics::remove_diacritics(&str::replace(&name, "?", "-"))
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Copy the files of the device to sas_in, keeping the directory tree
+/// Hidden files and directories are not copied
+/// The manifest of the transfer session is written in sas_in for keysas-in
+fn copy_files_in(mount_point: &PathBuf, source: &str) -> Result<()> {
     File::create(LOCK)?;
+    let session = SessionManifest::new(source);
+    session.write(Path::new(SAS_IN))?;
+    info!(
+        "Transfer session {} started for device {source}.",
+        session.id
+    );
     std::thread::scope(|s| {
-        for e in WalkDir::new(mount_point).into_iter().filter_map(|e| e.ok()) {
+        for e in WalkDir::new(mount_point)
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|e| e.ok())
+        {
             if e.metadata()
                 .expect("Cannot get metadata for file.")
                 .is_file()
@@ -418,18 +446,16 @@ fn copy_files_in(mount_point: &PathBuf) -> Result<()> {
                                  return;
                              }
                          };
-                         let entry = e.file_name().to_string_lossy();
-                         let entry_cleaned = str::replace(&entry, "?", "-");
-                         let path_to_write = format!(
-                             "{}{}",
-                             SAS_IN,
-                             diacritics::remove_diacritics(&entry_cleaned)
-                         );
-                         let path_to_tmp = format!(
-                             "{}{}",
-                             TMP_DIR,
-                             diacritics::remove_diacritics(&entry_cleaned)
-                         );
+                         // Path relative to the root of the device
+                         let entry = match e.path().strip_prefix(mount_point) {
+                             Ok(r) => clean_path(r),
+                             Err(_) => {
+                                 error!("Entry outside of the device: {path_to_read}");
+                                 return;
+                             }
+                         };
+                         let path_to_write = format!("{SAS_IN}{entry}");
+                         let path_to_tmp = format!("{TMP_DIR}{entry}");
 
                          // Create a tmp dir to be able to rename files later
                          let tmp = TMP_DIR.trim_end_matches("/");
@@ -448,6 +474,13 @@ fn copy_files_in(mount_point: &PathBuf) -> Result<()> {
                                  Err(e) => error!("Cannot create tmp directory: {e:?}"),
                              }
                          }
+                         // Directories of the file in tmp and sas_in
+                         for dir in [&path_to_tmp, &path_to_write].iter().filter_map(|p| Path::new(p).parent()) {
+                             if let Err(e) = fs::create_dir_all(dir) {
+                                 error!("Cannot create directory {dir:?}: {e:?}");
+                                 return;
+                             }
+                         }
                          match fs::metadata(path_to_read) {
                              Ok(mtdata) => {
                                  if Path::new(&path_to_read).exists() && !mtdata.is_dir() {
@@ -516,39 +549,57 @@ fn copy_files_in(mount_point: &PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// Move the files of sas_out to the signed device, keeping the directory tree
+/// The directories are removed from sas_out once their files are moved
 fn move_files_out(mount_point: &PathBuf) -> Result<()> {
-    let dir = fs::read_dir(SAS_OUT)?;
-    for entry in dir {
+    // Only the directories found before moving the files are removed once empty,
+    // keysas-out may be creating new ones for the files it is writing
+    let dirs: HashSet<PathBuf> = WalkDir::new(SAS_OUT)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.into_path())
+        .collect();
+    // Hidden files, e.g. the summaries of the open sessions, are still being written by keysas-out
+    for entry in WalkDir::new(SAS_OUT)
+        .min_depth(1)
+        .contents_first(true)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+    {
         let entry = entry?;
-        debug!("New entry found: {:?}.", entry.file_name());
+        debug!("New entry found: {:?}.", entry.path());
 
-        let path_to_write = format!(
-            "{}{}{}",
-            &mount_point.to_string_lossy(),
-            "/",
-            diacritics::remove_diacritics(&entry.file_name().to_string_lossy())
-        );
-        let path_to_read = format!(
-            "{}{}",
-            SAS_OUT,
-            entry.file_name().to_string_lossy().into_owned()
-        );
-        if !fs::metadata(&path_to_read)?.is_dir() {
-            match fs::copy(&path_to_read, path_to_write) {
-                Ok(_) => info!("Copying file: {path_to_read} to signed device."),
-                Err(e) => {
-                    error!("Error while copying file to signed device {path_to_read}: {e:?}");
-                    match unmount(mount_point, UnmountFlags::DETACH) {
-                        Ok(()) => debug!("Early removing mount point: {mount_point:?}"),
-                        Err(why) => {
-                            error!("Failed to unmount {mount_point:?}: {why}");
-                        }
+        let path_to_read = entry.path().to_string_lossy().into_owned();
+        if entry.file_type().is_dir() {
+            if !dirs.contains(entry.path()) {
+                continue;
+            }
+            match fs::remove_dir(entry.path()) {
+                Ok(_) => info!("Removing directory: {path_to_read}."),
+                Err(e) => warn!("Cannot remove directory {path_to_read}: {e:?}"),
+            }
+            continue;
+        }
+        let path_to_write = mount_point.join(clean_path(entry.path().strip_prefix(SAS_OUT)?));
+        if let Some(dir) = path_to_write.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::copy(&path_to_read, path_to_write) {
+            Ok(_) => info!("Copying file: {path_to_read} to signed device."),
+            Err(e) => {
+                error!("Error while copying file to signed device {path_to_read}: {e:?}");
+                match unmount(mount_point, UnmountFlags::DETACH) {
+                    Ok(()) => debug!("Early removing mount point: {mount_point:?}"),
+                    Err(why) => {
+                        error!("Failed to unmount {mount_point:?}: {why}");
                     }
                 }
             }
-            fs::remove_file(&path_to_read)?;
-            info!("Removing file: {path_to_read}.");
         }
+        fs::remove_file(&path_to_read)?;
+        info!("Removing file: {path_to_read}.");
     }
     info!("Moving files to outgoing device done.");
     Ok(())
@@ -843,6 +894,10 @@ fn main() -> Result<()> {
                     let id_serial = id_serial
                         .to_str()
                         .ok_or_else(|| anyhow!("Cannot convert id_serial to str."))?;
+                    // Source of the files in the transfer session manifest
+                    let source = format!(
+                        "{id_vendor_id}/{id_model_id}/{id_revision}/{id_serial} on {device}"
+                    );
 
                     let signed = is_signed(
                         device,
@@ -877,7 +932,7 @@ fn main() -> Result<()> {
                                 match hmac_challenge() {
                                     Some(name) => {
                                         info!("HMAC challenge successfull for user: {name} !");
-                                        copy_device_in(Path::new(&device))?;
+                                        copy_device_in(Path::new(&device), &source)?;
                                         info!("Unsigned USB device done.");
                                         ready_in()?;
                                     }
@@ -888,7 +943,7 @@ fn main() -> Result<()> {
                                 };
                             } else {
                                 info!("DEVICE NOT VALID2: {}", &device);
-                                copy_device_in(Path::new(&device))?;
+                                copy_device_in(Path::new(&device), &source)?;
                                 info!("Unsigned USB device done.");
                                 ready_in()?;
                             }
@@ -913,7 +968,7 @@ fn main() -> Result<()> {
                             info!("DEVICE NOT VALID3: {}", &device);
                             let serialized = serde_json::to_string(&keys)?;
                             websocket.send(Message::Text(serialized.into()))?;
-                            copy_device_in(Path::new(&device))?;
+                            copy_device_in(Path::new(&device), &source)?;
                             ready_in()?;
                             info!("Unsigned USB device done.");
                         }
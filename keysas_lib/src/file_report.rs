@@ -11,20 +11,34 @@
 //! {
 //!     "metadata": {
 //!         "name",             // String: File name
+//!         "path",             // String: path of the file relative to the input device, omitted if unknown
+//!         "session",          // String: identifier of the transfer session of the file, omitted if unknown
 //!         "date",             // String DD-MM-YYYY_HH-mm-SS-NN: Date of creation of the report
 //!         "file_type",        // String: file type
 //!         "is_valid",         // Boolean: true if all checks passed
 //!         "report": {
-//!             "yara",         // String: yara detailed report
-//!             "av",           // String: clamav detailed report
+//!             "yara",         // String: names of the yara rules matched
+//!             "yara_matches", // List: rule, namespace, tags, meta and matched strings of each yara match
+//!             "yara_ruleset", // String: SHA-256 hash of the yara rule set used for the scan
+//!             "av",           // List: names of the infections detected by the anti-virus engines
+//!             "av_engines",   // List: detailed report of each anti-virus engine
 //!             "type_allowed", // Boolean: false if forbidden type detected
 //!             "size",         // u64: file size
 //!             "corrupted",    // boolean: true if file integrity corruption detected
-//!             "toobig"        // Boolean, true file size is too big
+//!             "toobig",       // Boolean, true file size is too big
+//!             "analyzers",    // List: verdict of each analyzer run by keysas-transit
+//!             "archive",      // List: detailed report of each entry if the file is an archive
+//!             "office",       // List: active contents found in office documents
+//!             "pdf",          // List: active contents and anomalies found in PDF documents
+//!             "extension",    // List: mismatches between the file name and the detected type
+//!             "entropy",      // Object: entropy, packers and score of the file
+//!             "text",         // List: encoding, control characters, syntax errors and formulas found in text files
+//!             "metadata"      // List: metadata embedded in images, office and PDF documents (author, GPS, printer...)
 //!         }
 //!     },
 //!     "binding" : {
 //!         "file_digest",         // String: base64 encoded SHA256 digest of the file
+//!         "original_digest",     // String: base64 encoded SHA256 digest of the original file, only if it has been sanitized
 //!         "metadata_digest",     // String: base64 encoded SHA256 digest of the metadata
 //!         "station_certificate", // String: concatenation of the station signing certificates PEM
 //!         "report_signature",    // String: base64 encoded concatenation of the ED25519 and ML-DSA87 signatures
@@ -36,6 +50,7 @@
 //!
 
 use crate::keysas_key::KeysasKey;
+use crate::session::SessionManifest;
 use crate::sha256_digest;
 use crate::{
     certificate_field::validate_signing_certificate, keysas_hybrid_keypair::HybridKeyPair,
@@ -45,6 +60,7 @@ use base64::{Engine as _, engine::general_purpose};
 use oqs::sig::{Algorithm, Sig};
 use serde_derive::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
 use time::OffsetDateTime;
@@ -56,6 +72,12 @@ use x509_cert::Certificate;
 pub struct MetaData {
     /// Name of the file
     pub name: String,
+    /// Path of the file relative to the root of the input device
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    /// Identifier of the transfer session of the file
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub session: String,
     /// Date of the report creation
     pub date: String,
     /// Type of the file
@@ -72,6 +94,10 @@ pub struct MetaData {
 pub struct Bd {
     /// SHA256 digest of the file encoded in base64
     pub file_digest: String,
+    /// SHA256 digest of the original file encoded in base64
+    /// Only set if the file has been sanitized (CDR), `file_digest` is then the digest of the sanitized file
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub original_digest: String,
     /// SHA256 digest of the [MetaData] associated to the file
     pub metadata_digest: String,
     /// Station certificates: concatenation of its ED25519 and ML-DSA87 signing certificates with a '|' delimiter
@@ -90,12 +116,405 @@ pub struct Report {
     pub binding: Bd,
 }
 
+/// Verdict returned by an analyzer of keysas-transit
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum Verdict {
+    /// The check passed
+    Pass,
+    /// The check raised a finding that is recorded but does not block the file
+    Flag(String),
+    /// The check failed, the file must not be transfered
+    Reject(String),
+    /// A service needed by the check is unavailable, the file must not be transfered
+    Unavailable(String),
+    /// The analysis did not complete within its time budget, the file must not be transfered
+    Timeout(String),
+}
+
+impl Verdict {
+    /// Returns true if the verdict blocks the file
+    pub fn is_reject(&self) -> bool {
+        matches!(
+            self,
+            Verdict::Reject(_) | Verdict::Unavailable(_) | Verdict::Timeout(_)
+        )
+    }
+}
+
+/// Verdict of an analyzer associated to the analyzer name
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct AnalyzerVerdict {
+    /// Name of the analyzer
+    pub analyzer: String,
+    /// Result of the analysis
+    pub verdict: Verdict,
+}
+
+/// Result of the scan of a file by an anti-virus engine
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct AvEngineReport {
+    /// Name of the engine in the station policy
+    pub engine: String,
+    /// Names of the infections detected by the engine
+    pub infections: Vec<String>,
+    /// Error met during the scan, the infections are then unknown
+    pub error: Option<String>,
+}
+
+/// Value of a meta field of a Yara rule
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+#[serde(untagged)]
+pub enum YaraMetaValue {
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+/// String of a Yara rule found in the file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct YaraString {
+    /// Identifier of the string in the rule, e.g. "$a"
+    pub identifier: String,
+    /// Offsets of the first matches in the file
+    pub offsets: Vec<u64>,
+}
+
+/// Yara rule matched by a file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct YaraMatch {
+    /// Name of the rule
+    pub rule: String,
+    /// Namespace of the rule
+    pub namespace: String,
+    /// Tags of the rule
+    pub tags: Vec<String>,
+    /// Meta fields of the rule
+    pub meta: BTreeMap<String, YaraMetaValue>,
+    /// Strings found in the file, only if the offsets are reported
+    pub strings: Vec<YaraString>,
+}
+
+/// Detailed report of a file found inside an archive
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ArchiveEntry {
+    /// Path of the entry inside the archive, nested archives are separated by '/'
+    pub path: String,
+    /// Nesting level of the entry, 1 for the entries of the transfered archive
+    pub depth: u32,
+    /// Uncompressed size of the entry
+    pub size: u64,
+    /// Type of the entry
+    pub file_type: String,
+    /// True if the entry type is allowed
+    pub is_type_allowed: bool,
+    /// True if clamav tests pass
+    pub av_pass: bool,
+    /// Detailed report of clamav if the test failed
+    pub av_report: Vec<String>,
+    /// Result of each anti-virus engine
+    #[serde(default)]
+    pub av_engines: Vec<AvEngineReport>,
+    /// True if yara tests pass
+    pub yara_pass: bool,
+    /// Detailed report of yara if the test failed
+    pub yara_report: String,
+    /// Yara rules matched by the entry
+    #[serde(default)]
+    pub yara_matches: Vec<YaraMatch>,
+}
+
+/// Handling of a finding decided by the station policy
+/// Actions are ordered from the least to the most restrictive
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    bincode::Encode,
+    bincode::Decode,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The finding is recorded, the file can be transfered
+    Allow,
+    /// The finding is flagged in the report, the file can be transfered
+    Flag,
+    /// The file must not be transfered
+    Reject,
+}
+
+/// Kind of active content found in an office document
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OfficeFindingKind {
+    /// VBA macros
+    Macro,
+    /// Embedded OLE object
+    OleObject,
+    /// Relationship or field pointing outside of the document
+    ExternalLink,
+    /// DDE field
+    Dde,
+}
+
+/// Active content found in an office document
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct OfficeFinding {
+    /// Kind of content found
+    pub kind: OfficeFindingKind,
+    /// Part or stream of the document containing the content
+    pub location: String,
+    /// Details on the content, e.g. the target of a link
+    pub detail: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// Kind of active content or anomaly found in a PDF document
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PdfFindingKind {
+    /// JavaScript action or name tree
+    JavaScript,
+    /// Action run when the document is opened or on a page event
+    OpenAction,
+    /// Launch action running an external program
+    Launch,
+    /// Embedded file
+    EmbeddedFile,
+    /// XFA form
+    Xfa,
+    /// Encrypted document or stream
+    Encrypted,
+    /// Cross reference table that cannot be parsed or points to invalid objects
+    MalformedXref,
+}
+
+/// Active content or anomaly found in a PDF document
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct PdfFinding {
+    /// Kind of content found
+    pub kind: PdfFindingKind,
+    /// Object containing the content, e.g. "12 0 R", empty for the whole document
+    pub object: String,
+    /// Details on the content, e.g. the name of an embedded file
+    pub detail: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// Kind of mismatch between the file name and the detected type
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtensionFindingKind {
+    /// The extension does not match the detected type
+    Mismatch,
+    /// A decoy extension is placed before an executable one, e.g. invoice.pdf.exe
+    DoubleExtension,
+    /// The name contains unicode bidirectional control characters that can hide the real extension
+    BidiControl,
+    /// The name has no extension but the type is detected
+    Missing,
+}
+
+/// Mismatch between the file name and the detected type
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct ExtensionFinding {
+    /// Kind of mismatch
+    pub kind: ExtensionFindingKind,
+    /// Extension of the file name, the last one if there are several
+    pub declared: String,
+    /// Type detected from the content of the file
+    pub detected: String,
+    /// Details on the mismatch
+    pub detail: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// Kind of finding of the entropy analysis
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum EntropyFindingKind {
+    /// Blocks of high entropy in a type that should not contain compressed or encrypted data
+    HighEntropy,
+    /// Signature of an executable packer
+    Packer,
+    /// The score of the file reaches the threshold of the policy
+    Score,
+}
+
+/// Finding of the entropy analysis
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct EntropyFinding {
+    /// Kind of finding
+    pub kind: EntropyFindingKind,
+    /// Offset of the region or of the signature in the file
+    pub offset: u64,
+    /// Length of the region or of the signature
+    pub length: u64,
+    /// Details on the finding, e.g. the name of the packer
+    pub detail: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// Result of the entropy analysis of a file
+#[derive(
+    Debug, Serialize, Deserialize, Clone, PartialEq, Default, bincode::Encode, bincode::Decode,
+)]
+pub struct EntropyReport {
+    /// Shannon entropy of the whole file in bits per byte, from 0 to 8
+    pub entropy: f64,
+    /// Size of the blocks whose entropy is computed
+    pub block_size: u64,
+    /// Highest entropy of a block in bits per byte
+    pub max_block_entropy: f64,
+    /// Number of blocks over the entropy limit
+    pub high_blocks: u64,
+    /// Score of the file from 0 to 100, 0 if its type is expected to have a high entropy
+    pub score: u32,
+    /// Score from which the file is handled by the policy
+    pub threshold: u32,
+    /// High entropy regions, packers and score found
+    pub findings: Vec<EntropyFinding>,
+}
+
+/// Kind of anomaly found in a text file
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFindingKind {
+    /// The file is not valid UTF-8 and is read as Latin-1
+    Latin1,
+    /// Control characters other than tabulations and line breaks
+    ControlCharacter,
+    /// NUL characters
+    Nul,
+    /// XML or JSON document that is not well-formed
+    Malformed,
+    /// CSV cell starting like a formula, e.g. =HYPERLINK(...)
+    Formula,
+}
+
+/// Anomaly found in a text file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct TextFinding {
+    /// Kind of anomaly
+    pub kind: TextFindingKind,
+    /// Line of the first occurrence, starting at 1, 0 for the whole file
+    pub line: u64,
+    /// Column of the first occurrence in bytes, or cell number of a formula, starting at 1
+    pub column: u64,
+    /// Details on the anomaly, e.g. the beginning of the formula
+    pub detail: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// Kind of metadata embedded in a file
+#[derive(
+    Debug,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    bincode::Encode,
+    bincode::Decode,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataField {
+    /// GPS position where a picture was taken
+    Gps,
+    /// Author, creator or owner of the file, authors of comments
+    Author,
+    /// Last person who saved the document
+    LastModifiedBy,
+    /// Printer used with the document
+    Printer,
+    /// Revision number or history, authors of tracked changes
+    Revision,
+    /// Organization of the author
+    Company,
+    /// Software that created or modified the file
+    Software,
+    /// Make, model or serial number of the device that produced the file
+    Device,
+    /// Dates of creation, modification or printing
+    Date,
+    /// Title, subject, keywords, description or comments
+    Description,
+    /// Template the document is based on
+    Template,
+}
+
+/// Metadata embedded in a file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct MetadataFinding {
+    /// Kind of metadata
+    pub field: MetadataField,
+    /// Location of the metadata in the file, e.g. "EXIF", "docProps/core.xml" or "Info"
+    pub source: String,
+    /// Name of the metadata in its format, e.g. "Artist", "cp:lastModifiedBy" or "Author"
+    pub name: String,
+    /// Value of the metadata
+    pub value: String,
+    /// Handling decided by the station policy
+    pub action: Action,
+}
+
+/// List of SHA-256 digests maintained by the administrator
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashListKind {
+    /// Known good files, the other checks are bypassed
+    Allow,
+    /// Known bad files, always rejected
+    Deny,
+}
+
+/// Entry of a hash list matching the digest of the file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct HashListMatch {
+    /// List containing the digest
+    pub list: HashListKind,
+    /// Digest of the entry
+    pub digest: String,
+    /// Label of the entry
+    pub label: String,
+}
+
 /// Detailed report of the file checks.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileReport {
-    /// Detailed report of the yara checks
+    /// Names of the yara rules matched
     pub yara: String,
-    /// Detailed report of the clamav checks
+    /// Yara rules matched by the file
+    #[serde(default)]
+    pub yara_matches: Vec<YaraMatch>,
+    /// Hash of the Yara rule set used for the scan
+    #[serde(default)]
+    pub yara_ruleset: String,
+    /// Names of the infections detected by the anti-virus engines
     pub av: Vec<String>,
     /// True if the file type is allowed
     pub type_allowed: bool,
@@ -105,13 +524,47 @@ pub struct FileReport {
     pub corrupted: bool,
     /// True if the file size is too big
     pub toobig: bool,
+    /// Verdicts of the analyzers run on the file
+    #[serde(default)]
+    pub analyzers: Vec<AnalyzerVerdict>,
+    /// Detailed report of the entries if the file is an archive
+    #[serde(default)]
+    pub archive: Vec<ArchiveEntry>,
+    /// Active contents found if the file is an office document
+    #[serde(default)]
+    pub office: Vec<OfficeFinding>,
+    /// Active contents and anomalies found if the file is a PDF document
+    #[serde(default)]
+    pub pdf: Vec<PdfFinding>,
+    /// Mismatches between the file name and the detected type
+    #[serde(default)]
+    pub extension: Vec<ExtensionFinding>,
+    /// Entry of the allow or deny list matching the file digest
+    #[serde(default)]
+    pub hash_list: Option<HashListMatch>,
+    /// Result of each anti-virus engine
+    #[serde(default)]
+    pub av_engines: Vec<AvEngineReport>,
+    /// Entropy, packers and score of the file, None if not analyzed
+    #[serde(default)]
+    pub entropy: Option<EntropyReport>,
+    /// Anomalies found if the file is a text, CSV, XML or JSON file
+    #[serde(default)]
+    pub text: Vec<TextFinding>,
+    /// Metadata embedded in images, office and PDF documents
+    #[serde(default)]
+    pub metadata: Vec<MetadataFinding>,
 }
 
 /// Structure that holds a file metadata
-#[derive(Debug, Serialize, Deserialize, Clone, bincode::Decode)]
+#[derive(Debug, Serialize, Deserialize, Clone, bincode::Encode, bincode::Decode)]
 pub struct FileMetadata {
     /// Name of the file
     pub filename: String,
+    /// Path of the file relative to the root of the input device, file name included
+    pub path: String,
+    /// Transfer session of the file
+    pub session: SessionManifest,
     /// SHA256 digest of the file
     pub digest: String,
     /// True if a file corruption as occured during processing
@@ -130,12 +583,43 @@ pub struct FileMetadata {
     pub yara_pass: bool,
     /// Detailed report of yara if the test failed
     pub yara_report: String,
+    /// Yara rules matched by the file
+    pub yara_matches: Vec<YaraMatch>,
+    /// Hash of the Yara rule set used for the scan
+    pub yara_ruleset: String,
     /// Timestamp of the file entering the station
     pub timestamp: String,
     /// True if a file corruption occured during the processing
     pub is_corrupted: bool,
     /// Type of the file
     pub file_type: String,
+    /// Verdicts of the analyzers run on the file
+    pub verdicts: Vec<AnalyzerVerdict>,
+    /// Detailed report of the entries if the file is an archive
+    pub archive_entries: Vec<ArchiveEntry>,
+    /// Active contents found if the file is an office document
+    pub office_findings: Vec<OfficeFinding>,
+    /// Active contents and anomalies found if the file is a PDF document
+    pub pdf_findings: Vec<PdfFinding>,
+    /// Mismatches between the file name and the detected type
+    pub extension_findings: Vec<ExtensionFinding>,
+    /// Entry of the allow or deny list matching the file digest
+    pub hash_list: Option<HashListMatch>,
+    /// Result of each anti-virus engine
+    pub av_engines: Vec<AvEngineReport>,
+    /// Entropy, packers and score of the file, None if not analyzed
+    pub entropy: Option<EntropyReport>,
+    /// Anomalies found if the file is a text, CSV, XML or JSON file
+    pub text_findings: Vec<TextFinding>,
+    /// Metadata embedded in images, office and PDF documents
+    pub metadata_findings: Vec<MetadataFinding>,
+}
+
+impl FileMetadata {
+    /// Returns true if at least one analyzer rejected the file
+    pub fn is_rejected(&self) -> bool {
+        self.verdicts.iter().any(|v| v.verdict.is_reject())
+    }
 }
 
 /// Wrapper around the report metadata creation
@@ -157,15 +641,29 @@ pub fn generate_report_metadata(f: &FileMetadata) -> MetaData {
 
     let new_file_report = FileReport {
         yara: f.yara_report.clone(),
+        yara_matches: f.yara_matches.clone(),
+        yara_ruleset: f.yara_ruleset.clone(),
         av: f.av_report.clone(),
         type_allowed: f.is_type_allowed,
         size: f.size,
         corrupted: f.is_corrupted,
         toobig: f.is_toobig,
+        analyzers: f.verdicts.clone(),
+        archive: f.archive_entries.clone(),
+        office: f.office_findings.clone(),
+        pdf: f.pdf_findings.clone(),
+        extension: f.extension_findings.clone(),
+        hash_list: f.hash_list.clone(),
+        av_engines: f.av_engines.clone(),
+        entropy: f.entropy.clone(),
+        text: f.text_findings.clone(),
+        metadata: f.metadata_findings.clone(),
     };
 
     MetaData {
         name: f.filename.clone(),
+        path: f.path.clone(),
+        session: f.session.id.clone(),
         date: timestamp,
         file_type: f.file_type.clone(),
         is_valid: f.av_pass
@@ -173,13 +671,15 @@ pub fn generate_report_metadata(f: &FileMetadata) -> MetaData {
             && !f.is_toobig
             && !f.is_corrupted
             && f.is_digest_ok
-            && f.is_type_allowed,
+            && f.is_type_allowed
+            && !f.is_rejected(),
         report: new_file_report,
     }
 }
 
 /// Bind the report to the file by signing with ED25519 and ML-DSA87 the concatenation
 /// of the file digest and the report metadata digest.
+/// If the file has been sanitized, the digest of the original file is appended to the concatenation.
 /// The two signatures are concatenated (ED25519 first).
 /// All the fields of the binding are encoded in base64
 ///
@@ -187,11 +687,13 @@ pub fn generate_report_metadata(f: &FileMetadata) -> MetaData {
 ///
 /// * `f` - Metadata from the file analysis, it is used to get the file digest
 /// * `report_meta` - Report metadata that will be included in the json file
+/// * `sanitized_digest` - Digest of the sanitized file if the file has been sanitized
 /// * `sign_keys` - Hybrid key pair to sign the report
 /// * `sign_cert` - Hybrid key pair certificate that will be included in the report
 pub fn bind_and_sign(
     f: &FileMetadata,
     report_meta: &MetaData,
+    sanitized_digest: Option<&str>,
     sign_keys: Option<&HybridKeyPair>,
     sign_cert: &str,
 ) -> Result<Report, anyhow::Error> {
@@ -209,8 +711,18 @@ pub fn bind_and_sign(
         meta_digest.push_str(&format!("{result:x}"));
     }
 
+    // The file written is the sanitized one if there is one
+    let (file_digest, original_digest) = match sanitized_digest {
+        Some(d) => (d.to_string(), f.digest.clone()),
+        None => (f.digest.clone(), String::new()),
+    };
+
     // Sign the report and the file
-    let concat = format!("{}-{}", f.digest, meta_digest);
+    let mut concat = format!("{file_digest}-{meta_digest}");
+    if !original_digest.is_empty() {
+        concat.push('-');
+        concat.push_str(&original_digest);
+    }
 
     let mut signature = Vec::new();
 
@@ -225,7 +737,11 @@ pub fn bind_and_sign(
     Ok(Report {
         metadata: report_meta.clone(),
         binding: Bd {
-            file_digest: general_purpose::STANDARD.encode(f.digest.clone()),
+            file_digest: general_purpose::STANDARD.encode(file_digest),
+            original_digest: match original_digest.is_empty() {
+                true => String::new(),
+                false => general_purpose::STANDARD.encode(original_digest),
+            },
             metadata_digest: general_purpose::STANDARD.encode(meta_digest),
             station_certificate: sign_cert.to_string(),
             report_signature: general_purpose::STANDARD.encode(signature),
@@ -310,6 +826,14 @@ pub fn parse_report(
             return Err(anyhow!("Metadata reference is invalid"));
         }
         message.push_str(&meta_digest);
+
+        // Add the digest of the original file if it has been sanitized
+        if !report.binding.original_digest.is_empty() {
+            let original_digest =
+                general_purpose::STANDARD.decode(&report.binding.original_digest)?;
+            message.push('-');
+            message.push_str(&String::from_utf8(original_digest)?);
+        }
     }
 
     // Signature validation
@@ -380,13 +904,18 @@ mod tests_out {
     use pkcs8::der::{DecodePem, EncodePem};
     use x509_cert::Certificate;
 
-    use crate::file_report::{FileMetadata, bind_and_sign, generate_report_metadata};
+    use crate::file_report::{
+        AnalyzerVerdict, FileMetadata, Verdict, bind_and_sign, generate_report_metadata,
+    };
+    use crate::session::SessionManifest;
 
     #[test]
     fn test_metadata_valid_file() {
         // Generate dummy file data
         let file_data = FileMetadata {
             filename: "test.txt".to_string(),
+            path: "test.txt".to_string(),
+            session: SessionManifest::default(),
             digest: "00112233445566778899AABBCCDDEEFF".to_string(),
             is_digest_ok: true,
             is_toobig: false,
@@ -396,9 +925,21 @@ mod tests_out {
             av_report: Vec::new(),
             yara_pass: true,
             yara_report: "".to_string(),
+            yara_matches: Vec::new(),
+            yara_ruleset: String::new(),
             timestamp: "timestamp".to_string(),
             is_corrupted: false,
             file_type: "txt".to_string(),
+            verdicts: Vec::new(),
+            archive_entries: Vec::new(),
+            office_findings: Vec::new(),
+            pdf_findings: Vec::new(),
+            extension_findings: Vec::new(),
+            hash_list: None,
+            av_engines: Vec::new(),
+            entropy: None,
+            text_findings: Vec::new(),
+            metadata_findings: Vec::new(),
         };
 
         // Generate report metadata
@@ -410,6 +951,54 @@ mod tests_out {
         assert_eq!(meta.is_valid, true);
     }
 
+    #[test]
+    fn test_metadata_rejected_by_analyzer() {
+        // Generate dummy file data with all the legacy checks passing
+        let file_data = FileMetadata {
+            filename: "test.txt".to_string(),
+            path: "test.txt".to_string(),
+            session: SessionManifest::default(),
+            digest: "00112233445566778899AABBCCDDEEFF".to_string(),
+            is_digest_ok: true,
+            is_toobig: false,
+            size: 42,
+            is_type_allowed: true,
+            av_pass: true,
+            av_report: Vec::new(),
+            yara_pass: true,
+            yara_report: "".to_string(),
+            yara_matches: Vec::new(),
+            yara_ruleset: String::new(),
+            timestamp: "timestamp".to_string(),
+            is_corrupted: false,
+            file_type: "txt".to_string(),
+            verdicts: vec![
+                AnalyzerVerdict {
+                    analyzer: "digest".to_string(),
+                    verdict: Verdict::Pass,
+                },
+                AnalyzerVerdict {
+                    analyzer: "custom".to_string(),
+                    verdict: Verdict::Reject("forbidden content".to_string()),
+                },
+            ],
+            archive_entries: Vec::new(),
+            office_findings: Vec::new(),
+            pdf_findings: Vec::new(),
+            extension_findings: Vec::new(),
+            hash_list: None,
+            av_engines: Vec::new(),
+            entropy: None,
+            text_findings: Vec::new(),
+            metadata_findings: Vec::new(),
+        };
+
+        let meta = generate_report_metadata(&file_data);
+
+        assert!(!meta.is_valid);
+        assert_eq!(meta.report.analyzers, file_data.verdicts);
+    }
+
     #[test]
     #[cfg_attr(miri, ignore)]
     fn test_bind_and_sign() {
@@ -433,6 +1022,8 @@ mod tests_out {
         // Generate dummy file data
         let file_data = FileMetadata {
             filename: "test.txt".to_string(),
+            path: "test.txt".to_string(),
+            session: SessionManifest::default(),
             digest: "00112233445566778899AABBCCDDEEFF".to_string(),
             is_digest_ok: true,
             is_toobig: false,
@@ -442,14 +1033,26 @@ mod tests_out {
             av_report: Vec::new(),
             yara_pass: true,
             yara_report: "".to_string(),
+            yara_matches: Vec::new(),
+            yara_ruleset: String::new(),
             timestamp: "timestamp".to_string(),
             is_corrupted: false,
             file_type: "txt".to_string(),
+            verdicts: Vec::new(),
+            archive_entries: Vec::new(),
+            office_findings: Vec::new(),
+            pdf_findings: Vec::new(),
+            extension_findings: Vec::new(),
+            hash_list: None,
+            av_engines: Vec::new(),
+            entropy: None,
+            text_findings: Vec::new(),
+            metadata_findings: Vec::new(),
         };
 
         let meta = generate_report_metadata(&file_data);
 
-        let report = bind_and_sign(&file_data, &meta, Some(&sign_keys), &sign_cert).unwrap();
+        let report = bind_and_sign(&file_data, &meta, None, Some(&sign_keys), &sign_cert).unwrap();
         // Test the generated report
         // Reconstruct the public keys from the binding certficates
         let mut certs = report.binding.station_certificate.split('|');
@@ -528,4 +1131,68 @@ mod tests_out {
                 .is_ok()
         );
     }
+
+    #[test]
+    fn test_bind_sanitized_file() {
+        let file_data = FileMetadata {
+            filename: "test.png".to_string(),
+            path: "test.png".to_string(),
+            session: SessionManifest::default(),
+            digest: "00112233445566778899AABBCCDDEEFF".to_string(),
+            is_digest_ok: true,
+            is_toobig: false,
+            size: 42,
+            is_type_allowed: true,
+            av_pass: true,
+            av_report: Vec::new(),
+            yara_pass: true,
+            yara_report: "".to_string(),
+            yara_matches: Vec::new(),
+            yara_ruleset: String::new(),
+            timestamp: "timestamp".to_string(),
+            is_corrupted: false,
+            file_type: "image/png".to_string(),
+            verdicts: Vec::new(),
+            archive_entries: Vec::new(),
+            office_findings: Vec::new(),
+            pdf_findings: Vec::new(),
+            extension_findings: Vec::new(),
+            hash_list: None,
+            av_engines: Vec::new(),
+            entropy: None,
+            text_findings: Vec::new(),
+            metadata_findings: Vec::new(),
+        };
+        let meta = generate_report_metadata(&file_data);
+
+        // The original digest is only recorded for sanitized files
+        let report = bind_and_sign(&file_data, &meta, None, None, "").unwrap();
+        assert!(report.binding.original_digest.is_empty());
+        assert!(
+            !serde_json::to_string(&report.binding)
+                .unwrap()
+                .contains("original_digest")
+        );
+
+        let report = bind_and_sign(
+            &file_data,
+            &meta,
+            Some("FFEEDDCCBBAA99887766554433221100"),
+            None,
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(&report.binding.file_digest)
+                .unwrap(),
+            b"FFEEDDCCBBAA99887766554433221100"
+        );
+        assert_eq!(
+            general_purpose::STANDARD
+                .decode(&report.binding.original_digest)
+                .unwrap(),
+            file_data.digest.as_bytes()
+        );
+    }
 }
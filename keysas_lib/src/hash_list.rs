@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ * The "keysas-lib".
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the lists of SHA-256 digests
+ * maintained by the administrator.
+ */
+
+//! Allow and deny lists of SHA-256 digests
+//!
+//! A list is a text file with one entry per line: the SHA-256 digest of a file
+//! in hexadecimal followed by a label, e.g.
+//!
+//! ```text
+//! # Vendor installers
+//! e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 Installer v1.2
+//! ```
+//!
+//! Empty lines and lines starting with # are ignored.
+//! The lists are written by keysas-admin and read by keysas-transit.
+
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Default path of the deny list on the station
+pub const DENY_LIST_PATH: &str = "/etc/keysas/hash-deny.list";
+
+/// Default path of the allow list on the station
+pub const ALLOW_LIST_PATH: &str = "/etc/keysas/hash-allow.list";
+
+/// List of SHA-256 digests with their label
+#[derive(Debug, Default, Clone)]
+pub struct HashList {
+    /// Label of each digest, digests are in lowercase
+    entries: HashMap<String, String>,
+}
+
+impl HashList {
+    /// Parse the content of a list
+    /// Returns an error with the line number if an entry is not a valid SHA-256 digest
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (digest, label) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow!("Invalid SHA-256 digest on line {}", n + 1));
+            }
+            entries.insert(digest.to_lowercase(), label.trim().to_string());
+        }
+        Ok(Self { entries })
+    }
+
+    /// Load a list from a file
+    /// An empty list is returned if the file does not exist
+    pub fn load(path: &str) -> Result<Self> {
+        match fs::read_to_string(Path::new(path)) {
+            Ok(content) => Self::parse(&content).with_context(|| format!("Invalid list {path}")),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read list {path}")),
+        }
+    }
+
+    /// Returns the label of the digest if it is in the list
+    pub fn get(&self, digest: &str) -> Option<&str> {
+        self.entries.get(&digest.to_lowercase()).map(String::as_str)
+    }
+
+    /// Number of digests in the list
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the list is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests_hash_list {
+    use super::HashList;
+
+    #[test]
+    fn test_parse_hash_list() {
+        let list = HashList::parse(
+            "# Vendor installers\n\n\
+             E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855  Empty file \n\
+             ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb\n",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.get("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            Some("Empty file")
+        );
+        assert_eq!(
+            list.get("ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"),
+            Some("")
+        );
+        assert_eq!(list.get("00"), None);
+
+        let e = HashList::parse("# comment\nnot-a-digest label\n").unwrap_err();
+        assert_eq!(e.to_string(), "Invalid SHA-256 digest on line 2");
+        assert!(HashList::load("/nonexistent/hash.list").unwrap().is_empty());
+    }
+}
@@ -9,6 +9,8 @@
 //! This module contains utility functions for the rest of Keysas
 
 #![feature(str_split_remainder)]
+#![feature(unix_socket_ancillary_data)]
+#![feature(peer_credentials_unix_socket)]
 use anyhow::Result;
 use regex::Regex;
 use sha2::{Digest, Sha256};
@@ -21,13 +23,17 @@ use std::io::IoSlice;
 use std::io::{BufReader, Read};
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 pub mod certificate_field;
 pub mod file_report;
+pub mod hash_list;
 pub mod keysas_hybrid_keypair;
 pub mod keysas_key;
 pub mod pki;
+#[cfg(target_os = "linux")]
+pub mod protocol;
+pub mod session;
 
 // Init logger
 pub fn init_logger() {
@@ -38,7 +44,7 @@ pub fn init_logger() {
     }
 }
 
-/// This function computes the SHA-256 digest of a file
+/// This function computes the SHA-256 digest of a file or of any reader
 ///
 /// Example:
 ///```
@@ -59,7 +65,7 @@ pub fn init_logger() {
 /// let digest = sha256_digest(&fd).unwrap();
 /// assert_eq!(digest, String::from("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"))
 /// ```
-pub fn sha256_digest(input: &File) -> Result<String> {
+pub fn sha256_digest<R: Read>(input: R) -> Result<String> {
     let mut reader = BufReader::new(input);
 
     let digest = {
@@ -77,7 +83,11 @@ pub fn sha256_digest(input: &File) -> Result<String> {
     Ok(format!("{digest:x}"))
 }
 
-/// This function lists all files in a directory except hidden ones or links.
+/// Maximum depth of the directories walked by [list_files]
+pub const MAX_DEPTH: usize = 32;
+
+/// This function lists all files in a directory and its sub-directories except hidden ones or links.
+/// The paths returned are relative to the directory, hidden directories are not walked.
 ///
 /// Example:
 /// ```
@@ -89,35 +99,82 @@ pub fn sha256_digest(input: &File) -> Result<String> {
 ///
 /// let dir = tempdir().unwrap();
 /// let path = dir.path().join("transit");
-/// let _output = fs::create_dir_all(&path).unwrap();
+/// let _output = fs::create_dir_all(path.join("photos/2024")).unwrap();
+/// let _output = fs::create_dir_all(path.join(".trash")).unwrap();
 /// assert_eq!(true, Path::new(&path).exists());
 /// let file = path.join("file.txt");
 /// let _output = File::create(&file).unwrap();
 /// assert_eq!(true, Path::new(&file).exists());
-/// let files = list_files(path.to_str().unwrap());
-/// assert_eq!(files.unwrap(), ["file.txt"]);
+/// let _output = File::create(path.join("photos/2024/file.txt")).unwrap();
+/// let _output = File::create(path.join(".trash/file.txt")).unwrap();
+/// let mut files = list_files(path.to_str().unwrap()).unwrap();
+/// files.sort();
+/// assert_eq!(files, ["file.txt", "photos/2024/file.txt"]);
 /// ```
 pub fn list_files(directory: &str) -> Result<Vec<String>> {
-    let paths: std::fs::ReadDir = fs::read_dir(directory)?;
-
-    let mut names = paths
-        .filter_map(|entry| {
-            entry.ok().and_then(|e| {
-                let path = e.path();
-                if path.is_file() {
-                    path.file_name().and_then(|n| n.to_str().map(String::from))
-                } else {
-                    None
-                }
-            })
-        })
-        .collect::<Vec<String>>();
     // Not sending any files starting with dot like .bashrc
     let re = Regex::new(r"^\.([a-z])*")?;
-    names.retain(|x| !re.is_match(x));
+    let mut names = Vec::new();
+    let mut directories = vec![(PathBuf::new(), 0)];
+    while let Some((relative, depth)) = directories.pop() {
+        let entries = match fs::read_dir(Path::new(directory).join(&relative)) {
+            Ok(entries) => entries,
+            // Only the errors on the top level directory are reported
+            Err(e) if depth == 0 => return Err(e.into()),
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if re.is_match(name) {
+                continue;
+            }
+            // Links are not followed
+            match entry.file_type() {
+                Ok(t) if t.is_file() => {
+                    if let Some(path) = relative.join(name).to_str() {
+                        names.push(path.to_string());
+                    }
+                }
+                Ok(t) if t.is_dir() && depth < MAX_DEPTH => {
+                    directories.push((relative.join(name), depth + 1));
+                }
+                _ => (),
+            }
+        }
+    }
     Ok(names)
 }
 
+/// This function checks a path received from another daemon before it is used
+/// under a directory. It returns the path if it is relative and made of plain names only:
+/// no root, `..` or `.` component that could escape the directory.
+///
+/// Example:
+/// ```
+/// use keysas_lib::safe_relative_path;
+/// use std::path::PathBuf;
+///
+/// assert_eq!(safe_relative_path("photos/2024/file.txt"), Some(PathBuf::from("photos/2024/file.txt")));
+/// assert_eq!(safe_relative_path("photos//file.txt"), Some(PathBuf::from("photos/file.txt")));
+/// assert_eq!(safe_relative_path("../etc/passwd"), None);
+/// assert_eq!(safe_relative_path("photos/../../file.txt"), None);
+/// assert_eq!(safe_relative_path("/etc/passwd"), None);
+/// assert_eq!(safe_relative_path("./file.txt"), None);
+/// assert_eq!(safe_relative_path(""), None);
+/// ```
+pub fn safe_relative_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let plain = path.components().all(|c| matches!(c, Component::Normal(_)));
+    let depth = path.components().count();
+    match plain && depth > 0 && depth <= MAX_DEPTH + 1 {
+        true => Some(path.components().collect()),
+        false => None,
+    }
+}
+
 #[cfg(target_os = "linux")]
 pub fn convert_ioslice<'a>(
     files: &'a Vec<File>,
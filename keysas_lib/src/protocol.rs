@@ -0,0 +1,527 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the protocol between the keysas daemons
+ */
+
+//! keysas-in sends the files to keysas-transit, which sends them to keysas-out, on abstract
+//! unix sockets. Each file is sent in a frame with its descriptor attached (SCM_RIGHTS):
+//! ```text
+//! +-------------+--------------+-----------+-------------+------------------------+
+//! | magic: KSAS | version: u16 | kind: u8  | length: u32 | payload (bincode)      |
+//! +-------------+--------------+-----------+-------------+------------------------+
+//! ```
+//! The integers are big endian. The receiver replies to each file, in the order the files
+//! are received, with an acknowledgement once it is in charge of the file or with an error
+//! explaining why it is refused. The sender may send several files before reading the replies.
+//!
+//! A daemon survives the restart of its peers: the listening daemon accepts the next connection
+//! and the connecting one connects again with an exponential backoff, see [accept] and [connect].
+//!
+//! A frame that is not a frame of the protocol is refused and the connection closed, see [refuse].
+//! A frame of another version is refused the same way and the daemons stop instead of connecting
+//! again: the daemons of the station must be updated together.
+//! The peers are authenticated with their credentials (SO_PEERCRED): each daemon runs
+//! under its own user.
+
+use crate::session::SessionManifest;
+use anyhow::anyhow;
+use bincode::{Decode, Encode};
+use log::warn;
+use nix::unistd::User;
+use std::fmt;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{AncillaryData, SocketAddr, SocketAncillary, UnixListener, UnixStream};
+use std::thread;
+use std::time::Duration;
+
+/// Magic number starting each frame
+pub const MAGIC: &[u8; 4] = b"KSAS";
+/// Version of the protocol, to be increased on each change of the messages
+pub const VERSION: u16 = 1;
+/// Length of the frame header
+pub const HEADER_LEN: usize = 11;
+/// Maximum length of a payload
+pub const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+/// Delay before connecting again to a peer, doubled after each failure
+pub const RETRY_MIN: Duration = Duration::from_millis(100);
+/// Maximum delay before connecting again to a peer
+pub const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Metadata of a file sent by keysas-in to keysas-transit
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct InputMetadata {
+    /// Name of the file
+    pub filename: String,
+    /// Path of the file relative to the root of the input device, file name included
+    pub path: String,
+    /// Transfer session of the file
+    pub session: SessionManifest,
+    /// SHA256 digest of the file
+    pub digest: String,
+    /// Timestamp of the file entering the station
+    pub timestamp: String,
+    /// True if keysas-io failed to copy the file
+    pub is_corrupted: bool,
+}
+
+/// Kind of frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum Kind {
+    File = 1,
+    Ack = 2,
+    Error = 3,
+}
+
+impl Kind {
+    fn parse(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::File),
+            2 => Some(Self::Ack),
+            3 => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Reason of a refusal
+#[derive(Debug, Clone, Copy, PartialEq, Encode, Decode)]
+pub enum ErrorCode {
+    /// The frame is not a frame of the protocol
+    Invalid,
+    /// The version of the frame is not supported
+    Version,
+    /// The payload cannot be decoded
+    Decode,
+    /// The receiver cannot take charge of the file
+    Internal,
+}
+
+/// Error replied to a file
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct ErrorReply {
+    /// Reason of the refusal
+    pub code: ErrorCode,
+    /// Description of the error
+    pub message: String,
+}
+
+/// Error of the protocol
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The peer closed the connection
+    Closed,
+    /// Error of the socket
+    Io(io::Error),
+    /// Not a frame of the protocol, the connection cannot be used anymore
+    Invalid(String),
+    /// Frame of another version of the protocol
+    Version(u16),
+    /// The payload of the frame cannot be decoded, the connection can still be used
+    Decode(String),
+    /// The peer does not run under the expected user
+    Peer {
+        /// User of the peer
+        uid: u32,
+        /// Process of the peer
+        pid: Option<i32>,
+    },
+    /// The peer refused the file
+    Refused(ErrorReply),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed => write!(f, "connection closed by the peer"),
+            Self::Io(e) => write!(f, "socket error: {e}"),
+            Self::Invalid(e) => write!(f, "invalid frame: {e}"),
+            Self::Version(v) => write!(f, "protocol version {v} received, {VERSION} expected"),
+            Self::Decode(e) => write!(f, "cannot decode the payload: {e}"),
+            Self::Peer { uid, pid } => write!(f, "unexpected peer: uid {uid}, pid {pid:?}"),
+            Self::Refused(r) => write!(f, "refused by the peer ({:?}): {}", r.code, r.message),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Closed,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Returns true if the connection cannot be used anymore
+    /// The peer closes the connection after refusing an invalid frame
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Self::Decode(_) => false,
+            Self::Refused(r) => matches!(r.code, ErrorCode::Invalid | ErrorCode::Version),
+            _ => true,
+        }
+    }
+
+    /// Returns true if the peer runs another version of the protocol,
+    /// whether the version is detected here or reported by the peer
+    pub fn is_version(&self) -> bool {
+        match self {
+            Self::Version(_) => true,
+            Self::Refused(r) => r.code == ErrorCode::Version,
+            _ => false,
+        }
+    }
+
+    /// Error to reply to the peer
+    pub fn reply(&self) -> ErrorReply {
+        let code = match self {
+            Self::Invalid(_) => ErrorCode::Invalid,
+            Self::Version(_) => ErrorCode::Version,
+            Self::Decode(_) => ErrorCode::Decode,
+            _ => ErrorCode::Internal,
+        };
+        ErrorReply {
+            code,
+            message: self.to_string(),
+        }
+    }
+}
+
+/// Frame received
+#[derive(Debug)]
+enum Frame<T> {
+    File(T, OwnedFd),
+    Ack,
+    Error(ErrorReply),
+}
+
+fn header(kind: Kind, length: usize) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..6].copy_from_slice(&VERSION.to_be_bytes());
+    header[6] = kind as u8;
+    header[7..].copy_from_slice(&(length as u32).to_be_bytes());
+    header
+}
+
+/// Write a frame, the descriptor is attached to its first byte
+fn write_frame<T: Encode>(
+    stream: &UnixStream,
+    kind: Kind,
+    payload: &T,
+    fd: Option<BorrowedFd>,
+) -> Result<(), ProtocolError> {
+    let payload =
+        bincode::encode_to_vec(payload, bincode::config::standard()).map_err(io::Error::other)?;
+    if payload.len() > MAX_PAYLOAD {
+        let e = format!("payload of {} bytes is too big", payload.len());
+        return Err(io::Error::other(e).into());
+    }
+    let mut frame = header(kind, payload.len()).to_vec();
+    frame.extend(payload);
+    let mut ancillary_buffer = [0; 128];
+    let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+    if let Some(fd) = fd {
+        ancillary.add_fds(&[fd.as_raw_fd()]);
+    }
+    let sent = stream.send_vectored_with_ancillary(&[IoSlice::new(&frame)], &mut ancillary)?;
+    // The descriptor is sent with the first bytes, then the rest of the frame
+    (&*stream).write_all(&frame[sent..])?;
+    Ok(())
+}
+
+/// Read a frame
+fn read_frame<T: Decode<()>>(stream: &UnixStream) -> Result<Frame<T>, ProtocolError> {
+    let mut header = [0; HEADER_LEN];
+    let mut fds = Vec::new();
+    let mut read = 0;
+    while read < HEADER_LEN {
+        let mut ancillary_buffer = [0; 128];
+        let mut ancillary = SocketAncillary::new(&mut ancillary_buffer);
+        let bufs = &mut [IoSliceMut::new(&mut header[read..])];
+        let size = stream.recv_vectored_with_ancillary(bufs, &mut ancillary)?;
+        for ad in ancillary.messages().flatten() {
+            if let AncillaryData::ScmRights(rights) = ad {
+                // Safety: descriptors received with SCM_RIGHTS are new descriptors of this
+                // process that nothing else owns, they are closed if the frame is refused
+                fds.extend(rights.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        if ancillary.truncated() {
+            return Err(ProtocolError::Invalid("too many descriptors".into()));
+        }
+        if size == 0 {
+            return Err(ProtocolError::Closed);
+        }
+        read += size;
+    }
+
+    if &header[..4] != MAGIC {
+        return Err(ProtocolError::Invalid("bad magic number".into()));
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(ProtocolError::Version(version));
+    }
+    let kind = Kind::parse(header[6])
+        .ok_or_else(|| ProtocolError::Invalid(format!("unknown kind {}", header[6])))?;
+    let length = u32::from_be_bytes([header[7], header[8], header[9], header[10]]) as usize;
+    if length > MAX_PAYLOAD {
+        return Err(ProtocolError::Invalid(format!("payload of {length} bytes")));
+    }
+    let mut payload = vec![0; length];
+    (&*stream).read_exact(&mut payload)?;
+
+    match (kind, fds.len()) {
+        (Kind::File, 1) => Ok(Frame::File(decode(&payload)?, fds.remove(0))),
+        (Kind::File, n) => Err(ProtocolError::Decode(format!("{n} descriptors in a file"))),
+        (Kind::Ack, _) => Ok(Frame::Ack),
+        (Kind::Error, _) => Ok(Frame::Error(decode(&payload)?)),
+    }
+}
+
+/// Decode a payload, it must be decoded entirely
+fn decode<T: Decode<()>>(payload: &[u8]) -> Result<T, ProtocolError> {
+    let config = bincode::config::standard().with_limit::<MAX_PAYLOAD>();
+    match bincode::decode_from_slice(payload, config) {
+        Ok((value, length)) if length == payload.len() => Ok(value),
+        Ok((_, length)) => Err(ProtocolError::Decode(format!(
+            "{} bytes left",
+            payload.len() - length
+        ))),
+        Err(e) => Err(ProtocolError::Decode(e.to_string())),
+    }
+}
+
+/// Send a file, its reply is read with [receive_reply]
+///
+/// # Arguments
+///
+/// * `stream` - Connection with the peer
+/// * `metadata` - Metadata of the file
+/// * `fd` - Descriptor of the file
+pub fn send_file<T: Encode>(
+    stream: &UnixStream,
+    metadata: &T,
+    fd: BorrowedFd,
+) -> Result<(), ProtocolError> {
+    write_frame(stream, Kind::File, metadata, Some(fd))
+}
+
+/// Wait for the reply to the oldest file sent
+/// Returns [ProtocolError::Refused] if the peer refused the file
+pub fn receive_reply(stream: &UnixStream) -> Result<(), ProtocolError> {
+    match read_frame::<()>(stream)? {
+        Frame::Ack => Ok(()),
+        Frame::Error(reply) => Err(ProtocolError::Refused(reply)),
+        Frame::File(..) => Err(ProtocolError::Invalid("file received as reply".into())),
+    }
+}
+
+/// Receive a file, the peer waits for the reply: [reply_ack] or [reply_error]
+/// Returns [ProtocolError::Refused] if the peer refused a frame sent on the connection
+pub fn receive_file<T: Decode<()>>(stream: &UnixStream) -> Result<(T, OwnedFd), ProtocolError> {
+    match read_frame(stream)? {
+        Frame::File(metadata, fd) => Ok((metadata, fd)),
+        Frame::Error(reply) => Err(ProtocolError::Refused(reply)),
+        Frame::Ack => Err(ProtocolError::Invalid(
+            "reply received instead of a file".into(),
+        )),
+    }
+}
+
+/// Acknowledge the file received, the receiver is now in charge of it
+pub fn reply_ack(stream: &UnixStream) -> Result<(), ProtocolError> {
+    write_frame(stream, Kind::Ack, &(), None)
+}
+
+/// Refuse the file received
+pub fn reply_error(stream: &UnixStream, reply: &ErrorReply) -> Result<(), ProtocolError> {
+    write_frame(stream, Kind::Error, reply, None)
+}
+
+/// Tell the peer why the frame it sent is refused
+/// Nothing is sent for the errors of the connection itself or of the peer
+pub fn refuse(stream: &UnixStream, error: &ProtocolError) {
+    if !matches!(
+        error,
+        ProtocolError::Invalid(_) | ProtocolError::Version(_) | ProtocolError::Decode(_)
+    ) {
+        return;
+    }
+    if let Err(e) = reply_error(stream, &error.reply()) {
+        warn!("Failed to reply to the peer: {e}");
+    }
+}
+
+/// Connect to the abstract socket `name` of a peer running under the user `uid`
+/// The connection is retried with an exponential backoff until the peer is available
+pub fn connect(name: &str, uid: u32) -> Result<UnixStream, ProtocolError> {
+    let addr = SocketAddr::from_abstract_name(name)?;
+    let mut delay = RETRY_MIN;
+    loop {
+        let stream = UnixStream::connect_addr(&addr).map_err(ProtocolError::from);
+        match stream.and_then(|s| check_peer(&s, uid).map(|_| s)) {
+            Ok(s) => return Ok(s),
+            Err(e) => warn!("Cannot connect to {name}: {e}, retrying in {delay:?}"),
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(RETRY_MAX);
+    }
+}
+
+/// Accept the next connection of a peer running under the user `uid`,
+/// the connections of other users are refused
+pub fn accept(listener: &UnixListener, uid: u32) -> Result<UnixStream, ProtocolError> {
+    loop {
+        let (stream, _addr) = listener.accept()?;
+        match check_peer(&stream, uid) {
+            Ok(_) => return Ok(stream),
+            Err(e) => warn!("Connection refused: {e}"),
+        }
+    }
+}
+
+/// Returns the identifier of a user, to be called before the sandbox is activated
+pub fn user_id(name: &str) -> Result<u32, anyhow::Error> {
+    match User::from_name(name)? {
+        Some(user) => Ok(user.uid.as_raw()),
+        None => Err(anyhow!("unknown user")),
+    }
+}
+
+/// Check that the peer runs under the user `uid`
+pub fn check_peer(stream: &UnixStream, uid: u32) -> Result<(), ProtocolError> {
+    let cred = stream.peer_cred()?;
+    match cred.uid == uid {
+        true => Ok(()),
+        false => Err(ProtocolError::Peer {
+            uid: cred.uid,
+            pid: cred.pid,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::fd::AsFd;
+    use std::thread;
+
+    fn metadata() -> InputMetadata {
+        InputMetadata {
+            filename: "file.txt".into(),
+            path: "dir/file.txt".into(),
+            session: SessionManifest::new("network"),
+            digest: "digest".into(),
+            timestamp: "timestamp".into(),
+            is_corrupted: false,
+        }
+    }
+
+    #[test]
+    fn test_send_file() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let peer = thread::spawn(move || {
+            let (md, fd) = receive_file::<InputMetadata>(&receiver).unwrap();
+            let mut content = String::new();
+            File::from(fd).read_to_string(&mut content).unwrap();
+            reply_ack(&receiver).unwrap();
+            // The next file is refused
+            let e = receive_file::<u64>(&receiver).unwrap_err();
+            assert!(matches!(e, ProtocolError::Decode(_)));
+            assert!(!e.is_fatal());
+            reply_error(&receiver, &e.reply()).unwrap();
+            (md, content)
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file.txt"), b"content").unwrap();
+        let file = File::open(dir.path().join("file.txt")).unwrap();
+        let md = metadata();
+        // The replies are read once both files are sent
+        send_file(&sender, &md, file.as_fd()).unwrap();
+        send_file(&sender, &String::from("not a number"), file.as_fd()).unwrap();
+        receive_reply(&sender).unwrap();
+        match receive_reply(&sender) {
+            Err(ProtocolError::Refused(reply)) => assert_eq!(reply.code, ErrorCode::Decode),
+            r => panic!("Unexpected reply: {r:?}"),
+        }
+        let (received, content) = peer.join().unwrap();
+        assert_eq!(received.path, md.path);
+        assert_eq!(received.session, md.session);
+        assert_eq!(content, "content");
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        // Another version of the protocol
+        let mut frame = header(Kind::Ack, 0);
+        frame[5] = 2;
+        (&sender).write_all(&frame).unwrap();
+        let e = read_frame::<()>(&receiver).unwrap_err();
+        assert!(matches!(e, ProtocolError::Version(2)));
+        // The sender is told before the connection is closed, it must not connect again
+        refuse(&receiver, &e);
+        let e = receive_reply(&sender).unwrap_err();
+        assert!(e.is_fatal() && e.is_version());
+        // Not a frame of the protocol
+        (&sender).write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let e = receive_file::<InputMetadata>(&receiver).unwrap_err();
+        assert!(matches!(e, ProtocolError::Invalid(_)));
+        assert!(e.is_fatal());
+        drop(sender);
+        let mut rest = Vec::new();
+        (&receiver).read_to_end(&mut rest).unwrap();
+        assert!(matches!(
+            read_frame::<()>(&receiver),
+            Err(ProtocolError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_reconnection() {
+        let name = format!("keysas-test-{}", std::process::id());
+        let uid = nix::unistd::getuid().as_raw();
+        let listener =
+            UnixListener::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        let peer = thread::spawn(move || {
+            // The first connection is closed, as if the peer restarted
+            drop(accept(&listener, uid).unwrap());
+            let stream = accept(&listener, uid).unwrap();
+            let (md, _fd) = receive_file::<InputMetadata>(&stream).unwrap();
+            reply_ack(&stream).unwrap();
+            md
+        });
+
+        let file = tempfile::tempfile().unwrap();
+        let stream = connect(&name, uid).unwrap();
+        assert!(matches!(receive_reply(&stream), Err(ProtocolError::Closed)));
+        let stream = connect(&name, uid).unwrap();
+        send_file(&stream, &metadata(), file.as_fd()).unwrap();
+        receive_reply(&stream).unwrap();
+        assert_eq!(peer.join().unwrap().filename, "file.txt");
+    }
+
+    #[test]
+    fn test_check_peer() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let uid = receiver.peer_cred().unwrap().uid;
+        assert!(check_peer(&sender, uid).is_ok());
+        assert!(matches!(
+            check_peer(&sender, uid + 1),
+            Err(ProtocolError::Peer { .. })
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+// SPDX-License-Identifier: GPL-3.0-only
+/*
+ *
+ * (C) Copyright 2019-2025 Stephane Neveu, Luc Bonnafoux
+ *
+ * This file contains the transfer sessions and their signed summary
+ */
+
+//! The files of a transfer, e.g. the files of a USB device copied at once by keysas-io,
+//! share a session. keysas-io writes the [SessionManifest] of the device in sas_in,
+//! keysas-in completes it with the number and the size of the files it sends (or creates
+//! one for the files received from the network) and attaches it to each file.
+//!
+//! keysas-out writes a signed summary of the session next to the file reports, it is
+//! updated each time a file of the session goes out of the station. The summary is closed
+//! once all the files of the session are received, or once no file of the session has been
+//! received for a while, the last summary written is then final:
+//! ```json
+//! {
+//!     "summary": {
+//!         "session": {
+//!             "id",           // String: 128 bits random identifier in hexadecimal
+//!             "device",       // String: source of the files, e.g. the USB device vendor/model/revision/serial
+//!             "date",         // String DD-MM-YYYY_HH-mm-SS-NN: Date of creation of the session
+//!             "files",        // u64: number of files sent by keysas-in
+//!             "bytes"         // u64: total size of the files sent by keysas-in
+//!         },
+//!         "date",             // String DD-MM-YYYY_HH-mm-SS-NN: Date of the last update of the summary
+//!         "received",         // u64: number of files received by keysas-out
+//!         "valid",            // u64: number of files with a valid report
+//!         "complete",         // Boolean: true once all the files of the session are received
+//!         "closed",           // Boolean: true once the summary is final, complete or not
+//!         "files": [{
+//!             "path",         // String: path of the file relative to the input device
+//!             "digest",       // String: SHA256 digest of the file received by keysas-in
+//!             "is_valid",     // Boolean: true if all checks passed
+//!             "written"       // Boolean: true if the file has been written to sas_out
+//!         }]
+//!     },
+//!     "binding" : {
+//!         "summary_digest",      // String: base64 encoded SHA256 digest of the summary
+//!         "station_certificate", // String: concatenation of the station signing certificates PEM
+//!         "report_signature",    // String: base64 encoded concatenation of the ED25519 and ML-DSA87 signatures
+//!     }
+//! }
+//! ```
+
+use crate::keysas_hybrid_keypair::HybridKeyPair;
+use crate::keysas_key::KeysasKey;
+use anyhow::anyhow;
+use base64::{Engine as _, engine::general_purpose};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// Name of the manifest written by keysas-io in sas_in, hidden so that it is not sent
+pub const MANIFEST: &str = ".session";
+
+/// Extension of the session summary written by keysas-out
+pub const SUMMARY_EXTENSION: &str = "ksr";
+
+fn timestamp() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!(
+        "{}-{}-{}_{}-{}-{}-{}",
+        now.day(),
+        now.month(),
+        now.year(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.nanosecond()
+    )
+}
+
+/// Manifest of a transfer session
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Default, PartialEq, bincode::Encode, bincode::Decode,
+)]
+pub struct SessionManifest {
+    /// Random identifier of the session in hexadecimal
+    pub id: String,
+    /// Source of the files
+    pub device: String,
+    /// Date of creation of the session
+    pub date: String,
+    /// Number of files of the session
+    pub files: u64,
+    /// Total size of the files of the session
+    pub bytes: u64,
+}
+
+impl SessionManifest {
+    /// Create a new session for the files coming from `device`
+    /// The number and the size of the files are set by keysas-in
+    pub fn new(device: &str) -> Self {
+        Self {
+            id: format!("{:032x}", rand_dl::random::<u128>()),
+            device: device.to_string(),
+            date: timestamp(),
+            files: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Returns true if the identifier can name the summary file: 32 hexadecimal digits
+    ///
+    /// ```
+    /// use keysas_lib::session::SessionManifest;
+    ///
+    /// assert!(SessionManifest::new("network").is_valid_id());
+    /// let forged = SessionManifest { id: "../../etc/passwd".into(), ..Default::default() };
+    /// assert!(!forged.is_valid_id());
+    /// ```
+    pub fn is_valid_id(&self) -> bool {
+        self.id.len() == 32 && self.id.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    /// Write the manifest in the directory `dir`
+    /// The manifest is written next to its final name then renamed,
+    /// it is never read while partially written
+    pub fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        let tmp = dir.join(format!("{MANIFEST}.tmp"));
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(&tmp, dir.join(MANIFEST))?;
+        Ok(())
+    }
+
+    /// Read the manifest of the directory `dir` and remove it, None if there is none
+    /// The manifest is only removed once it has been parsed, an invalid manifest is kept
+    pub fn take(dir: &Path) -> Result<Option<Self>, anyhow::Error> {
+        let path = dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let manifest: Self = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        if !manifest.is_valid_id() {
+            return Err(anyhow!("Invalid session identifier: {}", manifest.id));
+        }
+        fs::remove_file(&path)?;
+        Ok(Some(manifest))
+    }
+}
+
+/// File of a session received by keysas-out
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionEntry {
+    /// Path of the file relative to the root of the input device
+    pub path: String,
+    /// SHA256 digest of the file received by keysas-in
+    pub digest: String,
+    /// True if all checks passed
+    pub is_valid: bool,
+    /// True if the file has been written to sas_out
+    pub written: bool,
+}
+
+/// Summary of a session
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionSummary {
+    /// Manifest of the session
+    pub session: SessionManifest,
+    /// Date of the last update of the summary
+    pub date: String,
+    /// Number of files received
+    pub received: u64,
+    /// Number of files with a valid report
+    pub valid: u64,
+    /// True once all the files of the session are received
+    pub complete: bool,
+    /// True once the summary is final: the session is complete or has expired
+    pub closed: bool,
+    /// Files received
+    pub files: Vec<SessionEntry>,
+}
+
+impl SessionSummary {
+    /// Create an empty summary for the session
+    pub fn new(session: SessionManifest) -> Self {
+        Self {
+            session,
+            date: timestamp(),
+            received: 0,
+            valid: 0,
+            complete: false,
+            closed: false,
+            files: Vec::new(),
+        }
+    }
+
+    /// Add a file received to the summary
+    /// A file sent again, with the same path and digest, replaces its previous entry
+    /// and is not counted twice
+    ///
+    /// ```
+    /// use keysas_lib::session::{SessionEntry, SessionManifest, SessionSummary};
+    ///
+    /// let mut manifest = SessionManifest::new("network");
+    /// manifest.files = 2;
+    /// let mut summary = SessionSummary::new(manifest);
+    /// let entry = SessionEntry { path: "a.txt".into(), digest: String::new(), is_valid: true, written: true };
+    /// summary.add(entry.clone());
+    /// summary.add(entry.clone());
+    /// assert!(!summary.complete);
+    /// summary.add(SessionEntry { path: "b.txt".into(), is_valid: false, written: false, ..entry });
+    /// assert!(summary.complete);
+    /// assert_eq!((summary.received, summary.valid), (2, 1));
+    /// ```
+    pub fn add(&mut self, entry: SessionEntry) {
+        if let Some(i) = self
+            .files
+            .iter()
+            .position(|e| e.path == entry.path && e.digest == entry.digest)
+        {
+            let previous = self.files.remove(i);
+            self.received -= 1;
+            if previous.is_valid {
+                self.valid -= 1;
+            }
+        }
+        self.received += 1;
+        if entry.is_valid {
+            self.valid += 1;
+        }
+        self.files.push(entry);
+        self.date = timestamp();
+        self.complete = self.received >= self.session.files;
+    }
+
+    /// Close the summary, no file of the session is expected anymore
+    pub fn close(&mut self) {
+        self.date = timestamp();
+        self.closed = true;
+    }
+}
+
+/// Signature of the session summary
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionBinding {
+    /// SHA256 digest of the [SessionSummary] encoded in base64
+    pub summary_digest: String,
+    /// Station certificates: concatenation of its ED25519 and ML-DSA87 signing certificates with a '|' delimiter
+    pub station_certificate: String,
+    /// Report signature: concatenation of the ED25519 and ML-DSA87 signatures in base64
+    pub report_signature: String,
+}
+
+/// Signed summary written for each session
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionReport {
+    /// Summary of the session
+    pub summary: SessionSummary,
+    /// Binding of the summary and the station
+    pub binding: SessionBinding,
+}
+
+/// Sign with ED25519 and ML-DSA87 the digest of the session summary
+/// The two signatures are concatenated (ED25519 first)
+///
+/// # Arguments
+///
+/// * `summary` - Summary of the session
+/// * `sign_keys` - Hybrid key pair to sign the summary
+/// * `sign_cert` - Hybrid key pair certificate that will be included in the summary
+pub fn sign_session(
+    summary: &SessionSummary,
+    sign_keys: Option<&HybridKeyPair>,
+    sign_cert: &str,
+) -> Result<SessionReport, anyhow::Error> {
+    let json_string = serde_json::to_string(summary)?;
+
+    let summary_digest = {
+        // Import Trait digest localy to avoid collisation with Trait defined in ed25519_dalek
+        use sha2::Digest;
+        let mut hasher = Sha256::new();
+        hasher.update(json_string.as_bytes());
+        format!("{:x}", hasher.finalize())
+    };
+
+    let mut signature = Vec::new();
+
+    if let Some(keys) = sign_keys {
+        // Sign with ED25519
+        signature.append(&mut keys.classic.message_sign(summary_digest.as_bytes())?);
+        // Sign with ML-DSA87
+        signature.append(&mut keys.pq.message_sign(summary_digest.as_bytes())?);
+    }
+
+    Ok(SessionReport {
+        summary: summary.clone(),
+        binding: SessionBinding {
+            summary_digest: general_purpose::STANDARD.encode(summary_digest),
+            station_certificate: sign_cert.to_string(),
+            report_signature: general_purpose::STANDARD.encode(signature),
+        },
+    })
+}